@@ -1,20 +1,406 @@
 #[macro_use]
 extern crate serde;
-use candid::{Decode, Encode};
-use ic_cdk::api::time;
+use candid::{Decode, Encode, Nat, Principal};
+use ic_cdk::api::caller;
+use ic_cdk::api::management_canister::http_request as outcall;
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
 use ic_stable_structures::{BoundedStorable, Cell, DefaultMemoryImpl, StableBTreeMap, Storable};
-use std::{borrow::Cow, cell::RefCell};
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    iter::Peekable,
+    ops::{Bound, RangeBounds},
+};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 type IdCell = Cell<u64, Memory>;
+type ConfigCell = Cell<Config, Memory>;
+type AccessPolicyCell = Cell<AccessPolicy, Memory>;
+type UpgradeHealthCell = Cell<UpgradeHealth, Memory>;
+type PublishedCatalogCell = Cell<PublishedCatalog, Memory>;
+type InvariantStateCell = Cell<InvariantState, Memory>;
+type SnapshotStateCell = Cell<SnapshotState, Memory>;
 
-#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default, PartialEq, Eq)]
+// Entries a storage shard takes before new entries go to the next shard of its collection
+const ORDER_SHARD_CAPACITY: u64 = 250_000;
+const EVENT_SHARD_CAPACITY: u64 = 1_000_000;
+
+// Limits on product tags and allergens, low enough that a product with the most and the
+// longest of them still fits in `Product::MAX_SIZE`
+const MAX_TAGS_PER_PRODUCT: usize = 10;
+const MAX_ALLERGENS_PER_PRODUCT: usize = 14;
+const MAX_PRODUCT_LABEL_LENGTH: usize = 16;
+// Limit on other short labels, such as custom category and variant names
+const MAX_LABEL_LENGTH: usize = 32;
+// Maximum length of the unit a product is sold by, and the highest tax rate in basis points
+const MAX_UNIT_LENGTH: usize = 16;
+const MAX_TAX_RATE_BPS: u32 = 10_000;
+
+// Limit on product names, which are indexed to keep them unique
+const MAX_PRODUCT_NAME_LENGTH: usize = 100;
+
+// Version of the public interface: the major version changes on breaking changes,
+// the minor version when endpoints or optional fields are added
+const API_VERSION_MAJOR: u32 = 5;
+const API_VERSION_MINOR: u32 = 0;
+
+// Versions of the public read-only API for third-party aggregators, and the revision of v1,
+// raised when optional fields or methods are added to it
+const PUBLIC_API_VERSIONS: [u32; 1] = [1];
+const API_V1_REVISION: u32 = 1;
+// Methods of v1, also answered under the `/api/v1` HTTP prefix
+const API_V1_METHODS: [&str; 4] = [
+    "api_v1_info",
+    "api_v1_shop_status",
+    "api_v1_list_products",
+    "api_v1_get_product",
+];
+
+// Number of attempts made to draw a free id before giving up
+const MAX_ID_ATTEMPTS: u32 = 16;
+// Number of low bits of a time-sortable id filled with random data
+const TIME_SORTABLE_RANDOM_BITS: u32 = 22;
+
+const NANOS_PER_DAY: u64 = 86_400 * 1_000_000_000;
+
+// How long a customer has to order the units set aside for them from a waitlist
+const WAITLIST_CLAIM_WINDOW_NS: u64 = 2 * 3_600 * 1_000_000_000;
+const MAX_WAITLIST_LENGTH: usize = 200;
+
+// Limits on configurable product options, e.g. cake tiers, flavors and toppers
+const MAX_OPTION_GROUPS: usize = 6;
+const MAX_OPTION_CHOICES: usize = 10;
+const MAX_OPTION_EXCLUSIONS: usize = 4;
+// Keeps orders of configured products within their storage bound
+const MAX_OPTIONS_PER_ORDER: usize = 30;
+
+// Limits on recurring subscription orders
+const MAX_SUBSCRIPTIONS_PER_CUSTOMER: usize = 10;
+const MAX_SUBSCRIPTION_ITEMS: usize = 10;
+const MAX_SUBSCRIPTION_CADENCE_DAYS: u32 = 90;
+// Consecutive failed runs after which a subscription is paused
+const MAX_SUBSCRIPTION_FAILURES: u32 = 3;
+
+// How long boundary nodes and browsers may cache catalog pages served over HTTP
+const CATALOG_CACHE_MAX_AGE_SECONDS: u64 = 60;
+
+// Work done by a single run of the retention rules, and the audit entries kept
+const RETENTION_CUSTOMER_BATCH: usize = 50;
+const RETENTION_SALES_BATCH: usize = 500;
+const MAX_RETENTION_AUDIT_ENTRIES: u64 = 200;
+
+// Maximum number of products fetched by a single get_products call
+const MAX_BATCH_PRODUCT_IDS: usize = 100;
+
+// Limits on orders
+const MAX_ORDER_LINES: usize = 20;
+const MAX_NOTES_LENGTH: usize = 500;
+// Lateness still counted as on time against an order's promised ready time, and the number of
+// late orders listed in an SLA report
+const SLA_GRACE_NS: u64 = 5 * 60 * 1_000_000_000;
+const MAX_SLA_LATE_ORDERS: usize = 20;
+// Entries returned per read of the call journal
+const MAX_JOURNAL_PAGE: u32 = 500;
+// How long a price quote stays valid
+const QUOTE_TTL_NS: u64 = 7 * NANOS_PER_DAY;
+// How long a checkout session holds its prices and stock, and how long it is kept once over
+const CHECKOUT_SESSION_TTL_NS: u64 = 15 * 60 * 1_000_000_000;
+const CHECKOUT_SESSION_RETENTION_NS: u64 = NANOS_PER_DAY;
+// How far ahead a pickup or delivery slot can be booked, and the limit on address fields
+const MAX_SLOT_ADVANCE_NS: u64 = 14 * NANOS_PER_DAY;
+const MAX_ADDRESS_FIELD_LENGTH: usize = 100;
+// Scheme of the pickup QR printed on packing labels
+const PICKUP_QR_PREFIX: &str = "BAKERY-PICKUP";
+
+// Alphabet of generated coupon and gift-card codes, leaving out the easily confused 0, O, 1 and I
+const CODE_ALPHABET: &[u8; 32] = b"23456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+// Random characters of a code, followed by one check character, and the most codes per batch
+const CODE_RANDOM_LENGTH: usize = 11;
+const MAX_CODES_PER_BATCH: u32 = 100;
+
+// Limits on the registry of accepted payment tokens
+const MAX_ACCEPTED_TOKENS: u64 = 20;
+const MAX_TOKEN_SYMBOL_LENGTH: usize = 16;
+const MAX_TOKEN_DECIMALS: u8 = 18;
+
+// How long a destructive action waits for a second admin, and how long decided actions are kept
+const PENDING_ACTION_TTL_NS: u64 = NANOS_PER_DAY;
+const PENDING_ACTION_RETENTION_NS: u64 = 30 * NANOS_PER_DAY;
+// How often products and orders are snapshotted, and how far back they can be restored
+const SNAPSHOT_INTERVAL_NS: u64 = NANOS_PER_DAY;
+const RESTORE_WINDOW_NS: u64 = 30 * NANOS_PER_DAY;
+// Records gone through per batch of a snapshot or restore, and the instructions a snapshot may
+// use per expiry sweep or a restore per call
+const SNAPSHOT_BATCH: usize = 500;
+const SNAPSHOT_INSTRUCTIONS: u64 = 2_000_000_000;
+// Limits on suppliers, the sales history reorder forecasts average over, and draft sizes
+const MAX_SUPPLIER_LEAD_TIME_DAYS: u32 = 60;
+const MAX_REORDER_HISTORY_DAYS: u32 = 90;
+const MAX_REORDER_COVER_DAYS: u32 = 60;
+const MAX_PURCHASE_ORDER_LINES: usize = 100;
+// Devices kept per staff principal, revoked ones included
+const MAX_DEVICES_PER_PRINCIPAL: usize = 10;
+// Photos a customer can attach to a return request
+const MAX_RETURN_PHOTOS: usize = 5;
+// Most pieces of evidence a payment dispute can collect from both sides
+const MAX_DISPUTE_EVIDENCE: usize = 20;
+// Most order times kept per customer for the hourly fraud rule
+const MAX_VELOCITY_ORDERS: usize = 100;
+const NANOS_PER_HOUR: u64 = 3_600 * 1_000_000_000;
+// Largest rounding increment, and most prices one rounding preview covers
+const MAX_ROUNDING_INCREMENT: u64 = 10_000;
+const MAX_ROUNDING_PREVIEW_PRICES: usize = 100;
+// Orders per chunk of an order export, and most chunks one export can have
+const ORDER_EXPORT_CHUNK_ORDERS: usize = 500;
+const MAX_ORDER_EXPORT_CHUNKS: usize = 256;
+// Products removed per batch of an approved clear, and how long a batch's confirmation lasts
+const MAX_CLEAR_BATCH: u32 = 500;
+const CLEAR_TOKEN_TTL_NS: u64 = 5 * 60 * 1_000_000_000;
+// Products a product can link to as related
+const MAX_RELATIONS_PER_PRODUCT: usize = 20;
+// Principals one customer account can link, how long a link challenge lasts, and the wrong
+// codes it takes before it is dropped
+const MAX_LINKED_PRINCIPALS: usize = 5;
+const LINK_CHALLENGE_TTL_NS: u64 = 10 * 60 * 1_000_000_000;
+const MAX_LINK_ATTEMPTS: u32 = 5;
+// Products one stocktake can cover, and the staff counting them
+const MAX_STOCKTAKE_PRODUCTS: usize = 200;
+const MAX_STOCKTAKE_COUNTERS: usize = 20;
+// Product lines kept in a daily close report, and the rollup batches it runs to freeze the day
+const MAX_CLOSE_SALES_LINES: usize = 1_000;
+const MAX_CLOSE_ROLLUP_BATCHES: usize = 50;
+// Cancellation policies the shop can set, and the longest window one can have
+const MAX_CANCELLATION_POLICIES: usize = 32;
+const MAX_CANCELLATION_WINDOW_HOURS: u32 = 24 * 30;
+// Furthest a staging canister's clock can be moved from the system time
+const MAX_CLOCK_OFFSET_SECONDS: i64 = 366 * 86_400;
+// Limits on the message catalog: translations kept, and the length of language tags, message
+// templates and translations
+const MAX_TRANSLATIONS: u64 = 1_000;
+const MAX_LANGUAGE_TAG_LENGTH: usize = 16;
+const MAX_MESSAGE_TEMPLATE_LENGTH: usize = 256;
+// Pattern of order numbers when the config sets none, e.g. BK-2024-000123
+const DEFAULT_ORDER_NUMBER_FORMAT: &str = "BK-{YYYY}-{SEQ:6}";
+// Limits on order numbers: rendered length with the sequence at its width, and sequence width
+const MAX_ORDER_NUMBER_LENGTH: usize = 32;
+const MAX_ORDER_NUMBER_SEQ_WIDTH: usize = 12;
+// First byte of records in the compact storage encoding; Candid records start with "DIDL"
+const COMPACT_FORMAT_TAG: u8 = 0xC1;
+// Records re-encoded per storage migration call, and the most a storage benchmark goes through
+const STORAGE_MIGRATION_BATCH: usize = 200;
+const MAX_STORAGE_BENCHMARK_RECORDS: u32 = 100;
+// Wait after a product change before checking the published catalog, so that a bulk edit
+// triggers one storefront build; failed build hook calls are retried after the retry delay, up
+// to the most attempts
+const BUILD_HOOK_DEBOUNCE_NS: u64 = 60 * 1_000_000_000;
+const BUILD_HOOK_RETRY_NS: u64 = 5 * 60 * 1_000_000_000;
+const MAX_BUILD_HOOK_ATTEMPTS: u32 = 8;
+// Price changes larger than this need a second admin's approval
+const LARGE_PRICE_CHANGE_PERCENT: u64 = 25;
+// How far ahead a price change can be scheduled
+const MAX_PRICE_SCHEDULE_NS: u64 = 365 * NANOS_PER_DAY;
+
+// Number of past versions kept per product for reviewing changes
+const MAX_PRODUCT_VERSIONS: usize = 50;
+
+// Maximum length of the id a point-of-sale system gives to a sale
+const MAX_EXTERNAL_ID_LENGTH: usize = 64;
+
+// How long a self-serve kiosk session lasts
+const KIOSK_SESSION_TTL_NS: u64 = 15 * 60 * 1_000_000_000;
+
+// Limits and cost of the staff notifier's HTTPS outcalls
+const MAX_NOTIFIER_CHANNELS: u64 = 10;
+const MAX_NOTIFIER_URL_LENGTH: usize = 256;
+const MAX_NOTIFIER_TEMPLATE_LENGTH: usize = 500;
+const NOTIFIER_MAX_RESPONSE_BYTES: u64 = 2_048;
+const NOTIFIER_OUTCALL_CYCLES: u128 = 300_000_000;
+const DEFAULT_NOTIFIER_TEMPLATE: &str =
+    "New {event}: order #{order_id}, {items} items, total {total}";
+
+// Receipt emails sent through the email bridge when no template is set, and the limit on templates
+const DEFAULT_RECEIPT_SUBJECT: &str = "Your receipt for order {order_number}";
+const DEFAULT_RECEIPT_BODY: &str =
+    "Thank you for your order {order_number} of {date}.\n\n{lines}\n\nTotal: {total}\n\n{shop}";
+const MAX_RECEIPT_TEMPLATE_LENGTH: usize = 2_000;
+// Times the receipt of one order can be sent again, and the length of the delivery error kept
+const MAX_RECEIPT_RESENDS: u32 = 5;
+const MAX_RECEIPT_ERROR_LENGTH: usize = 256;
+
+// Maximum number of staff responses kept on a support ticket
+const MAX_TICKET_RESPONSES: usize = 10;
+
+// Surprise boxes are filled with products whose best-before falls within this window, sold at
+// this discount
+const SURPRISE_BOX_WINDOW_NS: u64 = 12 * 3_600 * 1_000_000_000;
+const SURPRISE_BOX_PERCENT_OFF: u64 = 40;
+const SURPRISE_BOX_MAX_UNITS: u32 = 50;
+
+// Limits of the storage-condition log
+const MAX_SENSOR_BRIDGES: usize = 10;
+const MAX_CONDITION_READINGS_PER_LOCATION: usize = 2_000;
+const MAX_EXCEPTION_BATCHES: usize = 50;
+// How far in the future a reading may be timestamped, to allow for sensor clock drift
+const MAX_READING_CLOCK_SKEW_NS: u64 = 5 * 60 * 1_000_000_000;
+
+// Limits of the message thread of an order
+const MAX_ORDER_MESSAGES: u64 = 50;
+const MAX_ORDER_MESSAGE_LENGTH: usize = 500;
+
+// Maximum number of sub-principals an account owner can manage
+const MAX_SUB_PRINCIPALS_PER_OWNER: usize = 50;
+
+// Limits on price experiments
+const MIN_EXPERIMENT_VARIANTS: usize = 2;
+const MAX_EXPERIMENT_VARIANTS: usize = 4;
+
+// Limits on time-based pricing rules
+const MAX_PRICING_RULES: u64 = 50;
+const MINUTES_PER_DAY: u16 = 24 * 60;
+const NANOS_PER_MINUTE: u64 = 60 * 1_000_000_000;
+
+// Thresholds used when segmenting customers
+const REGULAR_MIN_ORDERS: u32 = 3;
+const VIP_MIN_ORDERS: u32 = 10;
+const CHURN_RISK_AFTER_NS: u64 = 60 * NANOS_PER_DAY;
+// Defaults of the pagination limits; responses stay well below the 2MB message limit
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const DEFAULT_MAX_PAGE_SIZE: u32 = 500;
+const DEFAULT_RESPONSE_BUDGET_BYTES: u64 = 1_500_000;
+const MAX_RESPONSE_BUDGET_BYTES: u64 = 1_900_000;
+
+// Retention limits of the notification inbox
+const MAX_NOTIFICATIONS_PER_PRINCIPAL: usize = 100;
+const NOTIFICATION_RETENTION_NS: u64 = 90 * NANOS_PER_DAY;
+// Number of inconsistencies kept in a health snapshot or index report
+const MAX_HEALTH_ISSUES: usize = 20;
+// Number of entries an index rebuild step goes through
+const INDEX_REBUILD_BATCH: usize = 500;
+// How long a draft cart may sit idle before the expiry sweep purges it, unless configured
+const DEFAULT_CART_TTL_SECONDS: u64 = 7 * 24 * 3_600;
+const NOTIFICATIONS_PAGE_SIZE: usize = 20;
+
+// How often the customer segments are recomputed
+const SEGMENT_REFRESH_INTERVAL_NS: u64 = NANOS_PER_DAY;
+// How often raw sale events are rolled up into daily counters
+const SALES_ROLLUP_INTERVAL_NS: u64 = 3_600 * 1_000_000_000;
+// How often expired reservations are purged
+const EXPIRY_SWEEP_INTERVAL_NS: u64 = 15 * 60 * 1_000_000_000;
+// Days after a birthday its reward is still issued, should the daily job have been held up
+const BIRTHDAY_REWARD_GRACE_DAYS: i64 = 7;
+// How often pending escrow releases and refunds are retried, and how many per run
+const ESCROW_SETTLE_INTERVAL_NS: u64 = 5 * 60 * 1_000_000_000;
+const ESCROW_SETTLE_BATCH: usize = 10;
+// Outbox effects dispatched per run, attempts before an effect is given up, the first retry
+// delay (doubled on each attempt up to the cap) and how long delivered effects are kept
+const OUTBOX_DISPATCH_BATCH: usize = 20;
+const MAX_OUTBOX_ATTEMPTS: u32 = 8;
+const OUTBOX_RETRY_BASE_NS: u64 = 60 * 1_000_000_000;
+const OUTBOX_RETRY_MAX_NS: u64 = 3_600 * 1_000_000_000;
+const OUTBOX_RETENTION_NS: u64 = 7 * NANOS_PER_DAY;
+// How often due subscriptions are turned into orders, and how many per run
+const SUBSCRIPTION_RUN_INTERVAL_NS: u64 = 15 * 60 * 1_000_000_000;
+const SUBSCRIPTION_RUN_BATCH: usize = 20;
+// Maximum number of raw sale events folded into the rollups per run
+const SALES_ROLLUP_BATCH: usize = 5_000;
+// Rows and instructions one report step may use before handing back a continuation cursor;
+// the instruction budget leaves room under the query limit for encoding the response
+const REPORT_STEP_MAX_ROWS: usize = 1_000;
+const REPORT_STEP_INSTRUCTIONS: u64 = 2_000_000_000;
+// Products and instructions one batch of a category reassignment may use
+const CATEGORY_REASSIGN_BATCH: usize = 500;
+const CATEGORY_REASSIGN_INSTRUCTIONS: u64 = 2_000_000_000;
+
+// Product category; the set of variants is frozen so clients built against it keep decoding,
+// and new categories are named through `Other`. Categories are equal when their names are,
+// ignoring case and surrounding spaces
+#[derive(candid::CandidType, Clone, Debug, Serialize, Deserialize, Default)]
 enum Category {
     #[default]
     Bakery,
     Cake,
     Cookies,
+    Other(String),
+}
+
+impl Category {
+    // Built-in categories
+    const ALL: [Category; 3] = [Category::Bakery, Category::Cake, Category::Cookies];
+
+    // Stable numeric code used as a key in stable storage; only built-in categories have one
+    fn code(&self) -> Option<u8> {
+        match self {
+            Category::Bakery => Some(0),
+            Category::Cake => Some(1),
+            Category::Cookies => Some(2),
+            Category::Other(name) => {
+                Category::builtin_named(name).and_then(|builtin| builtin.code())
+            }
+        }
+    }
+
+    // Built-in category of a free-text name, as older clients send it, e.g. "cake"
+    fn builtin_named(name: &str) -> Option<Category> {
+        let name = name.trim();
+        Category::ALL
+            .into_iter()
+            .find(|builtin| builtin.name().eq_ignore_ascii_case(name))
+    }
+
+    // Category as stored: free-text names of built-in categories are mapped to them, and other
+    // names are trimmed
+    fn canonical(self) -> Category {
+        match self {
+            Category::Other(name) => Category::builtin_named(&name)
+                .unwrap_or_else(|| Category::Other(name.trim().to_string())),
+            builtin => builtin,
+        }
+    }
+
+    // Name categories are compared and indexed by
+    fn comparison_name(&self) -> String {
+        match Category::builtin_named(self.name()) {
+            Some(builtin) => builtin.name().to_lowercase(),
+            None => self.name().trim().to_lowercase(),
+        }
+    }
+
+    // Name shown to customers: the variant for built-in categories, or the name given by admins
+    fn name(&self) -> &str {
+        match self {
+            Category::Bakery => "Bakery",
+            Category::Cake => "Cake",
+            Category::Cookies => "Cookies",
+            Category::Other(name) => name,
+        }
+    }
+}
+
+impl PartialEq for Category {
+    fn eq(&self, other: &Category) -> bool {
+        match (self, other) {
+            (Category::Other(_), _) | (_, Category::Other(_)) => {
+                self.comparison_name() == other.comparison_name()
+            }
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+}
+
+impl Eq for Category {}
+
+// Publication state of a product; drafts and archived products are hidden from public queries
+#[derive(
+    candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq, Eq,
+)]
+enum ProductStatus {
+    Draft,
+    #[default]
+    Published,
+    // Taken out of the active catalog, e.g. after selling nothing for a long time
+    Archived,
 }
 
 #[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
@@ -23,13 +409,193 @@ struct Product {
     name: String,
     category: Category,
     quantity: u32,
+    // Price in the smallest currency unit (e.g. cents); unset on products stored before prices
+    price: Option<u64>,
+    tags: Option<Vec<String>>,
+    allergens: Option<Vec<String>>,
+    // Products stored before publication states were added are published
+    status: Option<ProductStatus>,
+    // Time at which a draft becomes published on its own
+    publish_at: Option<u64>,
     created_at: u64,
     updated_at: Option<u64>,
+    // Limits on the quantity a single order line may request; unlimited when not set
+    min_order_qty: Option<u32>,
+    max_order_qty: Option<u32>,
+    // Quantities must be a multiple of this step (e.g. cupcakes sold by the half dozen)
+    order_qty_step: Option<u32>,
+    // Position on the storefront homepage, lowest first; not featured when not set
+    featured_rank: Option<u32>,
+    // Only sold to customers attested as adults, e.g. rum cakes
+    age_restricted: Option<bool>,
+    // When the current stock should be sold by; offered in surprise boxes as it gets close
+    best_before: Option<u64>,
+    // Settings in effect, inherited from the category defaults unless overridden
+    settings: Option<ProductSettings>,
+    // Settings set on the product itself, which category defaults do not replace
+    overridden_settings: Option<Vec<ProductSetting>>,
+    // Minutes needed to prepare an order line of this product; ready off the shelf when not set
+    prep_minutes: Option<u32>,
+    // Units of the quantity kept on display, which are not for sale
+    display_units: Option<u32>,
+    // Supplier purchase orders are drafted with when stock falls below the reorder threshold
+    preferred_supplier_id: Option<u64>,
+    // What a unit costs the bakery to make or buy, in the smallest currency unit; admins only
+    unit_cost: Option<u64>,
+    // Left out of listings and search but reachable and orderable by id, e.g. corporate offers
+    // shared by link
+    unlisted: Option<bool>,
 }
 
-// Implementing Storable for Product to convert to/from bytes for storage
-impl Storable for Product {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+impl Product {
+    // Price in the smallest currency unit; products stored before prices were added are free
+    fn price(&self) -> u64 {
+        self.price.unwrap_or(0)
+    }
+
+    fn tags(&self) -> &[String] {
+        self.tags.as_deref().unwrap_or_default()
+    }
+
+    fn allergens(&self) -> &[String] {
+        self.allergens.as_deref().unwrap_or_default()
+    }
+
+    fn status(&self) -> ProductStatus {
+        self.status.unwrap_or_default()
+    }
+}
+
+// Who a product is shown to; each audience sees more of the internal fields than the one before
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Audience {
+    // Customers and anonymous callers
+    Public,
+    // Viewers, who see supplier links but not costs
+    Staff,
+    Admin,
+}
+
+// Cost and margin of a product, for admins
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ProductMargin {
+    product_id: u64,
+    price: u64,
+    unit_cost: Option<u64>,
+    // Price less the unit cost, negative when sold at a loss
+    margin: Option<i64>,
+    // Margin in basis points of the price
+    margin_bps: Option<i64>,
+}
+
+// How a related product is linked to a product
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum RelationKind {
+    // Shown as a cross-sell, e.g. clotted cream with scones
+    GoesWellWith,
+    // The related product is an upgrade of the product, e.g. a larger cake
+    UpgradeOf,
+}
+
+// Link from a product to a related product, set by an admin
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ProductRelation {
+    kind: RelationKind,
+    // Position among the product's related products, lowest first
+    rank: u32,
+    created_at: u64,
+}
+
+impl Storable for ProductRelation {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ProductRelation {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Product related to another, as shown on the storefront
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RelatedProduct {
+    kind: RelationKind,
+    rank: u32,
+    product: Product,
+}
+
+// Settings a product inherits from its category unless it sets its own
+#[derive(candid::CandidType, Clone, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
+struct ProductSettings {
+    // Tax rate in basis points, e.g. 700 for 7%
+    tax_rate_bps: Option<u32>,
+    // How long new stock keeps; sets the best-before of new products when not given
+    shelf_life_hours: Option<u32>,
+    // Stock level at which the product should be reordered
+    reorder_threshold: Option<u32>,
+    // Unit the product is sold by, e.g. "loaf" or "kg"
+    unit: Option<String>,
+}
+
+impl ProductSettings {
+    fn is_empty(&self) -> bool {
+        *self == ProductSettings::default()
+    }
+
+    fn set(&self) -> Vec<ProductSetting> {
+        let mut set = Vec::new();
+        if self.tax_rate_bps.is_some() {
+            set.push(ProductSetting::TaxRate);
+        }
+        if self.shelf_life_hours.is_some() {
+            set.push(ProductSetting::ShelfLife);
+        }
+        if self.reorder_threshold.is_some() {
+            set.push(ProductSetting::ReorderThreshold);
+        }
+        if self.unit.is_some() {
+            set.push(ProductSetting::Unit);
+        }
+        set
+    }
+
+    // Keeps only the given settings
+    fn only(&self, settings: &[ProductSetting]) -> ProductSettings {
+        ProductSettings {
+            tax_rate_bps: self
+                .tax_rate_bps
+                .filter(|_| settings.contains(&ProductSetting::TaxRate)),
+            shelf_life_hours: self
+                .shelf_life_hours
+                .filter(|_| settings.contains(&ProductSetting::ShelfLife)),
+            reorder_threshold: self
+                .reorder_threshold
+                .filter(|_| settings.contains(&ProductSetting::ReorderThreshold)),
+            unit: self
+                .unit
+                .clone()
+                .filter(|_| settings.contains(&ProductSetting::Unit)),
+        }
+    }
+
+    // Fills the settings not set here from `defaults`
+    fn or(self, defaults: ProductSettings) -> ProductSettings {
+        ProductSettings {
+            tax_rate_bps: self.tax_rate_bps.or(defaults.tax_rate_bps),
+            shelf_life_hours: self.shelf_life_hours.or(defaults.shelf_life_hours),
+            reorder_threshold: self.reorder_threshold.or(defaults.reorder_threshold),
+            unit: self.unit.or(defaults.unit),
+        }
+    }
+}
+
+impl Storable for ProductSettings {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -38,248 +604,24294 @@ impl Storable for Product {
     }
 }
 
+impl BoundedStorable for ProductSettings {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// One of the settings a product can inherit
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum ProductSetting {
+    TaxRate,
+    ShelfLife,
+    ReorderThreshold,
+    Unit,
+}
+
+// Default settings of a built-in category
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CategoryDefaults {
+    category: Category,
+    defaults: ProductSettings,
+}
+
+// Implementing Storable for Product to convert to/from bytes for storage
+impl Storable for Product {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(StoredEntity::Products, self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
 // Implementing BoundedStorable to define size limitations for Product storage
 impl BoundedStorable for Product {
     const MAX_SIZE: u32 = 1024; // Maximum size for a Product in bytes
     const IS_FIXED_SIZE: bool = false;
 }
 
-thread_local! {
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
-        MemoryManager::init(DefaultMemoryImpl::default())
-    );
+// Snapshot of a product as written by one change, kept in its audit history
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ProductVersion {
+    at: u64,
+    changed_by: Principal,
+    product: Product,
+    // Set on the version recorded when the product was removed
+    removed: Option<bool>,
+}
+
+impl Storable for ProductVersion {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ProductVersion {
+    const MAX_SIZE: u32 = Product::MAX_SIZE + 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// One field whose value differs between two products or product versions
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct FieldDiff {
+    field: String,
+    before: String,
+    after: String,
+}
+
+// Point at which products and orders changed since the previous point were copied
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SnapshotPoint {
+    taken_at: u64,
+    products: u32,
+    orders: u32,
+}
+
+impl Storable for SnapshotPoint {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SnapshotPoint {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// What restoring the state as of `target_at` changes, or would change
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RestoreSummary {
+    target_at: u64,
+    products_restored: u32,
+    // Products removed since the target, put back without stock
+    products_recreated: u32,
+    // Products created since the target
+    products_archived: u32,
+    // Products whose history does not reach back to the target
+    products_unavailable: u32,
+    orders_restored: u32,
+    // Orders whose payment was settled since the target
+    orders_skipped: u32,
+    // Orders placed since the target, left as they are
+    orders_kept: u32,
+}
+
+// Point-in-time restore held until a second admin approves it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RestorePlan {
+    id: u64,
+    target_at: u64,
+    requested_by: Principal,
+    requested_at: u64,
+    expires_at: u64,
+    status: ActionStatus,
+    decided_by: Option<Principal>,
+    decided_at: Option<u64>,
+    // Changes expected, counted while the restore is pending
+    preview: RestoreSummary,
+    // Changes made once approved
+    applied: Option<RestoreSummary>,
+    // Progress of the preview while pending, then of the restore once approved; not set on
+    // restores counted and carried out in one go
+    progress: Option<RestoreProgress>,
+    last_batch_at: Option<u64>,
+    completed_at: Option<u64>,
+}
+
+impl RestorePlan {
+    fn progress(&self) -> RestoreProgress {
+        self.progress.unwrap_or(RestoreProgress {
+            phase: RestorePhase::Done,
+            cursor: None,
+        })
+    }
+}
+
+// Record type a restore is going through, in the order it goes through them
+#[derive(
+    candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq, Eq,
+)]
+enum RestorePhase {
+    #[default]
+    Products,
+    Orders,
+    Done,
+}
+
+// Progress of a restore, or of its preview, spread over several calls
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+struct RestoreProgress {
+    phase: RestorePhase,
+    // Id of the last product or order gone through in the current phase
+    cursor: Option<u64>,
+}
+
+// One batch of a restore preview; pass `progress` back for the next one until its phase is
+// `Done`, adding up the summaries
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RestorePreview {
+    summary: RestoreSummary,
+    progress: RestoreProgress,
+}
+
+// Stage of a snapshot
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum SnapshotPhase {
+    // Copying the records changed since the previous snapshot
+    Products,
+    Orders,
+    // Dropping the copies no restore can need any more
+    PruneProducts,
+    PruneOrders,
+}
+
+// Snapshot spread over several expiry sweeps
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SnapshotRun {
+    started_at: u64,
+    // Start of the previous snapshot; the records changed since are copied
+    since: Option<u64>,
+    phase: SnapshotPhase,
+    // Id of the last record gone through in the current phase
+    cursor: Option<u64>,
+    products: u32,
+    orders: u32,
+}
+
+// Snapshot in progress, if any
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct SnapshotState {
+    run: Option<SnapshotRun>,
+}
+
+impl Storable for SnapshotState {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for RestorePlan {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RestorePlan {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Supplier {
+    id: u64,
+    name: String,
+    contact: Option<String>,
+    // Days between sending an order and the stock arriving
+    lead_time_days: u32,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl Storable for Supplier {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Supplier {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SupplierPayload {
+    name: String,
+    contact: Option<String>,
+    lead_time_days: u32,
+}
+
+// Lifecycle of a purchase order; approved orders are sent to the supplier outside the canister
+#[derive(
+    candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq, Eq,
+)]
+enum PurchaseOrderStatus {
+    #[default]
+    Draft,
+    Approved,
+    Rejected,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PurchaseOrderLine {
+    product_id: u64,
+    quantity: u32,
+    // Stock for sale and the reorder threshold when the line was drafted
+    available: i64,
+    reorder_threshold: u32,
+    // Units expected to sell over the supplier's lead time and the cover days
+    forecast_units: u32,
+}
+
+// Purchase order drafted for a supplier from products running low
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PurchaseOrder {
+    id: u64,
+    supplier_id: u64,
+    lines: Vec<PurchaseOrderLine>,
+    status: PurchaseOrderStatus,
+    created_at: u64,
+    updated_at: Option<u64>,
+    decided_by: Option<Principal>,
+    decided_at: Option<u64>,
+    // Set once the supplier acknowledged the order through the supplier portal
+    supplier_response: Option<SupplierResponse>,
+}
+
+// Supplier's answer to an approved purchase order
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SupplierResponse {
+    acknowledged_by: Principal,
+    acknowledged_at: u64,
+    // When the supplier expects to deliver, and when they last posted it
+    expected_delivery_at: Option<u64>,
+    delivery_posted_at: Option<u64>,
+}
+
+// Stock of a product as its supplier sees it in the supplier portal
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SupplierStockLevel {
+    product_id: u64,
+    name: String,
+    quantity: u32,
+    // For sale once reservations and display units are taken out
+    available: i64,
+    reorder_threshold: Option<u32>,
+}
+
+impl Storable for PurchaseOrder {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PurchaseOrder {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Move of every product of one category to another, carried out in batches
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CategoryReassignment {
+    id: u64,
+    from: Category,
+    to: Category,
+    started_by: Principal,
+    started_at: u64,
+    products_at_start: u64,
+    moved: u64,
+    // Products of the old category still to move, as of the last batch
+    remaining: u64,
+    batches: u32,
+    // Id of the last product the current pass went through
+    cursor: Option<u64>,
+    last_batch_at: Option<u64>,
+    completed_at: Option<u64>,
+}
+
+impl Storable for CategoryReassignment {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CategoryReassignment {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Clearing of all products approved through a pending action, carried out in batches that
+// each need a fresh confirmation token
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ClearRun {
+    // Approved action the run carries out
+    action_id: u64,
+    approved_by: Principal,
+    started_at: u64,
+    products_at_start: u64,
+    removed: u64,
+    remaining: u64,
+    batches: u32,
+    last_batch_at: Option<u64>,
+    finished_at: Option<u64>,
+    // One-time confirmation for the next batch, bound to the admin it was issued to
+    token: Option<String>,
+    token_holder: Option<Principal>,
+    token_expires_at: Option<u64>,
+}
+
+impl Storable for ClearRun {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ClearRun {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Confirmation token for one batch of a clear
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ClearToken {
+    token: String,
+    expires_at: u64,
+    remaining: u64,
+}
+
+// Destructive operation held until a second admin approves it
+#[derive(candid::CandidType, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum ActionKind {
+    ClearAllProducts,
+    RemoveProduct {
+        product_id: u64,
+    },
+    ChangePrice {
+        product_id: u64,
+        old_price: u64,
+        new_price: u64,
+    },
+}
+
+// Lifecycle of a pending action
+#[derive(
+    candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq, Eq,
+)]
+enum ActionStatus {
+    #[default]
+    Pending,
+    Approved,
+    Rejected,
+    // Reported for pending actions past their expiry; never stored
+    Expired,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PendingAction {
+    id: u64,
+    action: ActionKind,
+    requested_by: Principal,
+    requested_at: u64,
+    expires_at: u64,
+    status: ActionStatus,
+    decided_by: Option<Principal>,
+    decided_at: Option<u64>,
+}
+
+impl Storable for PendingAction {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PendingAction {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Where a scheduled price change is
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum ScheduledPriceStatus {
+    Scheduled,
+    // A large change, waiting for a second admin
+    AwaitingApproval,
+    Applied,
+    Cancelled,
+    // Not applied at its time, e.g. because it was never approved or the product was removed
+    Lapsed,
+}
+
+// Price a product takes on automatically at a future time
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ScheduledPriceChange {
+    id: u64,
+    product_id: u64,
+    // Price when the change was scheduled
+    old_price: u64,
+    new_price: u64,
+    effective_at: u64,
+    scheduled_by: Principal,
+    scheduled_at: u64,
+    status: ScheduledPriceStatus,
+    approved_by: Option<Principal>,
+    // Time the change was applied, cancelled or lapsed
+    closed_at: Option<u64>,
+    lapse_reason: Option<String>,
+}
+
+impl Storable for ScheduledPriceChange {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ScheduledPriceChange {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// One recorded change of a product's price
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PriceChange {
+    product_id: u64,
+    old_price: u64,
+    new_price: u64,
+    changed_at: u64,
+    changed_by: Principal,
+}
+
+impl Storable for PriceChange {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PriceChange {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Result of a bulk price adjustment
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PriceAdjustmentSummary {
+    affected: u32,
+    changes: Vec<PriceChange>,
+}
+
+// Hold placed on part of a product's stock, excluded from the available quantity
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Reservation {
+    id: u64,
+    product_id: u64,
+    quantity: u32,
+    holder: Principal,
+    created_at: u64,
+    expires_at: Option<u64>,
+    // Set aside for the holder from a waitlist; claimed by their next order of the product
+    from_waitlist: Option<bool>,
+}
+
+impl Storable for Reservation {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Reservation {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Effective availability of a product once active reservations are taken into account
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Availability {
+    product_id: u64,
+    quantity: u32,
+    reserved: u32,
+    // Can go negative when stock is offloaded below the reserved amount
+    available: i64,
+    // Units kept on display, left out of the available stock
+    display_units: Option<u32>,
+}
+
+// Shelf space left in a category, as reported to admins
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CategoryCapacity {
+    category: Category,
+    // Maximum total units across the category; unlimited when not set
+    max_units: Option<u32>,
+    units_in_stock: u64,
+    remaining: Option<u64>,
+}
+
+// Strategy used to allocate ids for new records
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+enum IdStrategy {
+    // 1, 2, 3, ... in creation order
+    #[default]
+    Sequential,
+    // Random non-zero u64, checked against existing ids
+    RandomU64,
+    // Millisecond timestamp in the high bits, random data in the low bits (ULID-like)
+    TimeSortable,
+}
+
+// Canister-wide configuration; the id strategy is set at install time
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct Config {
+    id_strategy: IdStrategy,
+    // ICRC-7 canister minting collectible order receipts
+    nft_canister: Option<Principal>,
+    // Defaults apply when not set
+    pagination: Option<PaginationConfig>,
+    // Account escrowed payments are released to; the canister's default account when not set
+    shop_account: Option<Account>,
+    // Set while the shop is closed to non-admin updates
+    maintenance: Option<MaintenanceMode>,
+    // Nothing is purged when not set
+    retention: Option<RetentionPolicy>,
+    // Whether cafe and wholesale customers get their tier prices; everyone pays retail when not set
+    tier_pricing_enabled: Option<bool>,
+    // Idle time after which draft carts are purged; the default applies when not set
+    cart_ttl_seconds: Option<u64>,
+    // Principals of IoT bridges allowed to post storage-condition readings
+    sensor_bridges: Option<Vec<Principal>>,
+    // Prep minutes the kitchen can produce per day; orders are not limited when not set
+    daily_capacity_minutes: Option<u32>,
+    // Purchase orders are not drafted when not set
+    auto_reorder: Option<AutoReorderConfig>,
+    // Whether admin update calls must come from a registered device; not required when not set
+    require_staff_devices: Option<bool>,
+    // Computed prices are left as they are when not set
+    price_rounding: Option<PriceRounding>,
+    // Customers cancel without a fee when not set
+    cancellation_policies: Option<Vec<CancellationPolicy>>,
+    // Seconds the clock of a staging canister runs ahead of (or behind) the system time
+    clock_offset_seconds: Option<i64>,
+    // Pattern of human-friendly order numbers; the default applies when not set
+    order_number_format: Option<String>,
+    // Set while payments are paused, e.g. during a ledger incident
+    payments_pause: Option<PaymentsPause>,
+    // Record types written in the compact encoding; all are written in Candid when not set
+    compact_entities: Option<Vec<StoredEntity>>,
+    // HTTPS URL POSTed to when the published catalog changes, e.g. a storefront build hook
+    build_hook_url: Option<String>,
+    // Value of a loyalty point in the smallest currency unit; points cannot pay when not set
+    point_value: Option<u64>,
+    // Given to customers on their birthday; no rewards are issued when not set
+    birthday_reward: Option<BirthdayReward>,
+    // Heuristics orders are screened against; no order is flagged when not set
+    fraud_rules: Option<FraudRules>,
+    // Deprecation notices of the public API versions
+    public_api_deprecations: Option<Vec<ApiDeprecation>>,
+    // Printed on packing slips and labels
+    shop_info: Option<ShopInfo>,
+    // HTTP email API receipts are sent through; no receipts are emailed when not set
+    email_bridge: Option<EmailBridge>,
+    // Template of receipt emails; the default one applies when not set
+    receipt_template: Option<ReceiptTemplate>,
+    // Set on install for staging canisters, whose clock admins may move
+    staging: Option<bool>,
+    // Set once custom categories are indexed by their normalized name
+    category_keys_normalized: Option<bool>,
+}
+
+// HTTP email API, e.g. an SMTP-over-HTTP bridge, taking a JSON message per POST
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct EmailBridge {
+    url: String,
+    // Address receipts are sent from
+    from: String,
+    // Sent as a bearer token; never returned once set
+    api_key: Option<String>,
+}
+
+// Subject and body of receipt emails; both support {shop}, {order_id}, {order_number}, {date},
+// {lines}, {subtotal}, {discount} and {total}
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ReceiptTemplate {
+    subject: String,
+    body: String,
+}
+
+// Shop details printed on packing slips and labels
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ShopInfo {
+    name: String,
+    address: Option<String>,
+    phone: Option<String>,
+    website: Option<String>,
+}
+
+// Notice that a public API version is deprecated, and when it stops being served
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ApiDeprecation {
+    version: u32,
+    deprecated_at: u64,
+    sunset_at: Option<u64>,
+    // Version or method aggregators should move to, e.g. "v2"
+    successor: Option<String>,
+    note: Option<String>,
+}
+
+// Limits beyond which an order is flagged or held for staff review; a limit applies only when set
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+struct FraudRules {
+    // Orders a customer may place within any hour
+    max_orders_per_hour: Option<u32>,
+    // Days after their first order during which a customer counts as new
+    new_account_days: u32,
+    // Value a new customer may order per day, in the smallest currency unit
+    new_account_daily_value: Option<u64>,
+    // Orders per day that redeem a promotion, points or store credit
+    max_redemptions_per_day: Option<u32>,
+    action: FraudAction,
+}
+
+// What happens to an order that trips a fraud rule
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum FraudAction {
+    // The order goes ahead and waits in the review queue
+    Flag,
+    // The order stays placed until staff clear it
+    Hold,
+}
+
+// Points and store credit given to a customer once a year on their birthday
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+struct BirthdayReward {
+    points: u64,
+    // In the smallest currency unit
+    store_credit: u64,
+}
+
+// Clock every timestamp is read from: the system time moved by the staging offset
+#[derive(Clone, Copy, Default)]
+struct Clock {
+    offset_ns: i64,
+}
+
+impl Clock {
+    // Clock of the offset in the config, which only staging canisters honour
+    fn from_config() -> Clock {
+        let offset_seconds = CONFIG.with(|config| {
+            let config = config.borrow();
+            let config = config.get();
+            config
+                .clock_offset_seconds
+                .filter(|_| config.staging == Some(true))
+        });
+        Clock {
+            offset_ns: offset_seconds.unwrap_or(0) * 1_000_000_000,
+        }
+    }
+
+    fn now(&self) -> u64 {
+        system_time().saturating_add_signed(self.offset_ns)
+    }
+
+    // System time at which the clock shows `at`, for timers and ledger transfers
+    fn system_time_at(&self, at: u64) -> u64 {
+        at.saturating_add_signed(-self.offset_ns)
+    }
+}
+
+// Helper function to read the system time of the canister
+#[cfg(not(test))]
+fn system_time() -> u64 {
+    ic_cdk::api::time()
+}
+
+#[cfg(test)]
+thread_local! {
+    // System time seen by unit tests, which run outside a canister
+    static TEST_SYSTEM_TIME: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+// Helper function to read the system time fixed by a unit test
+#[cfg(test)]
+fn system_time() -> u64 {
+    TEST_SYSTEM_TIME.with(|time| time.get())
+}
+
+// Clock as reported to admins and staging tools
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ClockStatus {
+    now: u64,
+    system_time: u64,
+    offset_seconds: i64,
+}
+
+// How computed prices, e.g. after promotions or percentage adjustments, are rounded
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PriceRounding {
+    // Prices are rounded to the nearest multiple of this many smallest units, e.g. 5 or 10
+    increment: Option<u64>,
+    // Prices of at least one whole unit then end in this many cents, e.g. 99 for x.99, picking
+    // the nearest such price and the lower one on ties
+    ending: Option<u64>,
+    // Decimals amounts in a payment token are rounded up to
+    token_decimals: Vec<TokenRounding>,
+}
+
+// Decimals kept in amounts charged in a payment token, e.g. 2 for a stablecoin with 6 decimals
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TokenRounding {
+    symbol: String,
+    decimals: u8,
+}
+
+// Price before and after rounding, for a product or a sample price
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RoundingPreview {
+    product_id: Option<u64>,
+    price: u64,
+    rounded: u64,
+}
+
+// How purchase orders are drafted for products running low
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+struct AutoReorderConfig {
+    // Days of sales the demand forecast averages over
+    history_days: u32,
+    // Days of forecast demand an order covers once it arrives
+    cover_days: u32,
+}
+
+// Ages after which data is purged or aggregated; a rule applies only when set
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+struct RetentionPolicy {
+    // Customer profiles, adult attestations, notifications and order notes, counted from the
+    // customer's last order
+    customer_data_after_days: Option<u32>,
+    // Daily sales rollups are folded into monthly rollups
+    daily_sales_after_days: Option<u32>,
+}
+
+// Notice shown to callers while payments are paused and the rest of the shop stays open
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PaymentsPause {
+    message: String,
+    paused_by: Principal,
+    since: u64,
+}
+
+// Notice shown to callers while the shop is in maintenance mode
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct MaintenanceMode {
+    message: String,
+    // Expected end of the maintenance, if known
+    eta: Option<u64>,
+    since: u64,
+}
+
+impl Storable for Config {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Arguments accepted by the canister on install
+#[derive(candid::CandidType, Deserialize)]
+struct InitArgs {
+    id_strategy: Option<IdStrategy>,
+    // Installs a staging canister; cannot be changed afterwards
+    staging: Option<bool>,
+}
+
+// Principal wrapper so principals can be used as stable map keys
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct PrincipalKey(Principal);
+
+// Required to use principals in composite keys; the empty principal sorts before all others,
+// so the default also serves as a lower bound in range scans
+impl Default for PrincipalKey {
+    fn default() -> Self {
+        PrincipalKey(Principal::management_canister())
+    }
+}
+
+impl Storable for PrincipalKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_slice())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        PrincipalKey(Principal::from_slice(bytes.as_ref()))
+    }
+}
+
+impl BoundedStorable for PrincipalKey {
+    const MAX_SIZE: u32 = 29; // Principals are at most 29 bytes long
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Lifecycle of an order
+#[derive(
+    candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq, Eq,
+)]
+enum OrderStatus {
+    #[default]
+    Placed,
+    Preparing,
+    Ready,
+    PickedUp,
+    Delivered,
+    Cancelled,
+}
+
+impl OrderStatus {
+    const ALL: [OrderStatus; 6] = [
+        OrderStatus::Placed,
+        OrderStatus::Preparing,
+        OrderStatus::Ready,
+        OrderStatus::PickedUp,
+        OrderStatus::Delivered,
+        OrderStatus::Cancelled,
+    ];
+
+    // Stable numeric code used as a key in stable storage
+    fn code(&self) -> u8 {
+        match self {
+            OrderStatus::Placed => 0,
+            OrderStatus::Preparing => 1,
+            OrderStatus::Ready => 2,
+            OrderStatus::PickedUp => 3,
+            OrderStatus::Delivered => 4,
+            OrderStatus::Cancelled => 5,
+        }
+    }
+
+    // Whether an order may move from this status to `next`
+    fn can_transition_to(&self, next: &OrderStatus) -> bool {
+        use OrderStatus::*;
+        matches!(
+            (self, next),
+            (Placed, Preparing)
+                | (Placed, Cancelled)
+                | (Preparing, Ready)
+                | (Preparing, Cancelled)
+                | (Ready, PickedUp)
+                | (Ready, Delivered)
+                | (Ready, Cancelled)
+        )
+    }
+}
+
+// One product line of an order, priced when the order was placed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderLine {
+    product_id: u64,
+    quantity: u32,
+    // Includes the price deltas of the selected options
+    unit_price: u64,
+    options: Option<Vec<PricedOption>>,
+    // Units handed to the customer so far, when the order is fulfilled in parts
+    fulfilled: Option<u32>,
+    // Units the shop could not provide, refunded to the customer
+    unfulfillable: Option<u32>,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Order {
+    id: u64,
+    customer: Principal,
+    lines: Vec<OrderLine>,
+    subtotal: u64,
+    discount: u64,
+    total: u64,
+    promotion_id: Option<u64>,
+    status: OrderStatus,
+    // Collectible receipt minted for this order
+    nft_receipt: Option<OrderNft>,
+    // Customer instructions such as customizations
+    notes: Option<String>,
+    // Quote the order was created from, with its locked prices
+    quote_id: Option<u64>,
+    created_at: u64,
+    updated_at: Option<u64>,
+    // Time-based pricing rules that discounted the order's lines
+    applied_rules: Option<Vec<AppliedPricingRule>>,
+    // Token the customer chose to pay with, and the amount due in it
+    payment: Option<OrderPayment>,
+    // Account owner whose spending limit the order counted against
+    account_owner: Option<Principal>,
+    // Kiosk session the order was placed in, to be paid in cash on pickup
+    kiosk_session: Option<u64>,
+    // Staff shift open when the order was placed
+    shift_id: Option<u64>,
+    // Part of the total refunded for unfulfillable lines
+    refund_due: Option<u64>,
+    // Pickup or delivery slot chosen at checkout
+    schedule: Option<OrderSchedule>,
+    // Time the shop promised the order would be ready when it was placed
+    promised_ready_at: Option<u64>,
+    // Time the order was marked ready
+    ready_at: Option<u64>,
+    // Prep minutes the order takes from the production capacity of its day
+    prep_minutes: Option<u32>,
+    // Set when the customer cancelled the order, with the policies applied
+    cancellation: Option<OrderCancellation>,
+    // Human-friendly reference for receipts and phone support, e.g. BK-2024-000123
+    order_number: Option<String>,
+    // Tenders the order was split across, when store credit or points paid part of it
+    tenders: Option<OrderTenders>,
+}
+
+// Way of paying part of an order
+#[derive(candid::CandidType, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum Tender {
+    Points,
+    StoreCredit,
+    Token { symbol: String },
+    PayOnPickup,
+}
+
+// Part of an order paid with one tender; units are points for points and the smallest currency
+// unit otherwise
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderTender {
+    tender: Tender,
+    units: u64,
+    // Share of the order total, in the smallest currency unit
+    value: u64,
+    refunded_units: u64,
+}
+
+// Tenders of an order in the order they were applied, with the value refunded across them
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderTenders {
+    tenders: Vec<OrderTender>,
+    refunded: u64,
+}
+
+// Cancellation terms of a category for orders due within a window, e.g. custom cakes within
+// 24 hours of pickup
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CancellationPolicy {
+    category: Category,
+    // Hours before the order is due from which the policy applies
+    window_hours: u32,
+    // Share of the category's lines kept as a fee, in basis points; lines of the category cannot
+    // be cancelled inside the window when not set
+    fee_bps: Option<u32>,
+}
+
+// Policy applied to the lines of one category when an order was cancelled
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AppliedCancellationPolicy {
+    category: Category,
+    window_hours: u32,
+    fee_bps: u32,
+    lines_value: u64,
+    fee: u64,
+}
+
+// Fee and refund of an order cancelled by its customer
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderCancellation {
+    cancelled_at: u64,
+    by: Principal,
+    // Kept from a held payment; orders paid at the counter have no payment to keep it from
+    fee: u64,
+    refund: u64,
+    policies: Vec<AppliedCancellationPolicy>,
+}
+
+// Production capacity of one day, in prep minutes
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DayCapacity {
+    day: u64,
+    capacity_minutes: u32,
+    used_minutes: u64,
+    remaining_minutes: u64,
+}
+
+// Whether a made-to-order product can still be ordered for a day
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CalendarDay {
+    // Days since the Unix epoch (UTC)
+    day: u64,
+    available: bool,
+    // Prep minutes that day has left; not set when capacity is not limited or the day cannot be
+    // booked
+    remaining_minutes: Option<u64>,
+}
+
+// Days of a month a made-to-order product can be ordered for
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AvailabilityCalendar {
+    product_id: u64,
+    // Months since January 1970
+    month: u64,
+    prep_minutes: u32,
+    days: Vec<CalendarDay>,
+}
+
+// Period a report covers, from `from` (inclusive) to `to` (exclusive)
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+struct ReportPeriod {
+    from: u64,
+    to: u64,
+}
+
+// Manifest of an order export, whose hash is recorded in the certified call journal
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderExportManifest {
+    id: u64,
+    period: ReportPeriod,
+    order_count: u32,
+    // SHA-256 of each chunk's JSON lines, in chunk order
+    chunk_hashes: Vec<Vec<u8>>,
+    hash: Vec<u8>,
+    // Call journal entry recording the manifest hash as its arguments hash
+    journal_seq: u64,
+    created_by: Principal,
+    created_at: u64,
+}
+
+impl Storable for OrderExportManifest {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for OrderExportManifest {
+    const MAX_SIZE: u32 = 16_384;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// One chunk of an order export: newline-delimited JSON, one order per line
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderExportChunk {
+    export_id: u64,
+    chunk: u32,
+    chunk_count: u32,
+    chunk_hash: Vec<u8>,
+    manifest_hash: Vec<u8>,
+    jsonl: String,
+}
+
+// Order that was ready later than promised, or is still not ready past its promise
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LateOrder {
+    order_id: u64,
+    promised_ready_at: u64,
+    ready_at: Option<u64>,
+    late_by_ns: u64,
+}
+
+// On-time performance of the orders promised within a period
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SlaReport {
+    period: ReportPeriod,
+    // Orders promised within the period that were not cancelled
+    orders: u64,
+    on_time: u64,
+    late: u64,
+    // Orders past their promise that are not ready yet
+    overdue: u64,
+    // Share of the orders that were ready on time, in percent
+    on_time_percent: Option<u8>,
+    average_late_by_ns: Option<u64>,
+    // The latest orders first
+    late_orders: Vec<LateOrder>,
+}
+
+// Address an order is delivered to
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DeliveryAddress {
+    recipient: String,
+    line1: String,
+    line2: Option<String>,
+    city: String,
+    postal_code: String,
+    phone: Option<String>,
+}
+
+// When an order is picked up or delivered, and where to when delivered
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderSchedule {
+    slot_start: u64,
+    // Picked up at the shop when not set
+    delivery_address: Option<DeliveryAddress>,
+}
+
+impl Storable for Order {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(encode_record(StoredEntity::Orders, self))
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        decode_record(bytes.as_ref())
+    }
+}
+
+impl BoundedStorable for Order {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Customer segment derived from order history
+#[derive(
+    candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq, Eq,
+)]
+enum Segment {
+    #[default]
+    New,
+    Regular,
+    Vip,
+    ChurnRisk,
+}
+
+// Order statistics kept per customer principal
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Customer {
+    principal: Principal,
+    order_count: u32,
+    first_order_at: u64,
+    last_order_at: u64,
+    segment: Segment,
+    segment_updated_at: Option<u64>,
+}
+
+impl Storable for Customer {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Customer {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Percentage discount applied to orders, optionally limited to some segments
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Promotion {
+    id: u64,
+    name: String,
+    percent_off: u8,
+    // Applies to every customer when empty
+    segments: Vec<Segment>,
+    active: bool,
+    created_at: u64,
+}
+
+impl Storable for Promotion {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Promotion {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Discount applied during a daily time window, e.g. pastries half off in the last opening hour
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PricingRule {
+    id: u64,
+    name: String,
+    percent_off: u8,
+    // Applies to every category when empty
+    categories: Vec<Category>,
+    // Window in minutes after midnight UTC; wraps past midnight when start_minute > end_minute
+    start_minute: u16,
+    end_minute: u16,
+    active: bool,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl Storable for PricingRule {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for PricingRule {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// What customers assigned to an experiment variant are offered
+#[derive(candid::CandidType, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum VariantTreatment {
+    // Catalog price and the usual promotions
+    Control,
+    // Unit price of the experiment's product
+    Price { unit_price: u64 },
+    // Promotion applied to the whole order instead of the best available one
+    Promotion { promotion_id: u64 },
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ExperimentVariant {
+    name: String,
+    treatment: VariantTreatment,
+}
+
+// A/B test of a product's pricing; callers are assigned to a variant by a hash of their
+// principal, so they always see the same offer
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Experiment {
+    id: u64,
+    name: String,
+    product_id: u64,
+    variants: Vec<ExperimentVariant>,
+    active: bool,
+    created_at: u64,
+    ended_at: Option<u64>,
+}
+
+impl Storable for Experiment {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Experiment {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Conversions recorded for one experiment variant
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+struct VariantStats {
+    orders: u64,
+    units: u64,
+    revenue: u64,
+}
+
+impl Storable for VariantStats {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for VariantStats {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Results of one experiment variant, as reported to admins
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct VariantResult {
+    name: String,
+    treatment: VariantTreatment,
+    orders: u64,
+    units: u64,
+    revenue: u64,
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ExperimentResults {
+    experiment: Experiment,
+    variants: Vec<VariantResult>,
+}
+
+// Variant of an experiment a checkout was priced with
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+struct ExperimentAssignment {
+    experiment_id: u64,
+    product_id: u64,
+    variant: u8,
+}
+
+// Record of a pricing rule discounting one line of a quote or order
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AppliedPricingRule {
+    rule_id: u64,
+    product_id: u64,
+    percent_off: u8,
+    discount: u64,
+}
+
+// How the shop's prices are converted into amounts of a payment token
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum ConversionSource {
+    // One whole token is worth one whole currency unit (100 of the smallest unit)
+    Pegged,
+    // One whole token is worth a fixed number of the currency's smallest unit
+    Fixed { price_per_token: u64 },
+}
+
+// ICRC-1 ledger accepted as a means of payment
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AcceptedToken {
+    symbol: String,
+    decimals: u8,
+    ledger: Principal,
+    conversion: ConversionSource,
+    enabled: bool,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl Storable for AcceptedToken {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AcceptedToken {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Token and amount an order is to be paid with, converted when the order was placed
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderPayment {
+    symbol: String,
+    ledger: Principal,
+    // Amount in the token's base units
+    amount: Nat,
+    escrow: Option<Escrow>,
+}
+
+// Progress of a payment held in an order's escrow subaccount
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum EscrowStatus {
+    AwaitingPayment,
+    Held,
+    // Being transferred to the shop account once the order is picked up or delivered
+    Releasing,
+    Released,
+    // Being transferred back to the customer after the shop cancelled the order
+    Refunding,
+    Refunded,
+}
+
+// Escrow subaccount of the canister holding an order's payment until it is settled
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Escrow {
+    subaccount: Vec<u8>,
+    status: EscrowStatus,
+    // Creation time of the settlement transfer, reused on retries so the ledger deduplicates it
+    settle_requested_at: Option<u64>,
+    block_index: Option<Nat>,
+    last_error: Option<String>,
+    // Transfer refunding the unfulfillable lines before the rest is released to the shop
+    partial_refund_block: Option<Nat>,
+    // Transfer keeping a cancellation fee before the rest is refunded to the customer
+    cancellation_fee_block: Option<Nat>,
+}
+
+// Accounts of the shop's internal double-entry ledger, in the currency's smallest unit
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum LedgerAccount {
+    Cash,
+    Receivables,
+    Sales,
+    Refunds,
+    StoreCredit,
+    TaxPayable,
+    Inventory,
+    // Marketing expense, e.g. store credit given away as a birthday reward
+    Promotions,
+}
+
+impl LedgerAccount {
+    const ALL: [LedgerAccount; 8] = [
+        LedgerAccount::Cash,
+        LedgerAccount::Receivables,
+        LedgerAccount::Sales,
+        LedgerAccount::Refunds,
+        LedgerAccount::StoreCredit,
+        LedgerAccount::TaxPayable,
+        LedgerAccount::Inventory,
+        LedgerAccount::Promotions,
+    ];
+
+    fn code(&self) -> u8 {
+        match self {
+            LedgerAccount::Cash => 0,
+            LedgerAccount::Receivables => 1,
+            LedgerAccount::Sales => 2,
+            LedgerAccount::Refunds => 3,
+            LedgerAccount::StoreCredit => 4,
+            LedgerAccount::TaxPayable => 5,
+            LedgerAccount::Inventory => 6,
+            LedgerAccount::Promotions => 7,
+        }
+    }
+}
+
+// One side of a journal entry; exactly one of debit and credit is non-zero
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+struct Posting {
+    account: LedgerAccount,
+    debit: u64,
+    credit: u64,
+}
+
+// Balanced journal entry recording one financial operation
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct JournalEntry {
+    id: u64,
+    at: u64,
+    memo: String,
+    order_id: Option<u64>,
+    postings: Vec<Posting>,
+}
+
+impl Storable for JournalEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for JournalEntry {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Running totals posted to a ledger account
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+struct AccountTotals {
+    debits: u64,
+    credits: u64,
+}
+
+impl Storable for AccountTotals {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AccountTotals {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Balance of one account in the trial balance; positive balances are debit balances
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AccountBalance {
+    account: LedgerAccount,
+    debits: u64,
+    credits: u64,
+    balance: i128,
+}
+
+// Totals of every ledger account; debits and credits are equal when the books balance
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TrialBalance {
+    accounts: Vec<AccountBalance>,
+    total_debits: u64,
+    total_credits: u64,
+}
+
+// Movement of an account in one journal entry
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StatementLine {
+    entry_id: u64,
+    at: u64,
+    memo: String,
+    order_id: Option<u64>,
+    debit: u64,
+    credit: u64,
+}
+
+// Movements of an account over a period, one page at a time
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AccountStatement {
+    account: LedgerAccount,
+    from: u64,
+    to: u64,
+    opening_balance: i128,
+    closing_balance: i128,
+    lines: Vec<StatementLine>,
+    next_cursor: Option<u64>,
+}
+
+// Principal buying on behalf of an account owner, e.g. a staff purchasing card, with the
+// amount it may spend per UTC day
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SpendingLimit {
+    principal: Principal,
+    owner: Principal,
+    label: Option<String>,
+    daily_cap: u64,
+    // Day since the Unix epoch the spent amount applies to
+    spent_day: u64,
+    spent: u64,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl Storable for SpendingLimit {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for SpendingLimit {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// What a sub-principal can still spend today
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Allowance {
+    principal: Principal,
+    owner: Principal,
+    daily_cap: u64,
+    spent_today: u64,
+    remaining: u64,
+    resets_at: u64,
+}
+
+// Id given to a sale by an external point-of-sale system, used as a stable map key
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
+struct ExternalId(String);
+
+impl Storable for ExternalId {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        ExternalId(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl BoundedStorable for ExternalId {
+    const MAX_SIZE: u32 = MAX_EXTERNAL_ID_LENGTH as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Product name folded to lowercase with whitespace collapsed, used as the key of the name index
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Default)]
+struct ProductName(String);
+
+impl ProductName {
+    fn normalize(name: &str) -> Self {
+        ProductName(
+            name.split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ")
+                .to_lowercase(),
+        )
+    }
+}
+
+impl Storable for ProductName {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        ProductName(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl BoundedStorable for ProductName {
+    // Lowercasing can lengthen some characters, so leave room for it
+    const MAX_SIZE: u32 = 2 * MAX_PRODUCT_NAME_LENGTH as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Sale made through a physical point-of-sale system and imported into the canister
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ExternalSale {
+    external_id: String,
+    lines: Vec<OrderLine>,
+    total: u64,
+    // When the sale happened at the till
+    sold_at: u64,
+    imported_at: u64,
+}
+
+impl Storable for ExternalSale {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ExternalSale {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// State-changing endpoints whose access by the anonymous principal can be configured
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum Endpoint {
+    AddProduct,
+    UpdateProduct,
+    AddQuantity,
+    OffloadQuantity,
+    RemoveProduct,
+    ClearAllProducts,
+    ReserveStock,
+    ReleaseReservation,
+    PlaceOrder,
+    TransferStock,
+    WatchProduct,
+    RequestQuote,
+    AcceptQuote,
+    ConfirmPayment,
+    CreateTicket,
+    PlaceKioskOrder,
+}
+
+// Endpoints that accept calls from the anonymous principal; all are blocked by default
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct AccessPolicy {
+    anonymous_allowed: Vec<Endpoint>,
+}
+
+impl Storable for AccessPolicy {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// HTTP request forwarded to the canister by the HTTP gateway
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct HttpRequest {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+// HTTP response returned to the HTTP gateway
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct HttpResponse {
+    status_code: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+// ICRC-1 account, also used by ICRC-7 token ownership
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+// Arguments of an ICRC-2 transfer from an account that approved the canister
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+// Errors returned by an ICRC-2 transfer from
+#[derive(candid::CandidType, Clone, Debug, Serialize, Deserialize)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+// Arguments of an ICRC-1 transfer
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+// Errors returned by an ICRC-1 transfer
+#[derive(candid::CandidType, Clone, Debug, Serialize, Deserialize)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    TemporarilyUnavailable,
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+}
+
+// Subset of the ICRC-3 value type used for NFT metadata
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum MetadataValue {
+    Nat(Nat),
+    Text(String),
+}
+
+// Arguments of the `icrc7_mint` method expected on the configured NFT canister
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct NftMintArgs {
+    token_id: Nat,
+    owner: Account,
+    metadata: Vec<(String, MetadataValue)>,
+}
+
+// Collectible receipt attached to an order
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderNft {
+    order_id: u64,
+    nft_canister: Principal,
+    token_id: u64,
+}
+
+// Storage location within the shop
+#[derive(
+    candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq, Eq,
+)]
+enum Location {
+    // Counter display; holds whatever stock is not allocated elsewhere
+    #[default]
+    FrontShelf,
+    BackFridge,
+    Freezer,
+}
+
+impl Location {
+    // Back-of-house locations, in the order they are drawn from once the front shelf is empty
+    const BACK_OF_HOUSE: [Location; 2] = [Location::BackFridge, Location::Freezer];
+
+    fn code(&self) -> u8 {
+        match self {
+            Location::FrontShelf => 0,
+            Location::BackFridge => 1,
+            Location::Freezer => 2,
+        }
+    }
+}
+
+// Temperatures a storage location must stay within, in tenths of a degree Celsius
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+struct StorageRange {
+    min_decidegrees: i32,
+    max_decidegrees: i32,
+}
+
+impl StorageRange {
+    fn contains(&self, decidegrees: i32) -> bool {
+        (self.min_decidegrees..=self.max_decidegrees).contains(&decidegrees)
+    }
+}
+
+impl Storable for StorageRange {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for StorageRange {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storage-condition reading posted by staff or an IoT bridge
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ConditionReading {
+    location: Location,
+    // Tenths of a degree Celsius
+    temperature_decidegrees: i32,
+    // When the sensor took the reading
+    recorded_at: u64,
+    posted_by: Principal,
+    posted_at: u64,
+    // Not set when no range was configured for the location at the time
+    in_range: Option<bool>,
+}
+
+impl Storable for ConditionReading {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ConditionReading {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Units of a product kept at a location while it was out of range
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AffectedBatch {
+    product_id: u64,
+    quantity: u32,
+}
+
+// Period a location spent out of its storage range, with the stock kept there at the time
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StorageException {
+    id: u64,
+    location: Location,
+    range: StorageRange,
+    started_at: u64,
+    // Set by the first reading back in range
+    ended_at: Option<u64>,
+    min_decidegrees: i32,
+    max_decidegrees: i32,
+    out_of_range_readings: u32,
+    batches: Vec<AffectedBatch>,
+    // Staff notes on what was done with the affected stock
+    resolution: Option<String>,
+    resolved_by: Option<Principal>,
+    resolved_at: Option<u64>,
+}
+
+impl Storable for StorageException {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for StorageException {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Conditions of one location over a compliance reporting period
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct LocationConditionReport {
+    location: Location,
+    range: Option<StorageRange>,
+    readings: u32,
+    out_of_range_readings: u32,
+    min_decidegrees: Option<i32>,
+    max_decidegrees: Option<i32>,
+    exceptions: Vec<StorageException>,
+}
+
+// Units of a product held at one location
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LocationStock {
+    location: Location,
+    quantity: u32,
+}
+
+// Product shown on the counter display with its front-of-house stock
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CounterItem {
+    product_id: u64,
+    name: String,
+    price: u64,
+    quantity: u32,
+}
+
+// Lifecycle of a price quote
+#[derive(
+    candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq, Eq,
+)]
+enum QuoteStatus {
+    #[default]
+    Open,
+    Accepted,
+    // Reported for open quotes past their expiry; never stored
+    Expired,
+}
+
+// Priced offer for an order whose prices are locked until it expires
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Quote {
+    id: u64,
+    customer: Principal,
+    lines: Vec<OrderLine>,
+    customizations: Option<String>,
+    subtotal: u64,
+    discount: u64,
+    total: u64,
+    promotion_id: Option<u64>,
+    status: QuoteStatus,
+    order_id: Option<u64>,
+    created_at: u64,
+    expires_at: u64,
+    applied_rules: Option<Vec<AppliedPricingRule>>,
+}
+
+impl Storable for Quote {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Quote {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Background job run by the canister's global timer
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum Job {
+    RefreshSegments,
+    RollUpSales,
+    ExpirySweep,
+    SettleEscrows,
+    RunSubscriptions,
+}
+
+impl Job {
+    const ALL: [Job; 5] = [
+        Job::RefreshSegments,
+        Job::RollUpSales,
+        Job::ExpirySweep,
+        Job::SettleEscrows,
+        Job::RunSubscriptions,
+    ];
+
+    fn code(&self) -> u8 {
+        match self {
+            Job::RefreshSegments => 0,
+            Job::RollUpSales => 1,
+            Job::ExpirySweep => 2,
+            Job::SettleEscrows => 3,
+            Job::RunSubscriptions => 4,
+        }
+    }
+
+    fn interval_ns(&self) -> u64 {
+        match self {
+            Job::RefreshSegments => SEGMENT_REFRESH_INTERVAL_NS,
+            Job::RollUpSales => SALES_ROLLUP_INTERVAL_NS,
+            Job::ExpirySweep => EXPIRY_SWEEP_INTERVAL_NS,
+            Job::SettleEscrows => ESCROW_SETTLE_INTERVAL_NS,
+            Job::RunSubscriptions => SUBSCRIPTION_RUN_INTERVAL_NS,
+        }
+    }
+
+    fn run(&self) {
+        match self {
+            Job::RefreshSegments => {
+                refresh_customer_segments();
+                issue_birthday_rewards();
+            }
+            Job::RollUpSales => {
+                roll_up_sales_events();
+                draft_purchase_orders();
+            }
+            Job::ExpirySweep => {
+                purge_expired_reservations();
+                purge_old_notifications();
+                purge_old_pending_actions();
+                purge_expired_kiosk_sessions();
+                purge_idle_carts();
+                purge_old_checkout_sessions();
+                purge_delivered_outbox();
+                take_snapshots();
+                close_previous_day();
+                apply_retention(false);
+                // Catches drafts whose publishing time came without a change to the product
+                check_published_catalog();
+                check_invariants_nightly();
+            }
+            Job::SettleEscrows => {
+                retry_escrow_settlements();
+                dispatch_outbox();
+            }
+            Job::RunSubscriptions => run_due_subscriptions(),
+        }
+    }
+}
+
+// Schedule of a job, persisted so it survives upgrades
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ScheduledJob {
+    job: Job,
+    interval_ns: u64,
+    next_run: u64,
+    last_run: Option<u64>,
+    // Instructions used by the last run
+    last_instructions: Option<u64>,
+}
+
+impl Storable for ScheduledJob {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ScheduledJob {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Raw sale event; negative values record cancelled sales
+#[derive(Clone, Copy)]
+struct SaleEvent {
+    at: u64,
+    product_id: u64,
+    units: i32,
+    revenue: i64,
+}
+
+// Compact fixed-size encoding: 8 + 8 + 4 + 8 bytes, big-endian
+impl Storable for SaleEvent {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(Self::MAX_SIZE as usize);
+        bytes.extend_from_slice(&self.at.to_be_bytes());
+        bytes.extend_from_slice(&self.product_id.to_be_bytes());
+        bytes.extend_from_slice(&self.units.to_be_bytes());
+        bytes.extend_from_slice(&self.revenue.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        SaleEvent {
+            at: u64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            product_id: u64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+            units: i32::from_be_bytes(bytes[16..20].try_into().unwrap()),
+            revenue: i64::from_be_bytes(bytes[20..28].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for SaleEvent {
+    const MAX_SIZE: u32 = 28;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// Rolled-up sales of one product on one day
+#[derive(Clone, Copy, Default)]
+struct DailySales {
+    units: i64,
+    revenue: i64,
+}
+
+// Compact fixed-size encoding: 8 + 8 bytes, big-endian
+impl Storable for DailySales {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(Self::MAX_SIZE as usize);
+        bytes.extend_from_slice(&self.units.to_be_bytes());
+        bytes.extend_from_slice(&self.revenue.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        DailySales {
+            units: i64::from_be_bytes(bytes[0..8].try_into().unwrap()),
+            revenue: i64::from_be_bytes(bytes[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for DailySales {
+    const MAX_SIZE: u32 = 16;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+// Sales of one product on one day, as reported to admins
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DailySalesReport {
+    // Days since the Unix epoch (UTC)
+    day: u64,
+    product_id: u64,
+    units: i64,
+    revenue: i64,
+}
+
+// Interface version reported to frontends and tooling
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ApiVersion {
+    major: u32,
+    minor: u32,
+    package_version: String,
+}
+
+// What a notification is about
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum NotificationKind {
+    OrderReady { order_id: u64 },
+    BackInStock { product_id: u64 },
+    Promotion { promotion_id: u64 },
+    TicketResponse { ticket_id: u64 },
+    SubscriptionPaused { subscription_id: u64 },
+}
+
+// What a support ticket is about
+#[derive(
+    candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq, Eq,
+)]
+enum TicketCategory {
+    #[default]
+    Feedback,
+    Complaint,
+    Question,
+}
+
+// Lifecycle of a support ticket
+#[derive(
+    candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq, Eq,
+)]
+enum TicketStatus {
+    #[default]
+    Open,
+    Assigned,
+    Answered,
+    Closed,
+}
+
+// Staff reply on a support ticket
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TicketResponse {
+    author: Principal,
+    text: String,
+    created_at: u64,
+}
+
+// Customer feedback or complaint, optionally about one of their orders
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Ticket {
+    id: u64,
+    customer: Principal,
+    order_id: Option<u64>,
+    category: TicketCategory,
+    text: String,
+    status: TicketStatus,
+    // Staff member handling the ticket
+    assignee: Option<Principal>,
+    responses: Vec<TicketResponse>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl Storable for Ticket {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Ticket {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Lifecycle of a return request; only approved returns can be refunded
+#[derive(
+    candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq, Eq,
+)]
+enum ReturnStatus {
+    #[default]
+    Requested,
+    Approved,
+    Rejected,
+    Refunded,
+}
+
+// Photo of returned goods uploaded by the customer to an asset store
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ReturnPhoto {
+    // URL or asset key of the uploaded photo
+    reference: String,
+    content_type: String,
+    size_bytes: u64,
+}
+
+// Step of a return request, by the customer or staff
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ReturnEvent {
+    status: ReturnStatus,
+    by: Principal,
+    at: u64,
+    note: Option<String>,
+}
+
+// Units of an order line being returned
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ReturnLine {
+    product_id: u64,
+    quantity: u32,
+}
+
+// Return merchandise authorization for units of a delivered order
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ReturnRequest {
+    id: u64,
+    order_id: u64,
+    customer: Principal,
+    lines: Vec<ReturnLine>,
+    reason: String,
+    photos: Vec<ReturnPhoto>,
+    status: ReturnStatus,
+    // Every step so far, oldest first
+    events: Vec<ReturnEvent>,
+    // Amount refunded in the smallest currency unit, once refunded
+    refund_amount: Option<u64>,
+    // Whether the returned units were put back in stock
+    restocked: Option<bool>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl Storable for ReturnRequest {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ReturnRequest {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ReturnRequestPayload {
+    order_id: u64,
+    lines: Vec<ReturnLine>,
+    reason: String,
+    photos: Vec<ReturnPhoto>,
+}
+
+// Self-serve kiosk registered by staff
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Kiosk {
+    principal: Principal,
+    name: String,
+    created_at: u64,
+}
+
+impl Storable for Kiosk {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Kiosk {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Entry of the append-only call journal; `hash` chains it to the entry before, so dropping or
+// changing an entry breaks every hash after it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CallRecord {
+    seq: u64,
+    // Update method called, or `timer:<job>` for background jobs
+    method: String,
+    caller: Principal,
+    at: u64,
+    // SHA-256 of the Candid-encoded arguments; not set for background jobs
+    args_hash: Option<Vec<u8>>,
+    prev_hash: Vec<u8>,
+    hash: Vec<u8>,
+    // Registered device the caller was working from, if any
+    device_id: Option<String>,
+}
+
+impl Storable for CallRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CallRecord {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Latest entry of the call journal, certified by the subnet
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct JournalHead {
+    seq: u64,
+    hash: Vec<u8>,
+    // IC certificate over the head hash, set as the canister's certified data
+    certificate: Option<Vec<u8>>,
+}
+
+// Read-only principal granted access to the reporting queries, e.g. the shop's accountant
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Viewer {
+    principal: Principal,
+    name: String,
+    created_at: u64,
+}
+
+impl Storable for Viewer {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Viewer {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Terminal registered for a staff member, e.g. one of the shop's shared tills
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StaffDevice {
+    device_id: String,
+    label: String,
+    registered_by: Principal,
+    registered_at: u64,
+    revoked_at: Option<u64>,
+}
+
+// Devices registered for a staff principal. The active device is no longer set, since each call
+// names its own device; it is kept so records stored before still decode
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StaffDevices {
+    principal: Principal,
+    devices: Vec<StaffDevice>,
+    active_device_id: Option<String>,
+    active_since: Option<u64>,
+}
+
+impl Storable for StaffDevices {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for StaffDevices {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Customer account shared by principals linked from several devices; carts and new orders are
+// kept under the primary principal
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CustomerAccount {
+    id: u64,
+    primary: Principal,
+    // Every linked principal, the primary included
+    principals: Vec<Principal>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl Storable for CustomerAccount {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CustomerAccount {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Pending challenge to link another principal into the account of the one that started it;
+// only the hash of the code is kept
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LinkChallengeRecord {
+    code_hash: Vec<u8>,
+    expires_at: u64,
+    failed_attempts: u32,
+}
+
+impl Storable for LinkChallengeRecord {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LinkChallengeRecord {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Code shown on the device that started a link, to be entered on the device being linked
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LinkChallenge {
+    code: String,
+    expires_at: u64,
+}
+
+// Short-lived session of one customer at a kiosk; its token is only valid from that kiosk
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct KioskSession {
+    token: String,
+    kiosk: Principal,
+    started_at: u64,
+    expires_at: u64,
+}
+
+impl Storable for KioskSession {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for KioskSession {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Where the staff notifier delivers its messages
+#[derive(candid::CandidType, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum ChannelKind {
+    // JSON POST to any HTTPS endpoint
+    Webhook { url: String },
+    Telegram { bot_token: String, chat_id: String },
+    Discord { webhook_url: String },
+}
+
+// Order events staff can be notified about
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum NotifierEvent {
+    OrderPlaced,
+    OrderPaid,
+}
+
+// Channel messaging staff phones about orders, with its message template
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct NotifierChannel {
+    id: u64,
+    name: String,
+    kind: ChannelKind,
+    events: Vec<NotifierEvent>,
+    enabled: bool,
+    // Supports {event}, {order_id}, {order_number}, {items}, {total} and {customer}
+    template: String,
+    last_sent_at: Option<u64>,
+    last_error: Option<String>,
+    created_at: u64,
+    // Also messaged when the nightly invariant check finds violations
+    alerts: Option<bool>,
+}
+
+impl Storable for NotifierChannel {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for NotifierChannel {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Message stored in a principal's inbox
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Notification {
+    id: u64,
+    kind: NotificationKind,
+    message: String,
+    created_at: u64,
+    read: bool,
+}
+
+impl Storable for Notification {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Notification {
+    const MAX_SIZE: u32 = 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// One page of the caller's inbox, newest first
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct NotificationPage {
+    notifications: Vec<Notification>,
+    page: u32,
+    total: u32,
+    unread: u32,
+    // Order threads with messages the caller has not read yet
+    unread_order_messages: Option<Vec<OrderThread>>,
+}
+
+// Compact stock feed for storefronts that poll it
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct StockDigest {
+    // (product id, units available, product version) of each visible product, by product id;
+    // left empty when the caller already has the current digest
+    entries: Vec<(u64, u32, u64)>,
+    digest: u64,
+    unchanged: bool,
+}
+
+// How a checkout is paid
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq)]
+enum PaymentMethod {
+    PayOnPickup,
+    Token { symbol: String },
+}
+
+// Stage of a checkout session
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum CheckoutStatus {
+    Open,
+    Finalized,
+    Cancelled,
+    Expired,
+}
+
+// Checkout in progress: prices are locked and stock is reserved when it begins, and the order is
+// placed once every step is done; every step can be repeated, so a frontend can resume it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CheckoutSession {
+    id: u64,
+    customer: Principal,
+    lines: Vec<OrderLine>,
+    subtotal: u64,
+    discount: u64,
+    promotion_id: Option<u64>,
+    applied_rules: Vec<AppliedPricingRule>,
+    experiments: Vec<ExperimentAssignment>,
+    // Reservations holding the stock until the session ends
+    reservation_ids: Vec<u64>,
+    // Whether the lines came from the customer's cart, emptied once the order is placed
+    from_cart: bool,
+    // Set by the address step; the order is picked up when the step sets no address
+    delivery_address: Option<DeliveryAddress>,
+    slot_start: Option<u64>,
+    payment_method: Option<PaymentMethod>,
+    notes: Option<String>,
+    status: CheckoutStatus,
+    order_id: Option<u64>,
+    created_at: u64,
+    updated_at: u64,
+    expires_at: u64,
+    // Points and store credit chosen at the payment step
+    tenders: Option<Vec<TenderRequest>>,
+}
+
+impl Storable for CheckoutSession {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CheckoutSession {
+    const MAX_SIZE: u32 = Order::MAX_SIZE + 2048;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Effect on another canister or service queued in the outbox by the change that causes it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum OutboxEffect {
+    // Message to a notifier channel about an order event
+    StaffNotification {
+        channel_id: u64,
+        event: NotifierEvent,
+        order_id: u64,
+    },
+    // ICRC-2 transfer collecting the payment of a subscription order
+    SubscriptionPayment {
+        subscription_id: u64,
+        order_id: u64,
+    },
+}
+
+// Dispatch state of an outbox effect
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum OutboxStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+// Effect queued in the outbox with its delivery attempts
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OutboxEntry {
+    id: u64,
+    effect: OutboxEffect,
+    status: OutboxStatus,
+    attempts: u32,
+    created_at: u64,
+    next_attempt_at: u64,
+    last_attempt_at: Option<u64>,
+    delivered_at: Option<u64>,
+    last_error: Option<String>,
+}
+
+impl Storable for OutboxEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for OutboxEntry {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Message posted in the thread of an order by its customer or by staff
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderMessage {
+    order_id: u64,
+    // Position in the thread, starting at 0
+    seq: u64,
+    author: Principal,
+    from_staff: bool,
+    text: String,
+    posted_at: u64,
+}
+
+impl Storable for OrderMessage {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for OrderMessage {
+    const MAX_SIZE: u32 = MAX_ORDER_MESSAGE_LENGTH as u32 + 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Message thread of an order, with what each side has not read yet
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderThread {
+    order_id: u64,
+    customer: Principal,
+    message_count: u64,
+    last_message_at: u64,
+    // Messages the customer has not read yet
+    customer_unread: u32,
+    // Messages no staff member has read yet
+    staff_unread: u32,
+}
+
+impl Storable for OrderThread {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for OrderThread {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Limits applied to paginated list queries
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+struct PaginationConfig {
+    default_page_size: u32,
+    max_page_size: u32,
+    // Encoded size at which a page is cut short, whatever its item count
+    response_budget_bytes: u64,
+}
+
+impl Default for PaginationConfig {
+    fn default() -> Self {
+        PaginationConfig {
+            default_page_size: DEFAULT_PAGE_SIZE,
+            max_page_size: DEFAULT_MAX_PAGE_SIZE,
+            response_budget_bytes: DEFAULT_RESPONSE_BUDGET_BYTES,
+        }
+    }
+}
+
+// Page of products; pass `next_cursor` back to continue after the last product
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ProductPage {
+    products: Vec<Product>,
+    next_cursor: Option<u64>,
+}
+
+// Page of orders; pass `next_cursor` back to continue after the last order
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderPage {
+    orders: Vec<Order>,
+    next_cursor: Option<u64>,
+}
+
+// Page of product availabilities; pass `next_cursor` back to continue after the last product
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AvailabilityPage {
+    products: Vec<Availability>,
+    next_cursor: Option<u64>,
+}
+
+// Page of product margins; `next_cursor` is the position of the last margin in the sort order
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct MarginPage {
+    margins: Vec<ProductMargin>,
+    next_cursor: Option<u64>,
+}
+
+// Page of customers; pass `next_cursor` back to continue after the last customer
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CustomerPage {
+    customers: Vec<Customer>,
+    next_cursor: Option<Principal>,
+}
+
+// Customer waiting for a sold-out product, served in the order they joined
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct WaitlistEntry {
+    product_id: u64,
+    principal: Principal,
+    quantity: u32,
+    joined_at: u64,
+}
+
+impl Storable for WaitlistEntry {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for WaitlistEntry {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Caller's place on a product's waitlist
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct WaitlistPosition {
+    product_id: u64,
+    quantity: u32,
+    // 1 for the next customer to be served
+    position: u32,
+    // Units requested by the customers ahead
+    units_ahead: u64,
+    joined_at: u64,
+}
+
+// Choice within an option group and what it adds to the unit price
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OptionChoice {
+    // Unique across the product's option groups
+    name: String,
+    // Negative for choices cheaper than the base product, e.g. a smaller tier
+    price_delta: i64,
+    // Choices of other groups this one cannot be combined with
+    excludes: Vec<String>,
+}
+
+// Group of options of a configurable product, e.g. "Tiers" or "Fillings"
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OptionGroup {
+    name: String,
+    required: bool,
+    // 1 for a single choice
+    max_selections: u32,
+    choices: Vec<OptionChoice>,
+}
+
+// Options a product can be configured with
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OptionSchema {
+    product_id: u64,
+    groups: Vec<OptionGroup>,
+    updated_at: u64,
+}
+
+impl Storable for OptionSchema {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for OptionSchema {
+    const MAX_SIZE: u32 = 16384;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Choices selected in one option group
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OptionSelection {
+    group: String,
+    choices: Vec<String>,
+}
+
+// Selected choice with the price delta it was charged at
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PricedOption {
+    group: String,
+    choice: String,
+    price_delta: i64,
+}
+
+// Itemized unit price of a configured product
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ConfiguredPrice {
+    product_id: u64,
+    base_price: u64,
+    options: Vec<PricedOption>,
+    unit_price: u64,
+}
+
+// How the orders of a subscription are paid
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum SubscriptionPayment {
+    PayOnPickup,
+    // Collected from the customer's default account, which must approve the canister (ICRC-2)
+    Token { symbol: String },
+}
+
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum SubscriptionStatus {
+    Active,
+    // Paused by the customer, or by the canister after repeated failures
+    Paused,
+    Cancelled,
+}
+
+// Product and quantity ordered on every run of a subscription
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct SubscriptionItem {
+    product_id: u64,
+    quantity: u32,
+    selections: Option<Vec<OptionSelection>>,
+}
+
+// Recurring order placed on a schedule, e.g. a weekly bread box
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Subscription {
+    id: u64,
+    customer: Principal,
+    items: Vec<SubscriptionItem>,
+    cadence_days: u32,
+    payment: SubscriptionPayment,
+    notes: Option<String>,
+    status: SubscriptionStatus,
+    next_run_at: u64,
+    consecutive_failures: u32,
+    last_order_id: Option<u64>,
+    last_error: Option<String>,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl Storable for Subscription {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Subscription {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Staff member's working shift; stock movements and sales during it are tagged with its id
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Shift {
+    id: u64,
+    staff: Principal,
+    opened_at: u64,
+    closed_at: Option<u64>,
+}
+
+impl Storable for Shift {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Shift {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Reason a product's stock changed
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum StockMovementKind {
+    Restock,
+    // Offloaded stock, e.g. unsold or damaged goods
+    Waste,
+    // Quantity set directly on the product
+    Adjustment,
+    Sale,
+    // Units of a cancelled order returned to stock
+    Cancellation,
+}
+
+// Change of a product's stock recorded during a shift
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StockMovement {
+    product_id: u64,
+    kind: StockMovementKind,
+    // Positive when units were added to the stock
+    quantity_delta: i64,
+    // Sale value of the units for sales and cancellations
+    value: i64,
+    by: Principal,
+    order_id: Option<u64>,
+    at: u64,
+    // Units put on display when positive, or taken off it when negative; the quantity in stock
+    // does not change, only the part of it for sale
+    display_delta: Option<i64>,
+}
+
+impl Storable for StockMovement {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for StockMovement {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Stock movements of one product over a shift, in units
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct ShiftProductSummary {
+    product_id: u64,
+    sold: u64,
+    cancelled: u64,
+    wasted: u64,
+    restocked: u64,
+    // Net change from quantities set directly
+    adjusted: i64,
+}
+
+// End-of-day summary of a shift for reconciliation
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ShiftReport {
+    shift: Shift,
+    orders: u64,
+    // Value of the units sold, less cancellations
+    sales_total: i64,
+    units_sold: u64,
+    units_wasted: u64,
+    units_restocked: u64,
+    units_adjusted: i64,
+    products: Vec<ShiftProductSummary>,
+}
+
+// Who started and finished preparing an order, and when
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct OrderPrep {
+    started_at: Option<u64>,
+    started_by: Option<Principal>,
+    ready_at: Option<u64>,
+    ready_by: Option<Principal>,
+}
+
+impl Storable for OrderPrep {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for OrderPrep {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// What one staff member did within a report period
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StaffPerformance {
+    staff: Principal,
+    shifts: u32,
+    // Time on shift within the period
+    shift_ns: u64,
+    // Orders the staff member marked ready
+    orders_prepared: u32,
+    // From the start of preparation to ready, over the orders prepared with a recorded start
+    average_prep_ns: Option<u64>,
+    units_wasted: u64,
+    // Wasted units at their unit cost, for products with a cost
+    waste_cost: u64,
+    adjustments: u32,
+    // Net units added by the adjustments, negative when more were taken out
+    units_adjusted: i64,
+}
+
+// Per-staff counts of a period, from the shift logs, order preparation and stock movements
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StaffReport {
+    period: ReportPeriod,
+    staff: Vec<StaffPerformance>,
+}
+
+// Staff attestation that a customer is an adult, allowing them to buy age-restricted products
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct AgeAttestation {
+    principal: Principal,
+    attested_by: Principal,
+    attested_at: u64,
+}
+
+impl Storable for AgeAttestation {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for AgeAttestation {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Sales channel a customer buys through; retail customers pay the product price
+#[derive(
+    candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, Default, PartialEq, Eq,
+)]
+enum CustomerTier {
+    #[default]
+    Retail,
+    Cafe,
+    Wholesale,
+}
+
+impl CustomerTier {
+    fn code(&self) -> u8 {
+        match self {
+            CustomerTier::Retail => 0,
+            CustomerTier::Cafe => 1,
+            CustomerTier::Wholesale => 2,
+        }
+    }
+}
+
+// Tier a customer was assigned to by staff
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TierAssignment {
+    principal: Principal,
+    tier: CustomerTier,
+    assigned_by: Principal,
+    assigned_at: u64,
+}
+
+impl Storable for TierAssignment {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TierAssignment {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Price list entry of a product for a cafe or wholesale tier
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TierPrice {
+    product_id: u64,
+    tier: CustomerTier,
+    unit_price: u64,
+    // Applies on top of the product's own minimum
+    min_order_qty: Option<u32>,
+    updated_at: u64,
+}
+
+impl Storable for TierPrice {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TierPrice {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Named count in a health snapshot
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, PartialEq, Eq)]
+struct HealthCount {
+    name: String,
+    count: u64,
+}
+
+// Counters, entity counts and inconsistencies found in the stable state at some point
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct HealthSnapshot {
+    taken_at: u64,
+    counters: Vec<HealthCount>,
+    entities: Vec<HealthCount>,
+    // Counters behind the keys they issued; at most MAX_HEALTH_ISSUES are listed
+    issues: Vec<String>,
+    // Checksum of the counters and entity counts
+    checksum: u64,
+}
+
+// Secondary index kept next to the records it is derived from
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum IndexKind {
+    // Product ids by normalized name, derived from the products
+    ProductNames,
+    // Sub-principals by owner, derived from the spending limits
+    SubPrincipals,
+    // Orders awaiting an escrow release or refund, derived from the orders
+    PendingSettlements,
+}
+
+impl IndexKind {
+    const ALL: [IndexKind; 3] = [
+        IndexKind::ProductNames,
+        IndexKind::SubPrincipals,
+        IndexKind::PendingSettlements,
+    ];
+
+    fn code(&self) -> u8 {
+        match self {
+            IndexKind::ProductNames => 0,
+            IndexKind::SubPrincipals => 1,
+            IndexKind::PendingSettlements => 2,
+        }
+    }
+}
+
+// Result of checking an index against the records it is derived from
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct IndexReport {
+    kind: IndexKind,
+    entries: u64,
+    // Records without their index entry
+    missing: u64,
+    // Index entries pointing at no matching record
+    orphaned: u64,
+    // At most MAX_HEALTH_ISSUES of the problems found
+    samples: Vec<String>,
+}
+
+// Stage of an index rebuild
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum RebuildPhase {
+    // Adding the entries missing for the records
+    Records,
+    // Removing the orphaned entries
+    Entries,
+    Done,
+}
+
+// Progress of an index rebuild spread over several calls
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct IndexRebuild {
+    kind: IndexKind,
+    phase: RebuildPhase,
+    // Encoded key of the last record or entry gone through in the current phase
+    cursor: Option<Vec<u8>>,
+    added: u64,
+    removed: u64,
+    started_at: u64,
+    completed_at: Option<u64>,
+}
+
+impl Storable for IndexRebuild {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for IndexRebuild {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Health of the stable state around the last upgrade
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct UpgradeHealth {
+    pre_upgrade: Option<HealthSnapshot>,
+    post_upgrade: Option<HealthSnapshot>,
+    // Counts that changed across the upgrade
+    mismatches: Vec<String>,
+}
+
+impl Storable for UpgradeHealth {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Product put in a draft cart, with the unit price it was shown at
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CartLine {
+    product_id: u64,
+    quantity: u32,
+    selections: Option<Vec<OptionSelection>>,
+    unit_price: u64,
+    added_at: u64,
+}
+
+// Caller's draft cart, kept until checkout or until it sits idle past the cart TTL
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Cart {
+    owner: Principal,
+    lines: Vec<CartLine>,
+    created_at: u64,
+    last_activity_at: u64,
+}
+
+impl Storable for Cart {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Cart {
+    const MAX_SIZE: u32 = 8192;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Change made to a cart line when the cart is revived
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum CartChange {
+    // The product is gone, unpublished or its options no longer fit
+    Removed { product_id: u64, reason: String },
+    QuantityReduced { product_id: u64, from: u32, to: u32 },
+    PriceChanged { product_id: u64, from: u64, to: u64 },
+}
+
+// Revalidated cart with the changes made to it
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct CartRevival {
+    cart: Cart,
+    changes: Vec<CartChange>,
+}
+
+// Fulfillment progress of one order line
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct LineFulfillment {
+    product_id: u64,
+    quantity: u32,
+    fulfilled: u32,
+    unfulfillable: u32,
+    // Units the customer is still waiting for
+    remaining: u32,
+}
+
+// Fulfillment progress of an order, as shown to its customer
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct OrderFulfillment {
+    order_id: u64,
+    status: OrderStatus,
+    lines: Vec<LineFulfillment>,
+    refund_due: u64,
+    // Total still owed once unfulfillable lines are refunded
+    amount_due: u64,
+}
+
+// What a run of the retention rules purged, or would purge in a dry run
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct RetentionReport {
+    at: u64,
+    customers_purged: u32,
+    attestations_removed: u32,
+    notifications_removed: u32,
+    orders_scrubbed: u32,
+    daily_sales_rolled_up: u32,
+    // More was due than a single run handles
+    more_pending: bool,
+}
+
+impl Storable for RetentionReport {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for RetentionReport {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Monthly sales of a product, folded from daily rollups past their retention
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct MonthlySalesReport {
+    year: u32,
+    // 1 to 12
+    month: u32,
+    product_id: u64,
+    units: i64,
+    revenue: i64,
+}
+
+// Every record tied to a principal, as returned by a data export
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct MyDataExport {
+    principal: Principal,
+    exported_at: u64,
+    profile: Option<Customer>,
+    age_attestation: Option<AgeAttestation>,
+    orders: Vec<Order>,
+    quotes: Vec<Quote>,
+    reservations: Vec<Reservation>,
+    subscriptions: Vec<Subscription>,
+    tickets: Vec<Ticket>,
+    notifications: Vec<Notification>,
+    watched_products: Vec<u64>,
+    waitlists: Vec<WaitlistEntry>,
+    // Limit set on the principal when it buys on behalf of an account owner
+    spending_limit: Option<SpendingLimit>,
+    tier: Option<TierAssignment>,
+    cart: Option<Cart>,
+    order_messages: Vec<OrderMessage>,
+    checkout_sessions: Vec<CheckoutSession>,
+    // Sub-principals buying on behalf of the principal
+    sub_principals: Vec<SpendingLimit>,
+    shifts: Vec<Shift>,
+    returns: Vec<ReturnRequest>,
+    // Account the principal is linked into with other devices
+    account: Option<CustomerAccount>,
+    // Language the principal reads messages in
+    language: Option<String>,
+    // Store credit and points held by the principal
+    balance: Option<TenderBalance>,
+    disputes: Vec<Dispute>,
+    birthday: Option<CustomerBirthday>,
+    // Address the principal's receipts are emailed to
+    receipt_email: Option<CustomerEmail>,
+}
+
+// What deleting an account removed or anonymized
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct AccountDeletion {
+    orders_anonymized: u32,
+    tickets_anonymized: u32,
+    records_removed: u32,
+    returns_anonymized: u32,
+    disputes_anonymized: u32,
+}
+
+// Where a stocktake is in its flow
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum StocktakeStatus {
+    Counting,
+    // Counts are in and the discrepancies computed, waiting for a manager
+    Submitted,
+    Approved,
+    Rejected,
+}
+
+// Units of a product counted on the shelves
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StockCount {
+    product_id: u64,
+    counted: u32,
+    by: Principal,
+    at: u64,
+}
+
+// Difference between the counted and recorded stock of a product, computed on submission
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StocktakeLine {
+    product_id: u64,
+    expected: u32,
+    counted: u32,
+    // Positive when more units were counted than recorded
+    discrepancy: i64,
+}
+
+// Count of the stock of a set of products; in blind mode counting staff never see the
+// recorded quantities
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Stocktake {
+    id: u64,
+    blind: bool,
+    product_ids: Vec<u64>,
+    counters: Vec<Principal>,
+    status: StocktakeStatus,
+    // Latest count of each product
+    counts: Vec<StockCount>,
+    lines: Vec<StocktakeLine>,
+    started_by: Principal,
+    started_at: u64,
+    submitted_by: Option<Principal>,
+    submitted_at: Option<u64>,
+    decided_by: Option<Principal>,
+    decided_at: Option<u64>,
+    reason: Option<String>,
+}
+
+impl Storable for Stocktake {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Stocktake {
+    const MAX_SIZE: u32 = 32_768;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Product on a stocktake's count sheet; the recorded quantity is left out in blind mode
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StocktakeSheetLine {
+    product_id: u64,
+    name: String,
+    counted: Option<u32>,
+    expected: Option<u32>,
+}
+
+// Count sheet of a stocktake as shown to the caller
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StocktakeSheet {
+    id: u64,
+    blind: bool,
+    status: StocktakeStatus,
+    lines: Vec<StocktakeSheetLine>,
+}
+
+// Product with stock that passes its best-before by the next close, to be discounted in the
+// morning
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ExpiringItem {
+    product_id: u64,
+    name: String,
+    units: u32,
+    best_before: u64,
+}
+
+// End-of-day report of one UTC day, recorded once the day is over
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DailyClose {
+    // Days since the Unix epoch (UTC)
+    day: u64,
+    closed_at: u64,
+    // Orders placed during the day
+    orders: u32,
+    // Sales of the day as frozen at the close, per product
+    units: i64,
+    revenue: i64,
+    sales: Vec<DailySalesReport>,
+    // Set when the day sold more products than the report keeps lines for
+    sales_truncated: bool,
+    // Snapshot point in effect once the close ran
+    snapshot_at: Option<u64>,
+    carts_purged: u64,
+    quotes_expired: u32,
+    expiring: Vec<ExpiringItem>,
+}
+
+impl Storable for DailyClose {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for DailyClose {
+    const MAX_SIZE: u32 = 131_072;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Translation of a message into a language; `{}` in the template stands for a value, e.g.
+// "A product with id={} was not found", and the values go into the `{}` of the translation in
+// order, or into `{0}`, `{1}`, ... by position
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct MessageTranslation {
+    id: u64,
+    lang: String,
+    template: String,
+    translation: String,
+    updated_by: Principal,
+    updated_at: u64,
+}
+
+impl Storable for MessageTranslation {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for MessageTranslation {
+    const MAX_SIZE: u32 = 1_024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Language a principal reads messages in
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct LanguagePreference {
+    lang: String,
+    updated_at: u64,
+}
+
+impl Storable for LanguagePreference {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for LanguagePreference {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Order number as looked up, case-insensitively; also keys the sequence of each period of the
+// order number pattern, e.g. BK-2024-{SEQ}
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct OrderNumber(String);
+
+impl OrderNumber {
+    fn normalize(number: &str) -> Self {
+        OrderNumber(number.trim().to_ascii_uppercase())
+    }
+}
+
+impl Storable for OrderNumber {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        OrderNumber(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl BoundedStorable for OrderNumber {
+    // Sequences can outgrow their width, so leave room for the extra digits
+    const MAX_SIZE: u32 = 2 * MAX_ORDER_NUMBER_LENGTH as u32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Encoding a record type is written to stable memory in
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum StorageFormat {
+    Candid,
+    // Tagged binary encoding without Candid's type table, cheaper to encode and decode
+    Compact,
+}
+
+// Hot record types whose storage encoding can be switched
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum StoredEntity {
+    Products,
+    Orders,
+}
+
+// Value tags of the compact encoding
+const COMPACT_UNIT: u8 = 0; // unit, unit structs and None
+const COMPACT_SOME: u8 = 1;
+const COMPACT_FALSE: u8 = 2;
+const COMPACT_TRUE: u8 = 3;
+const COMPACT_UINT: u8 = 4; // LEB128
+const COMPACT_INT: u8 = 5; // zigzag LEB128
+const COMPACT_FLOAT: u8 = 6; // 8 bytes little-endian
+const COMPACT_STRING: u8 = 7; // length and UTF-8 bytes
+const COMPACT_BYTES: u8 = 8; // length and bytes
+const COMPACT_SEQ: u8 = 9; // count and values
+const COMPACT_MAP: u8 = 10; // count and key-value pairs
+const COMPACT_STRUCT: u8 = 11; // count and field values in declaration order
+const COMPACT_VARIANT: u8 = 12; // variant index and value
+
+// Error encoding or decoding a record in the compact encoding
+#[derive(Debug)]
+struct CompactError(String);
+
+impl std::fmt::Display for CompactError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CompactError {}
+
+impl serde::ser::Error for CompactError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        CompactError(msg.to_string())
+    }
+}
+
+impl serde::de::Error for CompactError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        CompactError(msg.to_string())
+    }
+}
+
+// Writer of the compact encoding. Struct fields are written by position, so a record written
+// before optional fields were added at the end of a struct still decodes, with them unset
+struct CompactSerializer {
+    out: Vec<u8>,
+}
+
+impl CompactSerializer {
+    fn uint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.out.push(value as u8);
+    }
+
+    fn tagged_uint(&mut self, tag: u8, value: u64) {
+        self.out.push(tag);
+        self.uint(value);
+    }
+
+    fn count(&mut self, tag: u8, len: Option<usize>) -> Result<(), CompactError> {
+        let len = len.ok_or_else(|| CompactError("Sequences need a known length".to_string()))?;
+        self.tagged_uint(tag, len as u64);
+        Ok(())
+    }
+}
+
+impl serde::Serializer for &mut CompactSerializer {
+    type Ok = ();
+    type Error = CompactError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<(), CompactError> {
+        self.out.push(if v { COMPACT_TRUE } else { COMPACT_FALSE });
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), CompactError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), CompactError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), CompactError> {
+        self.serialize_i64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), CompactError> {
+        self.tagged_uint(COMPACT_INT, ((v << 1) ^ (v >> 63)) as u64);
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), CompactError> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), CompactError> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), CompactError> {
+        self.serialize_u64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), CompactError> {
+        self.tagged_uint(COMPACT_UINT, v);
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), CompactError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), CompactError> {
+        self.out.push(COMPACT_FLOAT);
+        self.out.extend_from_slice(&v.to_le_bytes());
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), CompactError> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), CompactError> {
+        self.tagged_uint(COMPACT_STRING, v.len() as u64);
+        self.out.extend_from_slice(v.as_bytes());
+        Ok(())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), CompactError> {
+        self.tagged_uint(COMPACT_BYTES, v.len() as u64);
+        self.out.extend_from_slice(v);
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<(), CompactError> {
+        self.out.push(COMPACT_UNIT);
+        Ok(())
+    }
+
+    fn serialize_some<T: ?Sized + serde::Serialize>(self, value: &T) -> Result<(), CompactError> {
+        self.out.push(COMPACT_SOME);
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), CompactError> {
+        self.out.push(COMPACT_UNIT);
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), CompactError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+    ) -> Result<(), CompactError> {
+        self.tagged_uint(COMPACT_VARIANT, variant_index.into());
+        self.serialize_unit()
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + serde::Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), CompactError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + serde::Serialize>(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        value: &T,
+    ) -> Result<(), CompactError> {
+        self.tagged_uint(COMPACT_VARIANT, variant_index.into());
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, CompactError> {
+        self.count(COMPACT_SEQ, len)?;
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self, CompactError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _name: &'static str, len: usize) -> Result<Self, CompactError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        len: usize,
+    ) -> Result<Self, CompactError> {
+        self.tagged_uint(COMPACT_VARIANT, variant_index.into());
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self, CompactError> {
+        self.count(COMPACT_MAP, len)?;
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Self, CompactError> {
+        self.count(COMPACT_STRUCT, Some(len))?;
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        variant_index: u32,
+        _variant: &'static str,
+        len: usize,
+    ) -> Result<Self, CompactError> {
+        self.tagged_uint(COMPACT_VARIANT, variant_index.into());
+        self.serialize_struct("", len)
+    }
+}
+
+impl serde::ser::SerializeSeq for &mut CompactSerializer {
+    type Ok = ();
+    type Error = CompactError;
+
+    fn serialize_element<T: ?Sized + serde::Serialize>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), CompactError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), CompactError> {
+        Ok(())
+    }
+}
+
+impl serde::ser::SerializeTuple for &mut CompactSerializer {
+    type Ok = ();
+    type Error = CompactError;
+
+    fn serialize_element<T: ?Sized + serde::Serialize>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), CompactError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), CompactError> {
+        Ok(())
+    }
+}
+
+impl serde::ser::SerializeTupleStruct for &mut CompactSerializer {
+    type Ok = ();
+    type Error = CompactError;
+
+    fn serialize_field<T: ?Sized + serde::Serialize>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), CompactError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), CompactError> {
+        Ok(())
+    }
+}
+
+impl serde::ser::SerializeTupleVariant for &mut CompactSerializer {
+    type Ok = ();
+    type Error = CompactError;
+
+    fn serialize_field<T: ?Sized + serde::Serialize>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), CompactError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), CompactError> {
+        Ok(())
+    }
+}
+
+impl serde::ser::SerializeMap for &mut CompactSerializer {
+    type Ok = ();
+    type Error = CompactError;
+
+    fn serialize_key<T: ?Sized + serde::Serialize>(&mut self, key: &T) -> Result<(), CompactError> {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: ?Sized + serde::Serialize>(
+        &mut self,
+        value: &T,
+    ) -> Result<(), CompactError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), CompactError> {
+        Ok(())
+    }
+}
+
+impl serde::ser::SerializeStruct for &mut CompactSerializer {
+    type Ok = ();
+    type Error = CompactError;
+
+    fn serialize_field<T: ?Sized + serde::Serialize>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), CompactError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), CompactError> {
+        Ok(())
+    }
+}
+
+impl serde::ser::SerializeStructVariant for &mut CompactSerializer {
+    type Ok = ();
+    type Error = CompactError;
+
+    fn serialize_field<T: ?Sized + serde::Serialize>(
+        &mut self,
+        _key: &'static str,
+        value: &T,
+    ) -> Result<(), CompactError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), CompactError> {
+        Ok(())
+    }
+}
+
+// Reader of the compact encoding; the tags make it self-describing, which types such as `Nat`
+// rely on
+struct CompactDeserializer<'de> {
+    input: &'de [u8],
+}
+
+impl<'de> CompactDeserializer<'de> {
+    fn take(&mut self, len: usize) -> Result<&'de [u8], CompactError> {
+        if len > self.input.len() {
+            return Err(CompactError("Unexpected end of record".to_string()));
+        }
+        let (taken, rest) = self.input.split_at(len);
+        self.input = rest;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, CompactError> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn uint(&mut self) -> Result<u64, CompactError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            value |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(CompactError("Integer out of range".to_string()))
+    }
+
+    fn len(&mut self) -> Result<usize, CompactError> {
+        self.uint().map(|len| len as usize)
+    }
+}
+
+impl<'de> serde::Deserializer<'de> for &mut CompactDeserializer<'de> {
+    type Error = CompactError;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn deserialize_any<V: serde::de::Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, CompactError> {
+        match self.byte()? {
+            COMPACT_UNIT => visitor.visit_unit(),
+            COMPACT_SOME => visitor.visit_some(self),
+            COMPACT_FALSE => visitor.visit_bool(false),
+            COMPACT_TRUE => visitor.visit_bool(true),
+            COMPACT_UINT => visitor.visit_u64(self.uint()?),
+            COMPACT_INT => {
+                let raw = self.uint()?;
+                visitor.visit_i64((raw >> 1) as i64 ^ -((raw & 1) as i64))
+            }
+            COMPACT_FLOAT => {
+                let bytes = self.take(8)?;
+                visitor.visit_f64(f64::from_le_bytes(bytes.try_into().unwrap()))
+            }
+            COMPACT_STRING => {
+                let len = self.len()?;
+                let bytes = self.take(len)?;
+                let text = std::str::from_utf8(bytes)
+                    .map_err(|_| CompactError("Invalid UTF-8 in a string".to_string()))?;
+                visitor.visit_borrowed_str(text)
+            }
+            COMPACT_BYTES => {
+                let len = self.len()?;
+                visitor.visit_borrowed_bytes(self.take(len)?)
+            }
+            COMPACT_SEQ => {
+                let remaining = self.len()?;
+                visitor.visit_seq(CompactAccess {
+                    de: self,
+                    remaining,
+                    field: None,
+                })
+            }
+            tag @ (COMPACT_MAP | COMPACT_STRUCT) => {
+                let remaining = self.len()?;
+                visitor.visit_map(CompactAccess {
+                    de: self,
+                    remaining,
+                    field: (tag == COMPACT_STRUCT).then_some(0),
+                })
+            }
+            COMPACT_VARIANT => visitor.visit_enum(self),
+            tag => Err(CompactError(format!("Unknown value tag {}", tag))),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: serde::de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, CompactError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // A value not tagged as an option was written while its field was still required, so it
+    // reads as set
+    fn deserialize_option<V: serde::de::Visitor<'de>>(
+        self,
+        visitor: V,
+    ) -> Result<V::Value, CompactError> {
+        match self.input.first() {
+            Some(&COMPACT_UNIT) => {
+                self.byte()?;
+                visitor.visit_none()
+            }
+            Some(&COMPACT_SOME) => {
+                self.byte()?;
+                visitor.visit_some(self)
+            }
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        unit unit_struct seq tuple tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+// Elements of a sequence, entries of a map or fields of a struct being read; struct fields are
+// keyed by their position
+struct CompactAccess<'a, 'de> {
+    de: &'a mut CompactDeserializer<'de>,
+    remaining: usize,
+    field: Option<u64>,
+}
+
+impl<'de> serde::de::SeqAccess<'de> for CompactAccess<'_, 'de> {
+    type Error = CompactError;
+
+    fn next_element_seed<T: serde::de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, CompactError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+impl<'de> serde::de::MapAccess<'de> for CompactAccess<'_, 'de> {
+    type Error = CompactError;
+
+    fn next_key_seed<K: serde::de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, CompactError> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        self.remaining -= 1;
+        match self.field {
+            Some(index) => {
+                self.field = Some(index + 1);
+                seed.deserialize(serde::de::value::U64Deserializer::new(index))
+                    .map(Some)
+            }
+            None => seed.deserialize(&mut *self.de).map(Some),
+        }
+    }
+
+    fn next_value_seed<V: serde::de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, CompactError> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.remaining)
+    }
+}
+
+impl<'de> serde::de::EnumAccess<'de> for &mut CompactDeserializer<'de> {
+    type Error = CompactError;
+    type Variant = Self;
+
+    fn variant_seed<V: serde::de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self), CompactError> {
+        let index = self.uint()?;
+        let variant = seed.deserialize(serde::de::value::U64Deserializer::new(index))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de> serde::de::VariantAccess<'de> for &mut CompactDeserializer<'de> {
+    type Error = CompactError;
+
+    fn unit_variant(self) -> Result<(), CompactError> {
+        match self.byte()? {
+            COMPACT_UNIT => Ok(()),
+            tag => Err(CompactError(format!(
+                "Expected a unit variant, got tag {}",
+                tag
+            ))),
+        }
+    }
+
+    fn newtype_variant_seed<T: serde::de::DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, CompactError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: serde::de::Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, CompactError> {
+        serde::Deserializer::deserialize_any(self, visitor)
+    }
+
+    fn struct_variant<V: serde::de::Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, CompactError> {
+        serde::Deserializer::deserialize_any(self, visitor)
+    }
+}
+
+// Cost of one storage encoding over a sample of records, in instructions and bytes
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct FormatCost {
+    format: StorageFormat,
+    bytes: u64,
+    encode_instructions: u64,
+    decode_instructions: u64,
+}
+
+// Storage encodings compared on the first records of a record type
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StorageBenchmark {
+    entity: StoredEntity,
+    records: u32,
+    candid: FormatCost,
+    compact: FormatCost,
+    // Records that did not read back the same from the compact encoding; expected to be 0
+    mismatches: u32,
+}
+
+// Outcome of one batch of a storage migration
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StorageMigration {
+    entity: StoredEntity,
+    format: StorageFormat,
+    rewritten: u32,
+    // Id to pass to the next call; the migration is done when not set
+    next_cursor: Option<u64>,
+}
+
+// Version of the published catalog, which only changes with what a statically generated
+// storefront shows, and the delivery of its changes to the build hook
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct PublishedCatalog {
+    version: u64,
+    // SHA-256 over the public fields of the listed products
+    digest: Vec<u8>,
+    changed_at: Option<u64>,
+    // Set while a check of the published catalog is due
+    check_at: Option<u64>,
+    // Last version the build hook accepted
+    notified_version: u64,
+    attempts: u32,
+    last_attempt_at: Option<u64>,
+    last_error: Option<String>,
+}
+
+impl Storable for PublishedCatalog {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Build hook settings and delivery state, for admins
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct BuildHookStatus {
+    // Host of the hook URL, the rest of which often carries a secret
+    host: Option<String>,
+    catalog: PublishedCatalog,
+}
+
+// Store credit and loyalty points a customer can pay with, kept under the account holder
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct TenderBalance {
+    // In the smallest currency unit
+    store_credit: u64,
+    points: u64,
+    updated_at: u64,
+}
+
+impl Storable for TenderBalance {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for TenderBalance {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Lifecycle of a payment dispute
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum DisputeStatus {
+    Open,
+    // At least one side has backed its case
+    EvidenceSubmitted,
+    Resolved,
+}
+
+// How a payment dispute ended
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum DisputeOutcome {
+    // The customer was refunded the disputed amount, or part of it
+    CustomerWon,
+    ShopWon,
+    Withdrawn,
+}
+
+// Document backing one side of a dispute, e.g. a delivery photo or a bank statement
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DisputeEvidence {
+    // URL or asset key of the document
+    reference: String,
+    note: Option<String>,
+    by: Principal,
+    at: u64,
+}
+
+// Step of a dispute, by the customer or staff
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DisputeEvent {
+    status: DisputeStatus,
+    by: Principal,
+    at: u64,
+    note: Option<String>,
+}
+
+// Disagreement about an order's payment, tied to the ledger transfer it is about
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct Dispute {
+    id: u64,
+    order_id: u64,
+    customer: Principal,
+    ledger: Principal,
+    block_index: Nat,
+    // Amount disputed in the smallest currency unit
+    amount: u64,
+    reason: String,
+    status: DisputeStatus,
+    evidence: Vec<DisputeEvidence>,
+    // Every step so far, oldest first
+    events: Vec<DisputeEvent>,
+    outcome: Option<DisputeOutcome>,
+    // Amount refunded to the customer in the smallest currency unit, once resolved
+    refund_amount: Option<u64>,
+    opened_by: Principal,
+    created_at: u64,
+    updated_at: Option<u64>,
+}
+
+impl Storable for Dispute {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for Dispute {
+    const MAX_SIZE: u32 = 16_384;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Payload used to open a dispute about an order's payment
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DisputePayload {
+    order_id: u64,
+    // Disputed transfer on the order's payment ledger; the escrow settlement when omitted
+    block_index: Option<Nat>,
+    // The order total when omitted
+    amount: Option<u64>,
+    reason: String,
+}
+
+// Payload used to add a piece of evidence to a dispute
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DisputeEvidencePayload {
+    reference: String,
+    note: Option<String>,
+}
+
+// Disputes opened within a period and how they ended
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct DisputeReport {
+    period: ReportPeriod,
+    opened: u64,
+    open: u64,
+    evidence_submitted: u64,
+    resolved: u64,
+    customer_won: u64,
+    shop_won: u64,
+    withdrawn: u64,
+    // In the smallest currency unit
+    disputed_amount: u64,
+    refunded_amount: u64,
+    average_resolution_ns: Option<u64>,
+}
+
+// Month and day a customer was born on; the year is not kept
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+struct Birthday {
+    month: u8,
+    day: u8,
+}
+
+// Birthday of an account holder and the last year it was rewarded
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CustomerBirthday {
+    // Kept without a date once removed, so the year's reward is not issued twice
+    birthday: Option<Birthday>,
+    set_at: u64,
+    rewarded_year: Option<i64>,
+    rewarded_at: Option<u64>,
+}
+
+impl Storable for CustomerBirthday {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CustomerBirthday {
+    const MAX_SIZE: u32 = 128;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Units of a product wanted with the same options
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PickVariant {
+    // Chosen options as "group: choice", empty for the plain product
+    options: Vec<String>,
+    quantity: u32,
+}
+
+// Customer instructions of an order with units on a pick list entry
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PickNote {
+    order_id: u64,
+    order_number: Option<String>,
+    note: String,
+}
+
+// Units of a product to pick for one slot
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PickItem {
+    product_id: u64,
+    name: String,
+    quantity: u32,
+    variants: Vec<PickVariant>,
+    // Orders with units of the product, for labelling the boxes
+    order_ids: Vec<u64>,
+    notes: Vec<PickNote>,
+}
+
+// Products to pick for the orders of one pickup or delivery slot
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PickSlot {
+    // Orders without a slot are listed at the time they were promised for
+    slot_start: u64,
+    delivery: bool,
+    items: Vec<PickItem>,
+}
+
+// Everything to pick for the open orders due on a day, by slot and product
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PickList {
+    day: u64,
+    orders: u32,
+    units: u32,
+    // Earliest slot first, pickups before deliveries at the same time
+    slots: Vec<PickSlot>,
+}
+
+// Layout of a printed pick list
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum PickListFormat {
+    Text,
+    Csv,
+}
+
+// Cross-record rule found broken by the invariant check
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct InvariantViolation {
+    rule: String,
+    // Record the rule was checked on, e.g. "product 12" or "order 34"
+    record: String,
+    detail: String,
+}
+
+// Outcome of checking the invariants that hold across products, stock, orders and the ledger
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct InvariantReport {
+    checked_at: u64,
+    records_checked: u64,
+    violations: u64,
+    // Violations of each rule that was broken
+    by_rule: Vec<HealthCount>,
+    // At most MAX_HEALTH_ISSUES of the violations found
+    samples: Vec<InvariantViolation>,
+}
+
+// Last nightly invariant check, and the channels alerted about it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct InvariantState {
+    last: Option<InvariantReport>,
+    alerted_channels: u32,
+}
+
+impl Storable for InvariantState {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+// Large collection kept across several stable memories
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum ShardedCollection {
+    Orders,
+    SaleEvents,
+    StockMovements,
+}
+
+impl ShardedCollection {
+    fn code(&self) -> u8 {
+        match self {
+            ShardedCollection::Orders => 0,
+            ShardedCollection::SaleEvents => 1,
+            ShardedCollection::StockMovements => 2,
+        }
+    }
+
+    // Memory the collection was kept in before it was sharded, which stays its first shard
+    fn first_memory(&self) -> u8 {
+        match self {
+            ShardedCollection::Orders => 7,
+            ShardedCollection::SaleEvents => 13,
+            ShardedCollection::StockMovements => 43,
+        }
+    }
+
+    // Memories set aside for the collection's further shards, in the order they are opened
+    fn spare_memories(&self) -> std::ops::RangeInclusive<u8> {
+        match self {
+            ShardedCollection::Orders => 100..=115,
+            ShardedCollection::SaleEvents => 116..=131,
+            ShardedCollection::StockMovements => 132..=147,
+        }
+    }
+
+    fn shard_capacity(&self) -> u64 {
+        match self {
+            ShardedCollection::Orders => ORDER_SHARD_CAPACITY,
+            _ => EVENT_SHARD_CAPACITY,
+        }
+    }
+}
+
+// Shard opened for a collection after its first one, as recorded in the shard directory
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ShardInfo {
+    memory_id: u8,
+    opened_at: u64,
+}
+
+impl Storable for ShardInfo {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ShardInfo {
+    const MAX_SIZE: u32 = 64;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Storage shard of a collection with its size, for admins
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct StorageShard {
+    collection: ShardedCollection,
+    index: u8,
+    memory_id: u8,
+    entries: u64,
+    // Stable memory the shard has grown to
+    size_pages: u64,
+    // Not set for the first shard, which predates the directory
+    opened_at: Option<u64>,
+    // New entries go to this shard
+    active: bool,
+}
+
+// Ordered map of a collection kept across several stable memories, one StableBTreeMap each.
+// New keys go to the newest shard, and once it holds its capacity the next spare memory is
+// opened and recorded in the shard directory; existing keys are updated where they are. Reads
+// merge the shards in key order, so callers use it like a single map
+struct ShardedMap<K, V>
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    collection: ShardedCollection,
+    shards: Vec<StableBTreeMap<K, V, Memory>>,
+}
+
+impl<K, V> ShardedMap<K, V>
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    fn init(collection: ShardedCollection) -> Self {
+        let code = collection.code();
+        let opened: Vec<u8> = SHARD_DIRECTORY.with(|service| {
+            service
+                .borrow()
+                .range((code, 0)..=(code, u8::MAX))
+                .map(|(_, shard)| shard.memory_id)
+                .collect()
+        });
+        let shards = std::iter::once(collection.first_memory())
+            .chain(opened)
+            .map(|id| {
+                StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(id))))
+            })
+            .collect();
+        ShardedMap { collection, shards }
+    }
+
+    // Opens the collection's next shard; fails once its spare memories are used up
+    fn open_shard(&mut self) -> Result<u8, Error> {
+        let index = self.shards.len() as u8;
+        let spare = self.collection.spare_memories();
+        let memory_id = spare.start() + (index - 1);
+        if !spare.contains(&memory_id) {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "{:?} uses all of its {} shards.",
+                    self.collection,
+                    spare.len() + 1
+                ),
+            });
+        }
+        SHARD_DIRECTORY.with(|service| {
+            service.borrow_mut().insert(
+                (self.collection.code(), index),
+                ShardInfo {
+                    memory_id,
+                    opened_at: time(),
+                },
+            )
+        });
+        self.shards.push(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id))),
+        ));
+        Ok(index)
+    }
+
+    fn get(&self, key: &K) -> Option<V> {
+        self.shards.iter().rev().find_map(|shard| shard.get(key))
+    }
+
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some(shard) = self
+            .shards
+            .iter_mut()
+            .rev()
+            .find(|shard| shard.contains_key(&key))
+        {
+            return shard.insert(key, value);
+        }
+        let full = self
+            .shards
+            .last()
+            .is_some_and(|shard| shard.len() >= self.collection.shard_capacity());
+        // Once the spare memories are used up, the last shard keeps growing
+        if full {
+            let _ = self.open_shard();
+        }
+        self.shards
+            .last_mut()
+            .expect("a collection always has its first shard")
+            .insert(key, value)
+    }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        self.shards.iter_mut().find_map(|shard| shard.remove(key))
+    }
+
+    fn len(&self) -> u64 {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    fn last_key_value(&self) -> Option<(K, V)> {
+        self.shards
+            .iter()
+            .filter_map(|shard| shard.last_key_value())
+            .max_by(|a, b| a.0.cmp(&b.0))
+    }
+
+    fn iter(&self) -> MergedIter<'_, K, V> {
+        self.range(..)
+    }
+
+    fn range(&self, key_range: impl RangeBounds<K>) -> MergedIter<'_, K, V> {
+        let bounds = (
+            key_range.start_bound().cloned(),
+            key_range.end_bound().cloned(),
+        );
+        MergedIter {
+            heads: self
+                .shards
+                .iter()
+                .map(|shard| {
+                    let entries: Box<dyn Iterator<Item = (K, V)> + '_> =
+                        Box::new(shard.range(bounds.clone()));
+                    entries.peekable()
+                })
+                .collect(),
+        }
+    }
+}
+
+// Entries of one shard still to be merged
+type ShardEntries<'a, K, V> = Peekable<Box<dyn Iterator<Item = (K, V)> + 'a>>;
+
+// Iterator over the entries of a sharded map in key order, taking the smallest next key among
+// the shards at each step
+struct MergedIter<'a, K, V> {
+    heads: Vec<ShardEntries<'a, K, V>>,
+}
+
+impl<K: Ord + Clone, V> Iterator for MergedIter<'_, K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        let mut next: Option<(usize, K)> = None;
+        for (index, head) in self.heads.iter_mut().enumerate() {
+            if let Some((key, _)) = head.peek() {
+                if next.as_ref().is_none_or(|(_, smallest)| key < smallest) {
+                    next = Some((index, key.clone()));
+                }
+            }
+        }
+        let (index, _) = next?;
+        self.heads[index].next()
+    }
+}
+
+// Ordered map a batch of entries can be read from and written back to, whether it is kept in one
+// stable memory or sharded
+trait EntryMap<K, V> {
+    fn entries_from(&self, start: Bound<K>, limit: usize) -> Vec<(K, V)>;
+    fn put(&mut self, key: K, value: V);
+}
+
+impl<K, V> EntryMap<K, V> for StableBTreeMap<K, V, Memory>
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    fn entries_from(&self, start: Bound<K>, limit: usize) -> Vec<(K, V)> {
+        self.range((start, Bound::Unbounded)).take(limit).collect()
+    }
+
+    fn put(&mut self, key: K, value: V) {
+        self.insert(key, value);
+    }
+}
+
+impl<K, V> EntryMap<K, V> for ShardedMap<K, V>
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    fn entries_from(&self, start: Bound<K>, limit: usize) -> Vec<(K, V)> {
+        self.range((start, Bound::Unbounded)).take(limit).collect()
+    }
+
+    fn put(&mut self, key: K, value: V) {
+        self.insert(key, value);
+    }
+}
+
+// Fraud rule an order tripped, with what was counted against the limit
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum FraudSignal {
+    OrderRate { orders: u32, limit: u32 },
+    NewAccountValue { value: u64, limit: u64 },
+    RedemptionRate { redemptions: u32, limit: u32 },
+}
+
+// Where an order is in staff review
+#[derive(candid::CandidType, Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+enum ReviewStatus {
+    Flagged,
+    Held,
+    Cleared,
+    Rejected,
+}
+
+// Staff decision on an order waiting for review
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+enum ReviewDecision {
+    Clear,
+    // The order is cancelled and refunded
+    Reject,
+}
+
+// Order that tripped the fraud rules, waiting for or decided by staff
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct OrderReview {
+    order_id: u64,
+    customer: Principal,
+    total: u64,
+    signals: Vec<FraudSignal>,
+    status: ReviewStatus,
+    flagged_at: u64,
+    reviewed_by: Option<Principal>,
+    reviewed_at: Option<u64>,
+    note: Option<String>,
+}
+
+impl Storable for OrderReview {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for OrderReview {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Recent orders of a customer the fraud rules count against
+#[derive(candid::CandidType, Clone, Serialize, Deserialize, Default)]
+struct OrderVelocity {
+    // Times of the orders placed within the last hour
+    recent_orders: Vec<u64>,
+    // Day the totals below count, in days since the epoch
+    day: u64,
+    value_today: u64,
+    redemptions_today: u32,
+}
+
+impl Storable for OrderVelocity {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for OrderVelocity {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// The api_v1 types below are the public schema of v1 and are decoupled from the internal types:
+// fields are only ever added to them as optional, and anything else goes into a new version
+
+// Version and deprecation status sent with every v1 response
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ApiV1Meta {
+    version: u32,
+    revision: u32,
+    deprecation: Option<ApiDeprecation>,
+}
+
+// Description of the v1 surface
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ApiV1Info {
+    meta: ApiV1Meta,
+    methods: Vec<String>,
+    http_prefix: String,
+    // Versions served next to this one
+    versions: Vec<u32>,
+}
+
+// Whether the shop takes orders and payments
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ApiV1ShopStatus {
+    meta: ApiV1Meta,
+    open: bool,
+    accepting_payments: bool,
+    message: Option<String>,
+    reopens_at: Option<u64>,
+}
+
+// Published product as aggregators see it; the category is its name, so new categories never
+// break decoding
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ApiV1Product {
+    id: u64,
+    name: String,
+    category: String,
+    // In the smallest currency unit
+    price: u64,
+    tags: Vec<String>,
+    allergens: Vec<String>,
+    age_restricted: bool,
+    available: u32,
+    min_order_qty: Option<u32>,
+    max_order_qty: Option<u32>,
+    order_qty_step: Option<u32>,
+    // Set for products made to order
+    prep_minutes: Option<u32>,
+    updated_at: u64,
+}
+
+// Page of published products; pass `next_cursor` back to continue after the last product
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ApiV1ProductPage {
+    meta: ApiV1Meta,
+    products: Vec<ApiV1Product>,
+    next_cursor: Option<u64>,
+}
+
+// Product looked up by id; not set when it is unknown or not published
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ApiV1ProductResponse {
+    meta: ApiV1Meta,
+    product: Option<ApiV1Product>,
+}
+
+// One line of a packing slip, with the allergens of its product
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PackingLine {
+    product_id: u64,
+    name: String,
+    // Units still to pack, leaving out those already handed over or refunded
+    quantity: u32,
+    // Selected options as "group: choice"
+    options: Vec<String>,
+    allergens: Vec<String>,
+}
+
+// Everything a label printer needs for one order in a single call
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct PackingData {
+    shop: Option<ShopInfo>,
+    order_id: u64,
+    order_number: Option<String>,
+    status: OrderStatus,
+    placed_at: u64,
+    // Pickup or delivery slot, when one was chosen at checkout
+    slot_start: Option<u64>,
+    // Picked up at the shop when not set
+    delivery_address: Option<DeliveryAddress>,
+    notes: Option<String>,
+    lines: Vec<PackingLine>,
+    // Allergens across all the lines, sorted
+    allergen_warnings: Vec<String>,
+    total: u64,
+    // Scanned at the counter with confirm_pickup; only set for pickups
+    pickup_qr: Option<String>,
+}
+
+// What a generated code gives the customer who redeems it
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum CodeKind {
+    // Applies the promotion to the customer's next order, even while it is inactive
+    Coupon { promotion_id: u64 },
+    // Added to the customer's store credit, in the smallest currency unit
+    GiftCard { value: u64 },
+}
+
+// Coupon or gift-card code generated on the canister
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct IssuedCode {
+    // Formatted as e.g. CP-ABCD-EFGH-JKLM, the last character a check character
+    code: String,
+    kind: CodeKind,
+    created_by: Principal,
+    created_at: u64,
+    expires_at: Option<u64>,
+    redeemed_by: Option<Principal>,
+    redeemed_at: Option<u64>,
+}
+
+impl Storable for IssuedCode {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for IssuedCode {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Generated code in its canonical form, keying the issued codes
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct CodeKey(String);
+
+impl Storable for CodeKey {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Borrowed(self.0.as_bytes())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        CodeKey(String::from_utf8(bytes.into_owned()).unwrap())
+    }
+}
+
+impl BoundedStorable for CodeKey {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Address a customer's order receipts are emailed to
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct CustomerEmail {
+    address: String,
+    set_at: u64,
+}
+
+impl Storable for CustomerEmail {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for CustomerEmail {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Delivery of an order's receipt email through the email bridge, retried like outbox effects
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct ReceiptDelivery {
+    order_id: u64,
+    to: String,
+    status: OutboxStatus,
+    attempts: u32,
+    created_at: u64,
+    next_attempt_at: u64,
+    last_attempt_at: Option<u64>,
+    delivered_at: Option<u64>,
+    // At most MAX_RECEIPT_ERROR_LENGTH bytes
+    last_error: Option<String>,
+    // Times the receipt was sent again after the first delivery was queued
+    resends: u32,
+}
+
+impl Storable for ReceiptDelivery {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl BoundedStorable for ReceiptDelivery {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// Receipt email as it would be sent
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct RenderedReceipt {
+    subject: String,
+    body: String,
+}
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
+        MemoryManager::init(DefaultMemoryImpl::default())
+    );
+
+    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
+            .expect("Cannot create a counter")
+    );
+
+    static STORAGE: RefCell<StableBTreeMap<u64, Product, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
+    ));
+
+    static CONFIG: RefCell<ConfigCell> = RefCell::new(
+        ConfigCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))), Config::default())
+            .expect("Cannot create the config cell")
+    );
+
+    static RNG_STATE: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))), 0)
+            .expect("Cannot create the random state cell")
+    );
+
+    static RESERVATIONS: RefCell<StableBTreeMap<u64, Reservation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4)))
+    ));
+
+    static CATEGORY_CAPS: RefCell<StableBTreeMap<u8, u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5)))
+    ));
+
+    // Keyed by (product id, change timestamp)
+    static PRICE_HISTORY: RefCell<StableBTreeMap<(u64, u64), PriceChange, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6)))
+    ));
+
+    static ORDERS: RefCell<ShardedMap<u64, Order>> =
+        RefCell::new(ShardedMap::init(ShardedCollection::Orders));
+
+    static CUSTOMERS: RefCell<StableBTreeMap<PrincipalKey, Customer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8)))
+    ));
+
+    static PROMOTIONS: RefCell<StableBTreeMap<u64, Promotion, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9)))
+    ));
+
+    static ACCESS_POLICY: RefCell<AccessPolicyCell> = RefCell::new(
+        AccessPolicyCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+            AccessPolicy::default(),
+        )
+        .expect("Cannot create the access policy cell")
+    );
+
+    // Orders with a mint call in flight, so a receipt is never minted twice
+    static MINTS_IN_FLIGHT: RefCell<std::collections::HashSet<u64>> = RefCell::default();
+
+    // Back-of-house stock keyed by (product id, location code); the front shelf holds the rest
+    static LOCATION_STOCK: RefCell<StableBTreeMap<(u64, u8), u32, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11)))
+    ));
+
+    static QUOTES: RefCell<StableBTreeMap<u64, Quote, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12)))
+    ));
+
+    // Keyed by job code
+    static SCHEDULED_JOBS: RefCell<StableBTreeMap<u8, ScheduledJob, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16)))
+    ));
+
+    // Raw sale events waiting to be rolled up, keyed by sequence number
+    static SALE_EVENTS: RefCell<ShardedMap<u64, SaleEvent>> =
+        RefCell::new(ShardedMap::init(ShardedCollection::SaleEvents));
+
+    static SALE_EVENT_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))), 0)
+            .expect("Cannot create the sale event counter")
+    );
+
+    // Keyed by (day, product id)
+    static DAILY_SALES: RefCell<StableBTreeMap<(u64, u64), DailySales, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15)))
+    ));
+
+    // Keyed by (recipient, notification id); ids grow over time
+    static NOTIFICATIONS: RefCell<StableBTreeMap<(PrincipalKey, u64), Notification, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17)))
+    ));
+
+    static NOTIFICATION_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))), 0)
+            .expect("Cannot create the notification counter")
+    );
+
+    // Principals waiting for a product to come back in stock, keyed by (product id, principal)
+    static STOCK_WATCHERS: RefCell<StableBTreeMap<(u64, PrincipalKey), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19)))
+    ));
+
+    // Time-based pricing rules, keyed by rule id
+    static PRICING_RULES: RefCell<StableBTreeMap<u64, PricingRule, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20)))
+    ));
+
+    // Registry of accepted payment tokens, keyed by ledger principal
+    static ACCEPTED_TOKENS: RefCell<StableBTreeMap<PrincipalKey, AcceptedToken, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21)))
+    ));
+
+    // Orders whose escrowed payment is waiting to be released or refunded
+    static PENDING_SETTLEMENTS: RefCell<StableBTreeMap<u64, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22)))
+    ));
+
+    // Orders whose escrow settlement is currently awaiting a ledger response
+    static SETTLEMENTS_IN_FLIGHT: RefCell<std::collections::HashSet<u64>> = RefCell::default();
+
+    // Loaded from the config on first use, so it is back in effect after upgrades
+    static CLOCK: RefCell<Clock> = RefCell::new(Clock::from_config());
+
+    // Record types written in the compact encoding, loaded from the config on first use
+    static COMPACT_ENTITIES: RefCell<Vec<StoredEntity>> = RefCell::new(compact_entities());
+
+    // Journal of the internal double-entry ledger, keyed by entry id in posting order
+    static JOURNAL: RefCell<StableBTreeMap<u64, JournalEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23)))
+    ));
+
+    static JOURNAL_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))), 0)
+            .expect("Cannot create the journal counter")
+    );
+
+    // Running totals of each ledger account, keyed by account code
+    static ACCOUNT_TOTALS: RefCell<StableBTreeMap<u8, AccountTotals, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25)))
+    ));
+
+    // Spending limits of sub-principals, keyed by sub-principal
+    static SPENDING_LIMITS: RefCell<StableBTreeMap<PrincipalKey, SpendingLimit, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26)))
+    ));
+
+    // Sub-principals of each account owner, keyed by (owner, sub-principal)
+    static OWNER_SUB_PRINCIPALS: RefCell<StableBTreeMap<(PrincipalKey, PrincipalKey), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27)))
+    ));
+
+    // Sales imported from external point-of-sale systems, keyed by their external id
+    static EXTERNAL_SALES: RefCell<StableBTreeMap<ExternalId, ExternalSale, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28)))
+    ));
+
+    // Audit history of product writes, keyed by (product id, version sequence)
+    static PRODUCT_VERSIONS: RefCell<StableBTreeMap<(u64, u64), ProductVersion, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29)))
+    ));
+
+    static PRODUCT_VERSION_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))), 0)
+            .expect("Cannot create the product version counter")
+    );
+
+    // Destructive operations awaiting a second admin, keyed by action id
+    static PENDING_ACTIONS: RefCell<StableBTreeMap<u64, PendingAction, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31)))
+    ));
+
+    // Price experiments, keyed by experiment id
+    static EXPERIMENTS: RefCell<StableBTreeMap<u64, Experiment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32)))
+    ));
+
+    // Conversions per experiment variant, keyed by (experiment id, variant index)
+    static EXPERIMENT_STATS: RefCell<StableBTreeMap<(u64, u8), VariantStats, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33)))
+    ));
+
+    // Support tickets, keyed by ticket id
+    static TICKETS: RefCell<StableBTreeMap<u64, Ticket, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34)))
+    ));
+
+    // Registered self-serve kiosks, keyed by kiosk principal
+    static KIOSKS: RefCell<StableBTreeMap<PrincipalKey, Kiosk, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35)))
+    ));
+
+    // Kiosk sessions, keyed by the number their token encodes
+    static KIOSK_SESSIONS: RefCell<StableBTreeMap<u64, KioskSession, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36)))
+    ));
+
+    // Staff notifier channels, keyed by channel id
+    static NOTIFIER_CHANNELS: RefCell<StableBTreeMap<u64, NotifierChannel, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37)))
+    ));
+
+    // Waitlists of sold-out products, keyed by (product id, join sequence number)
+    static WAITLIST: RefCell<StableBTreeMap<(u64, u64), WaitlistEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38)))
+    ));
+
+    static WAITLIST_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39))), 0)
+            .expect("Cannot create the waitlist counter")
+    );
+
+    // Option schemas of configurable products, keyed by product id
+    static PRODUCT_OPTIONS: RefCell<StableBTreeMap<u64, OptionSchema, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40)))
+    ));
+
+    // Recurring subscription orders, keyed by subscription id
+    static SUBSCRIPTIONS: RefCell<StableBTreeMap<u64, Subscription, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41)))
+    ));
+
+    // Staff shifts, keyed by shift id
+    static SHIFTS: RefCell<StableBTreeMap<u64, Shift, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42)))
+    ));
+
+    // Stock movements recorded during shifts, keyed by (shift id, sequence number)
+    static STOCK_MOVEMENTS: RefCell<ShardedMap<(u64, u64), StockMovement>> =
+        RefCell::new(ShardedMap::init(ShardedCollection::StockMovements));
+
+    static STOCK_MOVEMENT_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44))), 0)
+            .expect("Cannot create the stock movement counter")
+    );
+
+    // Bumped on every catalog change, part of the ETag of catalog pages served over HTTP
+    static CATALOG_VERSION: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45))), 0)
+            .expect("Cannot create the catalog version counter")
+    );
+
+    static PUBLISHED_CATALOG: RefCell<PublishedCatalogCell> = RefCell::new(
+        PublishedCatalogCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(89))),
+            PublishedCatalog::default(),
+        )
+        .expect("Cannot create the published catalog cell")
+    );
+
+    static INVARIANT_STATE: RefCell<InvariantStateCell> = RefCell::new(
+        InvariantStateCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(93))),
+            InvariantState::default(),
+        )
+        .expect("Cannot create the invariant check cell")
+    );
+
+    // Set while a build hook call is in flight, so that checks do not overlap it
+    static BUILD_HOOK_IN_FLIGHT: RefCell<bool> = RefCell::default();
+
+    // Customers attested as adults by staff, keyed by customer principal
+    static AGE_ATTESTATIONS: RefCell<StableBTreeMap<PrincipalKey, AgeAttestation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46)))
+    ));
+
+    // Sales rollups older than the daily retention, keyed by (months since January 1970,
+    // product id)
+    static MONTHLY_SALES: RefCell<StableBTreeMap<(u64, u64), DailySales, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47)))
+    ));
+
+    // Audit of the retention runs that purged data, keyed by run time
+    static RETENTION_AUDIT: RefCell<StableBTreeMap<u64, RetentionReport, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48)))
+    ));
+    // Index of product ids by normalized name, keeping names unique
+    static PRODUCT_NAMES: RefCell<StableBTreeMap<ProductName, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49)))
+    ));
+    // Customer tier assignments; customers without one are retail customers
+    static CUSTOMER_TIERS: RefCell<StableBTreeMap<PrincipalKey, TierAssignment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50)))
+    ));
+
+    // Tier price lists, keyed by (product id, tier code)
+    static TIER_PRICES: RefCell<StableBTreeMap<(u64, u8), TierPrice, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51)))
+    ));
+    // Draft carts, keyed by owner
+    static CARTS: RefCell<StableBTreeMap<PrincipalKey, Cart, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52)))
+    ));
+    // Health snapshots taken around the last upgrade
+    static UPGRADE_HEALTH: RefCell<UpgradeHealthCell> = RefCell::new(
+        UpgradeHealthCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53))),
+            UpgradeHealth::default(),
+        )
+        .expect("Cannot create the upgrade health cell")
+    );
+    // Index rebuilds in progress or last completed, keyed by index kind code
+    static INDEX_REBUILDS: RefCell<StableBTreeMap<u8, IndexRebuild, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54)))
+    ));
+    // Order messages, keyed by (order id, position in the thread)
+    static ORDER_MESSAGES: RefCell<StableBTreeMap<(u64, u64), OrderMessage, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55)))
+    ));
+
+    // Order message threads, keyed by order id
+    static ORDER_THREADS: RefCell<StableBTreeMap<u64, OrderThread, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56)))
+    ));
+    // Latest version sequence of each product, keyed by product id
+    static PRODUCT_VERSION_HEADS: RefCell<StableBTreeMap<u64, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57)))
+    ));
+    // Storage ranges of the locations, keyed by location code
+    static STORAGE_RANGES: RefCell<StableBTreeMap<u8, StorageRange, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58)))
+    ));
+
+    // Storage-condition readings, keyed by (location code, sequence number)
+    static CONDITION_READINGS: RefCell<StableBTreeMap<(u8, u64), ConditionReading, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59)))
+    ));
+
+    static CONDITION_READING_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60))), 0)
+            .expect("Cannot create the condition reading counter")
+    );
+
+    // Periods locations spent out of range, keyed by exception id
+    static STORAGE_EXCEPTIONS: RefCell<StableBTreeMap<u64, StorageException, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61)))
+    ));
+    // Checkout sessions, keyed by session id
+    static CHECKOUT_SESSIONS: RefCell<StableBTreeMap<u64, CheckoutSession, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62)))
+    ));
+    // Effects waiting to be dispatched or recently dispatched, keyed by sequence number
+    static OUTBOX: RefCell<StableBTreeMap<u64, OutboxEntry, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63)))
+    ));
+
+    static OUTBOX_SEQ: RefCell<IdCell> = RefCell::new(
+        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64))), 0)
+            .expect("Cannot create the outbox counter")
+    );
+
+    // Outbox effects being dispatched, so that a retry does not overlap a running attempt
+    static OUTBOX_IN_FLIGHT: RefCell<std::collections::HashSet<u64>> = RefCell::default();
+    // Default settings of the built-in categories, keyed by category code
+    static CATEGORY_DEFAULTS: RefCell<StableBTreeMap<u8, ProductSettings, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(65)))
+    ));
+    // Read-only reporting principals, keyed by principal
+    static VIEWERS: RefCell<StableBTreeMap<PrincipalKey, Viewer, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(66)))
+    ));
+    // Append-only journal of the state-changing calls, keyed by sequence number
+    static CALL_JOURNAL: RefCell<StableBTreeMap<u64, CallRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(67)))
+    ));
+    // Copies of products and orders taken at snapshot points, keyed by id and point
+    static PRODUCT_SNAPSHOTS: RefCell<StableBTreeMap<(u64, u64), Product, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(68)))
+    ));
+
+    static ORDER_SNAPSHOTS: RefCell<StableBTreeMap<(u64, u64), Order, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(69)))
+    ));
+
+    static SNAPSHOT_POINTS: RefCell<StableBTreeMap<u64, SnapshotPoint, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(70)))
+    ));
+
+    // Point-in-time restores, keyed by plan id
+    static RESTORE_PLANS: RefCell<StableBTreeMap<u64, RestorePlan, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(71)))
+    ));
+
+    static SUPPLIERS: RefCell<StableBTreeMap<u64, Supplier, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(72)))
+    ));
+
+    static PURCHASE_ORDERS: RefCell<StableBTreeMap<u64, PurchaseOrder, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(73)))
+    ));
+
+    static STAFF_DEVICES: RefCell<StableBTreeMap<PrincipalKey, StaffDevices, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(74)))
+    ));
+
+    static RETURNS: RefCell<StableBTreeMap<u64, ReturnRequest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(75)))
+    ));
+
+    static ORDER_EXPORTS: RefCell<StableBTreeMap<u64, OrderExportManifest, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(76)))
+    ));
+
+    // Clears of all products, keyed by the id of the action that approved them
+    static CLEAR_RUNS: RefCell<StableBTreeMap<u64, ClearRun, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(77)))
+    ));
+
+    // Keyed by (product id, related product id)
+    static PRODUCT_RELATIONS: RefCell<StableBTreeMap<(u64, u64), ProductRelation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(78)))
+    ));
+
+    static CUSTOMER_ACCOUNTS: RefCell<StableBTreeMap<u64, CustomerAccount, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(79)))
+    ));
+
+    // Account each linked principal belongs to
+    static ACCOUNT_MEMBERS: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(80)))
+    ));
+
+    // Keyed by the principal that started the challenge
+    static LINK_CHALLENGES: RefCell<StableBTreeMap<PrincipalKey, LinkChallengeRecord, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(81)))
+    ));
+
+    static STOCKTAKES: RefCell<StableBTreeMap<u64, Stocktake, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(82)))
+    ));
+
+    // Keyed by the day since the Unix epoch (UTC)
+    static DAILY_CLOSES: RefCell<StableBTreeMap<u64, DailyClose, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(83)))
+    ));
+
+    // Products by `(category key, product id)`, so category pages are range scans
+    static PRODUCT_CATEGORIES: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(84)))
+    ));
+
+    static MESSAGE_CATALOG: RefCell<StableBTreeMap<u64, MessageTranslation, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(85)))
+    ));
+
+    static LANGUAGE_PREFERENCES: RefCell<StableBTreeMap<PrincipalKey, LanguagePreference, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(86)))
+    ));
+
+    static ORDER_NUMBERS: RefCell<StableBTreeMap<OrderNumber, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(87)))
+    ));
+
+    // Last sequence number issued in each period of the order number pattern
+    static ORDER_NUMBER_SEQUENCES: RefCell<StableBTreeMap<OrderNumber, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(88)))
+    ));
+
+    static TENDER_BALANCES: RefCell<StableBTreeMap<PrincipalKey, TenderBalance, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(90)))
+    ));
+
+    static DISPUTES: RefCell<StableBTreeMap<u64, Dispute, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(91)))
+    ));
+
+    static BIRTHDAYS: RefCell<StableBTreeMap<PrincipalKey, CustomerBirthday, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(92)))
+    ));
+
+    // Shards opened for the sharded collections after their first, by (collection, index)
+    static SHARD_DIRECTORY: RefCell<StableBTreeMap<(u8, u8), ShardInfo, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(94)))
+    ));
+
+    // Orders flagged or held by the fraud rules, by order id
+    static ORDER_REVIEWS: RefCell<StableBTreeMap<u64, OrderReview, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(95)))
+    ));
+
+    // Recent orders of each account holder, for the fraud rules
+    static ORDER_VELOCITY: RefCell<StableBTreeMap<PrincipalKey, OrderVelocity, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(96)))
+    ));
+
+    // Preparation steps of orders, by order id
+    static ORDER_PREP: RefCell<StableBTreeMap<u64, OrderPrep, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(97)))
+    ));
+
+    // Category reassignments, by id
+    static CATEGORY_REASSIGNMENTS: RefCell<StableBTreeMap<u64, CategoryReassignment, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(98)))
+    ));
+
+    // Price changes scheduled for a future time, by id
+    static SCHEDULED_PRICE_CHANGES: RefCell<StableBTreeMap<u64, ScheduledPriceChange, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(99)))
+    ));
+
+    // Number of orders in each status, by status code
+    static ORDER_STATUS_COUNTS: RefCell<StableBTreeMap<u8, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(148)))
+    ));
+
+    // Coupon and gift-card codes generated on the canister, by canonical code
+    static ISSUED_CODES: RefCell<StableBTreeMap<CodeKey, IssuedCode, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(149)))
+    ));
+
+    // Promotion of the coupon each account holder redeemed, until an order uses it
+    static PENDING_COUPONS: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(150)))
+    ));
+
+    // Supplier each supplier portal principal acts for
+    static SUPPLIER_PRINCIPALS: RefCell<StableBTreeMap<PrincipalKey, u64, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(151)))
+    ));
+
+    // Address each account holder's receipts are emailed to
+    static CUSTOMER_EMAILS: RefCell<StableBTreeMap<PrincipalKey, CustomerEmail, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(152)))
+    ));
+
+    // Receipt email of each order, with its delivery attempts
+    static RECEIPT_DELIVERIES: RefCell<StableBTreeMap<u64, ReceiptDelivery, Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(153)))
+    ));
+
+    // Snapshot of products and orders carried over to the next expiry sweep
+    static SNAPSHOT_STATE: RefCell<SnapshotStateCell> = RefCell::new(
+        SnapshotStateCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(154))),
+            SnapshotState::default(),
+        )
+        .expect("Cannot create the snapshot progress cell")
+    );
+
+    // Reservations by (product id, reservation id), derived from the reservations
+    static PRODUCT_RESERVATIONS: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(155)))
+    ));
+
+    // Relations by (related product id, product id), derived from the product relations
+    static RELATED_FROM: RefCell<StableBTreeMap<(u64, u64), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(156)))
+    ));
+
+    // Receipt emails being sent, so that a retry does not overlap a running attempt
+    static RECEIPTS_IN_FLIGHT: RefCell<std::collections::HashSet<u64>> = RefCell::default();
+}
+
+// Function to initialize the canister configuration on install
+#[ic_cdk::init]
+fn init(args: Option<InitArgs>) {
+    let (id_strategy, staging) = args.map_or((None, None), |args| (args.id_strategy, args.staging));
+    CONFIG.with(|config| {
+        config
+            .borrow_mut()
+            .set(Config {
+                id_strategy: id_strategy.unwrap_or_default(),
+                staging: staging.filter(|staging| *staging),
+                ..Default::default()
+            })
+            .expect("Cannot store the config")
+    });
+    RNG_STATE.with(|state| {
+        state
+            .borrow_mut()
+            .set(time())
+            .expect("Cannot seed the random state")
+    });
+    journal_call("init");
+    schedule_next_job();
+}
+
+// Function to re-arm the job timer from the persisted schedule, as timers do not survive
+// upgrades; jobs that fell due during the upgrade run on the next timer tick
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    journal_call("post_upgrade");
+    index_existing_product_names();
+    index_existing_product_categories();
+    rekey_product_categories();
+    index_existing_reservations();
+    index_existing_product_relations();
+    count_existing_orders();
+    verify_upgrade();
+    schedule_next_job();
+}
+
+// Function to persist a health snapshot before upgrading; it only reads counters, map sizes and
+// last keys, so its cost does not grow with the state and a large or broken state cannot block
+// the upgrade that fixes it
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    let snapshot = health_snapshot();
+    store_upgrade_health(UpgradeHealth {
+        pre_upgrade: Some(snapshot),
+        ..Default::default()
+    });
+}
+
+// Helper function to get the persisted schedule of a job, creating it on first use
+fn scheduled_job(job: Job, now: u64) -> ScheduledJob {
+    SCHEDULED_JOBS.with(|service| {
+        let mut jobs = service.borrow_mut();
+        match jobs.get(&job.code()) {
+            Some(scheduled) => scheduled,
+            None => {
+                let scheduled = ScheduledJob {
+                    job,
+                    interval_ns: job.interval_ns(),
+                    next_run: now.saturating_add(job.interval_ns()),
+                    last_run: None,
+                    last_instructions: None,
+                };
+                jobs.insert(job.code(), scheduled.clone());
+                scheduled
+            }
+        }
+    })
+}
+
+// Helper function to arm the canister's global timer for the earliest job run
+fn schedule_next_job() {
+    let now = time();
+    let next_run = Job::ALL
+        .iter()
+        .map(|job| scheduled_job(*job, now).next_run)
+        .chain(published_catalog().check_at)
+        .chain(next_price_change_at())
+        .min()
+        .unwrap_or(now)
+        // The system ignores timers set in the past, so never arm earlier than now
+        .max(now + 1);
+    let next_run = CLOCK.with(|clock| clock.borrow().system_time_at(next_run));
+    // SAFETY: `global_timer_set` only takes a timestamp and has no memory preconditions
+    unsafe {
+        ic0::global_timer_set(next_run as i64);
+    }
+}
+
+// Function to run a job and record the run in its persisted schedule
+fn run_job(job: Job, now: u64) -> ScheduledJob {
+    append_call_record(format!("timer:{:?}", job), ic_cdk::id(), None, None);
+    let started = ic_cdk::api::instruction_counter();
+    job.run();
+    let mut scheduled = scheduled_job(job, now);
+    scheduled.last_run = Some(now);
+    scheduled.last_instructions = Some(ic_cdk::api::instruction_counter() - started);
+    scheduled.next_run = now.saturating_add(scheduled.interval_ns);
+    SCHEDULED_JOBS.with(|service| service.borrow_mut().insert(job.code(), scheduled.clone()));
+    scheduled
+}
+
+// Entry point invoked by the system when the global timer fires
+#[export_name = "canister_global_timer"]
+extern "C" fn canister_global_timer() {
+    ic_cdk::setup();
+    let now = time();
+    for job in Job::ALL {
+        if scheduled_job(job, now).next_run <= now {
+            run_job(job, now);
+        }
+    }
+    if published_catalog().check_at.is_some_and(|at| at <= now) {
+        check_published_catalog();
+    }
+    if next_price_change_at().is_some_and(|at| at <= now) {
+        apply_due_price_changes(now);
+    }
+    schedule_next_job();
+}
+
+// Product payload struct used to create or update a product
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ProductPayload {
+    name: String,
+    quantity: u32,
+    category: Category,
+    // Left unchanged on update when omitted; defaults to 0 on creation
+    price: Option<u64>,
+    tags: Option<Vec<String>>,
+    allergens: Option<Vec<String>>,
+    // Left unchanged on update when omitted; new products are published by default
+    status: Option<ProductStatus>,
+    publish_at: Option<u64>,
+    // Left unchanged on update when omitted; 0 removes the limit
+    min_order_qty: Option<u32>,
+    max_order_qty: Option<u32>,
+    order_qty_step: Option<u32>,
+    // Left unchanged on update when omitted; not restricted by default
+    age_restricted: Option<bool>,
+    // Left unchanged on update when omitted; 0 removes it
+    best_before: Option<u64>,
+    // Settings the product overrides; left unchanged on update when omitted, and the settings
+    // not set are inherited from the category
+    settings: Option<ProductSettings>,
+    // Left unchanged on update when omitted; 0 removes it
+    prep_minutes: Option<u32>,
+    // Left unchanged on update when omitted; listed by default
+    unlisted: Option<bool>,
+}
+
+// Payload for adding or removing stock
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct StockPayload {
+    amount: u32,
+    // Location the stock is added to or taken from; defaults to the front shelf when adding
+    // and to front shelf first, then back of house, when offloading
+    location: Option<Location>,
+}
+
+// Products targeted by a bulk price adjustment
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum PriceScope {
+    All,
+    Category(Category),
+    Tag(String),
+}
+
+// Change applied to each price in a bulk adjustment
+#[derive(candid::CandidType, Serialize, Deserialize)]
+enum PriceAdjustment {
+    // Relative change in percent, e.g. -10 for a 10% discount
+    Percentage(i32),
+    // Absolute change in the smallest currency unit
+    Delta(i64),
+}
+
+// Payload for setting a product's price for a tier
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct TierPricePayload {
+    unit_price: u64,
+    min_order_qty: Option<u32>,
+}
+
+// Payload for posting a storage-condition reading
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ConditionReadingPayload {
+    location: Location,
+    // Tenths of a degree Celsius
+    temperature_decidegrees: i32,
+    // Time of posting when omitted
+    recorded_at: Option<u64>,
+}
+
+// Payload for placing a hold on a product's stock
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ReservationPayload {
+    product_id: u64,
+    amount: u32,
+    // Hold lifetime; the reservation never expires when omitted
+    ttl_seconds: Option<u64>,
+}
+
+// Product and quantity requested in an order
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct OrderLinePayload {
+    product_id: u64,
+    quantity: u32,
+    // Required for products with required option groups
+    selections: Option<Vec<OptionSelection>>,
+}
+
+// Payload used to place an order for the caller
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct OrderPayload {
+    lines: Vec<OrderLinePayload>,
+    notes: Option<String>,
+    // Symbol of an accepted token to pay with; priced in the shop's currency when omitted
+    payment_token: Option<String>,
+    // Points and store credit paying part of the order; the rest is paid with the token, or at
+    // the counter without one
+    tenders: Option<Vec<TenderRequest>>,
+}
+
+// Points or store credit to pay part of an order with, in points or the smallest currency unit
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+struct TenderRequest {
+    tender: Tender,
+    amount: u64,
+}
+
+// Payload used by admins to grant or take back store credit or points, e.g. for a gift card sold
+// at the counter
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct BalanceAdjustmentPayload {
+    customer: Principal,
+    tender: Tender,
+    // Added to the balance, or taken from it when negative
+    amount: i64,
+}
+
+// Payload used to request a price quote for a large or custom order
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct QuotePayload {
+    lines: Vec<OrderLinePayload>,
+    customizations: Option<String>,
+}
+
+// Payload used to create a promotion
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct PromotionPayload {
+    name: String,
+    percent_off: u8,
+    segments: Vec<Segment>,
+}
+
+// Report generated step by step with `report_step`
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum ReportRequest {
+    // Rolled-up daily sales between two days (inclusive), optionally for a single product
+    Sales {
+        from_day: u64,
+        to_day: u64,
+        product_id: Option<u64>,
+    },
+    // Movements of a ledger account posted in `[from, to)`
+    Ledger {
+        account: LedgerAccount,
+        from: u64,
+        to: u64,
+    },
+    // Stock movements recorded during a shift, optionally for a single product
+    StockMovements {
+        shift_id: u64,
+        product_id: Option<u64>,
+    },
+}
+
+// Row of a report
+#[derive(candid::CandidType, Clone, Serialize, Deserialize)]
+enum ReportRow {
+    Sales(DailySalesReport),
+    Ledger(StatementLine),
+    StockMovement(StockMovement),
+}
+
+// Running totals of a report: units sold or moved, and revenue, net debit or stock value
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize, Default)]
+struct ReportTotals {
+    rows: u64,
+    units: i64,
+    value: i64,
+}
+
+impl ReportTotals {
+    fn add(&mut self, row: &ReportRow) {
+        let (units, value) = match row {
+            ReportRow::Sales(sales) => (sales.units, sales.revenue),
+            ReportRow::Ledger(line) => (0, line.debit as i64 - line.credit as i64),
+            ReportRow::StockMovement(movement) => (movement.quantity_delta, movement.value),
+        };
+        self.rows += 1;
+        self.units += units;
+        self.value += value;
+    }
+}
+
+// Where the next report step resumes, carrying the totals of the steps so far
+#[derive(candid::CandidType, Clone, Copy, Serialize, Deserialize)]
+struct ReportCursor {
+    // Key of the last entry processed; single-number keys are paired with 0
+    after: (u64, u64),
+    totals: ReportTotals,
+}
+
+// One bounded slice of a report; the totals are final once there is no next cursor
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ReportStep {
+    rows: Vec<ReportRow>,
+    totals: ReportTotals,
+    next_cursor: Option<ReportCursor>,
+}
+
+// Position and size of a requested page; the first page is returned when omitted
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct PageRequest {
+    // Id of the last item of the previous page
+    cursor: Option<u64>,
+    limit: Option<u32>,
+}
+
+// Position and size of a requested page of customers; the first page is returned when omitted
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct CustomerPageRequest {
+    // Principal of the last customer of the previous page
+    cursor: Option<Principal>,
+    limit: Option<u32>,
+}
+
+// Payload for setting the order quantity limits of every product in a category
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct OrderQuantityPayload {
+    // Omitted limits are removed
+    min_order_qty: Option<u32>,
+    max_order_qty: Option<u32>,
+    order_qty_step: Option<u32>,
+}
+
+// Payload used to create or replace a pricing rule
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct PricingRulePayload {
+    name: String,
+    percent_off: u8,
+    categories: Vec<Category>,
+    start_minute: u16,
+    end_minute: u16,
+    // Left unchanged on update when omitted; new rules are active by default
+    active: Option<bool>,
+}
+
+// Payload used to register or update an accepted payment token
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TokenPayload {
+    symbol: String,
+    decimals: u8,
+    ledger: Principal,
+    conversion: ConversionSource,
+    // Left unchanged on update when omitted; new tokens are enabled by default
+    enabled: Option<bool>,
+}
+
+// Payload used by an account owner to add a sub-principal or change its limit
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SubPrincipalPayload {
+    principal: Principal,
+    daily_cap: u64,
+    label: Option<String>,
+}
+
+// One line of a sale rung up on an external point-of-sale system
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ExternalSaleLinePayload {
+    product_id: u64,
+    quantity: u32,
+    // Price charged at the till; the catalog price when omitted
+    unit_price: Option<u64>,
+}
+
+// Payload used to start a price experiment
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct ExperimentPayload {
+    name: String,
+    product_id: u64,
+    variants: Vec<ExperimentVariant>,
+}
+
+// Payload used by a customer to open a support ticket
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct TicketPayload {
+    order_id: Option<u64>,
+    category: TicketCategory,
+    text: String,
+}
+
+// Payload used to place an order from a kiosk session, paid in cash on pickup
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct KioskOrderPayload {
+    token: String,
+    lines: Vec<OrderLinePayload>,
+    notes: Option<String>,
+}
+
+// Payload used to add or replace a notifier channel
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct NotifierChannelPayload {
+    name: String,
+    kind: ChannelKind,
+    events: Vec<NotifierEvent>,
+    // Left unchanged on update when omitted; new channels are enabled by default
+    enabled: Option<bool>,
+    // Defaults to a short summary of the order
+    template: Option<String>,
+    // Left unchanged on update when omitted; off by default
+    alerts: Option<bool>,
+}
+
+// Payload used to create or replace a subscription
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct SubscriptionPayload {
+    items: Vec<SubscriptionItem>,
+    cadence_days: u32,
+    // Symbol of an accepted token; paid on pickup when omitted
+    payment_token: Option<String>,
+    notes: Option<String>,
+    // Time of the first order; defaults to now
+    start_at: Option<u64>,
+}
+
+// Units of an order line handed over or given up on
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct FulfillmentLinePayload {
+    product_id: u64,
+    quantity: u32,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct StocktakePayload {
+    // Every non-archived product when not set
+    product_ids: Option<Vec<u64>>,
+    counters: Vec<Principal>,
+    blind: bool,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct StockCountPayload {
+    product_id: u64,
+    counted: u32,
+}
+
+#[derive(candid::CandidType, Serialize, Deserialize)]
+struct TranslationPayload {
+    lang: String,
+    template: String,
+    // Removes the translation when not set
+    translation: Option<String>,
+}
+
+// Payload for deprecating a public API version
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ApiDeprecationPayload {
+    sunset_at: Option<u64>,
+    successor: Option<String>,
+    note: Option<String>,
+}
+
+// Products a count covers; a criterion applies only when set
+#[derive(candid::CandidType, Serialize, Deserialize, Default)]
+struct ProductCountFilter {
+    category: Option<Category>,
+    // Callers who are not admins only count published products
+    status: Option<ProductStatus>,
+    tag: Option<String>,
+    // Whether any units are available, or none
+    in_stock: Option<bool>,
+}
+
+// Custom error handling enum
+// Encoded through `ErrorReply` so that replies carry the message in the caller's language
+#[derive(Deserialize, Serialize)]
+enum Error {
+    NotFound { msg: String },
+    InvalidOperation { msg: String },
+    Unauthorized { msg: String },
+    CapacityExceeded { msg: String },
+    ExternalCallFailed { msg: String },
+    Maintenance { msg: String, eta: Option<u64> },
+    // The set of variants is frozen so clients built against it keep decoding; new kinds of
+    // errors are reported here, named by `kind`
+    Other { kind: String, msg: String },
+}
+
+// Wire form of `Error`, with the same shape and the message translated
+#[derive(candid::CandidType)]
+enum ErrorReply {
+    NotFound { msg: String },
+    InvalidOperation { msg: String },
+    Unauthorized { msg: String },
+    CapacityExceeded { msg: String },
+    ExternalCallFailed { msg: String },
+    Maintenance { msg: String, eta: Option<u64> },
+    Other { kind: String, msg: String },
+}
+
+impl candid::CandidType for Error {
+    fn _ty() -> candid::types::Type {
+        ErrorReply::_ty()
+    }
+
+    fn idl_serialize<S: candid::types::Serializer>(&self, serializer: S) -> Result<(), S::Error> {
+        let msg = localize(&caller(), self.message());
+        let reply = match self {
+            Error::NotFound { .. } => ErrorReply::NotFound { msg },
+            Error::InvalidOperation { .. } => ErrorReply::InvalidOperation { msg },
+            Error::Unauthorized { .. } => ErrorReply::Unauthorized { msg },
+            Error::CapacityExceeded { .. } => ErrorReply::CapacityExceeded { msg },
+            Error::ExternalCallFailed { .. } => ErrorReply::ExternalCallFailed { msg },
+            Error::Maintenance { eta, .. } => ErrorReply::Maintenance { msg, eta: *eta },
+            Error::Other { kind, .. } => ErrorReply::Other {
+                kind: kind.clone(),
+                msg,
+            },
+        };
+        reply.idl_serialize(serializer)
+    }
+}
+
+impl Error {
+    // Human-readable message of any error variant
+    fn message(&self) -> &str {
+        match self {
+            Error::NotFound { msg }
+            | Error::InvalidOperation { msg }
+            | Error::Unauthorized { msg }
+            | Error::CapacityExceeded { msg }
+            | Error::ExternalCallFailed { msg }
+            | Error::Maintenance { msg, .. }
+            | Error::Other { msg, .. } => msg,
+        }
+    }
+}
+
+// Utility function to advance the sequential id counter
+fn next_sequence() -> Result<u64, Error> {
+    ID_COUNTER
+        .with(|counter| {
+            let current_value = *counter.borrow().get();
+            counter.borrow_mut().set(current_value + 1)
+        })
+        .map_err(|_| Error::InvalidOperation {
+            msg: "Failed to generate a unique ID.".to_string(),
+        })
+}
+
+// Utility function to draw the next pseudo-random u64 (splitmix64 mixed with the current time)
+fn next_random() -> Result<u64, Error> {
+    RNG_STATE
+        .with(|state| {
+            let seed = state.borrow().get().wrapping_add(0x9E37_79B9_7F4A_7C15) ^ time();
+            state.borrow_mut().set(seed).map(|_| seed)
+        })
+        .map(|seed| {
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            z ^ (z >> 31)
+        })
+        .map_err(|_| Error::InvalidOperation {
+            msg: "Failed to generate a unique ID.".to_string(),
+        })
+}
+
+// Utility function to generate unique IDs using the configured strategy;
+// `is_taken` reports whether a candidate id is already used by the target collection
+fn generate_unique_id(is_taken: impl Fn(u64) -> bool) -> Result<u64, Error> {
+    let strategy = CONFIG.with(|config| config.borrow().get().id_strategy);
+    if strategy == IdStrategy::Sequential {
+        return next_sequence();
+    }
+
+    for _ in 0..MAX_ID_ATTEMPTS {
+        let random = next_random()?;
+        let id = match strategy {
+            IdStrategy::TimeSortable => {
+                let millis = time() / 1_000_000;
+                (millis << TIME_SORTABLE_RANDOM_BITS)
+                    | (random & ((1 << TIME_SORTABLE_RANDOM_BITS) - 1))
+            }
+            _ => random,
+        };
+        if id != 0 && !is_taken(id) {
+            return Ok(id);
+        }
+    }
+    Err(Error::InvalidOperation {
+        msg: "Failed to generate a unique ID.".to_string(),
+    })
+}
+
+// Helper function to restrict an endpoint to the canister's controllers, working from a
+// registered device while device binding is required
+fn ensure_admin() -> Result<(), Error> {
+    ensure_controller()?;
+    ensure_staff_device()
+}
+
+// Helper function to restrict an endpoint to the canister's controllers on any device, for
+// managing devices without locking admins out
+fn ensure_controller() -> Result<(), Error> {
+    if ic_cdk::api::is_controller(&caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "Only an admin can perform this operation.".to_string(),
+        })
+    }
+}
+
+// Helper function to refuse calls that do not name a registered device of the caller while
+// device binding is required
+fn ensure_staff_device() -> Result<(), Error> {
+    let required = CONFIG.with(|config| config.borrow().get().require_staff_devices);
+    if required != Some(true) {
+        return Ok(());
+    }
+    if call_device(&caller()).is_none() {
+        return Err(Error::Unauthorized {
+            msg: "Staff calls must name a registered device in a last record { staff_device } argument."
+                .to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Helper function to get the device named by the current call, if it is registered for the
+// principal and not revoked. Staff name it in an extra last argument
+// `record { staff_device : text }`, which the endpoint's own arguments ignore
+fn call_device(principal: &Principal) -> Option<String> {
+    let args = candid::IDLArgs::from_bytes(&ic_cdk::api::call::arg_data_raw()).ok()?;
+    let label = candid::types::Label::Named("staff_device".to_string());
+    let device_id = match args.args.last()? {
+        candid::IDLValue::Record(fields) => fields.iter().find_map(|field| match &field.val {
+            candid::IDLValue::Text(device_id) if field.id == label => Some(device_id.clone()),
+            _ => None,
+        })?,
+        _ => return None,
+    };
+    STAFF_DEVICES
+        .with(|service| service.borrow().get(&PrincipalKey(*principal)))
+        .filter(|devices| {
+            devices
+                .devices
+                .iter()
+                .any(|device| device.device_id == device_id && device.revoked_at.is_none())
+        })
+        .map(|_| device_id)
+}
+
+// Helper function to allow admins and viewers to call a reporting query
+fn ensure_viewer() -> Result<(), Error> {
+    if ensure_admin().is_ok() || is_viewer(&caller()) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "Only admins and viewers can see reports.".to_string(),
+        })
+    }
+}
+
+// Helper function to check whether a principal is a registered viewer
+fn is_viewer(principal: &Principal) -> bool {
+    VIEWERS.with(|service| service.borrow().contains_key(&PrincipalKey(*principal)))
+}
+
+// Helper function to reject anonymous callers unless the endpoint has been opened to them
+fn ensure_caller_allowed(endpoint: Endpoint) -> Result<(), Error> {
+    ensure_not_in_maintenance()?;
+    if endpoint != Endpoint::PlaceKioskOrder && is_kiosk(&caller()) {
+        return Err(Error::Unauthorized {
+            msg: "Kiosks can only browse and place orders in a kiosk session.".to_string(),
+        });
+    }
+    if is_viewer(&caller()) {
+        return Err(Error::Unauthorized {
+            msg: "Viewers have read-only access.".to_string(),
+        });
+    }
+    if linked_supplier(&caller()).is_some() {
+        return Err(Error::Unauthorized {
+            msg: "Suppliers can only use the supplier portal.".to_string(),
+        });
+    }
+    if caller() != Principal::anonymous() {
+        return Ok(());
+    }
+    let allowed =
+        ACCESS_POLICY.with(|policy| policy.borrow().get().anonymous_allowed.contains(&endpoint));
+    if allowed {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: format!(
+                "Anonymous callers cannot call {:?}. Please sign in first.",
+                endpoint
+            ),
+        })
+    }
+}
+
+// Helper function to sum the units in stock for a category, ignoring `exclude_id`
+fn category_units(category: &Category, exclude_id: Option<u64>) -> u64 {
+    STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(id, product)| &product.category == category && Some(*id) != exclude_id)
+            .map(|(_, product)| product.quantity as u64)
+            .sum()
+    })
+}
+
+// Function to check that `new_quantity` units of a product fit under its category cap;
+// `exclude_id` is the product being changed, whose current stock is replaced
+fn ensure_category_capacity(
+    category: &Category,
+    exclude_id: Option<u64>,
+    new_quantity: u32,
+) -> Result<(), Error> {
+    let Some(max_units) = category
+        .code()
+        .and_then(|code| CATEGORY_CAPS.with(|caps| caps.borrow().get(&code)))
+    else {
+        return Ok(());
+    };
+    let units_in_stock = category_units(category, exclude_id);
+    if units_in_stock + new_quantity as u64 > max_units as u64 {
+        return Err(Error::CapacityExceeded {
+            msg: format!(
+                "Category {:?} holds at most {} units. In stock: {}, remaining capacity: {}, requested: {}",
+                category,
+                max_units,
+                units_in_stock,
+                (max_units as u64).saturating_sub(units_in_stock),
+                new_quantity
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Function to validate ProductPayload inputs
+fn validate_product_payload(payload: &ProductPayload) -> Result<(), Error> {
+    if payload.name.trim().is_empty() {
+        return Err(Error::InvalidOperation {
+            msg: "Product name cannot be empty.".to_string(),
+        });
+    }
+    if payload.name.len() > MAX_PRODUCT_NAME_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Product names can be at most {} bytes long.",
+                MAX_PRODUCT_NAME_LENGTH
+            ),
+        });
+    }
+    if payload.quantity == 0 {
+        return Err(Error::InvalidOperation {
+            msg: "Product quantity must be greater than zero.".to_string(),
+        });
+    }
+    validate_category(&payload.category)?;
+    if let Some(tags) = &payload.tags {
+        validate_labels("tags", tags, MAX_TAGS_PER_PRODUCT)?;
+    }
+    if let Some(allergens) = &payload.allergens {
+        validate_labels("allergens", allergens, MAX_ALLERGENS_PER_PRODUCT)?;
+    }
+    if let Some(settings) = &payload.settings {
+        validate_product_settings(settings)?;
+    }
+    validate_order_quantity_limits(
+        order_limit(payload.min_order_qty),
+        order_limit(payload.max_order_qty),
+        order_limit(payload.order_qty_step),
+    )
+}
+
+// Function to validate the name of a custom category
+fn validate_category(category: &Category) -> Result<(), Error> {
+    if let Category::Other(name) = category {
+        if name.trim().is_empty() || name.len() > MAX_LABEL_LENGTH {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Category names must be non-empty and at most {} bytes long.",
+                    MAX_LABEL_LENGTH
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Function to validate product or category settings
+fn validate_product_settings(settings: &ProductSettings) -> Result<(), Error> {
+    if settings
+        .tax_rate_bps
+        .is_some_and(|rate| rate > MAX_TAX_RATE_BPS)
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Tax rates are in basis points and at most {}.",
+                MAX_TAX_RATE_BPS
+            ),
+        });
+    }
+    if let Some(unit) = &settings.unit {
+        if unit.trim().is_empty() || unit.len() > MAX_UNIT_LENGTH {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Units must be non-empty and at most {} bytes long.",
+                    MAX_UNIT_LENGTH
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Helper function to get the default settings of a category
+fn category_defaults(category: &Category) -> ProductSettings {
+    category
+        .code()
+        .and_then(|code| CATEGORY_DEFAULTS.with(|service| service.borrow().get(&code)))
+        .unwrap_or_default()
+}
+
+// Helper function to set the settings a product overrides and inherit the others from its
+// category's current defaults
+fn inherit_settings(product: &mut Product, own: ProductSettings) {
+    let overridden = own.set();
+    let settings = own.or(category_defaults(&product.category));
+    product.settings = (!settings.is_empty()).then_some(settings);
+    product.overridden_settings = (!overridden.is_empty()).then_some(overridden);
+}
+
+// Helper function to get the settings a product overrides
+fn own_settings(product: &Product) -> ProductSettings {
+    match (&product.settings, &product.overridden_settings) {
+        (Some(settings), Some(overridden)) => settings.only(overridden),
+        _ => ProductSettings::default(),
+    }
+}
+
+// Helper function to treat a zero order quantity limit as no limit
+fn order_limit(limit: Option<u32>) -> Option<u32> {
+    limit.filter(|limit| *limit > 0)
+}
+
+// Function to validate that order quantity limits can be satisfied together
+fn validate_order_quantity_limits(
+    min_order_qty: Option<u32>,
+    max_order_qty: Option<u32>,
+    order_qty_step: Option<u32>,
+) -> Result<(), Error> {
+    if let Some(max_order_qty) = max_order_qty {
+        let smallest = min_order_qty.unwrap_or(1).max(order_qty_step.unwrap_or(1));
+        if max_order_qty < smallest {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "The maximum order quantity ({}) must be at least the minimum order quantity and the quantity step ({}).",
+                    max_order_qty, smallest
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Function to check a requested quantity against a product's order quantity limits
+fn check_order_quantity(product: &Product, quantity: u32) -> Result<(), Error> {
+    if let Some(min_order_qty) = product.min_order_qty.filter(|min| quantity < *min) {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "'{}' (id={}) must be ordered in quantities of at least {}. Requested: {}",
+                product.name, product.id, min_order_qty, quantity
+            ),
+        });
+    }
+    if let Some(max_order_qty) = product.max_order_qty.filter(|max| quantity > *max) {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "'{}' (id={}) can be ordered in quantities of at most {}. Requested: {}",
+                product.name, product.id, max_order_qty, quantity
+            ),
+        });
+    }
+    if let Some(order_qty_step) = product
+        .order_qty_step
+        .filter(|step| !quantity.is_multiple_of(*step))
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "'{}' (id={}) is sold in multiples of {}. Requested: {}",
+                product.name, product.id, order_qty_step, quantity
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Function to validate a list of short labels such as tags or allergens
+fn validate_labels(kind: &str, labels: &[String], max_count: usize) -> Result<(), Error> {
+    if labels.len() > max_count {
+        return Err(Error::InvalidOperation {
+            msg: format!("A product can have at most {} {}.", max_count, kind),
+        });
+    }
+    if labels
+        .iter()
+        .any(|label| label.trim().is_empty() || label.len() > MAX_PRODUCT_LABEL_LENGTH)
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Product {} must be non-empty and at most {} bytes long.",
+                kind, MAX_PRODUCT_LABEL_LENGTH
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Function to check that a product fits in its storage slot, which would otherwise trap the
+// write; checked against the Candid encoding since versions are always stored in it
+fn validate_product_size(product: &Product) -> Result<(), Error> {
+    if Encode!(product).map_or(true, |bytes| bytes.len() > Product::MAX_SIZE as usize) {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Products can take at most {} bytes of storage; shorten the name or labels.",
+                Product::MAX_SIZE
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Helper function to normalize labels so they can be matched case-insensitively
+fn normalize_labels(labels: Vec<String>) -> Vec<String> {
+    let mut labels: Vec<String> = labels
+        .iter()
+        .map(|label| label.trim().to_lowercase())
+        .collect();
+    labels.sort();
+    labels.dedup();
+    labels
+}
+
+// Helper function to record a price change made by the caller in the price history
+fn record_price_change(product_id: u64, old_price: u64, new_price: u64) -> PriceChange {
+    record_price_change_by(product_id, old_price, new_price, caller())
+}
+
+// Helper function to record a price change made by `changed_by` in the price history, e.g. by
+// the admin who scheduled it when the timer applies it
+fn record_price_change_by(
+    product_id: u64,
+    old_price: u64,
+    new_price: u64,
+    changed_by: Principal,
+) -> PriceChange {
+    PRICE_HISTORY.with(|service| {
+        let mut history = service.borrow_mut();
+        // Keep every change even if several land on the same timestamp
+        let mut changed_at = time();
+        while history.contains_key(&(product_id, changed_at)) {
+            changed_at += 1;
+        }
+        let change = PriceChange {
+            product_id,
+            old_price,
+            new_price,
+            changed_at,
+            changed_by,
+        };
+        history.insert((product_id, changed_at), change.clone());
+        change
+    })
+}
+
+// Function to validate StockPayload inputs
+fn validate_stock_payload(payload: &StockPayload) -> Result<(), Error> {
+    if payload.amount == 0 {
+        return Err(Error::InvalidOperation {
+            msg: "Stock amount must be greater than zero.".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Helper function to retrieve a product by its ID
+fn _get_product(id: &u64) -> Option<Product> {
+    STORAGE.with(|service| service.borrow().get(id))
+}
+
+// Helper function to apply a due scheduled publication to a product
+fn with_effective_status(mut product: Product, now: u64) -> Product {
+    if product.status() == ProductStatus::Draft && product.publish_at.is_some_and(|at| at <= now) {
+        product.status = Some(ProductStatus::Published);
+        product.publish_at = None;
+    }
+    product
+}
+
+// Helper function to check whether the caller may see a product in public queries
+fn is_visible(product: &Product, caller_is_admin: bool) -> bool {
+    product.status() == ProductStatus::Published || caller_is_admin
+}
+
+// Helper function to check whether the caller may see a product in listings and search, which
+// leave out unlisted products unless the caller is an admin
+fn is_listed(product: &Product, caller_is_admin: bool) -> bool {
+    is_visible(product, caller_is_admin) && (caller_is_admin || product.unlisted != Some(true))
+}
+
+// Helper function to get the audience the caller's product views are made for
+fn caller_audience() -> Audience {
+    if ensure_admin().is_ok() {
+        Audience::Admin
+    } else if is_viewer(&caller()) {
+        Audience::Staff
+    } else {
+        Audience::Public
+    }
+}
+
+// Function to shape a product for an audience: only admins see what it costs, and supplier links
+// stay with staff. Every product returned by an endpoint that is not admin-only goes through here
+fn product_view(mut product: Product, audience: Audience) -> Product {
+    if audience != Audience::Admin {
+        product.unit_cost = None;
+    }
+    if audience == Audience::Public {
+        product.preferred_supplier_id = None;
+    }
+    product
+}
+
+// Helper function to shape a product for the caller
+fn product_for_caller(product: Product) -> Product {
+    product_view(product, caller_audience())
+}
+
+// Helper function to retrieve a product as seen by the caller, hiding unpublished drafts
+fn _get_visible_product(id: &u64) -> Option<Product> {
+    let audience = caller_audience();
+    _get_product(id)
+        .map(|product| with_effective_status(product, time()))
+        .filter(|product| is_visible(product, audience == Audience::Admin))
+        .map(|product| product_view(product, audience))
+}
+
+// Helper function to get the pagination limits in effect
+fn pagination_config() -> PaginationConfig {
+    CONFIG.with(|config| config.borrow().get().pagination.unwrap_or_default())
+}
+
+// Function to collect one page of `(id, item)` pairs, stopping at the page size or when the
+// encoded response would exceed the response budget; also returns the cursor of the next page
+fn take_page<K, T: candid::CandidType>(
+    items: impl Iterator<Item = (K, T)>,
+    page: &PageRequest,
+) -> (Vec<T>, Option<K>) {
+    let config = pagination_config();
+    let limit = page
+        .limit
+        .unwrap_or(config.default_page_size)
+        .clamp(1, config.max_page_size) as usize;
+    let mut taken = Vec::new();
+    let mut last_id = None;
+    let mut size = 0u64;
+    for (id, item) in items {
+        let item_size = Encode!(&item).map_or(0, |bytes| bytes.len() as u64);
+        let over_budget = !taken.is_empty() && size + item_size > config.response_budget_bytes;
+        if taken.len() == limit || over_budget {
+            return (taken, last_id);
+        }
+        size += item_size;
+        last_id = Some(id);
+        taken.push(item);
+    }
+    (taken, None)
+}
+
+// Helper function to get the bound a page starts from
+fn page_start(page: &PageRequest) -> (Bound<u64>, Bound<u64>) {
+    match page.cursor {
+        Some(cursor) => (Bound::Excluded(cursor), Bound::Unbounded),
+        None => (Bound::Unbounded, Bound::Unbounded),
+    }
+}
+
+// Helper function to get one page of the products visible to the caller that match `filter`
+fn visible_products_page(page: &PageRequest, filter: impl Fn(&Product) -> bool) -> ProductPage {
+    let now = time();
+    let audience = caller_audience();
+    STORAGE.with(|service| {
+        let storage = service.borrow();
+        let products = storage
+            .range(page_start(page))
+            .map(|(id, product)| (id, with_effective_status(product, now)))
+            .filter(|(_, product)| {
+                is_listed(product, audience == Audience::Admin) && filter(product)
+            })
+            .map(|(id, product)| (id, product_view(product, audience)));
+        let (products, next_cursor) = take_page(products, page);
+        ProductPage {
+            products,
+            next_cursor,
+        }
+    })
+}
+
+// Helper function to list the products visible to the caller that match `filter`
+fn visible_products(filter: impl Fn(&Product) -> bool) -> Vec<Product> {
+    let now = time();
+    let audience = caller_audience();
+    STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, product)| with_effective_status(product, now))
+            .filter(|product| is_listed(product, audience == Audience::Admin) && filter(product))
+            .map(|product| product_view(product, audience))
+            .collect()
+    })
+}
+
+// Query function to retrieve a product by ID
+#[ic_cdk::query]
+fn get_product(id: u64) -> Result<Product, Error> {
+    match _get_visible_product(&id) {
+        Some(product) => Ok(product),
+        None => Err(Error::NotFound {
+            msg: format!("A product with id={} was not found", id),
+        }),
+    }
+}
+
+// Query function to retrieve several products by ID, e.g. the lines of a cart, in request order;
+// requests for more IDs than the batch limit are refused before anything is looked up
+#[ic_cdk::query]
+fn get_products(ids: Vec<u64>) -> Result<Vec<Result<Product, Error>>, Error> {
+    if ids.len() > MAX_BATCH_PRODUCT_IDS {
+        return Err(Error::CapacityExceeded {
+            msg: format!(
+                "At most {} products can be fetched at once.",
+                MAX_BATCH_PRODUCT_IDS
+            ),
+        });
+    }
+    Ok(ids.into_iter().map(get_product).collect())
+}
+
+// Helper function to update the canister configuration
+fn update_config(change: impl FnOnce(&mut Config)) -> Result<Config, Error> {
+    CONFIG.with(|config| {
+        let mut updated = config.borrow().get().clone();
+        change(&mut updated);
+        config
+            .borrow_mut()
+            .set(updated.clone())
+            .map_err(|_| Error::InvalidOperation {
+                msg: "Failed to store the config.".to_string(),
+            })?;
+        Ok(updated)
+    })
+}
+
+// Query function to get the current stock of a product by ID
+#[ic_cdk::query]
+fn get_stock(id: u64) -> Result<u32, Error> {
+    match _get_visible_product(&id) {
+        Some(product) => Ok(product.quantity),
+        None => Err(Error::NotFound {
+            msg: format!("A product with id={} was not found", id),
+        }),
+    }
+}
+
+// Helper function to look up the id of the product with the given name, ignoring case and spacing
+fn product_id_by_name(name: &str) -> Option<u64> {
+    PRODUCT_NAMES.with(|service| service.borrow().get(&ProductName::normalize(name)))
+}
+
+// Function to check that no product other than `id` already has the given name
+fn ensure_unique_name(name: &str, id: Option<u64>) -> Result<(), Error> {
+    match product_id_by_name(name) {
+        Some(existing) if Some(existing) != id => Err(Error::Other {
+            kind: "Conflict".to_string(),
+            msg: format!(
+                "A product named '{}' already exists (id={})",
+                name, existing
+            ),
+        }),
+        _ => Ok(()),
+    }
+}
+
+// Helper function to point the name index at a product, dropping its previous name
+fn index_product_name(previous_name: Option<&str>, product: &Product) {
+    PRODUCT_NAMES.with(|service| {
+        let mut names = service.borrow_mut();
+        if let Some(previous) = previous_name.map(ProductName::normalize) {
+            if names.get(&previous) == Some(product.id) {
+                names.remove(&previous);
+            }
+        }
+        names.insert(ProductName::normalize(&product.name), product.id);
+    });
+}
+
+// Helper function to get the key a category is indexed under: the code of a built-in category,
+// or the first bytes of a hash of the name, with the top bit set, for the others
+fn category_key(category: &Category) -> u64 {
+    match category.code() {
+        Some(code) => code as u64,
+        None => name_key(&category.comparison_name()),
+    }
+}
+
+// Helper function to get the index key of a custom category name
+fn name_key(name: &str) -> u64 {
+    let digest = Sha256::digest(name.as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap()) | 1 << 63
+}
+
+// Function to move custom categories indexed by their name as given, on upgrade from a release
+// that did not normalize them, to the key of their normalized name
+fn rekey_product_categories() {
+    if CONFIG.with(|config| config.borrow().get().category_keys_normalized) == Some(true) {
+        return;
+    }
+    STORAGE.with(|service| {
+        for (id, product) in service.borrow().iter() {
+            let Category::Other(name) = &product.category else {
+                continue;
+            };
+            let (given, normalized) = (name_key(name), category_key(&product.category));
+            if given != normalized {
+                PRODUCT_CATEGORIES.with(|index| {
+                    let mut index = index.borrow_mut();
+                    if index.remove(&(given, id)).is_some() {
+                        index.insert((normalized, id), ());
+                    }
+                });
+            }
+        }
+    });
+    // The config cell only fails to store values over its size bound
+    let _ = update_config(|config| config.category_keys_normalized = Some(true));
+}
+
+// Helper function to point the category index at a product, dropping its previous category
+fn index_product_category(previous: Option<&Category>, product: &Product) {
+    PRODUCT_CATEGORIES.with(|service| {
+        let mut categories = service.borrow_mut();
+        if let Some(previous) = previous {
+            categories.remove(&(category_key(previous), product.id));
+        }
+        categories.insert((category_key(&product.category), product.id), ());
+    });
+}
+
+// Function to build the reservations by product on upgrade from a release without them
+fn index_existing_reservations() {
+    if !PRODUCT_RESERVATIONS.with(|service| service.borrow().is_empty()) {
+        return;
+    }
+    RESERVATIONS.with(|service| {
+        for (id, reservation) in service.borrow().iter() {
+            PRODUCT_RESERVATIONS
+                .with(|index| index.borrow_mut().insert((reservation.product_id, id), ()));
+        }
+    });
+}
+
+// Function to build the relations by related product on upgrade from a release without them
+fn index_existing_product_relations() {
+    if !RELATED_FROM.with(|service| service.borrow().is_empty()) {
+        return;
+    }
+    PRODUCT_RELATIONS.with(|service| {
+        for ((product_id, related_id), _) in service.borrow().iter() {
+            RELATED_FROM.with(|index| index.borrow_mut().insert((related_id, product_id), ()));
+        }
+    });
+}
+
+// Function to build the category index on upgrade from a release without it
+fn index_existing_product_categories() {
+    if !PRODUCT_CATEGORIES.with(|service| service.borrow().is_empty()) {
+        return;
+    }
+    STORAGE.with(|service| {
+        for (_, product) in service.borrow().iter() {
+            index_product_category(None, &product);
+        }
+    });
+}
+
+// Function to build the name index on upgrade from a release without it; when names already
+// collide, the first product keeps the name and the others must be renamed on their next update
+fn index_existing_product_names() {
+    if !PRODUCT_NAMES.with(|service| service.borrow().is_empty()) {
+        return;
+    }
+    STORAGE.with(|service| {
+        for (_, product) in service.borrow().iter() {
+            let key = ProductName::normalize(&product.name);
+            if key.0.len() > ProductName::MAX_SIZE as usize {
+                continue;
+            }
+            PRODUCT_NAMES.with(|names| {
+                let mut names = names.borrow_mut();
+                if !names.contains_key(&key) {
+                    names.insert(key, product.id);
+                }
+            });
+        }
+    });
+}
+
+// Query function to find the product with exactly the given name, ignoring case and spacing, so
+// frontends can warn about a duplicate before submitting
+#[ic_cdk::query]
+fn find_exact(name: String) -> Option<Product> {
+    product_id_by_name(&name)
+        .and_then(|id| _get_visible_product(&id))
+        .filter(|product| is_listed(product, ensure_admin().is_ok()))
+}
+
+// Function to insert a product into the stable storage
+fn do_insert(product: &Product) {
+    STORAGE.with(|service| service.borrow_mut().insert(product.id, product.clone()));
+    fit_locations(product.id, product.quantity);
+    record_product_version(product);
+}
+
+// Function to add a new product to the storage
+#[ic_cdk::update]
+fn add_product(payload: ProductPayload) -> Result<Product, Error> {
+    ensure_caller_allowed(Endpoint::AddProduct)?;
+    journal_call("add_product");
+    validate_product_payload(&payload)?;
+    ensure_category_capacity(&payload.category, None, payload.quantity)?;
+    ensure_unique_name(&payload.name, None)?;
+
+    let id = generate_unique_id(|id| _get_product(&id).is_some())?;
+    let mut product = Product {
+        id,
+        name: payload.name,
+        category: payload.category.canonical(),
+        quantity: payload.quantity,
+        price: Some(payload.price.unwrap_or(0)),
+        tags: Some(normalize_labels(payload.tags.unwrap_or_default())),
+        allergens: Some(normalize_labels(payload.allergens.unwrap_or_default())),
+        status: Some(payload.status.unwrap_or_default()),
+        publish_at: payload.publish_at,
+        created_at: time(),
+        updated_at: None,
+        min_order_qty: order_limit(payload.min_order_qty),
+        max_order_qty: order_limit(payload.max_order_qty),
+        order_qty_step: order_limit(payload.order_qty_step),
+        featured_rank: None,
+        age_restricted: payload.age_restricted.filter(|restricted| *restricted),
+        best_before: payload.best_before.filter(|best_before| *best_before > 0),
+        settings: None,
+        overridden_settings: None,
+        prep_minutes: payload.prep_minutes.filter(|minutes| *minutes > 0),
+        display_units: None,
+        preferred_supplier_id: None,
+        unit_cost: None,
+        unlisted: payload.unlisted.filter(|unlisted| *unlisted),
+    };
+    inherit_settings(&mut product, payload.settings.unwrap_or_default());
+    if product.best_before.is_none() {
+        product.best_before = product
+            .settings
+            .as_ref()
+            .and_then(|settings| settings.shelf_life_hours)
+            .map(|hours| product.created_at + hours as u64 * 3_600 * 1_000_000_000);
+    }
+    validate_product_size(&product)?;
+
+    STORAGE.with(|service| service.borrow_mut().insert(product.id, product.clone()));
+    index_product_name(None, &product);
+    index_product_category(None, &product);
+    record_product_version(&product);
+    Ok(product_for_caller(product))
+}
+
+// Function to update an existing product's details
+#[ic_cdk::update]
+fn update_product(id: u64, payload: ProductPayload) -> Result<Product, Error> {
+    ensure_caller_allowed(Endpoint::UpdateProduct)?;
+    journal_call("update_product");
+    validate_product_payload(&payload)?;
+    ensure_category_capacity(&payload.category, Some(id), payload.quantity)?;
+    ensure_unique_name(&payload.name, Some(id))?;
+
+    STORAGE
+        .with(|service| {
+            let mut storage = service.borrow_mut();
+            if let Some(mut product) = storage.get(&id) {
+                let previous_quantity = product.quantity;
+                let previous_name = product.name.clone();
+                let previous_category = product.category.clone();
+                let own = payload.settings.unwrap_or_else(|| own_settings(&product));
+                product.name = payload.name;
+                product.category = payload.category.canonical();
+                product.quantity = payload.quantity;
+                if let Some(tags) = payload.tags {
+                    product.tags = Some(normalize_labels(tags));
+                }
+                if let Some(allergens) = payload.allergens {
+                    product.allergens = Some(normalize_labels(allergens));
+                }
+                if let Some(status) = payload.status {
+                    product.status = Some(status);
+                }
+                if payload.publish_at.is_some() {
+                    product.publish_at = payload.publish_at;
+                }
+                if let Some(min_order_qty) = payload.min_order_qty {
+                    product.min_order_qty = order_limit(Some(min_order_qty));
+                }
+                if let Some(max_order_qty) = payload.max_order_qty {
+                    product.max_order_qty = order_limit(Some(max_order_qty));
+                }
+                if let Some(order_qty_step) = payload.order_qty_step {
+                    product.order_qty_step = order_limit(Some(order_qty_step));
+                }
+                if let Some(age_restricted) = payload.age_restricted {
+                    product.age_restricted = Some(age_restricted);
+                }
+                if let Some(best_before) = payload.best_before {
+                    product.best_before = (best_before > 0).then_some(best_before);
+                }
+                inherit_settings(&mut product, own);
+                if let Some(prep_minutes) = payload.prep_minutes {
+                    product.prep_minutes = (prep_minutes > 0).then_some(prep_minutes);
+                }
+                if let Some(unlisted) = payload.unlisted {
+                    product.unlisted = unlisted.then_some(true);
+                }
+                validate_order_quantity_limits(
+                    product.min_order_qty,
+                    product.max_order_qty,
+                    product.order_qty_step,
+                )?;
+                validate_product_size(&product)?;
+                if let Some(price) = payload.price.filter(|price| *price != product.price()) {
+                    if is_large_price_change(product.price(), price) {
+                        // The price stays as is until a second admin approves the change
+                        request_action(ActionKind::ChangePrice {
+                            product_id: id,
+                            old_price: product.price(),
+                            new_price: price,
+                        })?;
+                    } else {
+                        record_price_change(id, product.price(), price);
+                        product.price = Some(price);
+                    }
+                }
+                product.updated_at = Some(time());
+                storage.insert(id, product.clone());
+                index_product_name(Some(&previous_name), &product);
+                index_product_category(Some(&previous_category), &product);
+                fit_locations(id, product.quantity);
+                record_product_version(&product);
+                if product.quantity != previous_quantity {
+                    record_stock_movement(
+                        &caller(),
+                        id,
+                        StockMovementKind::Adjustment,
+                        product.quantity as i64 - previous_quantity as i64,
+                        0,
+                        None,
+                    );
+                }
+                notify_if_back_in_stock(&product, previous_quantity);
+                Ok(product)
+            } else {
+                Err(Error::NotFound {
+                    msg: format!("Product with id={} not found", id),
+                })
+            }
+        })
+        // Reads the product back, so it has to run once the storage is released
+        .inspect(|product| allocate_waitlist(product.id))
+        .map(product_for_caller)
+}
+
+// Function to add stock to a product's quantity
+#[ic_cdk::update]
+fn add_quantity(id: u64, payload: StockPayload) -> Result<Product, Error> {
+    ensure_caller_allowed(Endpoint::AddQuantity)?;
+    journal_call("add_quantity");
+    // Validate the stock payload
+    validate_stock_payload(&payload)?;
+
+    match STORAGE.with(|service| service.borrow().get(&id)) {
+        Some(mut product) => {
+            ensure_category_capacity(
+                &product.category,
+                Some(id),
+                product.quantity.saturating_add(payload.amount),
+            )?;
+            if let Some(location) = payload.location.filter(|l| *l != Location::FrontShelf) {
+                let held = back_location_quantity(id, location);
+                set_back_location_quantity(id, location, held.saturating_add(payload.amount));
+            }
+            let previous_quantity = product.quantity;
+            product.quantity += payload.amount;
+            product.updated_at = Some(time());
+            do_insert(&product);
+            record_stock_movement(
+                &caller(),
+                id,
+                StockMovementKind::Restock,
+                payload.amount as i64,
+                0,
+                None,
+            );
+            allocate_waitlist(product.id);
+            notify_if_back_in_stock(&product, previous_quantity);
+            Ok(product_for_caller(product))
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Couldn't add quantity to product with id={}. Product not found", id),
+        }),
+    }
+}
+
+// Function to feature a product on the storefront homepage at a rank, or unfeature it with
+// `None`
+#[ic_cdk::update]
+fn set_featured(product_id: u64, rank: Option<u32>) -> Result<Product, Error> {
+    ensure_admin()?;
+    journal_call("set_featured");
+    let mut product = _get_product(&product_id).ok_or(Error::NotFound {
+        msg: format!("A product with id={} was not found", product_id),
+    })?;
+    product.featured_rank = rank;
+    product.updated_at = Some(time());
+    do_insert(&product);
+    Ok(product)
+}
+
+// Query function to list the featured products in homepage order
+#[ic_cdk::query]
+fn list_featured() -> Vec<Product> {
+    let mut featured = visible_products(|product| product.featured_rank.is_some());
+    featured.sort_by_key(|product| (product.featured_rank, product.id));
+    featured
+}
+
+// Function to link a product to a related product, replacing any link between the two; links
+// go one way, so products that go well with each other are linked both ways
+#[ic_cdk::update]
+fn set_product_relation(
+    product_id: u64,
+    related_id: u64,
+    kind: RelationKind,
+    rank: u32,
+) -> Result<Vec<RelatedProduct>, Error> {
+    ensure_admin()?;
+    journal_call("set_product_relation");
+    if product_id == related_id {
+        return Err(Error::InvalidOperation {
+            msg: "A product cannot be related to itself.".to_string(),
+        });
+    }
+    for id in [product_id, related_id] {
+        if _get_product(&id).is_none() {
+            return Err(Error::NotFound {
+                msg: format!("A product with id={} was not found", id),
+            });
+        }
+    }
+    let (linked, exists) = PRODUCT_RELATIONS.with(|service| {
+        let relations = service.borrow();
+        (
+            relations
+                .range((product_id, 0)..=(product_id, u64::MAX))
+                .count(),
+            relations.contains_key(&(product_id, related_id)),
+        )
+    });
+    if !exists && linked >= MAX_RELATIONS_PER_PRODUCT {
+        return Err(Error::CapacityExceeded {
+            msg: format!(
+                "A product can have at most {} related products.",
+                MAX_RELATIONS_PER_PRODUCT
+            ),
+        });
+    }
+    PRODUCT_RELATIONS.with(|service| {
+        service.borrow_mut().insert(
+            (product_id, related_id),
+            ProductRelation {
+                kind,
+                rank,
+                created_at: time(),
+            },
+        )
+    });
+    RELATED_FROM.with(|service| service.borrow_mut().insert((related_id, product_id), ()));
+    Ok(related_products(product_id))
+}
+
+// Function to unlink a related product from a product
+#[ic_cdk::update]
+fn remove_product_relation(product_id: u64, related_id: u64) -> Result<Vec<RelatedProduct>, Error> {
+    ensure_admin()?;
+    journal_call("remove_product_relation");
+    PRODUCT_RELATIONS
+        .with(|service| service.borrow_mut().remove(&(product_id, related_id)))
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "Product id={} is not related to product id={}",
+                product_id, related_id
+            ),
+        })?;
+    RELATED_FROM.with(|service| service.borrow_mut().remove(&(related_id, product_id)));
+    Ok(related_products(product_id))
+}
+
+// Helper function to remove the links from and to a removed product
+fn remove_product_relations(product_id: u64) {
+    let related_ids: Vec<u64> = PRODUCT_RELATIONS.with(|service| {
+        service
+            .borrow()
+            .range((product_id, 0)..=(product_id, u64::MAX))
+            .map(|((_, related_id), _)| related_id)
+            .collect()
+    });
+    let linking_ids: Vec<u64> = RELATED_FROM.with(|service| {
+        service
+            .borrow()
+            .range((product_id, 0)..=(product_id, u64::MAX))
+            .map(|((_, from), _)| from)
+            .collect()
+    });
+    PRODUCT_RELATIONS.with(|service| {
+        let mut relations = service.borrow_mut();
+        for related_id in &related_ids {
+            relations.remove(&(product_id, *related_id));
+        }
+        for from in &linking_ids {
+            relations.remove(&(*from, product_id));
+        }
+    });
+    RELATED_FROM.with(|service| {
+        let mut index = service.borrow_mut();
+        for related_id in related_ids {
+            index.remove(&(related_id, product_id));
+        }
+        for from in linking_ids {
+            index.remove(&(product_id, from));
+        }
+    });
+}
+
+// Query function to list the products related to a product that the caller can see, by rank,
+// e.g. for "goes well with" and upgrade suggestions on its page
+#[ic_cdk::query]
+fn related_products(product_id: u64) -> Vec<RelatedProduct> {
+    if _get_visible_product(&product_id).is_none() {
+        return Vec::new();
+    }
+    let mut related: Vec<RelatedProduct> = PRODUCT_RELATIONS.with(|service| {
+        service
+            .borrow()
+            .range((product_id, 0)..=(product_id, u64::MAX))
+            .filter_map(|((_, related_id), relation)| {
+                Some(RelatedProduct {
+                    kind: relation.kind,
+                    rank: relation.rank,
+                    product: _get_visible_product(&related_id)
+                        .filter(|product| is_listed(product, ensure_admin().is_ok()))?,
+                })
+            })
+            .collect()
+    });
+    related.sort_by_key(|related| (related.rank, related.product.id));
+    related
+}
+
+// Helper function to list the ids of a category's products after a cursor, up to `limit`
+fn category_product_ids(category: &Category, after: Option<u64>, limit: usize) -> Vec<u64> {
+    let key = category_key(category);
+    let start = after.map_or(Bound::Included((key, 0)), |id| Bound::Excluded((key, id)));
+    PRODUCT_CATEGORIES.with(|service| {
+        service
+            .borrow()
+            .range((start, Bound::Included((key, u64::MAX))))
+            .map(|((_, id), _)| id)
+            .take(limit)
+            .collect()
+    })
+}
+
+// Helper function to count the products of a category from the index alone, which also counts
+// custom categories sharing its key
+fn category_product_count(category: &Category) -> u64 {
+    let key = category_key(category);
+    PRODUCT_CATEGORIES
+        .with(|service| service.borrow().range((key, 0)..=(key, u64::MAX)).count() as u64)
+}
+
+// Helper function to check whether any product is in a category
+fn category_has_products(category: &Category) -> bool {
+    let key = category_key(category);
+    PRODUCT_CATEGORIES.with(|service| {
+        service
+            .borrow()
+            .range((key, 0)..=(key, u64::MAX))
+            // Names of custom categories can share a key, so the category is checked too
+            .any(|((_, id), _)| {
+                _get_product(&id).is_some_and(|product| &product.category == category)
+            })
+    })
+}
+
+// Function to move a product to another category, where it inherits the defaults of the new
+// category for the settings it does not override
+fn move_product_category(mut product: Product, to: &Category, now: u64) {
+    let previous = product.category.clone();
+    let own = own_settings(&product);
+    product.category = to.clone();
+    inherit_settings(&mut product, own);
+    product.updated_at = Some(now);
+    STORAGE.with(|service| service.borrow_mut().insert(product.id, product.clone()));
+    index_product_category(Some(&previous), &product);
+    record_product_version(&product);
+}
+
+// Function to move every product of one category to another, e.g. when the taxonomy is
+// restructured. Each call moves one batch, bounded in products and instructions, and reports
+// the progress; call again with the same categories until it is completed. The move is refused
+// when the products would not fit under the new category's cap
+#[ic_cdk::update]
+fn reassign_category(from: Category, to: Category) -> Result<CategoryReassignment, Error> {
+    ensure_admin()?;
+    journal_call("reassign_category");
+    validate_category(&to)?;
+    let to = to.canonical();
+    if from == to {
+        return Err(Error::InvalidOperation {
+            msg: "Products can only be moved to another category.".to_string(),
+        });
+    }
+    let now = time();
+    let active = CATEGORY_REASSIGNMENTS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, run)| run)
+            .find(|run| run.completed_at.is_none())
+    });
+    let mut run = match active {
+        Some(run) if run.from == from && run.to == to => run,
+        Some(run) => {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Moving {:?} to {:?} (id={}) is still in progress",
+                    run.from, run.to, run.id
+                ),
+            })
+        }
+        None => {
+            let units = category_units(&from, None);
+            ensure_category_capacity(&to, None, units.min(u32::MAX as u64) as u32)?;
+            let products = category_product_count(&from);
+            CategoryReassignment {
+                id: generate_unique_id(|id| {
+                    CATEGORY_REASSIGNMENTS.with(|service| service.borrow().contains_key(&id))
+                })?,
+                from,
+                to,
+                started_by: caller(),
+                started_at: now,
+                products_at_start: products,
+                moved: 0,
+                remaining: products,
+                batches: 0,
+                cursor: None,
+                last_batch_at: None,
+                completed_at: None,
+            }
+        }
+    };
+
+    let started = ic_cdk::api::instruction_counter();
+    let ids = category_product_ids(&run.from, run.cursor, CATEGORY_REASSIGN_BATCH);
+    let exhausted = ids.len() < CATEGORY_REASSIGN_BATCH;
+    let mut stopped_early = false;
+    for id in ids {
+        if ic_cdk::api::instruction_counter() - started > CATEGORY_REASSIGN_INSTRUCTIONS {
+            stopped_early = true;
+            break;
+        }
+        run.cursor = Some(id);
+        if let Some(product) = _get_product(&id).filter(|product| product.category == run.from) {
+            move_product_category(product, &run.to, now);
+            run.moved += 1;
+        }
+    }
+    run.batches += 1;
+    run.last_batch_at = Some(now);
+    run.remaining = category_product_count(&run.from);
+    if exhausted && !stopped_early {
+        // Products added to the old category behind the cursor are picked up by another pass
+        if category_has_products(&run.from) {
+            run.cursor = None;
+        } else {
+            run.remaining = 0;
+            run.completed_at = Some(now);
+        }
+    }
+    CATEGORY_REASSIGNMENTS.with(|service| service.borrow_mut().insert(run.id, run.clone()));
+    Ok(run)
+}
+
+// Query function to list the category reassignments, newest first
+#[ic_cdk::query]
+fn list_category_reassignments() -> Result<Vec<CategoryReassignment>, Error> {
+    ensure_admin()?;
+    let mut runs: Vec<CategoryReassignment> = CATEGORY_REASSIGNMENTS
+        .with(|service| service.borrow().iter().map(|(_, run)| run).collect());
+    runs.sort_by_key(|run| std::cmp::Reverse(run.started_at));
+    Ok(runs)
+}
+
+// Query function to list the products of a category, one page at a time
+#[ic_cdk::query]
+fn search_by_category(category: Category, page: Option<PageRequest>) -> ProductPage {
+    let page = page.unwrap_or_default();
+    let key = category_key(&category);
+    let start = page.cursor.map_or(Bound::Included((key, 0)), |cursor| {
+        Bound::Excluded((key, cursor))
+    });
+    let now = time();
+    let audience = caller_audience();
+    PRODUCT_CATEGORIES.with(|service| {
+        let index = service.borrow();
+        let products = index
+            .range((start, Bound::Included((key, u64::MAX))))
+            .filter_map(|((_, id), _)| _get_product(&id).map(|product| (id, product)))
+            .map(|(id, product)| (id, with_effective_status(product, now)))
+            // Names of custom categories can share a key, so the category is checked too
+            .filter(|(_, product)| {
+                product.category == category && is_listed(product, audience == Audience::Admin)
+            })
+            .map(|(id, product)| (id, product_view(product, audience)));
+        let (products, next_cursor) = take_page(products, &page);
+        ProductPage {
+            products,
+            next_cursor,
+        }
+    })
+}
+
+// Helper function to take units out of a product's stock only while its quantity is still the
+// one the caller read, so read-then-write callers learn about a race instead of overselling;
+// calls within the canister read and write in one message and pass the quantity they read
+fn take_stock(product: &mut Product, expected: u32, amount: u32) -> Result<(), Error> {
+    if product.quantity != expected {
+        return Err(Error::Other {
+            kind: "Conflict".to_string(),
+            msg: format!(
+                "The quantity of product id={} is {}, not the expected {}",
+                product.id, product.quantity, expected
+            ),
+        });
+    }
+    if amount > product.quantity {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Cannot take more than available quantity. Available: {}, Trying to take: {}",
+                product.quantity, amount
+            ),
+        });
+    }
+    product.quantity -= amount;
+    Ok(())
+}
+
+// Function to remove stock from a product's quantity
+#[ic_cdk::update]
+fn offload_quantity(id: u64, payload: StockPayload) -> Result<Product, Error> {
+    ensure_caller_allowed(Endpoint::OffloadQuantity)?;
+    journal_call("offload_quantity");
+    offload_stock(id, payload, None)
+}
+
+// Function to remove stock from a product's quantity only if it still holds the quantity the
+// caller last read; a POS terminal that gets a Conflict error rereads and decides again
+#[ic_cdk::update]
+fn offload_if(id: u64, expected_quantity: u32, amount: u32) -> Result<Product, Error> {
+    ensure_caller_allowed(Endpoint::OffloadQuantity)?;
+    journal_call("offload_if");
+    offload_stock(
+        id,
+        StockPayload {
+            amount,
+            location: None,
+        },
+        Some(expected_quantity),
+    )
+}
+
+// Helper function to offload stock, compared against an expected quantity when one is given
+fn offload_stock(id: u64, payload: StockPayload, expected: Option<u32>) -> Result<Product, Error> {
+    // Validate the stock payload
+    validate_stock_payload(&payload)?;
+
+    match STORAGE.with(|service| service.borrow().get(&id)) {
+        Some(mut product) => {
+            let expected = expected.unwrap_or(product.quantity);
+            if product.quantity == 0 && expected == 0 {
+                return Err(Error::InvalidOperation {
+                    msg: format!("Product with id={} cannot be offloaded because the quantity is 0", id),
+                });
+            }
+            take_stock(&mut product, expected, payload.amount)?;
+            if let Some(location) = payload.location {
+                let held = location_quantity(&product, location);
+                if payload.amount > held {
+                    return Err(Error::InvalidOperation {
+                        msg: format!(
+                            "Cannot offload more than held at {:?}. Held: {}, Trying to offload: {}",
+                            location, held, payload.amount
+                        ),
+                    });
+                }
+                if location != Location::FrontShelf {
+                    set_back_location_quantity(id, location, held - payload.amount);
+                }
+            }
+            // Display items may be the ones thrown away at the end of the day
+            if product.display_units.is_some() {
+                product.display_units =
+                    Some(display_units_of(&product)).filter(|display| *display > 0);
+            }
+            product.updated_at = Some(time());
+            do_insert(&product);
+            record_stock_movement(
+                &caller(),
+                id,
+                StockMovementKind::Waste,
+                -(payload.amount as i64),
+                0,
+                None,
+            );
+            Ok(product_for_caller(product))
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Couldn't offload a product with id={}. Product not found", id),
+        }),
+    }
+}
+
+// Function to get all products, one page at a time
+#[ic_cdk::query]
+fn list_all_products(page: Option<PageRequest>) -> ProductPage {
+    visible_products_page(&page.unwrap_or_default(), |_| true)
+}
+
+// Function to request clearing all products; once a second admin approves it, the products
+// are removed in batches with `clear_products_batch`
+#[ic_cdk::update]
+fn clear_all_products() -> Result<PendingAction, Error> {
+    ensure_admin()?;
+    journal_call("clear_all_products");
+    request_action(ActionKind::ClearAllProducts)
+}
+
+// Helper function to get the clear of all products in progress, if any
+fn active_clear_run() -> Option<ClearRun> {
+    CLEAR_RUNS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, run)| run)
+            .find(|run| run.finished_at.is_none())
+    })
+}
+
+// Function to start the clear approved by an action; nothing is removed until the first batch
+fn start_clear_run(action: &PendingAction) -> Result<(), Error> {
+    if let Some(run) = active_clear_run() {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "The clear approved by action id={} is still in progress",
+                run.action_id
+            ),
+        });
+    }
+    let products = STORAGE.with(|service| service.borrow().len());
+    let run = ClearRun {
+        action_id: action.id,
+        approved_by: caller(),
+        started_at: time(),
+        products_at_start: products,
+        removed: 0,
+        remaining: products,
+        batches: 0,
+        last_batch_at: None,
+        finished_at: None,
+        token: None,
+        token_holder: None,
+        token_expires_at: None,
+    };
+    CLEAR_RUNS.with(|service| service.borrow_mut().insert(action.id, run));
+    Ok(())
+}
+
+// Function to get a one-time token confirming the next batch of the clear in progress
+#[ic_cdk::update]
+fn request_clear_token() -> Result<ClearToken, Error> {
+    ensure_admin()?;
+    journal_call("request_clear_token");
+    let mut run = active_clear_run().ok_or(Error::NotFound {
+        msg: "No clear of all products has been approved.".to_string(),
+    })?;
+    let token = format!("{:016x}", next_random()?);
+    let expires_at = time().saturating_add(CLEAR_TOKEN_TTL_NS);
+    run.token = Some(token.clone());
+    run.token_holder = Some(caller());
+    run.token_expires_at = Some(expires_at);
+    CLEAR_RUNS.with(|service| service.borrow_mut().insert(run.action_id, run.clone()));
+    Ok(ClearToken {
+        token,
+        expires_at,
+        remaining: run.remaining,
+    })
+}
+
+// Function to remove up to `limit` products of the approved clear, confirmed by a token from
+// `request_clear_token` that is used up by the batch; reports the progress, including the
+// products remaining
+#[ic_cdk::update]
+fn clear_products_batch(limit: u32, token: String) -> Result<ClearRun, Error> {
+    ensure_admin()?;
+    journal_call("clear_products_batch");
+    let mut run = active_clear_run().ok_or(Error::NotFound {
+        msg: "No clear of all products has been approved.".to_string(),
+    })?;
+    let now = time();
+    let confirmed = run.token.as_ref() == Some(&token)
+        && run.token_holder == Some(caller())
+        && run.token_expires_at.is_some_and(|at| at > now);
+    if !confirmed {
+        return Err(Error::Unauthorized {
+            msg: "The confirmation token is invalid, used or expired. Please request a new one."
+                .to_string(),
+        });
+    }
+    if limit == 0 || limit > MAX_CLEAR_BATCH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "A batch removes between 1 and {} products.",
+                MAX_CLEAR_BATCH
+            ),
+        });
+    }
+    let ids: Vec<u64> = STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .take(limit as usize)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    for id in &ids {
+        do_remove_product(*id)?;
+    }
+    run.removed += ids.len() as u64;
+    run.remaining = STORAGE.with(|service| service.borrow().len());
+    run.batches += 1;
+    run.last_batch_at = Some(now);
+    run.token = None;
+    run.token_holder = None;
+    run.token_expires_at = None;
+    if run.remaining == 0 {
+        run.finished_at = Some(now);
+    }
+    CLEAR_RUNS.with(|service| service.borrow_mut().insert(run.action_id, run.clone()));
+    Ok(run)
+}
+
+// Query function to get the progress of the latest clear of all products
+#[ic_cdk::query]
+fn get_clear_progress() -> Result<Option<ClearRun>, Error> {
+    ensure_admin()?;
+    Ok(CLEAR_RUNS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, run)| run)
+            .max_by_key(|run| run.started_at)
+    }))
+}
+
+// Function to request removing a product, carried out once a second admin approves it
+#[ic_cdk::update]
+fn remove_product(id: u64) -> Result<PendingAction, Error> {
+    ensure_admin()?;
+    journal_call("remove_product");
+    if _get_product(&id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("Couldn't delete a product with id={}. Product not found", id),
+        });
+    }
+    request_action(ActionKind::RemoveProduct { product_id: id })
+}
+
+// Function to remove a product from storage
+fn do_remove_product(id: u64) -> Result<Product, Error> {
+    let product = STORAGE.with(|service| {
+        service.borrow_mut().remove(&id).ok_or(Error::NotFound {
+            msg: format!("Couldn't delete a product with id={}. Product not found", id),
+        })
+    })?;
+    PRODUCT_NAMES.with(|service| {
+        let key = ProductName::normalize(&product.name);
+        let mut names = service.borrow_mut();
+        if names.get(&key) == Some(id) {
+            names.remove(&key);
+        }
+    });
+    PRODUCT_CATEGORIES.with(|service| {
+        service
+            .borrow_mut()
+            .remove(&(category_key(&product.category), id))
+    });
+    remove_product_reservations(id);
+    remove_waitlist(id);
+    bump_catalog_version();
+    PRODUCT_OPTIONS.with(|service| service.borrow_mut().remove(&id));
+    PRODUCT_VERSION_HEADS.with(|service| service.borrow_mut().remove(&id));
+    remove_tier_prices(id);
+    remove_product_relations(id);
+    fit_locations(id, 0);
+    record_product_removal(&product);
+    Ok(product)
+}
+
+// Helper function to check whether a reservation still holds stock at the given time
+fn is_reservation_active(reservation: &Reservation, now: u64) -> bool {
+    reservation
+        .expires_at
+        .is_none_or(|expires_at| expires_at > now)
+}
+
+// Helper function to sum the active reservations of every product
+fn reserved_quantities() -> HashMap<u64, u32> {
+    let now = time();
+    let mut reserved = HashMap::new();
+    RESERVATIONS.with(|service| {
+        for (_, reservation) in service.borrow().iter() {
+            if is_reservation_active(&reservation, now) {
+                *reserved.entry(reservation.product_id).or_insert(0u32) += reservation.quantity;
+            }
+        }
+    });
+    reserved
+}
+
+// Helper function to compute the availability of a product from its reserved amount
+fn availability_of(product: &Product, reserved: u32) -> Availability {
+    let display = display_units_of(product);
+    Availability {
+        product_id: product.id,
+        quantity: product.quantity,
+        reserved,
+        available: product.quantity as i64 - reserved as i64 - display as i64,
+        display_units: (display > 0).then_some(display),
+    }
+}
+
+// Helper function to get the units of a product on display, never more than it has
+fn display_units_of(product: &Product) -> u32 {
+    product.display_units.unwrap_or(0).min(product.quantity)
+}
+
+// Function to store a reservation, keeping the reservations by product in step
+fn insert_reservation(reservation: Reservation) {
+    PRODUCT_RESERVATIONS.with(|service| {
+        service
+            .borrow_mut()
+            .insert((reservation.product_id, reservation.id), ())
+    });
+    RESERVATIONS.with(|service| service.borrow_mut().insert(reservation.id, reservation));
+}
+
+// Function to remove a reservation, keeping the reservations by product in step
+fn remove_reservation(id: u64) -> Option<Reservation> {
+    let reservation = RESERVATIONS.with(|service| service.borrow_mut().remove(&id))?;
+    PRODUCT_RESERVATIONS.with(|service| service.borrow_mut().remove(&(reservation.product_id, id)));
+    Some(reservation)
+}
+
+// Helper function to list the reservations held on a product
+fn reservations_of(product_id: u64) -> Vec<Reservation> {
+    let ids: Vec<u64> = PRODUCT_RESERVATIONS.with(|service| {
+        service
+            .borrow()
+            .range((product_id, 0)..=(product_id, u64::MAX))
+            .map(|((_, id), _)| id)
+            .collect()
+    });
+    RESERVATIONS.with(|service| {
+        let reservations = service.borrow();
+        ids.into_iter()
+            .filter_map(|id| reservations.get(&id))
+            .collect()
+    })
+}
+
+// Helper function to drop every reservation held on a product
+fn remove_product_reservations(product_id: u64) {
+    for reservation in reservations_of(product_id) {
+        remove_reservation(reservation.id);
+    }
+}
+
+// Query function to get the stock of a product that is not held by reservations
+#[ic_cdk::query]
+fn get_availability(product_id: u64) -> Result<Availability, Error> {
+    match _get_visible_product(&product_id) {
+        Some(product) => {
+            let reserved = reserved_quantities().get(&product_id).copied().unwrap_or(0);
+            Ok(availability_of(&product, reserved))
+        }
+        None => Err(Error::NotFound {
+            msg: format!("A product with id={} was not found", product_id),
+        }),
+    }
+}
+
+// Query function to list products with nothing left to sell once reservations are applied, one
+// page at a time
+#[ic_cdk::query]
+fn list_out_of_stock(page: Option<PageRequest>) -> AvailabilityPage {
+    let page = page.unwrap_or_default();
+    let now = time();
+    let audience = caller_audience();
+    let reserved = reserved_quantities();
+    STORAGE.with(|service| {
+        let storage = service.borrow();
+        let products = storage
+            .range(page_start(&page))
+            .map(|(id, product)| (id, with_effective_status(product, now)))
+            .filter(|(_, product)| is_listed(product, audience == Audience::Admin))
+            .map(|(id, product)| {
+                let reserved = reserved.get(&id).copied().unwrap_or(0);
+                (id, availability_of(&product, reserved))
+            })
+            .filter(|(_, availability)| availability.available <= 0);
+        let (products, next_cursor) = take_page(products, &page);
+        AvailabilityPage {
+            products,
+            next_cursor,
+        }
+    })
+}
+
+// Function to hold part of a product's stock for the caller
+#[ic_cdk::update]
+fn reserve_stock(payload: ReservationPayload) -> Result<Reservation, Error> {
+    ensure_caller_allowed(Endpoint::ReserveStock)?;
+    journal_call("reserve_stock");
+    if payload.amount == 0 {
+        return Err(Error::InvalidOperation {
+            msg: "Reservation amount must be greater than zero.".to_string(),
+        });
+    }
+    let availability = get_availability(payload.product_id)?;
+    if !waitlist_of(payload.product_id).is_empty() {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Product with id={} has a waitlist; returning stock goes to it first",
+                payload.product_id
+            ),
+        });
+    }
+    if (payload.amount as i64) > availability.available {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Cannot reserve more than available quantity. Available: {}, Trying to reserve: {}",
+                availability.available.max(0),
+                payload.amount
+            ),
+        });
+    }
+
+    let id =
+        generate_unique_id(|id| RESERVATIONS.with(|service| service.borrow().contains_key(&id)))?;
+    let now = time();
+    let reservation = Reservation {
+        id,
+        product_id: payload.product_id,
+        quantity: payload.amount,
+        holder: caller(),
+        created_at: now,
+        expires_at: payload
+            .ttl_seconds
+            .map(|ttl| now.saturating_add(ttl.saturating_mul(1_000_000_000))),
+        from_waitlist: None,
+    };
+    insert_reservation(reservation.clone());
+    Ok(reservation)
+}
+
+// Function to release a reservation held by the caller
+#[ic_cdk::update]
+fn release_reservation(id: u64) -> Result<Reservation, Error> {
+    ensure_caller_allowed(Endpoint::ReleaseReservation)?;
+    journal_call("release_reservation");
+    RESERVATIONS
+        .with(|service| {
+            let mut reservations = service.borrow_mut();
+            match reservations.get(&id) {
+                Some(reservation) if reservation.holder == caller() => {
+                    reservations.remove(&id);
+                    PRODUCT_RESERVATIONS
+                        .with(|service| service.borrow_mut().remove(&(reservation.product_id, id)));
+                    Ok(reservation)
+                }
+                Some(_) => Err(Error::InvalidOperation {
+                    msg: format!("Reservation with id={} is held by another principal", id),
+                }),
+                None => Err(Error::NotFound {
+                    msg: format!(
+                        "Couldn't release a reservation with id={}. Reservation not found",
+                        id
+                    ),
+                }),
+            }
+        })
+        .inspect(|reservation| allocate_waitlist(reservation.product_id))
+}
+
+// Function to set or clear the maximum total units allowed in a category
+#[ic_cdk::update]
+fn set_category_cap(category: Category, max_units: Option<u32>) -> Result<CategoryCapacity, Error> {
+    ensure_admin()?;
+    journal_call("set_category_cap");
+    let code = category.code().ok_or(Error::InvalidOperation {
+        msg: "Caps can only be set on built-in categories.".to_string(),
+    })?;
+    CATEGORY_CAPS.with(|caps| match max_units {
+        Some(max_units) => caps.borrow_mut().insert(code, max_units),
+        None => caps.borrow_mut().remove(&code),
+    });
+    Ok(category_capacity(category))
+}
+
+// Function to set the default settings of a built-in category, inherited by products created
+// afterwards; existing products are re-synced with `recompute_inherited`
+#[ic_cdk::update]
+fn set_category_defaults(
+    category: Category,
+    defaults: ProductSettings,
+) -> Result<CategoryDefaults, Error> {
+    ensure_admin()?;
+    journal_call("set_category_defaults");
+    let code = category.code().ok_or(Error::InvalidOperation {
+        msg: "Defaults can only be set on built-in categories.".to_string(),
+    })?;
+    validate_product_settings(&defaults)?;
+    CATEGORY_DEFAULTS.with(|service| {
+        if defaults.is_empty() {
+            service.borrow_mut().remove(&code)
+        } else {
+            service.borrow_mut().insert(code, defaults.clone())
+        }
+    });
+    Ok(CategoryDefaults { category, defaults })
+}
+
+// Query function to get the default settings of every built-in category
+#[ic_cdk::query]
+fn get_category_defaults() -> Vec<CategoryDefaults> {
+    Category::ALL
+        .into_iter()
+        .map(|category| CategoryDefaults {
+            defaults: category_defaults(&category),
+            category,
+        })
+        .collect()
+}
+
+// Function to re-sync the inherited settings of a product with its category's current
+// defaults, keeping the settings it overrides
+#[ic_cdk::update]
+fn recompute_inherited(product_id: u64) -> Result<Product, Error> {
+    ensure_caller_allowed(Endpoint::UpdateProduct)?;
+    journal_call("recompute_inherited");
+    let mut product = _get_product(&product_id).ok_or(Error::NotFound {
+        msg: format!("Product with id={} not found", product_id),
+    })?;
+    let previous = product.settings.clone();
+    let own = own_settings(&product);
+    inherit_settings(&mut product, own);
+    if product.settings != previous {
+        product.updated_at = Some(time());
+        STORAGE.with(|service| service.borrow_mut().insert(product_id, product.clone()));
+        record_product_version(&product);
+    }
+    Ok(product_for_caller(product))
+}
+
+// Function to set the order quantity limits of every product in a category at once
+#[ic_cdk::update]
+fn set_category_order_limits(
+    category: Category,
+    payload: OrderQuantityPayload,
+) -> Result<u32, Error> {
+    ensure_admin()?;
+    journal_call("set_category_order_limits");
+    let min_order_qty = order_limit(payload.min_order_qty);
+    let max_order_qty = order_limit(payload.max_order_qty);
+    let order_qty_step = order_limit(payload.order_qty_step);
+    validate_order_quantity_limits(min_order_qty, max_order_qty, order_qty_step)?;
+
+    let now = time();
+    STORAGE.with(|service| {
+        let mut storage = service.borrow_mut();
+        let products: Vec<Product> = storage
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| product.category == category)
+            .collect();
+        for mut product in products.iter().cloned() {
+            product.min_order_qty = min_order_qty;
+            product.max_order_qty = max_order_qty;
+            product.order_qty_step = order_qty_step;
+            product.updated_at = Some(now);
+            record_product_version(&product);
+            storage.insert(product.id, product);
+        }
+        Ok(products.len() as u32)
+    })
+}
+
+// Helper function to report the shelf space used and left in a category
+fn category_capacity(category: Category) -> CategoryCapacity {
+    let max_units = category
+        .code()
+        .and_then(|code| CATEGORY_CAPS.with(|caps| caps.borrow().get(&code)));
+    let units_in_stock = category_units(&category, None);
+    CategoryCapacity {
+        category,
+        max_units,
+        units_in_stock,
+        remaining: max_units.map(|max_units| (max_units as u64).saturating_sub(units_in_stock)),
+    }
+}
+
+// Query function to list the built-in categories followed by the other categories in use
+#[ic_cdk::query]
+fn list_categories() -> Vec<Category> {
+    let mut categories = Category::ALL.to_vec();
+    for product in visible_products(|product| matches!(product.category, Category::Other(_))) {
+        if !categories.contains(&product.category) {
+            categories.push(product.category);
+        }
+    }
+    categories
+}
+
+// Query function to get the remaining shelf capacity of every category
+#[ic_cdk::query]
+fn get_category_capacity() -> Vec<CategoryCapacity> {
+    Category::ALL.into_iter().map(category_capacity).collect()
+}
+
+// Helper function to apply a price adjustment, rounding percentages to the nearest unit and
+// then by the price rounding rules
+fn adjusted_price(price: u64, adjustment: &PriceAdjustment) -> Option<u64> {
+    let price = price as i128;
+    let adjusted = match adjustment {
+        PriceAdjustment::Percentage(percent) => {
+            let scaled = price * (100 + *percent as i128);
+            let adjusted = (scaled + 50).div_euclid(100);
+            round_price(u64::try_from(adjusted).ok()?) as i128
+        }
+        PriceAdjustment::Delta(delta) => price + *delta as i128,
+    };
+    u64::try_from(adjusted).ok()
+}
+
+// Function to change the price of every product in a category or tag in one call
+#[ic_cdk::update]
+fn adjust_prices(
+    scope: PriceScope,
+    adjustment: PriceAdjustment,
+) -> Result<PriceAdjustmentSummary, Error> {
+    ensure_admin()?;
+    journal_call("adjust_prices");
+
+    let tag = match &scope {
+        PriceScope::Tag(tag) => Some(tag.trim().to_lowercase()),
+        _ => None,
+    };
+    let targets: Vec<Product> = STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| match &scope {
+                PriceScope::All => true,
+                PriceScope::Category(category) => &product.category == category,
+                PriceScope::Tag(_) => tag.as_ref().is_some_and(|tag| product.tags().contains(tag)),
+            })
+            .collect()
+    });
+
+    // Compute every new price first so a single invalid result leaves all prices untouched
+    let mut updates = Vec::with_capacity(targets.len());
+    for product in targets {
+        let new_price =
+            adjusted_price(product.price(), &adjustment).ok_or(Error::InvalidOperation {
+                msg: format!(
+                    "Adjustment would make the price of product id={} negative or too large",
+                    product.id
+                ),
+            })?;
+        if new_price != product.price() {
+            updates.push((product, new_price));
+        }
+    }
+
+    let now = time();
+    let mut changes = Vec::with_capacity(updates.len());
+    for (mut product, new_price) in updates {
+        changes.push(record_price_change(product.id, product.price(), new_price));
+        product.price = Some(new_price);
+        product.updated_at = Some(now);
+        do_insert(&product);
+    }
+    Ok(PriceAdjustmentSummary {
+        affected: changes.len() as u32,
+        changes,
+    })
+}
+
+// Query function to get the recorded price changes of a product, oldest first
+#[ic_cdk::query]
+fn get_price_history(product_id: u64) -> Vec<PriceChange> {
+    PRICE_HISTORY.with(|service| {
+        service
+            .borrow()
+            .range((product_id, 0)..=(product_id, u64::MAX))
+            .map(|(_, change)| change)
+            .collect()
+    })
+}
+
+// Helper function to retrieve the order statistics of a customer
+fn _get_customer(principal: &Principal) -> Option<Customer> {
+    CUSTOMERS.with(|service| service.borrow().get(&PrincipalKey(*principal)))
+}
+
+// Helper function to derive a customer's segment from their order history
+fn segment_for(customer: &Customer, now: u64) -> Segment {
+    if now.saturating_sub(customer.last_order_at) > CHURN_RISK_AFTER_NS {
+        Segment::ChurnRisk
+    } else if customer.order_count >= VIP_MIN_ORDERS {
+        Segment::Vip
+    } else if customer.order_count >= REGULAR_MIN_ORDERS {
+        Segment::Regular
+    } else {
+        Segment::New
+    }
+}
+
+// Function to recompute the segment of every customer, run by the job timer
+fn refresh_customer_segments() {
+    let now = time();
+    CUSTOMERS.with(|service| {
+        let updates: Vec<Customer> = service
+            .borrow()
+            .iter()
+            .filter_map(|(_, mut customer)| {
+                let segment = segment_for(&customer, now);
+                (segment != customer.segment).then(|| {
+                    customer.segment = segment;
+                    customer.segment_updated_at = Some(now);
+                    customer
+                })
+            })
+            .collect();
+        let mut customers = service.borrow_mut();
+        for customer in updates {
+            customers.insert(PrincipalKey(customer.principal), customer);
+        }
+    });
+}
+
+// Helper function to update a customer's order statistics after an order is placed
+fn record_customer_order(principal: Principal, now: u64) {
+    let customer = match _get_customer(&principal) {
+        Some(mut customer) => {
+            customer.order_count += 1;
+            customer.last_order_at = now;
+            customer
+        }
+        None => Customer {
+            principal,
+            order_count: 1,
+            first_order_at: now,
+            last_order_at: now,
+            segment: Segment::New,
+            segment_updated_at: None,
+        },
+    };
+    CUSTOMERS.with(|service| {
+        service
+            .borrow_mut()
+            .insert(PrincipalKey(principal), customer)
+    });
+}
+
+// Helper function to pick the largest active promotion available to a segment
+fn best_promotion_for(segment: Segment) -> Option<Promotion> {
+    PROMOTIONS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, promotion)| promotion)
+            .filter(|promotion| {
+                promotion.active
+                    && (promotion.segments.is_empty() || promotion.segments.contains(&segment))
+            })
+            .max_by_key(|promotion| promotion.percent_off)
+    })
+}
+
+// Helper function to retrieve an order by its ID
+fn _get_order(id: &u64) -> Option<Order> {
+    ORDERS.with(|service| service.borrow().get(id))
+}
+
+// Order contents priced for a customer, ready to be committed
+struct PricedOrder {
+    lines: Vec<OrderLine>,
+    subtotal: u64,
+    discount: u64,
+    promotion_id: Option<u64>,
+    applied_rules: Vec<AppliedPricingRule>,
+    experiments: Vec<ExperimentAssignment>,
+}
+
+// Function to validate the lines a customer requests against the stock, priced at catalog prices
+fn check_order_lines(
+    customer: &Principal,
+    requested: &[OrderLinePayload],
+) -> Result<Vec<OrderLine>, Error> {
+    if requested.is_empty() || requested.len() > MAX_ORDER_LINES {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "An order must have between 1 and {} lines.",
+                MAX_ORDER_LINES
+            ),
+        });
+    }
+
+    let tier = effective_tier(customer);
+    let mut lines: Vec<OrderLine> = Vec::with_capacity(requested.len());
+    for line in requested {
+        if line.quantity == 0 {
+            return Err(Error::InvalidOperation {
+                msg: "Order quantities must be greater than zero.".to_string(),
+            });
+        }
+        if lines
+            .iter()
+            .any(|existing| existing.product_id == line.product_id)
+        {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Product id={} appears in more than one line",
+                    line.product_id
+                ),
+            });
+        }
+        // Checked against the customer rather than the caller, as subscription orders are
+        // placed by the canister's timer
+        let product = _get_product(&line.product_id)
+            .map(|product| with_effective_status(product, time()))
+            .filter(|product| is_visible(product, ic_cdk::api::is_controller(customer)))
+            .ok_or(Error::NotFound {
+                msg: format!("A product with id={} was not found", line.product_id),
+            })?;
+        let reserved = reserved_quantities().get(&product.id).copied().unwrap_or(0);
+        let available = availability_of(&product, reserved).available
+            + claimable_quantity(*customer, line.product_id) as i64;
+        if (line.quantity as i64) > available {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Not enough stock for product id={}. Available: {}, Requested: {}",
+                    line.product_id,
+                    available.max(0),
+                    line.quantity
+                ),
+            });
+        }
+        check_order_quantity(&product, line.quantity)?;
+        let tier_price = tier_price_of(product.id, tier);
+        check_tier_minimum(&product, tier_price.as_ref(), line.quantity)?;
+        check_age_restriction(customer, &product)?;
+        let options = resolve_configuration(&product, line.selections.as_deref().unwrap_or(&[]))?;
+        let base_price = tier_price.map_or(product.price(), |price| price.unit_price);
+        lines.push(OrderLine {
+            product_id: product.id,
+            quantity: line.quantity,
+            unit_price: configured_unit_price(base_price, &options)?,
+            options: (!options.is_empty()).then_some(options),
+            fulfilled: None,
+            unfulfillable: None,
+        });
+    }
+    if lines
+        .iter()
+        .map(|line| line.options.as_ref().map_or(0, Vec::len))
+        .sum::<usize>()
+        > MAX_OPTIONS_PER_ORDER
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "An order can select at most {} options in total.",
+                MAX_OPTIONS_PER_ORDER
+            ),
+        });
+    }
+    Ok(lines)
+}
+
+// Helper function to total priced lines, apply the pricing rules in effect now and then the
+// best promotion for the customer to what is left
+fn price_order(customer: &Principal, lines: Vec<OrderLine>) -> PricedOrder {
+    let segment = _get_customer(customer)
+        .map(|customer| customer.segment)
+        .unwrap_or_default();
+    let (lines, experiments, experiment_promotion) = apply_experiments(customer, lines);
+    // A redeemed coupon is kept for a later order while a segment promotion is as good
+    let promotion = experiment_promotion.or_else(|| {
+        [pending_coupon(customer), best_promotion_for(segment)]
+            .into_iter()
+            .flatten()
+            .max_by_key(|promotion| promotion.percent_off)
+    });
+    let subtotal: u64 = lines
+        .iter()
+        .map(|line| line.unit_price * line.quantity as u64)
+        .sum();
+    let applied_rules = applicable_pricing_rules(&lines, time());
+    let rule_discount: u64 = applied_rules.iter().map(|applied| applied.discount).sum();
+    let promotion_discount = promotion.as_ref().map_or(0, |promotion| {
+        (subtotal - rule_discount) * promotion.percent_off as u64 / 100
+    });
+    // A discounted total is rounded, the difference going into the discount
+    let discount = match rule_discount + promotion_discount {
+        0 => 0,
+        discount => subtotal - round_price(subtotal - discount).min(subtotal),
+    };
+    PricedOrder {
+        lines,
+        subtotal,
+        discount,
+        promotion_id: promotion.map(|promotion| promotion.id),
+        applied_rules,
+        experiments,
+    }
+}
+
+// Helper function to check whether a time falls within a pricing rule's daily window
+fn in_pricing_window(rule: &PricingRule, now: u64) -> bool {
+    let minute = ((now % NANOS_PER_DAY) / NANOS_PER_MINUTE) as u16;
+    if rule.start_minute <= rule.end_minute {
+        (rule.start_minute..rule.end_minute).contains(&minute)
+    } else {
+        minute >= rule.start_minute || minute < rule.end_minute
+    }
+}
+
+// Function to pick, for each line, the largest active pricing rule in effect at a time
+fn applicable_pricing_rules(lines: &[OrderLine], now: u64) -> Vec<AppliedPricingRule> {
+    let rules: Vec<PricingRule> = PRICING_RULES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, rule)| rule)
+            .filter(|rule| rule.active && in_pricing_window(rule, now))
+            .collect()
+    });
+    if rules.is_empty() {
+        return Vec::new();
+    }
+
+    lines
+        .iter()
+        .filter_map(|line| {
+            let category = _get_product(&line.product_id)?.category;
+            let rule = rules
+                .iter()
+                .filter(|rule| rule.categories.is_empty() || rule.categories.contains(&category))
+                .max_by_key(|rule| rule.percent_off)?;
+            Some(AppliedPricingRule {
+                rule_id: rule.id,
+                product_id: line.product_id,
+                percent_off: rule.percent_off,
+                discount: line.unit_price * line.quantity as u64 * rule.percent_off as u64 / 100,
+            })
+        })
+        .collect()
+}
+
+// Function to store a priced order and take its units out of stock; lines must have been
+// checked against the stock within the same call. Token payments get an escrow subaccount
+fn commit_order(
+    customer: Principal,
+    priced: PricedOrder,
+    notes: Option<String>,
+    quote_id: Option<u64>,
+    payment: Option<OrderPayment>,
+    kiosk_session: Option<u64>,
+) -> Result<Order, Error> {
+    let now = time();
+    let total = priced.subtotal - priced.discount;
+    let account_owner = check_spending_limit(&customer, total, now)?;
+    let id = generate_unique_id(|id| _get_order(&id).is_some())?;
+    let shift_id = current_shift(&customer).map(|shift| shift.id);
+    let prep_minutes = order_prep_minutes(&priced.lines);
+    let promised_ready_at = promise_ready_time(prep_minutes, now)?;
+    let payment = payment.map(|payment| OrderPayment {
+        escrow: Some(Escrow {
+            subaccount: escrow_subaccount(id),
+            status: EscrowStatus::AwaitingPayment,
+            settle_requested_at: None,
+            block_index: None,
+            last_error: None,
+            partial_refund_block: None,
+            cancellation_fee_block: None,
+        }),
+        ..payment
+    });
+    // Every line is taken out of its product before any is stored, so a line short of stock
+    // leaves the others untouched
+    let mut products = Vec::with_capacity(priced.lines.len());
+    for line in &priced.lines {
+        let mut product =
+            _get_product(&line.product_id).expect("checked lines refer to existing products");
+        let expected = product.quantity;
+        take_stock(&mut product, expected, line.quantity)?;
+        products.push(product);
+    }
+    for (line, mut product) in priced.lines.iter().zip(products) {
+        product.updated_at = Some(now);
+        do_insert(&product);
+        claim_waitlist_reservations(customer, line.product_id);
+        record_stock_movement(
+            &customer,
+            line.product_id,
+            StockMovementKind::Sale,
+            -(line.quantity as i64),
+            (line.unit_price * line.quantity as u64) as i64,
+            Some(id),
+        );
+    }
+    let order_number = next_order_number(now)?;
+    let order = Order {
+        id,
+        customer,
+        lines: priced.lines,
+        subtotal: priced.subtotal,
+        discount: priced.discount,
+        total: priced.subtotal - priced.discount,
+        promotion_id: priced.promotion_id,
+        status: OrderStatus::Placed,
+        nft_receipt: None,
+        notes,
+        quote_id,
+        created_at: now,
+        updated_at: None,
+        applied_rules: Some(priced.applied_rules),
+        payment,
+        account_owner,
+        kiosk_session,
+        shift_id,
+        refund_due: None,
+        schedule: None,
+        promised_ready_at: Some(promised_ready_at),
+        ready_at: None,
+        prep_minutes: Some(prep_minutes),
+        cancellation: None,
+        order_number: Some(order_number.clone()),
+        tenders: None,
+    };
+    store_order(order.clone());
+    ORDER_NUMBERS.with(|service| {
+        service
+            .borrow_mut()
+            .insert(OrderNumber::normalize(&order_number), id)
+    });
+    record_customer_order(customer, now);
+    record_sales(&order.lines, 1, now);
+    record_experiment_conversions(&priced.experiments, &order.lines);
+    use_pending_coupon(&customer, order.promotion_id);
+    queue_receipt_email(&order, 0);
+    notify_staff(NotifierEvent::OrderPlaced, &order);
+    if account_owner.is_some() {
+        record_spending(&customer, total, now, true);
+    }
+    post_transfer(
+        format!("Order #{} placed", id),
+        Some(id),
+        LedgerAccount::Receivables,
+        LedgerAccount::Sales,
+        order.total,
+    );
+    Ok(order)
+}
+
+// Function to store an order, keeping the count of orders in each status in step
+fn store_order(order: Order) {
+    let status = order.status;
+    let previous = ORDERS.with(|service| service.borrow_mut().insert(order.id, order));
+    let previous = previous.map(|previous| previous.status);
+    if previous == Some(status) {
+        return;
+    }
+    ORDER_STATUS_COUNTS.with(|service| {
+        let mut counts = service.borrow_mut();
+        if let Some(previous) = previous {
+            let count = counts.get(&previous.code()).unwrap_or(0);
+            counts.insert(previous.code(), count.saturating_sub(1));
+        }
+        let count = counts.get(&status.code()).unwrap_or(0);
+        counts.insert(status.code(), count + 1);
+    });
+}
+
+// Function to count the orders in each status on upgrade from a release without the counts
+fn count_existing_orders() {
+    if !ORDER_STATUS_COUNTS.with(|service| service.borrow().is_empty()) {
+        return;
+    }
+    let mut counts: HashMap<u8, u64> = HashMap::new();
+    ORDERS.with(|service| {
+        for (_, order) in service.borrow().iter() {
+            *counts.entry(order.status.code()).or_default() += 1;
+        }
+    });
+    ORDER_STATUS_COUNTS.with(|service| {
+        let mut service = service.borrow_mut();
+        for (code, count) in counts {
+            service.insert(code, count);
+        }
+    });
+}
+
+// Helper function to add up the prep times of the products in an order
+fn order_prep_minutes(lines: &[OrderLine]) -> u32 {
+    lines
+        .iter()
+        .filter_map(|line| _get_product(&line.product_id))
+        .filter_map(|product| product.prep_minutes)
+        .fold(0u32, u32::saturating_add)
+}
+
+// Helper function to get the day an order is produced: the day of its slot, or else of its
+// promised ready time
+fn production_day(order: &Order) -> Option<u64> {
+    order
+        .schedule
+        .as_ref()
+        .map(|schedule| schedule.slot_start)
+        .or(order.promised_ready_at)
+        .map(|at| at / NANOS_PER_DAY)
+}
+
+// Helper function to report the production capacity of a day, when one is configured
+fn day_capacity(day: u64) -> Option<DayCapacity> {
+    let capacity_minutes = CONFIG.with(|config| config.borrow().get().daily_capacity_minutes)?;
+    let used_minutes = production_minutes_used(day..day + 1)
+        .remove(&day)
+        .unwrap_or(0);
+    Some(DayCapacity {
+        day,
+        capacity_minutes,
+        used_minutes,
+        remaining_minutes: (capacity_minutes as u64).saturating_sub(used_minutes),
+    })
+}
+
+// Helper function to sum the prep minutes booked on each of a range of days by orders that are
+// not cancelled
+fn production_minutes_used(days: std::ops::Range<u64>) -> HashMap<u64, u64> {
+    let mut used = HashMap::new();
+    ORDERS.with(|service| {
+        for (_, order) in service.borrow().iter() {
+            if order.status == OrderStatus::Cancelled {
+                continue;
+            }
+            let (Some(day), Some(minutes)) = (production_day(&order), order.prep_minutes) else {
+                continue;
+            };
+            if days.contains(&day) {
+                *used.entry(day).or_insert(0) += minutes as u64;
+            }
+        }
+    });
+    used
+}
+
+// Helper function to check that a day has room for an order taking `prep_minutes`
+fn ensure_day_capacity(day: u64, prep_minutes: u32) -> Result<(), Error> {
+    match day_capacity(day) {
+        Some(capacity) if prep_minutes > 0 && capacity.remaining_minutes < prep_minutes as u64 => {
+            Err(Error::CapacityExceeded {
+                msg: format!(
+                    "Day {} has {} prep minutes left and the order needs {}. Please choose another slot.",
+                    day, capacity.remaining_minutes, prep_minutes
+                ),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+// Helper function to promise when a new order will be ready: orders are prepared one after
+// another, so it starts once the orders already promised are ready and takes the prep times of
+// its products. When that day's production capacity is used up, the promise is pushed out to
+// the next day with room, and the order is refused when no day within the slot horizon has any
+fn promise_ready_time(prep_minutes: u32, now: u64) -> Result<u64, Error> {
+    let queue_clears_at = ORDERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, order)| {
+                matches!(order.status, OrderStatus::Placed | OrderStatus::Preparing)
+            })
+            .filter_map(|(_, order)| order.promised_ready_at)
+            .max()
+            .unwrap_or(now)
+            .max(now)
+    });
+    let prep_ns = prep_minutes as u64 * 60 * 1_000_000_000;
+    let promise = queue_clears_at.saturating_add(prep_ns);
+    let first_day = promise / NANOS_PER_DAY;
+    let last_day = now.saturating_add(MAX_SLOT_ADVANCE_NS) / NANOS_PER_DAY;
+    for day in first_day..=last_day.max(first_day) {
+        if ensure_day_capacity(day, prep_minutes).is_ok() {
+            return Ok(if day == first_day {
+                promise
+            } else {
+                (day * NANOS_PER_DAY).saturating_add(prep_ns)
+            });
+        }
+    }
+    Err(Error::CapacityExceeded {
+        msg: "The kitchen is fully booked for the next 14 days.".to_string(),
+    })
+}
+
+// Function to set the prep minutes the kitchen can produce per day, or with none lift the limit
+#[ic_cdk::update]
+fn set_daily_capacity(minutes: Option<u32>) -> Result<Option<u32>, Error> {
+    ensure_admin()?;
+    journal_call("set_daily_capacity");
+    update_config(|config| config.daily_capacity_minutes = minutes)?;
+    Ok(minutes)
+}
+
+// Query function to show which days of a month (counted from January 1970) a made-to-order
+// product can still be ordered for: days from today up to the slot horizon whose production
+// capacity has room for the product's prep minutes
+#[ic_cdk::query]
+fn availability_calendar(product_id: u64, month: u64) -> Result<AvailabilityCalendar, Error> {
+    let product = _get_visible_product(&product_id).ok_or(Error::NotFound {
+        msg: format!("A product with id={} was not found", product_id),
+    })?;
+    let prep_minutes = product.prep_minutes.ok_or(Error::InvalidOperation {
+        msg: format!("Product id={} is not made to order", product_id),
+    })?;
+    let now = time();
+    let bookable = now / NANOS_PER_DAY..=now.saturating_add(MAX_SLOT_ADVANCE_NS) / NANOS_PER_DAY;
+    let first_day = first_day_of_month(month);
+    let next_month = first_day_of_month(month.saturating_add(1));
+    let capacity_minutes = CONFIG.with(|config| config.borrow().get().daily_capacity_minutes);
+    let used = production_minutes_used(first_day..next_month);
+    let days = (first_day..next_month)
+        .map(|day| {
+            if !bookable.contains(&day) {
+                return CalendarDay {
+                    day,
+                    available: false,
+                    remaining_minutes: None,
+                };
+            }
+            let remaining_minutes = capacity_minutes.map(|capacity| {
+                (capacity as u64).saturating_sub(used.get(&day).copied().unwrap_or(0))
+            });
+            CalendarDay {
+                day,
+                available: remaining_minutes
+                    .is_none_or(|remaining| remaining >= prep_minutes as u64),
+                remaining_minutes,
+            }
+        })
+        .collect();
+    Ok(AvailabilityCalendar {
+        product_id,
+        month,
+        prep_minutes,
+        days,
+    })
+}
+
+// Query function to list the production capacity left on each of the next `days` days, e.g.
+// to offer only slots with room
+#[ic_cdk::query]
+fn production_capacity(days: u32) -> Vec<DayCapacity> {
+    let today = time() / NANOS_PER_DAY;
+    let days = (days as u64).min(MAX_SLOT_ADVANCE_NS / NANOS_PER_DAY + 1);
+    (today..today + days).filter_map(day_capacity).collect()
+}
+
+// Query function to report how many orders promised within a period were ready on time
+#[ic_cdk::query]
+fn sla_report(period: ReportPeriod) -> Result<SlaReport, Error> {
+    ensure_viewer()?;
+    if period.from > period.to {
+        return Err(Error::InvalidOperation {
+            msg: "A report period cannot end before it starts.".to_string(),
+        });
+    }
+    let now = time();
+    let mut report = SlaReport {
+        period,
+        orders: 0,
+        on_time: 0,
+        late: 0,
+        overdue: 0,
+        on_time_percent: None,
+        average_late_by_ns: None,
+        late_orders: Vec::new(),
+    };
+    let mut total_late_by: u64 = 0;
+    ORDERS.with(|service| {
+        for (_, order) in service.borrow().iter() {
+            let Some(promised) = order
+                .promised_ready_at
+                .filter(|promised| (period.from..period.to).contains(promised))
+            else {
+                continue;
+            };
+            if order.status == OrderStatus::Cancelled {
+                continue;
+            }
+            let finished = order.ready_at.unwrap_or(now);
+            if order.ready_at.is_none() && finished <= promised.saturating_add(SLA_GRACE_NS) {
+                // Not ready yet, but not late either
+                continue;
+            }
+            report.orders += 1;
+            if finished <= promised.saturating_add(SLA_GRACE_NS) {
+                report.on_time += 1;
+                continue;
+            }
+            let late_by = finished - promised;
+            if order.ready_at.is_some() {
+                report.late += 1;
+                total_late_by += late_by;
+            } else {
+                report.overdue += 1;
+            }
+            report.late_orders.push(LateOrder {
+                order_id: order.id,
+                promised_ready_at: promised,
+                ready_at: order.ready_at,
+                late_by_ns: late_by,
+            });
+        }
+    });
+    report.on_time_percent = (report.on_time * 100)
+        .checked_div(report.orders)
+        .map(|percent| percent as u8);
+    report.average_late_by_ns = total_late_by.checked_div(report.late);
+    report
+        .late_orders
+        .sort_by_key(|order| std::cmp::Reverse(order.promised_ready_at));
+    report.late_orders.truncate(MAX_SLA_LATE_ORDERS);
+    Ok(report)
+}
+
+// Function to place an order for the caller, taking the ordered units out of stock
+#[ic_cdk::update]
+fn place_order(payload: OrderPayload) -> Result<Order, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    journal_call("place_order");
+    place_caller_order(payload)
+}
+
+// Helper function to place an order for the caller once the endpoint guard passed
+fn place_caller_order(payload: OrderPayload) -> Result<Order, Error> {
+    validate_notes(&payload.notes)?;
+    if payload.payment_token.is_some() {
+        ensure_payments_open()?;
+    }
+
+    let token = payload
+        .payment_token
+        .as_deref()
+        .map(accepted_token_by_symbol)
+        .transpose()?;
+    let customer = account_holder();
+    let lines = check_order_lines(&customer, &payload.lines)?;
+    let priced = price_order(&customer, lines);
+    let rest = token
+        .as_ref()
+        .map_or(Tender::PayOnPickup, |token| Tender::Token {
+            symbol: token.symbol.clone(),
+        });
+    let tenders = split_tenders(
+        &customer,
+        priced.subtotal - priced.discount,
+        payload.tenders.as_deref().unwrap_or_default(),
+        rest,
+    )?;
+    let due = remaining_due(&tenders, priced.subtotal - priced.discount);
+    let payment = token.filter(|_| due > 0).map(|token| OrderPayment {
+        amount: token_amount(&token, due),
+        symbol: token.symbol,
+        ledger: token.ledger,
+        escrow: None,
+    });
+    let mut order = commit_order(customer, priced, payload.notes, None, payment, None)?;
+    apply_tenders(&mut order, tenders);
+    screen_order(&order);
+    Ok(order)
+}
+
+// Helper function to find an enabled accepted token by its symbol
+fn accepted_token_by_symbol(symbol: &str) -> Result<AcceptedToken, Error> {
+    ACCEPTED_TOKENS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, token)| token)
+            .find(|token| token.enabled && token.symbol.eq_ignore_ascii_case(symbol))
+            .ok_or(Error::InvalidOperation {
+                msg: format!("{} is not an accepted payment token", symbol),
+            })
+    })
+}
+
+// Helper function to convert a price in the currency's smallest unit into token base units,
+// rounding up so the shop is never paid less than the price, to the token's decimals rule if any
+fn token_amount(token: &AcceptedToken, price: u64) -> Nat {
+    let scale = 10u128.pow(token.decimals as u32);
+    let price_per_token = match token.conversion {
+        ConversionSource::Pegged => 100,
+        ConversionSource::Fixed { price_per_token } => price_per_token as u128,
+    };
+    let amount = (price as u128 * scale).div_ceil(price_per_token);
+    let rounding = CONFIG.with(|config| config.borrow().get().price_rounding.clone());
+    let kept = rounding.and_then(|rounding| {
+        rounding
+            .token_decimals
+            .into_iter()
+            .find(|rule| rule.symbol == token.symbol)
+            .map(|rule| rule.decimals)
+    });
+    match kept {
+        Some(decimals) if decimals < token.decimals => {
+            let step = 10u128.pow((token.decimals - decimals) as u32);
+            Nat::from(amount.div_ceil(step) * step)
+        }
+        _ => Nat::from(amount),
+    }
+}
+
+// Query function to retrieve an order; customers can only see their own orders
+#[ic_cdk::query]
+fn get_order(id: u64) -> Result<Order, Error> {
+    match _get_order(&id) {
+        Some(order) if is_own_record(&order.customer) || ensure_admin().is_ok() => Ok(order),
+        _ => Err(Error::NotFound {
+            msg: format!("An order with id={} was not found", id),
+        }),
+    }
+}
+
+// Query function to list the caller's orders, one page at a time
+#[ic_cdk::query]
+fn list_my_orders(page: Option<PageRequest>) -> OrderPage {
+    let principals = account_principals(&caller());
+    let page = page.unwrap_or_default();
+    ORDERS.with(|service| {
+        let orders = service.borrow();
+        let (orders, next_cursor) = take_page(
+            orders
+                .range(page_start(&page))
+                .filter(|(_, order)| principals.contains(&order.customer)),
+            &page,
+        );
+        OrderPage {
+            orders,
+            next_cursor,
+        }
+    })
+}
+
+// Function to move an order through its lifecycle; cancelled orders are returned to stock
+#[ic_cdk::update]
+fn update_order_status(id: u64, status: OrderStatus) -> Result<Order, Error> {
+    ensure_admin()?;
+    journal_call("update_order_status");
+    do_update_order_status(id, status, caller())
+}
+
+// Function to apply an order status change on behalf of `actor`
+fn do_update_order_status(id: u64, status: OrderStatus, actor: Principal) -> Result<Order, Error> {
+    let mut order = _get_order(&id).ok_or(Error::NotFound {
+        msg: format!("Couldn't update an order with id={}. Order not found", id),
+    })?;
+    if !order.status.can_transition_to(&status) {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Order with id={} cannot move from {:?} to {:?}",
+                id, order.status, status
+            ),
+        });
+    }
+    if status != OrderStatus::Cancelled && is_held(id) {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Order with id={} is held for review. Clear it before moving it on.",
+                id
+            ),
+        });
+    }
+
+    let now = time();
+    let mut refunded_to_balances = 0;
+    if status == OrderStatus::Cancelled {
+        if order
+            .lines
+            .iter()
+            .any(|line| line.fulfilled.unwrap_or(0) > 0)
+        {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Order with id={} is partly fulfilled; mark its remaining lines unfulfillable instead",
+                    id
+                ),
+            });
+        }
+        // Unfulfillable units were never there to return, and their sales are already undone
+        let lines = open_lines(&order);
+        for line in &lines {
+            // Products removed since the order was placed have no stock to return to
+            if let Some(mut product) = _get_product(&line.product_id) {
+                let previous_quantity = product.quantity;
+                product.quantity = product.quantity.saturating_add(line.quantity);
+                product.updated_at = Some(now);
+                do_insert(&product);
+                record_stock_movement(
+                    &actor,
+                    product.id,
+                    StockMovementKind::Cancellation,
+                    line.quantity as i64,
+                    -((line.unit_price * line.quantity as u64) as i64),
+                    Some(id),
+                );
+                allocate_waitlist(product.id);
+                notify_if_back_in_stock(&product, previous_quantity);
+            }
+        }
+        record_sales(&lines, -1, now);
+        if order.account_owner.is_some() && order.created_at / NANOS_PER_DAY == now / NANOS_PER_DAY
+        {
+            record_spending(&order.customer, order.total, now, false);
+        }
+        // A cancellation fee is kept from every tender in proportion to its share
+        let fee = order
+            .cancellation
+            .as_ref()
+            .map_or(0, |cancellation| cancellation.fee);
+        let refund = amount_due(&order).saturating_sub(fee);
+        refunded_to_balances = refund_tenders(&mut order, refund);
+    }
+    if status == OrderStatus::Ready {
+        notify(
+            order.customer,
+            NotificationKind::OrderReady { order_id: id },
+            format!("Your order #{} is ready.", id),
+        );
+    }
+    if matches!(status, OrderStatus::PickedUp | OrderStatus::Delivered) {
+        // Whatever was not handed over in parts leaves with the customer now
+        for line in order.lines.iter_mut() {
+            if line.fulfilled.is_some() || line.unfulfillable.is_some() {
+                line.fulfilled = Some(line.quantity - line.unfulfillable.unwrap_or(0));
+            }
+        }
+    }
+    post_order_status_change(&order, status, refunded_to_balances);
+    if status == OrderStatus::Ready {
+        order.ready_at = Some(now);
+    }
+    let settling = request_escrow_settlement(&mut order, status, now);
+    record_prep_step(id, status, actor, now);
+    order.status = status;
+    order.updated_at = Some(now);
+    store_order(order.clone());
+    if settling {
+        ic_cdk::spawn(settle_escrow(id));
+    }
+    Ok(order)
+}
+
+// Query function to get the caller's segment and order statistics
+#[ic_cdk::query]
+fn get_my_customer_profile() -> Result<Customer, Error> {
+    _get_customer(&caller()).ok_or(Error::NotFound {
+        msg: "No orders have been placed by the caller yet".to_string(),
+    })
+}
+
+// Query function to list the known customers with their segment, one page at a time
+#[ic_cdk::query]
+fn list_customers(
+    segment: Option<Segment>,
+    page: Option<CustomerPageRequest>,
+) -> Result<CustomerPage, Error> {
+    ensure_viewer()?;
+    let page = page.unwrap_or_default();
+    let start = page.cursor.map_or(Bound::Unbounded, |cursor| {
+        Bound::Excluded(PrincipalKey(cursor))
+    });
+    Ok(CUSTOMERS.with(|service| {
+        let customers = service.borrow();
+        let (customers, next_cursor) = take_page(
+            customers
+                .range((start, Bound::Unbounded))
+                .map(|(key, customer)| (key.0, customer))
+                .filter(|(_, customer)| segment.is_none_or(|segment| customer.segment == segment)),
+            &PageRequest {
+                cursor: None,
+                limit: page.limit,
+            },
+        );
+        CustomerPage {
+            customers,
+            next_cursor,
+        }
+    }))
+}
+
+// Function to recompute customer segments now instead of waiting for the daily job
+#[ic_cdk::update]
+fn refresh_segments() -> Result<(), Error> {
+    ensure_admin()?;
+    journal_call("refresh_segments");
+    refresh_customer_segments();
+    Ok(())
+}
+
+// Function to create a promotion, optionally targeted at some customer segments
+#[ic_cdk::update]
+fn create_promotion(payload: PromotionPayload) -> Result<Promotion, Error> {
+    ensure_admin()?;
+    journal_call("create_promotion");
+    if payload.name.trim().is_empty() {
+        return Err(Error::InvalidOperation {
+            msg: "Promotion name cannot be empty.".to_string(),
+        });
+    }
+    if payload.percent_off == 0 || payload.percent_off > 100 {
+        return Err(Error::InvalidOperation {
+            msg: "Promotion discount must be between 1 and 100 percent.".to_string(),
+        });
+    }
+
+    let id =
+        generate_unique_id(|id| PROMOTIONS.with(|service| service.borrow().contains_key(&id)))?;
+    let promotion = Promotion {
+        id,
+        name: payload.name,
+        percent_off: payload.percent_off,
+        segments: payload.segments,
+        active: true,
+        created_at: time(),
+    };
+    PROMOTIONS.with(|service| service.borrow_mut().insert(id, promotion.clone()));
+    notify_promotion(&promotion);
+    Ok(promotion)
+}
+
+// Function to enable or disable a promotion
+#[ic_cdk::update]
+fn set_promotion_active(id: u64, active: bool) -> Result<Promotion, Error> {
+    ensure_admin()?;
+    journal_call("set_promotion_active");
+    PROMOTIONS.with(|service| {
+        let mut promotions = service.borrow_mut();
+        let mut promotion = promotions.get(&id).ok_or(Error::NotFound {
+            msg: format!("A promotion with id={} was not found", id),
+        })?;
+        promotion.active = active;
+        promotions.insert(id, promotion.clone());
+        Ok(promotion)
+    })
+}
+
+// Query function to list all promotions
+#[ic_cdk::query]
+fn list_promotions() -> Vec<Promotion> {
+    PROMOTIONS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, promotion)| promotion)
+            .collect()
+    })
+}
+
+// Function to register an accepted payment token, or update the one using the same ledger
+#[ic_cdk::update]
+fn register_token(payload: TokenPayload) -> Result<AcceptedToken, Error> {
+    ensure_admin()?;
+    journal_call("register_token");
+    let symbol = payload.symbol.trim().to_string();
+    if symbol.is_empty() || symbol.len() > MAX_TOKEN_SYMBOL_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Token symbols must be non-empty and at most {} bytes long.",
+                MAX_TOKEN_SYMBOL_LENGTH
+            ),
+        });
+    }
+    if payload.decimals > MAX_TOKEN_DECIMALS {
+        return Err(Error::InvalidOperation {
+            msg: format!("Tokens can have at most {} decimals.", MAX_TOKEN_DECIMALS),
+        });
+    }
+    if payload.conversion == (ConversionSource::Fixed { price_per_token: 0 }) {
+        return Err(Error::InvalidOperation {
+            msg: "A fixed token price must be greater than zero.".to_string(),
+        });
+    }
+
+    let key = PrincipalKey(payload.ledger);
+    ACCEPTED_TOKENS.with(|service| {
+        let mut tokens = service.borrow_mut();
+        if tokens
+            .iter()
+            .any(|(ledger, token)| ledger != key && token.symbol.eq_ignore_ascii_case(&symbol))
+        {
+            return Err(Error::InvalidOperation {
+                msg: format!("Another ledger is already registered as {}", symbol),
+            });
+        }
+        let now = time();
+        let token = match tokens.get(&key) {
+            Some(existing) => AcceptedToken {
+                symbol,
+                decimals: payload.decimals,
+                ledger: payload.ledger,
+                conversion: payload.conversion,
+                enabled: payload.enabled.unwrap_or(existing.enabled),
+                created_at: existing.created_at,
+                updated_at: Some(now),
+            },
+            None if tokens.len() >= MAX_ACCEPTED_TOKENS => {
+                return Err(Error::CapacityExceeded {
+                    msg: format!("At most {} tokens can be accepted.", MAX_ACCEPTED_TOKENS),
+                })
+            }
+            None => AcceptedToken {
+                symbol,
+                decimals: payload.decimals,
+                ledger: payload.ledger,
+                conversion: payload.conversion,
+                enabled: payload.enabled.unwrap_or(true),
+                created_at: now,
+                updated_at: None,
+            },
+        };
+        tokens.insert(key, token.clone());
+        Ok(token)
+    })
+}
+
+// Function to remove a token from the registry; orders already placed keep their payment
+#[ic_cdk::update]
+fn remove_token(ledger: Principal) -> Result<AcceptedToken, Error> {
+    ensure_admin()?;
+    journal_call("remove_token");
+    ACCEPTED_TOKENS
+        .with(|service| service.borrow_mut().remove(&PrincipalKey(ledger)))
+        .ok_or(Error::NotFound {
+            msg: format!("No token is registered for ledger {}", ledger),
+        })
+}
+
+// Query function to list the tokens customers can pay with; admins also see disabled ones
+#[ic_cdk::query]
+fn list_accepted_tokens() -> Vec<AcceptedToken> {
+    let is_admin = ensure_admin().is_ok();
+    ACCEPTED_TOKENS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, token)| token)
+            .filter(|token| is_admin || token.enabled)
+            .collect()
+    })
+}
+
+// Function to validate a pricing rule payload
+fn validate_pricing_rule_payload(payload: &PricingRulePayload) -> Result<(), Error> {
+    if payload.name.trim().is_empty() {
+        return Err(Error::InvalidOperation {
+            msg: "Pricing rule name cannot be empty.".to_string(),
+        });
+    }
+    if payload.percent_off == 0 || payload.percent_off > 100 {
+        return Err(Error::InvalidOperation {
+            msg: "Pricing rule discount must be between 1 and 100 percent.".to_string(),
+        });
+    }
+    if payload.start_minute >= MINUTES_PER_DAY
+        || payload.end_minute >= MINUTES_PER_DAY
+        || payload.start_minute == payload.end_minute
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "A pricing window must start and end at different minutes between 0 and {}.",
+                MINUTES_PER_DAY - 1
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Function to create a time-based pricing rule
+#[ic_cdk::update]
+fn create_pricing_rule(payload: PricingRulePayload) -> Result<PricingRule, Error> {
+    ensure_admin()?;
+    journal_call("create_pricing_rule");
+    validate_pricing_rule_payload(&payload)?;
+    if PRICING_RULES.with(|service| service.borrow().len()) >= MAX_PRICING_RULES {
+        return Err(Error::CapacityExceeded {
+            msg: format!("At most {} pricing rules can exist.", MAX_PRICING_RULES),
+        });
+    }
+
+    let id =
+        generate_unique_id(|id| PRICING_RULES.with(|service| service.borrow().contains_key(&id)))?;
+    let rule = PricingRule {
+        id,
+        name: payload.name,
+        percent_off: payload.percent_off,
+        categories: payload.categories,
+        start_minute: payload.start_minute,
+        end_minute: payload.end_minute,
+        active: payload.active.unwrap_or(true),
+        created_at: time(),
+        updated_at: None,
+    };
+    PRICING_RULES.with(|service| service.borrow_mut().insert(id, rule.clone()));
+    Ok(rule)
+}
+
+// Function to replace the settings of a pricing rule; quotes already issued keep their prices
+#[ic_cdk::update]
+fn update_pricing_rule(id: u64, payload: PricingRulePayload) -> Result<PricingRule, Error> {
+    ensure_admin()?;
+    journal_call("update_pricing_rule");
+    validate_pricing_rule_payload(&payload)?;
+    PRICING_RULES.with(|service| {
+        let mut rules = service.borrow_mut();
+        let mut rule = rules.get(&id).ok_or(Error::NotFound {
+            msg: format!("A pricing rule with id={} was not found", id),
+        })?;
+        rule.name = payload.name;
+        rule.percent_off = payload.percent_off;
+        rule.categories = payload.categories;
+        rule.start_minute = payload.start_minute;
+        rule.end_minute = payload.end_minute;
+        if let Some(active) = payload.active {
+            rule.active = active;
+        }
+        rule.updated_at = Some(time());
+        rules.insert(id, rule.clone());
+        Ok(rule)
+    })
+}
+
+// Function to delete a pricing rule
+#[ic_cdk::update]
+fn delete_pricing_rule(id: u64) -> Result<PricingRule, Error> {
+    ensure_admin()?;
+    journal_call("delete_pricing_rule");
+    PRICING_RULES
+        .with(|service| service.borrow_mut().remove(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("A pricing rule with id={} was not found", id),
+        })
+}
+
+// Query function to list all pricing rules
+#[ic_cdk::query]
+fn list_pricing_rules() -> Vec<PricingRule> {
+    PRICING_RULES.with(|service| service.borrow().iter().map(|(_, rule)| rule).collect())
+}
+
+// Function to reject non-admin updates while the shop is in maintenance mode
+fn ensure_not_in_maintenance() -> Result<(), Error> {
+    match CONFIG.with(|config| config.borrow().get().maintenance.clone()) {
+        Some(maintenance) if ensure_admin().is_err() => Err(Error::Maintenance {
+            msg: maintenance.message,
+            eta: maintenance.eta,
+        }),
+        _ => Ok(()),
+    }
+}
+
+// Function to close the shop to non-admin updates, e.g. for upgrades and stocktakes, or to
+// reopen it; queries keep serving either way
+#[ic_cdk::update]
+fn set_maintenance_mode(
+    enabled: bool,
+    message: Option<String>,
+    eta: Option<u64>,
+) -> Result<Option<MaintenanceMode>, Error> {
+    ensure_admin()?;
+    journal_call("set_maintenance_mode");
+    validate_notes(&message)?;
+    let maintenance = enabled.then(|| MaintenanceMode {
+        message: message
+            .unwrap_or_else(|| "The shop is down for maintenance. Please try again later.".into()),
+        eta,
+        since: time(),
+    });
+    update_config(|config| config.maintenance = maintenance.clone())?;
+    Ok(maintenance)
+}
+
+// Query function to get the maintenance notice, if the shop is in maintenance mode
+#[ic_cdk::query]
+fn get_maintenance_mode() -> Option<MaintenanceMode> {
+    CONFIG.with(|config| config.borrow().get().maintenance.clone())
+}
+
+// Helper function to get the payments pause, if payments are paused
+fn payments_pause() -> Option<PaymentsPause> {
+    CONFIG.with(|config| config.borrow().get().payments_pause.clone())
+}
+
+// Helper function to refuse payment capture and checkout finalization while payments are
+// paused; unlike maintenance mode, admins are refused too
+fn ensure_payments_open() -> Result<(), Error> {
+    match payments_pause() {
+        Some(pause) => Err(Error::Other {
+            kind: "PaymentsPaused".to_string(),
+            msg: pause.message,
+        }),
+        None => Ok(()),
+    }
+}
+
+// Function to pause payments, e.g. during a ledger incident: checkouts, payment confirmations,
+// subscription charges and escrow settlements wait, while browsing, carts and staff inventory
+// operations carry on
+#[ic_cdk::update]
+fn pause_payments(message: Option<String>) -> Result<PaymentsPause, Error> {
+    ensure_admin()?;
+    journal_call("pause_payments");
+    validate_notes(&message)?;
+    let pause = PaymentsPause {
+        message: message.unwrap_or_else(|| {
+            "Payments are paused for now. Your cart is kept; please try again later.".into()
+        }),
+        paused_by: caller(),
+        since: time(),
+    };
+    update_config(|config| config.payments_pause = Some(pause.clone()))?;
+    Ok(pause)
+}
+
+// Function to resume payments; escrow settlements and subscription charges held back by the
+// pause are retried right away
+#[ic_cdk::update]
+fn resume_payments() -> Result<(), Error> {
+    ensure_admin()?;
+    journal_call("resume_payments");
+    update_config(|config| config.payments_pause = None)?;
+    retry_escrow_settlements();
+    dispatch_outbox();
+    Ok(())
+}
+
+// Query function to get the payments pause, if payments are paused
+#[ic_cdk::query]
+fn get_payments_pause() -> Option<PaymentsPause> {
+    payments_pause()
+}
+
+// Function to allow or block the anonymous principal on a state-changing endpoint
+#[ic_cdk::update]
+fn set_anonymous_access(endpoint: Endpoint, allowed: bool) -> Result<AccessPolicy, Error> {
+    ensure_admin()?;
+    journal_call("set_anonymous_access");
+    ACCESS_POLICY.with(|policy| {
+        let mut updated = policy.borrow().get().clone();
+        updated
+            .anonymous_allowed
+            .retain(|existing| *existing != endpoint);
+        if allowed {
+            updated.anonymous_allowed.push(endpoint);
+        }
+        policy
+            .borrow_mut()
+            .set(updated.clone())
+            .map_err(|_| Error::InvalidOperation {
+                msg: "Failed to store the access policy.".to_string(),
+            })?;
+        Ok(updated)
+    })
+}
+
+// Query function to get the endpoints currently open to anonymous callers
+#[ic_cdk::query]
+fn get_access_policy() -> AccessPolicy {
+    ACCESS_POLICY.with(|policy| policy.borrow().get().clone())
+}
+
+// Function to publish a draft product immediately
+#[ic_cdk::update]
+fn publish_product(id: u64) -> Result<Product, Error> {
+    ensure_admin()?;
+    journal_call("publish_product");
+    let mut product = _get_product(&id).ok_or(Error::NotFound {
+        msg: format!(
+            "Couldn't publish a product with id={}. Product not found",
+            id
+        ),
+    })?;
+    product.status = Some(ProductStatus::Published);
+    product.publish_at = None;
+    product.updated_at = Some(time());
+    do_insert(&product);
+    Ok(product)
+}
+
+// Function to schedule, or cancel with `None`, the automatic publication of a draft product
+#[ic_cdk::update]
+fn schedule_publish(id: u64, publish_at: Option<u64>) -> Result<Product, Error> {
+    ensure_admin()?;
+    journal_call("schedule_publish");
+    let mut product = _get_product(&id).ok_or(Error::NotFound {
+        msg: format!(
+            "Couldn't schedule a product with id={}. Product not found",
+            id
+        ),
+    })?;
+    if product.status() != ProductStatus::Draft {
+        return Err(Error::InvalidOperation {
+            msg: format!("Product with id={} is already published", id),
+        });
+    }
+    product.publish_at = publish_at;
+    product.updated_at = Some(time());
+    do_insert(&product);
+    Ok(product)
+}
+
+// Query function to list draft products that are not yet public
+#[ic_cdk::query]
+fn list_draft_products() -> Result<Vec<Product>, Error> {
+    ensure_admin()?;
+    Ok(visible_products(|product| {
+        product.status() == ProductStatus::Draft
+    }))
+}
+
+// Function to archive products older than `min_age` nanoseconds that sold nothing since
+// `no_sales_since`, returning the ids of the archived products
+#[ic_cdk::update]
+fn archive_stale_products(no_sales_since: u64, min_age: u64) -> Result<Vec<u64>, Error> {
+    ensure_admin()?;
+    journal_call("archive_stale_products");
+    let now = time();
+
+    // Products that moved in the period, from the daily rollups and the events not yet rolled up
+    let mut sold = std::collections::HashSet::new();
+    DAILY_SALES.with(|service| {
+        for ((_, product_id), sales) in service
+            .borrow()
+            .range((no_sales_since / NANOS_PER_DAY, 0)..)
+        {
+            if sales.units > 0 {
+                sold.insert(product_id);
+            }
+        }
+    });
+    SALE_EVENTS.with(|service| {
+        for (_, event) in service.borrow().iter() {
+            if event.at >= no_sales_since && event.units > 0 {
+                sold.insert(event.product_id);
+            }
+        }
+    });
+    MONTHLY_SALES.with(|service| {
+        for ((_, product_id), sales) in service
+            .borrow()
+            .range((month_of_day(no_sales_since / NANOS_PER_DAY), 0)..)
+        {
+            if sales.units > 0 {
+                sold.insert(product_id);
+            }
+        }
+    });
+
+    let stale: Vec<Product> = STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| {
+                product.status() != ProductStatus::Archived
+                    && product.created_at.saturating_add(min_age) <= now
+                    && !sold.contains(&product.id)
+            })
+            .collect()
+    });
+    let mut archived = Vec::with_capacity(stale.len());
+    for mut product in stale {
+        product.status = Some(ProductStatus::Archived);
+        product.publish_at = None;
+        product.updated_at = Some(now);
+        do_insert(&product);
+        archived.push(product.id);
+    }
+    Ok(archived)
+}
+
+// Query function to list archived products
+#[ic_cdk::query]
+fn list_archived_products() -> Result<Vec<Product>, Error> {
+    ensure_admin()?;
+    Ok(visible_products(|product| {
+        product.status() == ProductStatus::Archived
+    }))
+}
+
+// Helper function to escape text before embedding it in HTML
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// Helper function to format a price in the smallest currency unit with two decimals
+fn format_price(price: u64) -> String {
+    format!("{}.{:02}", price / 100, price % 100)
+}
+
+// Helper function to build an HTML page response
+fn html_response(status_code: u16, title: &str, body: &str) -> HttpResponse {
+    let page = format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <title>{}</title></head><body>{}</body></html>",
+        escape_html(title),
+        body
+    );
+    HttpResponse {
+        status_code,
+        headers: vec![(
+            "Content-Type".to_string(),
+            "text/html; charset=utf-8".to_string(),
+        )],
+        body: page.into_bytes(),
+    }
+}
+
+// Helper function to render the shelf label page of a published product
+fn render_product_page(product: &Product, available: i64) -> HttpResponse {
+    let allergens = if product.allergens().is_empty() {
+        "None declared".to_string()
+    } else {
+        escape_html(&product.allergens().join(", "))
+    };
+    let availability = if available > 0 {
+        format!("{} available", available)
+    } else {
+        "Sold out".to_string()
+    };
+    let body = format!(
+        "<h1>{}</h1><p>Category: {}</p><p>Price: {}</p><p>Allergens: {}</p><p>{}</p>",
+        escape_html(&product.name),
+        escape_html(product.category.name()),
+        format_price(product.price()),
+        allergens,
+        availability
+    );
+    html_response(200, &product.name, &body)
+}
+
+// Function rendering the published catalog with its availability as JSON
+fn render_catalog_json() -> HttpResponse {
+    let now = time();
+    let reserved = reserved_quantities();
+    let products: Vec<serde_json::Value> = STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, product)| with_effective_status(product, now))
+            .filter(|product| is_listed(product, false))
+            .map(|product| {
+                let reserved = reserved.get(&product.id).copied().unwrap_or(0);
+                serde_json::json!({
+                    "id": product.id,
+                    "name": product.name,
+                    "category": product.category,
+                    "price": product.price,
+                    "allergens": product.allergens,
+                    "age_restricted": is_age_restricted(&product),
+                    "available": availability_of(&product, reserved).available.max(0),
+                })
+            })
+            .collect()
+    });
+    HttpResponse {
+        status_code: 200,
+        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+        body: serde_json::json!({ "products": products })
+            .to_string()
+            .into_bytes(),
+    }
+}
+
+// Helper function to add caching headers to a catalog response, answering 304 Not Modified when
+// the client already holds it; the ETag combines the catalog version with a digest of the body,
+// which also changes with time-based effects such as scheduled publishing
+fn with_cache_headers(request: &HttpRequest, mut response: HttpResponse) -> HttpResponse {
+    let cache_control = (
+        "Cache-Control".to_string(),
+        format!("public, max-age={}", CATALOG_CACHE_MAX_AGE_SECONDS),
+    );
+    if response.status_code != 200 {
+        // Short-lived caching of misses keeps repeated probes of unknown pages at the boundary
+        response.headers.push(cache_control);
+        return response;
+    }
+    let version = CATALOG_VERSION.with(|counter| *counter.borrow().get());
+    let etag = format!("\"{:x}-{:016x}\"", version, fnv1a(response.body.iter()));
+    let not_modified = request
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("If-None-Match"))
+        .flat_map(|(_, value)| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == etag || tag == "*");
+    let headers = vec![cache_control, ("ETag".to_string(), etag)];
+    if not_modified {
+        return HttpResponse {
+            status_code: 304,
+            headers,
+            body: Vec::new(),
+        };
+    }
+    response.headers.extend(headers);
+    response
+}
+
+// Helper function to advance the catalog version after products change; the published catalog
+// is checked for changes a little later
+fn bump_catalog_version() {
+    CATALOG_VERSION.with(|counter| {
+        let version = *counter.borrow().get();
+        counter
+            .borrow_mut()
+            .set(version + 1)
+            .expect("Cannot advance the catalog version");
+    });
+    let mut published = published_catalog();
+    if published.check_at.is_none() {
+        published.check_at = Some(time() + BUILD_HOOK_DEBOUNCE_NS);
+        store_published_catalog(published);
+        schedule_next_job();
+    }
+}
+
+// Query function serving the catalog as JSON at `/catalog.json`, a simple HTML page per
+// product at `/p/{id}` and the public API under `/api/v1`, with caching headers
+#[ic_cdk::query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    if request.method != "GET" {
+        return HttpResponse {
+            status_code: 405,
+            headers: vec![("Allow".to_string(), "GET".to_string())],
+            body: Vec::new(),
+        };
+    }
+    let response = route_http_request(&request);
+    with_cache_headers(&request, response)
+}
+
+// Function to render the catalog response for a request path
+fn route_http_request(request: &HttpRequest) -> HttpResponse {
+    let not_found = || html_response(404, "Not found", "<h1>Not found</h1>");
+    let path = request.url.split(['?', '#']).next().unwrap_or_default();
+    if let Some(rest) = path.strip_prefix("/api/v1") {
+        return route_api_v1(request, rest);
+    }
+    if path == "/catalog.json" {
+        return render_catalog_json();
+    }
+    let Some(id) = path
+        .strip_prefix("/p/")
+        .and_then(|id| id.trim_end_matches('/').parse::<u64>().ok())
+    else {
+        return not_found();
+    };
+    let now = time();
+    match _get_product(&id).map(|product| with_effective_status(product, now)) {
+        Some(product) if product.status() == ProductStatus::Published => {
+            let reserved = reserved_quantities().get(&id).copied().unwrap_or(0);
+            render_product_page(&product, availability_of(&product, reserved).available)
+        }
+        _ => not_found(),
+    }
+}
+
+// Function to set, or clear with `None`, the ICRC-7 canister minting order receipts
+#[ic_cdk::update]
+fn set_nft_canister(nft_canister: Option<Principal>) -> Result<(), Error> {
+    ensure_admin()?;
+    journal_call("set_nft_canister");
+    update_config(|config| config.nft_canister = nft_canister)?;
+    Ok(())
+}
+
+// Function to mint a collectible ICRC-7 receipt for a special order, owned by its customer
+#[ic_cdk::update]
+async fn mint_order_nft(order_id: u64) -> Result<OrderNft, Error> {
+    ensure_admin()?;
+    journal_call("mint_order_nft");
+    let nft_canister = CONFIG
+        .with(|config| config.borrow().get().nft_canister)
+        .ok_or(Error::InvalidOperation {
+            msg: "No NFT canister is configured for receipts.".to_string(),
+        })?;
+    let order = _get_order(&order_id).ok_or(Error::NotFound {
+        msg: format!("An order with id={} was not found", order_id),
+    })?;
+    if order.nft_receipt.is_some() {
+        return Err(Error::InvalidOperation {
+            msg: format!("A receipt was already minted for order id={}", order_id),
+        });
+    }
+    if order.status == OrderStatus::Cancelled {
+        return Err(Error::InvalidOperation {
+            msg: format!("Order with id={} is cancelled", order_id),
+        });
+    }
+    if !MINTS_IN_FLIGHT.with(|mints| mints.borrow_mut().insert(order_id)) {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "A receipt is already being minted for order id={}",
+                order_id
+            ),
+        });
+    }
+
+    let args = NftMintArgs {
+        token_id: Nat::from(order_id),
+        owner: Account {
+            owner: order.customer,
+            subaccount: None,
+        },
+        metadata: vec![
+            (
+                "order_id".to_string(),
+                MetadataValue::Nat(Nat::from(order_id)),
+            ),
+            (
+                "total".to_string(),
+                MetadataValue::Nat(Nat::from(order.total)),
+            ),
+            (
+                "issued_at".to_string(),
+                MetadataValue::Nat(Nat::from(order.created_at)),
+            ),
+        ],
+    };
+    let result: Result<(Result<Nat, String>,), _> =
+        ic_cdk::call(nft_canister, "icrc7_mint", (args,)).await;
+    MINTS_IN_FLIGHT.with(|mints| mints.borrow_mut().remove(&order_id));
+
+    match result {
+        Ok((Ok(_),)) => {
+            // Re-read the order, it may have changed while the call was in flight
+            let mut order = _get_order(&order_id).ok_or(Error::NotFound {
+                msg: format!("An order with id={} was not found", order_id),
+            })?;
+            let receipt = OrderNft {
+                order_id,
+                nft_canister,
+                token_id: order_id,
+            };
+            order.nft_receipt = Some(receipt.clone());
+            order.updated_at = Some(time());
+            store_order(order);
+            Ok(receipt)
+        }
+        Ok((Err(msg),)) => Err(Error::ExternalCallFailed {
+            msg: format!("NFT canister rejected the mint: {}", msg),
+        }),
+        Err((code, msg)) => Err(Error::ExternalCallFailed {
+            msg: format!("Failed to call the NFT canister ({:?}): {}", code, msg),
+        }),
+    }
+}
+
+// Query function to get the collectible receipt minted for an order
+#[ic_cdk::query]
+fn get_order_nft(order_id: u64) -> Result<OrderNft, Error> {
+    get_order(order_id)?.nft_receipt.ok_or(Error::NotFound {
+        msg: format!("No receipt was minted for order id={}", order_id),
+    })
+}
+
+// Helper function to get the units of a product held at a back-of-house location
+fn back_location_quantity(product_id: u64, location: Location) -> u32 {
+    LOCATION_STOCK.with(|service| {
+        service
+            .borrow()
+            .get(&(product_id, location.code()))
+            .unwrap_or(0)
+    })
+}
+
+// Helper function to set the units of a product held at a back-of-house location
+fn set_back_location_quantity(product_id: u64, location: Location, quantity: u32) {
+    LOCATION_STOCK.with(|service| {
+        let mut locations = service.borrow_mut();
+        if quantity == 0 {
+            locations.remove(&(product_id, location.code()));
+        } else {
+            locations.insert((product_id, location.code()), quantity);
+        }
+    });
+}
+
+// Helper function to get the units of a product held at any location
+fn location_quantity(product: &Product, location: Location) -> u32 {
+    match location {
+        Location::FrontShelf => {
+            let back: u32 = Location::BACK_OF_HOUSE
+                .iter()
+                .map(|location| back_location_quantity(product.id, *location))
+                .sum();
+            product.quantity.saturating_sub(back)
+        }
+        _ => back_location_quantity(product.id, location),
+    }
+}
+
+// Function to shrink back-of-house stock so that it never exceeds the product's total quantity;
+// stock leaving without a location is therefore taken from the front shelf first
+fn fit_locations(product_id: u64, quantity: u32) {
+    let mut remaining = quantity;
+    for location in Location::BACK_OF_HOUSE {
+        let held = back_location_quantity(product_id, location);
+        if held > remaining {
+            set_back_location_quantity(product_id, location, remaining);
+        }
+        remaining -= held.min(remaining);
+    }
+}
+
+// Query function to get the stock of a product at every location
+#[ic_cdk::query]
+fn get_location_stock(product_id: u64) -> Result<Vec<LocationStock>, Error> {
+    let product = _get_visible_product(&product_id).ok_or(Error::NotFound {
+        msg: format!("A product with id={} was not found", product_id),
+    })?;
+    Ok([
+        Location::FrontShelf,
+        Location::BackFridge,
+        Location::Freezer,
+    ]
+    .into_iter()
+    .map(|location| LocationStock {
+        location,
+        quantity: location_quantity(&product, location),
+    })
+    .collect())
+}
+
+// Function to move stock of a product between two locations
+#[ic_cdk::update]
+fn transfer_stock(
+    product_id: u64,
+    from: Location,
+    to: Location,
+    amount: u32,
+) -> Result<Vec<LocationStock>, Error> {
+    ensure_caller_allowed(Endpoint::TransferStock)?;
+    journal_call("transfer_stock");
+    if amount == 0 || from == to {
+        return Err(Error::InvalidOperation {
+            msg: "Transfers need a positive amount and two different locations.".to_string(),
+        });
+    }
+    let product = _get_product(&product_id).ok_or(Error::NotFound {
+        msg: format!(
+            "Couldn't transfer stock of product with id={}. Product not found",
+            product_id
+        ),
+    })?;
+    let held = location_quantity(&product, from);
+    if amount > held {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Cannot transfer more than held at {:?}. Held: {}, Trying to transfer: {}",
+                from, held, amount
+            ),
+        });
+    }
+
+    if from != Location::FrontShelf {
+        set_back_location_quantity(product_id, from, held - amount);
+    }
+    if to != Location::FrontShelf {
+        let held = back_location_quantity(product_id, to);
+        set_back_location_quantity(product_id, to, held + amount);
+    }
+    get_location_stock(product_id)
+}
+
+// Function to set units of a product aside for display, or with a negative `delta` put them
+// back on sale, recording the move in the open shift
+fn move_display_units(product_id: u64, delta: i64) -> Result<Availability, Error> {
+    let mut product = _get_product(&product_id).ok_or(Error::NotFound {
+        msg: format!("A product with id={} was not found", product_id),
+    })?;
+    let reserved = reserved_quantities().get(&product_id).copied().unwrap_or(0);
+    let display = display_units_of(&product);
+    let sellable = availability_of(&product, reserved).available.max(0);
+    if delta > sellable {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Only {} units of product id={} are for sale and can be put on display",
+                sellable, product_id
+            ),
+        });
+    }
+    if -delta > display as i64 {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Only {} units of product id={} are on display",
+                display, product_id
+            ),
+        });
+    }
+    let display = (display as i64 + delta) as u32;
+    product.display_units = (display > 0).then_some(display);
+    product.updated_at = Some(time());
+    do_insert(&product);
+    store_stock_movement(StockMovement {
+        product_id,
+        kind: StockMovementKind::Adjustment,
+        quantity_delta: 0,
+        value: 0,
+        by: caller(),
+        order_id: None,
+        at: time(),
+        display_delta: Some(delta),
+    });
+    Ok(availability_of(&product, reserved))
+}
+
+// Function to put units of a product on display, taking them off sale
+#[ic_cdk::update]
+fn move_to_display(product_id: u64, amount: u32) -> Result<Availability, Error> {
+    ensure_caller_allowed(Endpoint::TransferStock)?;
+    journal_call("move_to_display");
+    if amount == 0 {
+        return Err(Error::InvalidOperation {
+            msg: "The amount must be greater than zero.".to_string(),
+        });
+    }
+    move_display_units(product_id, amount as i64)
+}
+
+// Function to take units of a product off display and put them back on sale
+#[ic_cdk::update]
+fn return_from_display(product_id: u64, amount: u32) -> Result<Availability, Error> {
+    ensure_caller_allowed(Endpoint::TransferStock)?;
+    journal_call("return_from_display");
+    if amount == 0 {
+        return Err(Error::InvalidOperation {
+            msg: "The amount must be greater than zero.".to_string(),
+        });
+    }
+    move_display_units(product_id, -(amount as i64))
+        .inspect(|availability| allocate_waitlist(availability.product_id))
+}
+
+// Query function to list the products on the counter display with their front-of-house stock
+#[ic_cdk::query]
+fn list_counter_display() -> Vec<CounterItem> {
+    let reserved = reserved_quantities();
+    visible_products(|product| product.status() == ProductStatus::Published)
+        .into_iter()
+        .filter_map(|product| {
+            // Reserved and display units are set aside from the counter first
+            let front = location_quantity(&product, Location::FrontShelf)
+                .saturating_sub(reserved.get(&product.id).copied().unwrap_or(0))
+                .saturating_sub(display_units_of(&product));
+            (front > 0).then_some(CounterItem {
+                product_id: product.id,
+                price: product.price(),
+                name: product.name,
+                quantity: front,
+            })
+        })
+        .collect()
+}
+
+// Function to validate free-text order notes
+fn validate_notes(notes: &Option<String>) -> Result<(), Error> {
+    if notes
+        .as_ref()
+        .is_some_and(|notes| notes.len() > MAX_NOTES_LENGTH)
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!("Notes can be at most {} bytes long.", MAX_NOTES_LENGTH),
+        });
+    }
+    Ok(())
+}
+
+// Helper function to report open quotes past their expiry as expired
+fn with_quote_expiry(mut quote: Quote, now: u64) -> Quote {
+    if quote.status == QuoteStatus::Open && quote.expires_at <= now {
+        quote.status = QuoteStatus::Expired;
+    }
+    quote
+}
+
+// Function to request a priced quote for the caller; the prices hold until the quote expires
+#[ic_cdk::update]
+fn request_quote(payload: QuotePayload) -> Result<Quote, Error> {
+    ensure_caller_allowed(Endpoint::RequestQuote)?;
+    journal_call("request_quote");
+    validate_notes(&payload.customizations)?;
+
+    let customer = caller();
+    let lines = check_order_lines(&customer, &payload.lines)?;
+    let priced = price_order(&customer, lines);
+    let id = generate_unique_id(|id| QUOTES.with(|service| service.borrow().contains_key(&id)))?;
+    let now = time();
+    let quote = Quote {
+        id,
+        customer,
+        lines: priced.lines,
+        customizations: payload.customizations,
+        subtotal: priced.subtotal,
+        discount: priced.discount,
+        total: priced.subtotal - priced.discount,
+        promotion_id: priced.promotion_id,
+        status: QuoteStatus::Open,
+        order_id: None,
+        created_at: now,
+        expires_at: now.saturating_add(QUOTE_TTL_NS),
+        applied_rules: Some(priced.applied_rules),
+    };
+    QUOTES.with(|service| service.borrow_mut().insert(id, quote.clone()));
+    Ok(quote)
+}
+
+// Function to turn an open quote into an order at the quoted prices
+#[ic_cdk::update]
+fn accept_quote(quote_id: u64) -> Result<Order, Error> {
+    ensure_caller_allowed(Endpoint::AcceptQuote)?;
+    journal_call("accept_quote");
+    let now = time();
+    let mut quote = QUOTES
+        .with(|service| service.borrow().get(&quote_id))
+        .filter(|quote| quote.customer == caller())
+        .map(|quote| with_quote_expiry(quote, now))
+        .ok_or(Error::NotFound {
+            msg: format!("A quote with id={} was not found", quote_id),
+        })?;
+    if quote.status != QuoteStatus::Open {
+        return Err(Error::InvalidOperation {
+            msg: format!("Quote with id={} is {:?}", quote_id, quote.status),
+        });
+    }
+
+    // Stock may have changed since the quote was issued; prices may not
+    let requested: Vec<OrderLinePayload> = quote
+        .lines
+        .iter()
+        .map(|line| OrderLinePayload {
+            product_id: line.product_id,
+            quantity: line.quantity,
+            selections: line.options.as_deref().map(selections_of),
+        })
+        .collect();
+    check_order_lines(&caller(), &requested)?;
+    let priced = PricedOrder {
+        lines: quote.lines.clone(),
+        subtotal: quote.subtotal,
+        discount: quote.discount,
+        promotion_id: quote.promotion_id,
+        applied_rules: quote.applied_rules.clone().unwrap_or_default(),
+        experiments: Vec::new(),
+    };
+    let order = commit_order(
+        quote.customer,
+        priced,
+        quote.customizations.clone(),
+        Some(quote_id),
+        None,
+        None,
+    )?;
+
+    quote.status = QuoteStatus::Accepted;
+    quote.order_id = Some(order.id);
+    QUOTES.with(|service| service.borrow_mut().insert(quote_id, quote));
+    Ok(order)
+}
+
+// Query function to retrieve a quote; customers can only see their own quotes
+#[ic_cdk::query]
+fn get_quote(quote_id: u64) -> Result<Quote, Error> {
+    QUOTES
+        .with(|service| service.borrow().get(&quote_id))
+        .filter(|quote| quote.customer == caller() || ensure_admin().is_ok())
+        .map(|quote| with_quote_expiry(quote, time()))
+        .ok_or(Error::NotFound {
+            msg: format!("A quote with id={} was not found", quote_id),
+        })
+}
+
+// Query function to list the caller's quotes
+#[ic_cdk::query]
+fn list_my_quotes() -> Vec<Quote> {
+    let customer = caller();
+    let now = time();
+    QUOTES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, quote)| quote)
+            .filter(|quote| quote.customer == customer)
+            .map(|quote| with_quote_expiry(quote, now))
+            .collect()
+    })
+}
+
+// Function to append one raw sale event per order line; `sign` is -1 for cancellations
+fn record_sales(lines: &[OrderLine], sign: i64, now: u64) {
+    for line in lines {
+        let event = SaleEvent {
+            at: now,
+            product_id: line.product_id,
+            units: (sign * line.quantity as i64) as i32,
+            revenue: sign * (line.unit_price * line.quantity as u64) as i64,
+        };
+        let seq = SALE_EVENT_SEQ.with(|counter| {
+            let seq = *counter.borrow().get();
+            counter
+                .borrow_mut()
+                .set(seq + 1)
+                .expect("Cannot advance the sale event counter");
+            seq
+        });
+        SALE_EVENTS.with(|service| service.borrow_mut().insert(seq, event));
+    }
+}
+
+// Function to fold a bounded batch of raw sale events into the daily rollups, returning how
+// many events are left
+fn roll_up_sales_events() -> u64 {
+    let batch: Vec<(u64, SaleEvent)> =
+        SALE_EVENTS.with(|service| service.borrow().iter().take(SALES_ROLLUP_BATCH).collect());
+    DAILY_SALES.with(|service| {
+        let mut rollups = service.borrow_mut();
+        for (_, event) in &batch {
+            let key = (event.at / NANOS_PER_DAY, event.product_id);
+            let mut sales = rollups.get(&key).unwrap_or_default();
+            sales.units += event.units as i64;
+            sales.revenue += event.revenue;
+            rollups.insert(key, sales);
+        }
+    });
+    SALE_EVENTS.with(|service| {
+        let mut events = service.borrow_mut();
+        for (seq, _) in &batch {
+            events.remove(seq);
+        }
+        events.len()
+    })
+}
+
+// Function to roll up pending sale events now instead of waiting for the hourly job
+#[ic_cdk::update]
+fn roll_up_sales() -> Result<u64, Error> {
+    ensure_admin()?;
+    journal_call("roll_up_sales");
+    Ok(roll_up_sales_events())
+}
+
+// Query function to get the rolled-up daily sales between two days (inclusive), optionally
+// for a single product; events from the last rollup interval are not included yet, and days
+// past the retention policy are only available as monthly sales
+#[ic_cdk::query]
+fn get_daily_sales(
+    from_day: u64,
+    to_day: u64,
+    product_id: Option<u64>,
+) -> Result<Vec<DailySalesReport>, Error> {
+    ensure_viewer()?;
+    Ok(DAILY_SALES.with(|service| {
+        service
+            .borrow()
+            .range((from_day, 0)..=(to_day, u64::MAX))
+            .filter(|((_, id), _)| product_id.is_none_or(|product_id| *id == product_id))
+            .map(|((day, product_id), sales)| DailySalesReport {
+                day,
+                product_id,
+                units: sales.units,
+                revenue: sales.revenue,
+            })
+            .collect()
+    }))
+}
+
+// Query function to get the interface version so clients can detect compatibility at runtime
+#[ic_cdk::query]
+fn get_api_version() -> ApiVersion {
+    ApiVersion {
+        major: API_VERSION_MAJOR,
+        minor: API_VERSION_MINOR,
+        package_version: env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+// Query function to get the canister's Candid interface, used by tooling such as Candid UI
+#[ic_cdk::query(name = "__get_candid_interface_tmp_hack")]
+fn get_candid_interface() -> String {
+    __export_service()
+}
+
+// Function to drop reservations whose hold has expired
+fn purge_expired_reservations() -> u64 {
+    let now = time();
+    let expired: Vec<u64> = RESERVATIONS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, reservation)| !is_reservation_active(reservation, now))
+            .map(|(id, _)| id)
+            .collect()
+    });
+    let count = expired.len() as u64;
+    let mut products: Vec<u64> = expired
+        .into_iter()
+        .filter_map(|id| remove_reservation(id).map(|reservation| reservation.product_id))
+        .collect();
+    products.sort_unstable();
+    products.dedup();
+    for product_id in products {
+        allocate_waitlist(product_id);
+    }
+    count
+}
+
+// Query function to list the background jobs with their persisted schedule
+#[ic_cdk::query]
+fn list_scheduled_jobs() -> Result<Vec<ScheduledJob>, Error> {
+    ensure_admin()?;
+    let now = time();
+    Ok(Job::ALL
+        .iter()
+        .map(|job| {
+            SCHEDULED_JOBS
+                .with(|service| service.borrow().get(&job.code()))
+                .unwrap_or(ScheduledJob {
+                    job: *job,
+                    interval_ns: job.interval_ns(),
+                    next_run: now.saturating_add(job.interval_ns()),
+                    last_run: None,
+                    last_instructions: None,
+                })
+        })
+        .collect())
+}
+
+// Function to run a background job immediately; its next regular run is pushed back
+#[ic_cdk::update]
+fn run_job_now(job: Job) -> Result<ScheduledJob, Error> {
+    ensure_admin()?;
+    journal_call("run_job_now");
+    let scheduled = run_job(job, time());
+    schedule_next_job();
+    Ok(scheduled)
+}
+
+// Helper function to list the keys of a principal's notifications, oldest first
+fn notification_keys(principal: Principal) -> Vec<(PrincipalKey, u64)> {
+    let key = PrincipalKey(principal);
+    NOTIFICATIONS.with(|service| {
+        service
+            .borrow()
+            .range((key, 0)..=(key, u64::MAX))
+            .map(|(key, _)| key)
+            .collect()
+    })
+}
+
+// Function to store a message in a principal's inbox, dropping the oldest beyond the limit
+fn notify(recipient: Principal, kind: NotificationKind, message: String) {
+    let id = NOTIFICATION_SEQ.with(|counter| {
+        let id = *counter.borrow().get();
+        counter
+            .borrow_mut()
+            .set(id + 1)
+            .expect("Cannot advance the notification counter");
+        id
+    });
+    let notification = Notification {
+        id,
+        kind,
+        message: localize(&recipient, &message),
+        created_at: time(),
+        read: false,
+    };
+    let existing = notification_keys(recipient);
+    NOTIFICATIONS.with(|service| {
+        let mut notifications = service.borrow_mut();
+        let overflow = (existing.len() + 1).saturating_sub(MAX_NOTIFICATIONS_PER_PRINCIPAL);
+        for key in existing.iter().take(overflow) {
+            notifications.remove(key);
+        }
+        notifications.insert((PrincipalKey(recipient), id), notification);
+    });
+}
+
+// Helper function to tell watchers that a product they wait for has stock again
+fn notify_if_back_in_stock(product: &Product, previous_quantity: u32) {
+    if previous_quantity > 0 || product.quantity == 0 {
+        return;
+    }
+    let watchers: Vec<(u64, PrincipalKey)> = STOCK_WATCHERS.with(|service| {
+        service
+            .borrow()
+            .range((product.id, PrincipalKey::default())..)
+            .take_while(|((product_id, _), _)| *product_id == product.id)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    for key in watchers {
+        notify(
+            key.1 .0,
+            NotificationKind::BackInStock {
+                product_id: product.id,
+            },
+            format!("{} is back in stock.", product.name),
+        );
+        STOCK_WATCHERS.with(|service| service.borrow_mut().remove(&key));
+    }
+}
+
+// Helper function to announce a new promotion to the customers it targets
+fn notify_promotion(promotion: &Promotion) {
+    let recipients: Vec<Principal> = CUSTOMERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, customer)| customer)
+            .filter(|customer| {
+                promotion.segments.is_empty() || promotion.segments.contains(&customer.segment)
+            })
+            .map(|customer| customer.principal)
+            .collect()
+    });
+    for recipient in recipients {
+        notify(
+            recipient,
+            NotificationKind::Promotion {
+                promotion_id: promotion.id,
+            },
+            format!(
+                "{}: {}% off your next order.",
+                promotion.name, promotion.percent_off
+            ),
+        );
+    }
+}
+
+// Function to drop notifications older than the retention period
+fn purge_old_notifications() {
+    let cutoff = time().saturating_sub(NOTIFICATION_RETENTION_NS);
+    NOTIFICATIONS.with(|service| {
+        let expired: Vec<(PrincipalKey, u64)> = service
+            .borrow()
+            .iter()
+            .filter(|(_, notification)| notification.created_at < cutoff)
+            .map(|(key, _)| key)
+            .collect();
+        let mut notifications = service.borrow_mut();
+        for key in expired {
+            notifications.remove(&key);
+        }
+    });
+}
+
+// Query function to get a page of the caller's notifications, newest first
+#[ic_cdk::query]
+fn get_my_notifications(page: u32) -> NotificationPage {
+    let key = PrincipalKey(caller());
+    let mut notifications: Vec<Notification> = NOTIFICATIONS.with(|service| {
+        service
+            .borrow()
+            .range((key, 0)..=(key, u64::MAX))
+            .map(|(_, notification)| notification)
+            .collect()
+    });
+    let total = notifications.len() as u32;
+    let unread = notifications
+        .iter()
+        .filter(|notification| !notification.read)
+        .count() as u32;
+    notifications.reverse();
+    let staff = ensure_admin().is_ok();
+    let threads: Vec<OrderThread> = ORDER_THREADS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, thread)| thread)
+            .filter(|thread| {
+                if thread.customer == key.0 {
+                    thread.customer_unread > 0
+                } else {
+                    staff && thread.staff_unread > 0
+                }
+            })
+            .collect()
+    });
+    NotificationPage {
+        notifications: notifications
+            .into_iter()
+            .skip(page as usize * NOTIFICATIONS_PAGE_SIZE)
+            .take(NOTIFICATIONS_PAGE_SIZE)
+            .collect(),
+        page,
+        total,
+        unread,
+        unread_order_messages: (!threads.is_empty()).then_some(threads),
+    }
+}
+
+// Function to mark some of the caller's notifications as read, returning how many changed
+#[ic_cdk::update]
+fn mark_read(ids: Vec<u64>) -> Result<u32, Error> {
+    ensure_caller_allowed(Endpoint::WatchProduct)?;
+    journal_call("mark_read");
+    let key = PrincipalKey(caller());
+    Ok(NOTIFICATIONS.with(|service| {
+        let mut notifications = service.borrow_mut();
+        let mut marked = 0;
+        for id in ids {
+            if let Some(mut notification) = notifications.get(&(key, id)) {
+                if !notification.read {
+                    notification.read = true;
+                    notifications.insert((key, id), notification);
+                    marked += 1;
+                }
+            }
+        }
+        marked
+    }))
+}
+
+// Function to get notified in the inbox when a sold-out product is restocked
+#[ic_cdk::update]
+fn notify_when_back_in_stock(product_id: u64) -> Result<(), Error> {
+    ensure_caller_allowed(Endpoint::WatchProduct)?;
+    journal_call("notify_when_back_in_stock");
+    let product = _get_visible_product(&product_id).ok_or(Error::NotFound {
+        msg: format!("A product with id={} was not found", product_id),
+    })?;
+    if product.quantity > 0 {
+        return Err(Error::InvalidOperation {
+            msg: format!("Product with id={} is in stock", product_id),
+        });
+    }
+    STOCK_WATCHERS.with(|service| {
+        service
+            .borrow_mut()
+            .insert((product_id, PrincipalKey(caller())), ())
+    });
+    Ok(())
+}
+
+// Function to change the pagination limits of list queries
+#[ic_cdk::update]
+fn set_pagination_config(pagination: PaginationConfig) -> Result<PaginationConfig, Error> {
+    ensure_admin()?;
+    journal_call("set_pagination_config");
+    if pagination.default_page_size == 0
+        || pagination.default_page_size > pagination.max_page_size
+        || pagination.response_budget_bytes == 0
+        || pagination.response_budget_bytes > MAX_RESPONSE_BUDGET_BYTES
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Page sizes must satisfy 0 < default <= max and the budget must be between 1 and {} bytes.",
+                MAX_RESPONSE_BUDGET_BYTES
+            ),
+        });
+    }
+    update_config(|config| config.pagination = Some(pagination))?;
+    Ok(pagination)
+}
+
+// Query function to get the pagination limits in effect
+#[ic_cdk::query]
+fn get_pagination_config() -> PaginationConfig {
+    pagination_config()
+}
+
+// Helper function to derive the escrow subaccount of an order from its id
+fn escrow_subaccount(order_id: u64) -> Vec<u8> {
+    let mut subaccount = vec![0u8; 32];
+    subaccount[24..].copy_from_slice(&order_id.to_be_bytes());
+    subaccount
+}
+
+// Helper function to get the account escrowed payments are released to
+fn shop_account() -> Account {
+    CONFIG
+        .with(|config| config.borrow().get().shop_account.clone())
+        .unwrap_or(Account {
+            owner: ic_cdk::id(),
+            subaccount: None,
+        })
+}
+
+// Function to set, or reset with `None`, the account escrowed payments are released to
+#[ic_cdk::update]
+fn set_shop_account(account: Option<Account>) -> Result<Account, Error> {
+    ensure_admin()?;
+    journal_call("set_shop_account");
+    if account
+        .as_ref()
+        .and_then(|account| account.subaccount.as_ref())
+        .is_some_and(|subaccount| subaccount.len() != 32)
+    {
+        return Err(Error::InvalidOperation {
+            msg: "Subaccounts must be 32 bytes long.".to_string(),
+        });
+    }
+    update_config(|config| config.shop_account = account)?;
+    Ok(shop_account())
+}
+
+// Helper function to get the escrowed payment of an order visible to the caller
+fn order_escrow(order_id: u64) -> Result<(Order, OrderPayment, Escrow), Error> {
+    let order = get_order(order_id)?;
+    match order.payment.clone() {
+        Some(payment) => {
+            let escrow = payment.escrow.clone().ok_or(Error::NotFound {
+                msg: format!("Order with id={} has no escrow", order_id),
+            })?;
+            Ok((order, payment, escrow))
+        }
+        None => Err(Error::NotFound {
+            msg: format!("Order with id={} is not paid with a token", order_id),
+        }),
+    }
+}
+
+// Query function to get the account a customer pays an order into
+#[ic_cdk::query]
+fn get_payment_account(order_id: u64) -> Result<Account, Error> {
+    let (_, _, escrow) = order_escrow(order_id)?;
+    Ok(Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(escrow.subaccount),
+    })
+}
+
+// Helper function to ask a ledger for the balance of an account
+async fn ledger_balance(ledger: Principal, account: Account) -> Result<Nat, String> {
+    let result: Result<(Nat,), _> = ic_cdk::call(ledger, "icrc1_balance_of", (account,)).await;
+    result
+        .map(|(balance,)| balance)
+        .map_err(|(code, msg)| format!("Failed to query the ledger balance ({:?}): {}", code, msg))
+}
+
+// Function to confirm that a customer has paid an order into its escrow subaccount
+#[ic_cdk::update]
+async fn confirm_payment(order_id: u64) -> Result<Order, Error> {
+    ensure_caller_allowed(Endpoint::ConfirmPayment)?;
+    journal_call("confirm_payment");
+    ensure_payments_open()?;
+    let (order, payment, escrow) = order_escrow(order_id)?;
+    if order.status == OrderStatus::Cancelled || escrow.status != EscrowStatus::AwaitingPayment {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Payment of order id={} cannot be confirmed while it is {:?}",
+                order_id, escrow.status
+            ),
+        });
+    }
+
+    let account = Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(escrow.subaccount),
+    };
+    let balance = ledger_balance(payment.ledger, account)
+        .await
+        .map_err(|msg| Error::ExternalCallFailed { msg })?;
+    if balance < payment.amount {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Received {} of the {} {} due for order id={}",
+                balance, payment.amount, payment.symbol, order_id
+            ),
+        });
+    }
+
+    mark_payment_held(order_id)
+}
+
+// Function to record that the payment of an order reached its escrow
+fn mark_payment_held(order_id: u64) -> Result<Order, Error> {
+    // Re-read the order, it may have changed while a ledger call was in flight
+    let mut order = _get_order(&order_id).ok_or(Error::NotFound {
+        msg: format!("An order with id={} was not found", order_id),
+    })?;
+    if let Some(escrow) = order
+        .payment
+        .as_mut()
+        .and_then(|payment| payment.escrow.as_mut())
+        .filter(|escrow| escrow.status == EscrowStatus::AwaitingPayment)
+    {
+        escrow.status = EscrowStatus::Held;
+        order.updated_at = Some(time());
+        store_order(order.clone());
+        notify_staff(NotifierEvent::OrderPaid, &order);
+        post_transfer(
+            format!("Payment of order #{} held in escrow", order_id),
+            Some(order_id),
+            LedgerAccount::Cash,
+            LedgerAccount::Receivables,
+            remaining_due(&order.tenders, order.total),
+        );
+    }
+    Ok(order)
+}
+
+// Helper function to start releasing or refunding an order's escrow as its status changes;
+// returns whether a settlement was requested
+fn request_escrow_settlement(order: &mut Order, status: OrderStatus, now: u64) -> bool {
+    let Some(escrow) = order
+        .payment
+        .as_mut()
+        .and_then(|payment| payment.escrow.as_mut())
+    else {
+        return false;
+    };
+    let next = match (status, escrow.status) {
+        (OrderStatus::PickedUp | OrderStatus::Delivered, EscrowStatus::Held) => {
+            EscrowStatus::Releasing
+        }
+        // Customers may have paid without confirming, so unconfirmed escrows are refunded too
+        (OrderStatus::Cancelled, EscrowStatus::AwaitingPayment | EscrowStatus::Held) => {
+            EscrowStatus::Refunding
+        }
+        _ => return false,
+    };
+    escrow.status = next;
+    escrow.settle_requested_at = Some(now);
+    PENDING_SETTLEMENTS.with(|service| service.borrow_mut().insert(order.id, ()));
+    true
+}
+
+// Function to transfer an order's escrowed balance to the shop or back to the customer,
+// leaving the settlement pending with its error when the ledger cannot complete it
+async fn settle_escrow(order_id: u64) {
+    // Settlements stay queued while payments are paused
+    if payments_pause().is_some() {
+        return;
+    }
+    if !SETTLEMENTS_IN_FLIGHT.with(|settlements| settlements.borrow_mut().insert(order_id)) {
+        return;
+    }
+    let outcome = transfer_escrow(order_id).await;
+    SETTLEMENTS_IN_FLIGHT.with(|settlements| settlements.borrow_mut().remove(&order_id));
+
+    let mut order = _get_order(&order_id);
+    let Some(escrow) = order
+        .as_mut()
+        .and_then(|order| order.payment.as_mut())
+        .and_then(|payment| payment.escrow.as_mut())
+        .filter(|escrow| {
+            matches!(
+                escrow.status,
+                EscrowStatus::Releasing | EscrowStatus::Refunding
+            )
+        })
+    else {
+        // Nothing is left to settle for this order
+        PENDING_SETTLEMENTS.with(|service| service.borrow_mut().remove(&order_id));
+        return;
+    };
+    match outcome {
+        Ok(block_index) => {
+            escrow.status = match escrow.status {
+                EscrowStatus::Refunding => EscrowStatus::Refunded,
+                _ => EscrowStatus::Released,
+            };
+            escrow.block_index = block_index;
+            escrow.last_error = None;
+            PENDING_SETTLEMENTS.with(|service| service.borrow_mut().remove(&order_id));
+        }
+        Err((retry_at, msg)) => {
+            if retry_at.is_some() {
+                escrow.settle_requested_at = retry_at;
+            }
+            escrow.last_error = Some(msg);
+        }
+    }
+    if let Some(mut order) = order {
+        order.updated_at = Some(time());
+        store_order(order);
+    }
+}
+
+// Helper function to make the ledger transfer settling an escrow; fails with a new transfer
+// creation time when the previous one became too old to retry
+async fn transfer_escrow(order_id: u64) -> Result<Option<Nat>, (Option<u64>, String)> {
+    let order = _get_order(&order_id).ok_or((None, "Order not found".to_string()))?;
+    let payment = order
+        .payment
+        .clone()
+        .ok_or((None, "Order is not paid with a token".to_string()))?;
+    let escrow = payment
+        .escrow
+        .clone()
+        .ok_or((None, "Order has no escrow".to_string()))?;
+    let to = match escrow.status {
+        EscrowStatus::Releasing => shop_account(),
+        EscrowStatus::Refunding => Account {
+            owner: order.customer,
+            subaccount: None,
+        },
+        status => return Err((None, format!("Escrow is {:?}", status))),
+    };
+
+    let from = Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(escrow.subaccount.clone()),
+    };
+    let balance = ledger_balance(payment.ledger, from)
+        .await
+        .map_err(|msg| (None, msg))?;
+    let fee: Result<(Nat,), _> = ic_cdk::call(payment.ledger, "icrc1_fee", ()).await;
+    let (fee,) = fee.map_err(|(code, msg)| {
+        (
+            None,
+            format!("Failed to query the ledger fee ({:?}): {}", code, msg),
+        )
+    })?;
+    // Nothing left to move, e.g. an unpaid order or a transfer that already went through
+    if balance <= fee {
+        return Ok(None);
+    }
+
+    let mut balance = balance;
+    let refund = partial_refund_amount(&order, &payment).min(balance.clone());
+    if escrow.status == EscrowStatus::Releasing
+        && escrow.partial_refund_block.is_none()
+        && refund > fee
+    {
+        // Unfulfillable lines go back to the customer before the rest is released to the shop
+        let mut memo = order_id.to_be_bytes().to_vec();
+        memo.push(1);
+        let args = TransferArg {
+            from_subaccount: Some(escrow.subaccount.clone()),
+            to: Account {
+                owner: order.customer,
+                subaccount: None,
+            },
+            amount: refund.clone() - fee.clone(),
+            fee: Some(fee.clone()),
+            memo: Some(memo),
+            created_at_time: escrow.settle_requested_at.map(ledger_time),
+        };
+        let block_index = ledger_transfer(payment.ledger, args).await?;
+        ORDERS.with(|service| {
+            let mut orders = service.borrow_mut();
+            if let Some(mut order) = orders.get(&order_id) {
+                if let Some(escrow) = order
+                    .payment
+                    .as_mut()
+                    .and_then(|payment| payment.escrow.as_mut())
+                {
+                    escrow.partial_refund_block = Some(block_index);
+                }
+                orders.insert(order_id, order);
+            }
+        });
+        balance -= refund;
+        if balance <= fee {
+            return Ok(None);
+        }
+    }
+    let kept = cancellation_fee_amount(&order, &payment).min(balance.clone());
+    if escrow.status == EscrowStatus::Refunding
+        && escrow.cancellation_fee_block.is_none()
+        && kept > fee
+    {
+        // The cancellation fee goes to the shop before the rest is refunded to the customer
+        let mut memo = order_id.to_be_bytes().to_vec();
+        memo.push(2);
+        let args = TransferArg {
+            from_subaccount: Some(escrow.subaccount.clone()),
+            to: shop_account(),
+            amount: kept.clone() - fee.clone(),
+            fee: Some(fee.clone()),
+            memo: Some(memo),
+            created_at_time: escrow.settle_requested_at.map(ledger_time),
+        };
+        let block_index = ledger_transfer(payment.ledger, args).await?;
+        ORDERS.with(|service| {
+            let mut orders = service.borrow_mut();
+            if let Some(mut order) = orders.get(&order_id) {
+                if let Some(escrow) = order
+                    .payment
+                    .as_mut()
+                    .and_then(|payment| payment.escrow.as_mut())
+                {
+                    escrow.cancellation_fee_block = Some(block_index);
+                }
+                orders.insert(order_id, order);
+            }
+        });
+        balance -= kept;
+        if balance <= fee {
+            return Ok(None);
+        }
+    }
+
+    let args = TransferArg {
+        from_subaccount: Some(escrow.subaccount),
+        to,
+        amount: balance - fee.clone(),
+        fee: Some(fee),
+        memo: Some(order_id.to_be_bytes().to_vec()),
+        created_at_time: escrow.settle_requested_at.map(ledger_time),
+    };
+    ledger_transfer(payment.ledger, args).await.map(Some)
+}
+
+// Helper function to make an ICRC-1 transfer, returning its block index; fails with a new
+// transfer creation time when the previous one became too old to retry
+async fn ledger_transfer(
+    ledger: Principal,
+    args: TransferArg,
+) -> Result<Nat, (Option<u64>, String)> {
+    let result: Result<(Result<Nat, TransferError>,), _> =
+        ic_cdk::call(ledger, "icrc1_transfer", (args,)).await;
+    match result {
+        Ok((Ok(block_index),)) => Ok(block_index),
+        Ok((Err(TransferError::Duplicate { duplicate_of }),)) => Ok(duplicate_of),
+        Ok((Err(TransferError::TooOld),)) => {
+            Err((Some(time()), "Transfer too old, retrying".to_string()))
+        }
+        Ok((Err(error),)) => Err((None, format!("Ledger rejected the transfer: {:?}", error))),
+        Err((code, msg)) => Err((
+            None,
+            format!("Failed to call the ledger ({:?}): {}", code, msg),
+        )),
+    }
+}
+
+// Function to retry a batch of pending escrow releases and refunds
+fn retry_escrow_settlements() {
+    let pending: Vec<u64> = PENDING_SETTLEMENTS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(order_id, _)| order_id)
+            .take(ESCROW_SETTLE_BATCH)
+            .collect()
+    });
+    for order_id in pending {
+        ic_cdk::spawn(settle_escrow(order_id));
+    }
+}
+
+// Function to record a balanced journal entry and update the account totals; an unbalanced
+// entry is a bug and traps so the whole operation is rolled back
+fn post_entry(memo: String, order_id: Option<u64>, postings: Vec<Posting>) {
+    let debits: u64 = postings.iter().map(|posting| posting.debit).sum();
+    let credits: u64 = postings.iter().map(|posting| posting.credit).sum();
+    assert_eq!(debits, credits, "Unbalanced journal entry: {}", memo);
+    if debits == 0 {
+        return;
+    }
+
+    let id = JOURNAL_SEQ.with(|counter| {
+        let id = *counter.borrow().get();
+        counter
+            .borrow_mut()
+            .set(id + 1)
+            .expect("Cannot increment the journal counter");
+        id
+    });
+    ACCOUNT_TOTALS.with(|service| {
+        let mut totals = service.borrow_mut();
+        for posting in &postings {
+            let mut account = totals.get(&posting.account.code()).unwrap_or_default();
+            account.debits += posting.debit;
+            account.credits += posting.credit;
+            totals.insert(posting.account.code(), account);
+        }
+    });
+    let entry = JournalEntry {
+        id,
+        at: time(),
+        memo,
+        order_id,
+        postings,
+    };
+    JOURNAL.with(|service| service.borrow_mut().insert(id, entry));
+}
+
+// Helper function to post an amount debited to one account and credited to another
+fn post_transfer(
+    memo: String,
+    order_id: Option<u64>,
+    debit: LedgerAccount,
+    credit: LedgerAccount,
+    amount: u64,
+) {
+    post_entry(
+        memo,
+        order_id,
+        vec![
+            Posting {
+                account: debit,
+                debit: amount,
+                credit: 0,
+            },
+            Posting {
+                account: credit,
+                debit: 0,
+                credit: amount,
+            },
+        ],
+    );
+}
+
+// Function to post the ledger entries of an order moving to a new status; refunds already given
+// back to the customer's balances are left out
+fn post_order_status_change(order: &Order, status: OrderStatus, refunded_to_balances: u64) {
+    let escrow_status = order
+        .payment
+        .as_ref()
+        .and_then(|payment| payment.escrow.as_ref())
+        .map(|escrow| escrow.status);
+    match status {
+        // Orders not paid with a token are settled at the counter
+        OrderStatus::PickedUp | OrderStatus::Delivered if order.payment.is_none() => post_transfer(
+            format!("Order #{} paid at the counter", order.id),
+            Some(order.id),
+            LedgerAccount::Cash,
+            LedgerAccount::Receivables,
+            cash_due(order),
+        ),
+        OrderStatus::Cancelled => {
+            let credit = match escrow_status {
+                Some(EscrowStatus::Held) => LedgerAccount::Cash,
+                _ => LedgerAccount::Receivables,
+            };
+            // A cancellation fee stays with the shop
+            let fee = order
+                .cancellation
+                .as_ref()
+                .map_or(0, |cancellation| cancellation.fee);
+            post_transfer(
+                format!("Order #{} cancelled", order.id),
+                Some(order.id),
+                LedgerAccount::Refunds,
+                credit,
+                amount_due(order)
+                    .saturating_sub(fee)
+                    .saturating_sub(refunded_to_balances),
+            )
+        }
+        _ => {}
+    }
+}
+
+// Helper function to get the debit balance of account totals
+fn debit_balance(totals: &AccountTotals) -> i128 {
+    totals.debits as i128 - totals.credits as i128
+}
+
+// Query function to get the totals and balance of every ledger account
+#[ic_cdk::query]
+fn trial_balance() -> Result<TrialBalance, Error> {
+    ensure_viewer()?;
+    let accounts: Vec<AccountBalance> = ACCOUNT_TOTALS.with(|service| {
+        let totals = service.borrow();
+        LedgerAccount::ALL
+            .iter()
+            .map(|account| {
+                let account_totals = totals.get(&account.code()).unwrap_or_default();
+                AccountBalance {
+                    account: *account,
+                    debits: account_totals.debits,
+                    credits: account_totals.credits,
+                    balance: debit_balance(&account_totals),
+                }
+            })
+            .collect()
+    });
+    Ok(TrialBalance {
+        total_debits: accounts.iter().map(|account| account.debits).sum(),
+        total_credits: accounts.iter().map(|account| account.credits).sum(),
+        accounts,
+    })
+}
+
+// Helper function to find the id of the first journal entry posted at or after a time;
+// entries are never removed and their times never decrease with their ids
+fn first_entry_at_or_after(at: u64) -> u64 {
+    let (mut low, mut high) = (0, JOURNAL_SEQ.with(|counter| *counter.borrow().get()));
+    JOURNAL.with(|service| {
+        let journal = service.borrow();
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if journal.get(&mid).is_some_and(|entry| entry.at < at) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+    });
+    low
+}
+
+// Helper function to get the movement of an account in a journal entry
+fn statement_line(entry: JournalEntry, account: LedgerAccount) -> Option<StatementLine> {
+    let (debit, credit) = entry
+        .postings
+        .iter()
+        .filter(|posting| posting.account == account)
+        .fold(None, |sum: Option<(u64, u64)>, posting| {
+            let (debit, credit) = sum.unwrap_or_default();
+            Some((debit + posting.debit, credit + posting.credit))
+        })?;
+    Some(StatementLine {
+        entry_id: entry.id,
+        at: entry.at,
+        memo: entry.memo,
+        order_id: entry.order_id,
+        debit,
+        credit,
+    })
+}
+
+// Query function to list the movements of a ledger account posted in `[from, to)`, with its
+// balances at the start and end of the period
+#[ic_cdk::query]
+fn account_statement(
+    account: LedgerAccount,
+    from: u64,
+    to: u64,
+    page: Option<PageRequest>,
+) -> Result<AccountStatement, Error> {
+    ensure_viewer()?;
+    if from > to {
+        return Err(Error::InvalidOperation {
+            msg: "A statement period cannot end before it starts.".to_string(),
+        });
+    }
+
+    let start = first_entry_at_or_after(from);
+    let end = first_entry_at_or_after(to);
+    let current = ACCOUNT_TOTALS
+        .with(|service| debit_balance(&service.borrow().get(&account.code()).unwrap_or_default()));
+    // Work back from the current balance over the entries posted since the period started
+    let (mut during, mut after) = (0i128, 0i128);
+    JOURNAL.with(|service| {
+        for (id, entry) in service.borrow().range(start..) {
+            if let Some(line) = statement_line(entry, account) {
+                let movement = line.debit as i128 - line.credit as i128;
+                if id < end {
+                    during += movement;
+                } else {
+                    after += movement;
+                }
+            }
+        }
+    });
+    let closing_balance = current - after;
+
+    let page = page.unwrap_or_default();
+    let first = page.cursor.map_or(start, |cursor| start.max(cursor + 1));
+    let (lines, next_cursor) = JOURNAL.with(|service| {
+        take_page(
+            service
+                .borrow()
+                .range(first..end.max(first))
+                .filter_map(|(id, entry)| statement_line(entry, account).map(|line| (id, line))),
+            &page,
+        )
+    });
+    Ok(AccountStatement {
+        account,
+        from,
+        to,
+        opening_balance: closing_balance - during,
+        closing_balance,
+        lines,
+        next_cursor,
+    })
+}
+
+// Helper function to get the spending limit of a sub-principal, with the spent amount reset
+// when a new day has started
+fn current_spending_limit(principal: &Principal, now: u64) -> Option<SpendingLimit> {
+    SPENDING_LIMITS
+        .with(|service| service.borrow().get(&PrincipalKey(*principal)))
+        .map(|mut limit| {
+            if limit.spent_day != now / NANOS_PER_DAY {
+                limit.spent_day = now / NANOS_PER_DAY;
+                limit.spent = 0;
+            }
+            limit
+        })
+}
+
+// Function to check that a checkout fits in the customer's daily allowance, if they are a
+// sub-principal; returns the account owner the spending counts against
+fn check_spending_limit(
+    customer: &Principal,
+    amount: u64,
+    now: u64,
+) -> Result<Option<Principal>, Error> {
+    let Some(limit) = current_spending_limit(customer, now) else {
+        return Ok(None);
+    };
+    let remaining = limit.daily_cap.saturating_sub(limit.spent);
+    if amount > remaining {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "This order exceeds the daily spending limit. Remaining today: {}, Order total: {}",
+                remaining, amount
+            ),
+        });
+    }
+    Ok(Some(limit.owner))
+}
+
+// Helper function to add to, or give back, the amount a sub-principal spent today
+fn record_spending(customer: &Principal, amount: u64, now: u64, spend: bool) {
+    if let Some(mut limit) = current_spending_limit(customer, now) {
+        limit.spent = if spend {
+            limit.spent.saturating_add(amount)
+        } else {
+            limit.spent.saturating_sub(amount)
+        };
+        SPENDING_LIMITS.with(|service| service.borrow_mut().insert(PrincipalKey(*customer), limit));
+    }
+}
+
+// Helper function to report the allowance left to a sub-principal today
+fn allowance_of(limit: SpendingLimit) -> Allowance {
+    Allowance {
+        principal: limit.principal,
+        owner: limit.owner,
+        daily_cap: limit.daily_cap,
+        spent_today: limit.spent,
+        remaining: limit.daily_cap.saturating_sub(limit.spent),
+        resets_at: (limit.spent_day + 1) * NANOS_PER_DAY,
+    }
+}
+
+// Helper function to ensure sub-principals are managed by signed-in callers
+fn ensure_account_owner() -> Result<Principal, Error> {
+    ensure_not_in_maintenance()?;
+    let owner = caller();
+    if owner == Principal::anonymous() || is_kiosk(&owner) {
+        return Err(Error::Unauthorized {
+            msg: "Anonymous callers cannot manage sub-principals. Please sign in first."
+                .to_string(),
+        });
+    }
+    Ok(owner)
+}
+
+// Helper function to list the sub-principals of an account owner
+fn sub_principals_of(owner: Principal) -> Vec<Principal> {
+    let key = PrincipalKey(owner);
+    OWNER_SUB_PRINCIPALS.with(|service| {
+        service
+            .borrow()
+            .range((key, PrincipalKey::default())..)
+            .take_while(|((owner, _), _)| *owner == key)
+            .map(|((_, sub), _)| sub.0)
+            .collect()
+    })
+}
+
+// Function to add a sub-principal buying on the caller's behalf, or change its daily cap
+#[ic_cdk::update]
+fn set_sub_principal(payload: SubPrincipalPayload) -> Result<SpendingLimit, Error> {
+    let owner = ensure_account_owner()?;
+    journal_call("set_sub_principal");
+    if payload.principal == owner || payload.principal == Principal::anonymous() {
+        return Err(Error::InvalidOperation {
+            msg: "A sub-principal must be a signed-in principal other than its owner.".to_string(),
+        });
+    }
+    if SPENDING_LIMITS.with(|service| service.borrow().contains_key(&PrincipalKey(owner))) {
+        return Err(Error::InvalidOperation {
+            msg: "Sub-principals cannot have sub-principals of their own.".to_string(),
+        });
+    }
+    if payload
+        .label
+        .as_ref()
+        .is_some_and(|label| label.trim().is_empty() || label.len() > MAX_LABEL_LENGTH)
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Sub-principal labels must be non-empty and at most {} bytes long.",
+                MAX_LABEL_LENGTH
+            ),
+        });
+    }
+
+    let now = time();
+    let existing = current_spending_limit(&payload.principal, now);
+    let limit = match existing {
+        Some(limit) if limit.owner != owner => {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "{} is already a sub-principal of another account",
+                    payload.principal
+                ),
+            })
+        }
+        Some(limit) => SpendingLimit {
+            label: payload.label,
+            daily_cap: payload.daily_cap,
+            updated_at: Some(now),
+            ..limit
+        },
+        None => {
+            if sub_principals_of(owner).len() >= MAX_SUB_PRINCIPALS_PER_OWNER {
+                return Err(Error::CapacityExceeded {
+                    msg: format!(
+                        "An account can have at most {} sub-principals.",
+                        MAX_SUB_PRINCIPALS_PER_OWNER
+                    ),
+                });
+            }
+            SpendingLimit {
+                principal: payload.principal,
+                owner,
+                label: payload.label,
+                daily_cap: payload.daily_cap,
+                spent_day: now / NANOS_PER_DAY,
+                spent: 0,
+                created_at: now,
+                updated_at: None,
+            }
+        }
+    };
+    SPENDING_LIMITS.with(|service| {
+        service
+            .borrow_mut()
+            .insert(PrincipalKey(payload.principal), limit.clone())
+    });
+    OWNER_SUB_PRINCIPALS.with(|service| {
+        service
+            .borrow_mut()
+            .insert((PrincipalKey(owner), PrincipalKey(payload.principal)), ())
+    });
+    Ok(limit)
+}
+
+// Function to stop a sub-principal from buying on the caller's behalf
+#[ic_cdk::update]
+fn remove_sub_principal(principal: Principal) -> Result<SpendingLimit, Error> {
+    let owner = ensure_account_owner()?;
+    journal_call("remove_sub_principal");
+    let limit = current_spending_limit(&principal, time())
+        .filter(|limit| limit.owner == owner)
+        .ok_or(Error::NotFound {
+            msg: format!("{} is not a sub-principal of this account", principal),
+        })?;
+    SPENDING_LIMITS.with(|service| service.borrow_mut().remove(&PrincipalKey(principal)));
+    OWNER_SUB_PRINCIPALS.with(|service| {
+        service
+            .borrow_mut()
+            .remove(&(PrincipalKey(owner), PrincipalKey(principal)))
+    });
+    Ok(limit)
+}
+
+// Query function to list the caller's sub-principals with what they can still spend today
+#[ic_cdk::query]
+fn list_sub_principals() -> Vec<Allowance> {
+    let now = time();
+    sub_principals_of(caller())
+        .iter()
+        .filter_map(|principal| current_spending_limit(principal, now))
+        .map(allowance_of)
+        .collect()
+}
+
+// Query function to get what a sub-principal can still spend today; defaults to the caller,
+// and owners and admins may look up other sub-principals
+#[ic_cdk::query]
+fn get_remaining_allowance(principal: Option<Principal>) -> Result<Allowance, Error> {
+    let principal = principal.unwrap_or_else(caller);
+    current_spending_limit(&principal, time())
+        .filter(|limit| {
+            limit.principal == caller() || limit.owner == caller() || ensure_admin().is_ok()
+        })
+        .map(allowance_of)
+        .ok_or(Error::NotFound {
+            msg: format!("{} has no spending limit", principal),
+        })
+}
+
+// Function to record a sale made through a physical point-of-sale system, taking its units
+// out of stock; importing the same external id again returns the sale recorded the first time
+#[ic_cdk::update]
+fn import_external_sale(
+    external_id: String,
+    lines: Vec<ExternalSaleLinePayload>,
+    timestamp: u64,
+) -> Result<ExternalSale, Error> {
+    ensure_admin()?;
+    journal_call("import_external_sale");
+    if external_id.trim().is_empty() || external_id.len() > MAX_EXTERNAL_ID_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "External sale ids must be non-empty and at most {} bytes long.",
+                MAX_EXTERNAL_ID_LENGTH
+            ),
+        });
+    }
+    let key = ExternalId(external_id.clone());
+    if let Some(existing) = EXTERNAL_SALES.with(|service| service.borrow().get(&key)) {
+        return Ok(existing);
+    }
+    let now = time();
+    if timestamp > now {
+        return Err(Error::InvalidOperation {
+            msg: "An external sale cannot be dated in the future.".to_string(),
+        });
+    }
+    if lines.is_empty() || lines.len() > MAX_ORDER_LINES {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "An external sale must have between 1 and {} lines.",
+                MAX_ORDER_LINES
+            ),
+        });
+    }
+
+    let mut sale_lines: Vec<OrderLine> = Vec::with_capacity(lines.len());
+    for line in &lines {
+        if line.quantity == 0 {
+            return Err(Error::InvalidOperation {
+                msg: "Sale quantities must be greater than zero.".to_string(),
+            });
+        }
+        let product = _get_product(&line.product_id).ok_or(Error::NotFound {
+            msg: format!("A product with id={} was not found", line.product_id),
+        })?;
+        sale_lines.push(OrderLine {
+            product_id: product.id,
+            quantity: line.quantity,
+            unit_price: line.unit_price.unwrap_or(product.price()),
+            options: None,
+            fulfilled: None,
+            unfulfillable: None,
+        });
+    }
+
+    // The units already left the shop, so the stock is cleared rather than the import refused
+    for line in &sale_lines {
+        if let Some(mut product) = _get_product(&line.product_id) {
+            let expected = product.quantity;
+            take_stock(&mut product, expected, line.quantity.min(expected))?;
+            product.updated_at = Some(now);
+            do_insert(&product);
+            record_stock_movement(
+                &caller(),
+                line.product_id,
+                StockMovementKind::Sale,
+                -(line.quantity as i64),
+                (line.unit_price * line.quantity as u64) as i64,
+                None,
+            );
+        }
+    }
+    record_sales(&sale_lines, 1, timestamp);
+    let total = sale_lines
+        .iter()
+        .map(|line| line.unit_price * line.quantity as u64)
+        .sum();
+    post_transfer(
+        format!("External sale {} imported", external_id),
+        None,
+        LedgerAccount::Cash,
+        LedgerAccount::Sales,
+        total,
+    );
+
+    let sale = ExternalSale {
+        external_id,
+        lines: sale_lines,
+        total,
+        sold_at: timestamp,
+        imported_at: now,
+    };
+    EXTERNAL_SALES.with(|service| service.borrow_mut().insert(key, sale.clone()));
+    Ok(sale)
+}
+
+// Query function to look up an imported sale by its external id
+#[ic_cdk::query]
+fn get_external_sale(external_id: String) -> Result<ExternalSale, Error> {
+    ensure_admin()?;
+    EXTERNAL_SALES
+        .with(|service| service.borrow().get(&ExternalId(external_id.clone())))
+        .ok_or(Error::NotFound {
+            msg: format!("No external sale with id={} was imported", external_id),
+        })
+}
+
+// Function to record the removal of a product in its history, so restores know it was gone
+fn record_product_removal(product: &Product) {
+    let seq = PRODUCT_VERSION_SEQ.with(|counter| {
+        let seq = *counter.borrow().get();
+        counter
+            .borrow_mut()
+            .set(seq + 1)
+            .expect("Cannot advance the product version counter");
+        seq
+    });
+    PRODUCT_VERSIONS.with(|service| {
+        service.borrow_mut().insert(
+            (product.id, seq),
+            ProductVersion {
+                at: time(),
+                changed_by: caller(),
+                product: product.clone(),
+                removed: Some(true),
+            },
+        )
+    });
+}
+
+// Function to append a product's new state to its audit history, dropping the oldest
+// versions beyond the limit
+fn record_product_version(product: &Product) {
+    bump_catalog_version();
+    let seq = PRODUCT_VERSION_SEQ.with(|counter| {
+        let seq = *counter.borrow().get();
+        counter
+            .borrow_mut()
+            .set(seq + 1)
+            .expect("Cannot advance the product version counter");
+        seq
+    });
+    PRODUCT_VERSION_HEADS.with(|service| service.borrow_mut().insert(product.id, seq));
+    PRODUCT_VERSIONS.with(|service| {
+        let mut versions = service.borrow_mut();
+        let existing: Vec<(u64, u64)> = versions
+            .range((product.id, 0)..=(product.id, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        let overflow = (existing.len() + 1).saturating_sub(MAX_PRODUCT_VERSIONS);
+        for key in existing.iter().take(overflow) {
+            versions.remove(key);
+        }
+        versions.insert(
+            (product.id, seq),
+            ProductVersion {
+                at: time(),
+                changed_by: caller(),
+                product: product.clone(),
+                removed: None,
+            },
+        );
+    });
+}
+
+// Helper function to list the comparable fields of a product with their displayed values
+fn product_fields(product: &Product) -> Vec<(&'static str, String)> {
+    vec![
+        ("id", product.id.to_string()),
+        ("name", product.name.clone()),
+        ("category", format!("{:?}", product.category)),
+        ("quantity", product.quantity.to_string()),
+        ("price", product.price().to_string()),
+        ("tags", product.tags().join(", ")),
+        ("allergens", product.allergens().join(", ")),
+        ("status", format!("{:?}", product.status)),
+        ("publish_at", format!("{:?}", product.publish_at)),
+        ("created_at", product.created_at.to_string()),
+        ("updated_at", format!("{:?}", product.updated_at)),
+        ("min_order_qty", format!("{:?}", product.min_order_qty)),
+        ("max_order_qty", format!("{:?}", product.max_order_qty)),
+        ("order_qty_step", format!("{:?}", product.order_qty_step)),
+        ("featured_rank", format!("{:?}", product.featured_rank)),
+        ("age_restricted", format!("{:?}", product.age_restricted)),
+        ("best_before", format!("{:?}", product.best_before)),
+        ("settings", format!("{:?}", product.settings)),
+        (
+            "overridden_settings",
+            format!("{:?}", product.overridden_settings),
+        ),
+        ("prep_minutes", format!("{:?}", product.prep_minutes)),
+        ("display_units", format!("{:?}", product.display_units)),
+        (
+            "preferred_supplier_id",
+            format!("{:?}", product.preferred_supplier_id),
+        ),
+        ("unit_cost", format!("{:?}", product.unit_cost)),
+        ("unlisted", format!("{:?}", product.unlisted)),
+    ]
+}
+
+// Helper function to list the fields whose values differ between two products
+fn diff_fields(before: &Product, after: &Product) -> Vec<FieldDiff> {
+    product_fields(before)
+        .into_iter()
+        .zip(product_fields(after))
+        .filter(|((_, before), (_, after))| before != after)
+        .map(|((field, before), (_, after))| FieldDiff {
+            field: field.to_string(),
+            before,
+            after,
+        })
+        .collect()
+}
+
+// Query function to compare two products field by field, listing the fields that differ
+#[ic_cdk::query]
+fn diff_products(id_a: u64, id_b: u64) -> Result<Vec<FieldDiff>, Error> {
+    ensure_admin()?;
+    let product = |id: u64| {
+        _get_product(&id).ok_or(Error::NotFound {
+            msg: format!("A product with id={} was not found", id),
+        })
+    };
+    Ok(diff_fields(&product(id_a)?, &product(id_b)?))
+}
+
+// Helper function to get the version of a product in effect at a time
+fn product_version_at(id: u64, at: u64) -> Result<ProductVersion, Error> {
+    PRODUCT_VERSIONS
+        .with(|service| {
+            service
+                .borrow()
+                .range((id, 0)..=(id, u64::MAX))
+                .map(|(_, version)| version)
+                .take_while(|version| version.at <= at)
+                .last()
+        })
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "No recorded version of product id={} exists at time {}",
+                id, at
+            ),
+        })
+}
+
+// Query function to list what changed in a product between two times, using its audit history
+#[ic_cdk::query]
+fn diff_product_versions(id: u64, t1: u64, t2: u64) -> Result<Vec<FieldDiff>, Error> {
+    ensure_admin()?;
+    Ok(diff_fields(
+        &product_version_at(id, t1)?.product,
+        &product_version_at(id, t2)?.product,
+    ))
+}
+
+// Query function to list the recorded versions of a product, oldest first
+#[ic_cdk::query]
+fn get_product_history(id: u64) -> Result<Vec<ProductVersion>, Error> {
+    ensure_admin()?;
+    Ok(PRODUCT_VERSIONS.with(|service| {
+        service
+            .borrow()
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(_, version)| version)
+            .collect()
+    }))
+}
+
+// Helper function to check whether a price change needs a second admin's approval;
+// setting the first price of a product never does
+fn is_large_price_change(old_price: u64, new_price: u64) -> bool {
+    old_price > 0 && old_price.abs_diff(new_price) * 100 > old_price * LARGE_PRICE_CHANGE_PERCENT
+}
+
+// Helper function to report pending actions past their expiry as expired
+fn with_action_expiry(mut action: PendingAction, now: u64) -> PendingAction {
+    if action.status == ActionStatus::Pending && action.expires_at <= now {
+        action.status = ActionStatus::Expired;
+    }
+    action
+}
+
+// Function to hold a destructive operation until a second admin approves it
+fn request_action(action: ActionKind) -> Result<PendingAction, Error> {
+    let id = generate_unique_id(|id| {
+        PENDING_ACTIONS.with(|service| service.borrow().contains_key(&id))
+    })?;
+    let now = time();
+    let pending = PendingAction {
+        id,
+        action,
+        requested_by: caller(),
+        requested_at: now,
+        expires_at: now.saturating_add(PENDING_ACTION_TTL_NS),
+        status: ActionStatus::Pending,
+        decided_by: None,
+        decided_at: None,
+    };
+    PENDING_ACTIONS.with(|service| service.borrow_mut().insert(id, pending.clone()));
+    Ok(pending)
+}
+
+// Query function to list the actions waiting for a second admin
+#[ic_cdk::query]
+fn list_pending_actions() -> Result<Vec<PendingAction>, Error> {
+    ensure_admin()?;
+    let now = time();
+    Ok(PENDING_ACTIONS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, action)| with_action_expiry(action, now))
+            .filter(|action| action.status == ActionStatus::Pending)
+            .collect()
+    }))
+}
+
+// Helper function to get an action a second admin may still decide on
+fn decidable_action(id: u64) -> Result<PendingAction, Error> {
+    ensure_admin()?;
+    let action = PENDING_ACTIONS
+        .with(|service| service.borrow().get(&id))
+        .map(|action| with_action_expiry(action, time()))
+        .ok_or(Error::NotFound {
+            msg: format!("A pending action with id={} was not found", id),
+        })?;
+    if action.status != ActionStatus::Pending {
+        return Err(Error::InvalidOperation {
+            msg: format!("Action with id={} is {:?}", id, action.status),
+        });
+    }
+    if action.requested_by == caller() {
+        return Err(Error::Unauthorized {
+            msg: "An action must be approved or rejected by a different admin.".to_string(),
+        });
+    }
+    Ok(action)
+}
+
+// Helper function to store the decision taken on an action
+fn decide_action(mut action: PendingAction, status: ActionStatus) -> PendingAction {
+    action.status = status;
+    action.decided_by = Some(caller());
+    action.decided_at = Some(time());
+    PENDING_ACTIONS.with(|service| service.borrow_mut().insert(action.id, action.clone()));
+    action
+}
+
+// Function to approve and carry out an action requested by another admin
+#[ic_cdk::update]
+fn approve_action(id: u64) -> Result<PendingAction, Error> {
+    let action = decidable_action(id)?;
+    journal_call("approve_action");
+    match &action.action {
+        ActionKind::ClearAllProducts => start_clear_run(&action)?,
+        ActionKind::RemoveProduct { product_id } => {
+            do_remove_product(*product_id)?;
+        }
+        ActionKind::ChangePrice {
+            product_id,
+            old_price,
+            new_price,
+        } => {
+            let mut product = _get_product(product_id).ok_or(Error::NotFound {
+                msg: format!("A product with id={} was not found", product_id),
+            })?;
+            if product.price() != *old_price {
+                return Err(Error::InvalidOperation {
+                    msg: format!(
+                        "The price of product id={} changed since the action was requested",
+                        product_id
+                    ),
+                });
+            }
+            record_price_change(*product_id, *old_price, *new_price);
+            product.price = Some(*new_price);
+            product.updated_at = Some(time());
+            do_insert(&product);
+        }
+    }
+    Ok(decide_action(action, ActionStatus::Approved))
+}
+
+// Function to reject an action requested by another admin
+#[ic_cdk::update]
+fn reject_action(id: u64) -> Result<PendingAction, Error> {
+    let action = decidable_action(id)?;
+    journal_call("reject_action");
+    Ok(decide_action(action, ActionStatus::Rejected))
+}
+
+// Function to drop actions decided on or expired long ago
+fn purge_old_pending_actions() {
+    let cutoff = time().saturating_sub(PENDING_ACTION_RETENTION_NS);
+    PENDING_ACTIONS.with(|service| {
+        let old: Vec<u64> = service
+            .borrow()
+            .iter()
+            .filter(|(_, action)| action.expires_at <= cutoff)
+            .map(|(id, _)| id)
+            .collect();
+        let mut actions = service.borrow_mut();
+        for id in old {
+            actions.remove(&id);
+        }
+    });
+}
+
+// Helper function to deterministically pick the variant of an experiment shown to a caller,
+// using an FNV-1a hash of the experiment id and principal
+fn experiment_variant(experiment: &Experiment, principal: &Principal) -> u8 {
+    let id = experiment.id.to_be_bytes();
+    let hash = fnv1a(id.iter().chain(principal.as_slice()));
+    (hash % experiment.variants.len() as u64) as u8
+}
+
+// Helper function computing the 64-bit FNV-1a hash of some bytes
+fn fnv1a<'a>(bytes: impl Iterator<Item = &'a u8>) -> u64 {
+    bytes.fold(0xCBF2_9CE4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01B3)
+    })
+}
+
+// Helper function to get the active experiment running on a product, if any
+fn active_experiment_for(product_id: u64) -> Option<Experiment> {
+    EXPERIMENTS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, experiment)| experiment)
+            .find(|experiment| experiment.active && experiment.product_id == product_id)
+    })
+}
+
+// Function to reprice lines according to the experiment variants the customer is assigned
+// to; also returns the assignments and the promotion a variant replaces the usual one with
+fn apply_experiments(
+    customer: &Principal,
+    mut lines: Vec<OrderLine>,
+) -> (Vec<OrderLine>, Vec<ExperimentAssignment>, Option<Promotion>) {
+    let mut assignments = Vec::new();
+    let mut promotion = None;
+    // Experiments test retail prices, so tier customers are left out of them
+    if effective_tier(customer) != CustomerTier::Retail {
+        return (lines, assignments, promotion);
+    }
+    for line in lines.iter_mut() {
+        let Some(experiment) = active_experiment_for(line.product_id) else {
+            continue;
+        };
+        let variant = experiment_variant(&experiment, customer);
+        match experiment.variants[variant as usize].treatment {
+            VariantTreatment::Control => {}
+            VariantTreatment::Price { unit_price } => {
+                // The variant replaces the base price; option deltas still apply
+                let options = line.options.as_deref().unwrap_or(&[]);
+                line.unit_price = configured_unit_price(unit_price, options).unwrap_or(0);
+            }
+            VariantTreatment::Promotion { promotion_id } => {
+                if promotion.is_none() {
+                    promotion = PROMOTIONS.with(|service| service.borrow().get(&promotion_id));
+                }
+            }
+        }
+        assignments.push(ExperimentAssignment {
+            experiment_id: experiment.id,
+            product_id: line.product_id,
+            variant,
+        });
+    }
+    (lines, assignments, promotion)
+}
+
+// Function to count an order towards the experiment variants it was priced with
+fn record_experiment_conversions(assignments: &[ExperimentAssignment], lines: &[OrderLine]) {
+    EXPERIMENT_STATS.with(|service| {
+        let mut stats = service.borrow_mut();
+        for assignment in assignments {
+            let Some(line) = lines
+                .iter()
+                .find(|line| line.product_id == assignment.product_id)
+            else {
+                continue;
+            };
+            let key = (assignment.experiment_id, assignment.variant);
+            let mut variant = stats.get(&key).unwrap_or_default();
+            variant.orders += 1;
+            variant.units += line.quantity as u64;
+            variant.revenue += line.unit_price * line.quantity as u64;
+            stats.insert(key, variant);
+        }
+    });
+}
+
+// Function to start a price experiment on a product
+#[ic_cdk::update]
+fn create_experiment(payload: ExperimentPayload) -> Result<Experiment, Error> {
+    ensure_admin()?;
+    journal_call("create_experiment");
+    if payload.name.trim().is_empty() {
+        return Err(Error::InvalidOperation {
+            msg: "Experiment name cannot be empty.".to_string(),
+        });
+    }
+    if payload.variants.len() < MIN_EXPERIMENT_VARIANTS
+        || payload.variants.len() > MAX_EXPERIMENT_VARIANTS
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "An experiment must have between {} and {} variants.",
+                MIN_EXPERIMENT_VARIANTS, MAX_EXPERIMENT_VARIANTS
+            ),
+        });
+    }
+    if payload
+        .variants
+        .iter()
+        .any(|variant| variant.name.trim().is_empty() || variant.name.len() > MAX_LABEL_LENGTH)
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Variant names must be non-empty and at most {} bytes long.",
+                MAX_LABEL_LENGTH
+            ),
+        });
+    }
+    for variant in &payload.variants {
+        if let VariantTreatment::Promotion { promotion_id } = variant.treatment {
+            if !PROMOTIONS.with(|service| service.borrow().contains_key(&promotion_id)) {
+                return Err(Error::NotFound {
+                    msg: format!("A promotion with id={} was not found", promotion_id),
+                });
+            }
+        }
+    }
+    if _get_product(&payload.product_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("A product with id={} was not found", payload.product_id),
+        });
+    }
+    if active_experiment_for(payload.product_id).is_some() {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "An experiment is already running on product id={}",
+                payload.product_id
+            ),
+        });
+    }
+
+    let id =
+        generate_unique_id(|id| EXPERIMENTS.with(|service| service.borrow().contains_key(&id)))?;
+    let experiment = Experiment {
+        id,
+        name: payload.name,
+        product_id: payload.product_id,
+        variants: payload.variants,
+        active: true,
+        created_at: time(),
+        ended_at: None,
+    };
+    EXPERIMENTS.with(|service| service.borrow_mut().insert(id, experiment.clone()));
+    Ok(experiment)
+}
+
+// Function to end a running experiment; its results are kept
+#[ic_cdk::update]
+fn end_experiment(id: u64) -> Result<Experiment, Error> {
+    ensure_admin()?;
+    journal_call("end_experiment");
+    EXPERIMENTS.with(|service| {
+        let mut experiments = service.borrow_mut();
+        let mut experiment = experiments.get(&id).ok_or(Error::NotFound {
+            msg: format!("An experiment with id={} was not found", id),
+        })?;
+        if experiment.active {
+            experiment.active = false;
+            experiment.ended_at = Some(time());
+            experiments.insert(id, experiment.clone());
+        }
+        Ok(experiment)
+    })
+}
+
+// Query function to list all experiments
+#[ic_cdk::query]
+fn list_experiments() -> Result<Vec<Experiment>, Error> {
+    ensure_admin()?;
+    Ok(EXPERIMENTS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, experiment)| experiment)
+            .collect()
+    }))
+}
+
+// Query function to get the conversions recorded for each variant of an experiment
+#[ic_cdk::query]
+fn get_experiment_results(id: u64) -> Result<ExperimentResults, Error> {
+    ensure_viewer()?;
+    let experiment = EXPERIMENTS
+        .with(|service| service.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("An experiment with id={} was not found", id),
+        })?;
+    let variants = EXPERIMENT_STATS.with(|service| {
+        let stats = service.borrow();
+        experiment
+            .variants
+            .iter()
+            .enumerate()
+            .map(|(index, variant)| {
+                let recorded = stats.get(&(id, index as u8)).unwrap_or_default();
+                VariantResult {
+                    name: variant.name.clone(),
+                    treatment: variant.treatment.clone(),
+                    orders: recorded.orders,
+                    units: recorded.units,
+                    revenue: recorded.revenue,
+                }
+            })
+            .collect()
+    });
+    Ok(ExperimentResults {
+        experiment,
+        variants,
+    })
+}
+
+// Query function to get the unit price the caller is offered for a product, taking the
+// caller's tier and running experiments into account
+#[ic_cdk::query]
+fn get_my_price(product_id: u64) -> Result<u64, Error> {
+    let product = _get_visible_product(&product_id).ok_or(Error::NotFound {
+        msg: format!("A product with id={} was not found", product_id),
+    })?;
+    let line = OrderLine {
+        product_id,
+        quantity: 1,
+        unit_price: tier_price_of(product_id, effective_tier(&caller()))
+            .map_or(product.price(), |price| price.unit_price),
+        options: None,
+        fulfilled: None,
+        unfulfillable: None,
+    };
+    let (lines, _, _) = apply_experiments(&caller(), vec![line]);
+    Ok(lines[0].unit_price)
+}
+
+// Helper function to validate the text of a ticket or ticket response
+fn validate_ticket_text(text: &str) -> Result<(), Error> {
+    if text.trim().is_empty() || text.len() > MAX_NOTES_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Ticket text must be non-empty and at most {} bytes long.",
+                MAX_NOTES_LENGTH
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Function to open a support ticket, optionally about one of the caller's orders
+#[ic_cdk::update]
+fn create_ticket(payload: TicketPayload) -> Result<Ticket, Error> {
+    ensure_caller_allowed(Endpoint::CreateTicket)?;
+    journal_call("create_ticket");
+    validate_ticket_text(&payload.text)?;
+    let customer = caller();
+    if let Some(order_id) = payload.order_id {
+        _get_order(&order_id)
+            .filter(|order| is_own_record(&order.customer))
+            .ok_or(Error::NotFound {
+                msg: format!("An order with id={} was not found", order_id),
+            })?;
+    }
+
+    let id = generate_unique_id(|id| TICKETS.with(|service| service.borrow().contains_key(&id)))?;
+    let ticket = Ticket {
+        id,
+        customer,
+        order_id: payload.order_id,
+        category: payload.category,
+        text: payload.text,
+        status: TicketStatus::Open,
+        assignee: None,
+        responses: Vec::new(),
+        created_at: time(),
+        updated_at: None,
+    };
+    TICKETS.with(|service| service.borrow_mut().insert(id, ticket.clone()));
+    Ok(ticket)
+}
+
+// Query function to list the caller's support tickets
+#[ic_cdk::query]
+fn list_my_tickets() -> Vec<Ticket> {
+    let customer = caller();
+    TICKETS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, ticket)| ticket)
+            .filter(|ticket| ticket.customer == customer)
+            .collect()
+    })
+}
+
+// Query function to retrieve a ticket; customers can only see their own tickets
+#[ic_cdk::query]
+fn get_ticket(id: u64) -> Result<Ticket, Error> {
+    TICKETS
+        .with(|service| service.borrow().get(&id))
+        .filter(|ticket| ticket.customer == caller() || ensure_admin().is_ok())
+        .ok_or(Error::NotFound {
+            msg: format!("A ticket with id={} was not found", id),
+        })
+}
+
+// Query function to list the tickets in a status, or all tickets, for staff
+#[ic_cdk::query]
+fn list_tickets(status: Option<TicketStatus>) -> Result<Vec<Ticket>, Error> {
+    ensure_admin()?;
+    Ok(TICKETS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, ticket)| ticket)
+            .filter(|ticket| status.is_none_or(|status| ticket.status == status))
+            .collect()
+    }))
+}
+
+// Query function to list the support history of an order
+#[ic_cdk::query]
+fn list_order_tickets(order_id: u64) -> Result<Vec<Ticket>, Error> {
+    get_order(order_id)?;
+    Ok(TICKETS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, ticket)| ticket)
+            .filter(|ticket| ticket.order_id == Some(order_id))
+            .collect()
+    }))
+}
+
+// Helper function to change an open ticket on behalf of staff
+fn update_ticket(
+    id: u64,
+    change: impl FnOnce(&mut Ticket) -> Result<(), Error>,
+) -> Result<Ticket, Error> {
+    TICKETS.with(|service| {
+        let mut tickets = service.borrow_mut();
+        let mut ticket = tickets.get(&id).ok_or(Error::NotFound {
+            msg: format!("A ticket with id={} was not found", id),
+        })?;
+        if ticket.status == TicketStatus::Closed {
+            return Err(Error::InvalidOperation {
+                msg: format!("Ticket with id={} is closed", id),
+            });
+        }
+        change(&mut ticket)?;
+        ticket.updated_at = Some(time());
+        tickets.insert(id, ticket.clone());
+        Ok(ticket)
+    })
+}
+
+// Function to assign a ticket to a staff member
+#[ic_cdk::update]
+fn assign_ticket(id: u64, assignee: Principal) -> Result<Ticket, Error> {
+    ensure_admin()?;
+    journal_call("assign_ticket");
+    update_ticket(id, |ticket| {
+        ticket.assignee = Some(assignee);
+        if ticket.status == TicketStatus::Open {
+            ticket.status = TicketStatus::Assigned;
+        }
+        Ok(())
+    })
+}
+
+// Function to reply to a ticket; the customer is told in their inbox
+#[ic_cdk::update]
+fn respond_to_ticket(id: u64, text: String) -> Result<Ticket, Error> {
+    ensure_admin()?;
+    journal_call("respond_to_ticket");
+    validate_ticket_text(&text)?;
+    let ticket = update_ticket(id, |ticket| {
+        if ticket.responses.len() >= MAX_TICKET_RESPONSES {
+            return Err(Error::CapacityExceeded {
+                msg: format!(
+                    "A ticket can have at most {} responses.",
+                    MAX_TICKET_RESPONSES
+                ),
+            });
+        }
+        ticket.responses.push(TicketResponse {
+            author: caller(),
+            text,
+            created_at: time(),
+        });
+        ticket.status = TicketStatus::Answered;
+        Ok(())
+    })?;
+    notify(
+        ticket.customer,
+        NotificationKind::TicketResponse { ticket_id: id },
+        format!("We replied to your ticket #{}.", id),
+    );
+    Ok(ticket)
+}
+
+// Function to close a ticket
+#[ic_cdk::update]
+fn close_ticket(id: u64) -> Result<Ticket, Error> {
+    ensure_admin()?;
+    journal_call("close_ticket");
+    update_ticket(id, |ticket| {
+        ticket.status = TicketStatus::Closed;
+        Ok(())
+    })
+}
+
+// Helper function to check whether a principal is a registered kiosk
+fn is_kiosk(principal: &Principal) -> bool {
+    KIOSKS.with(|service| service.borrow().contains_key(&PrincipalKey(*principal)))
+}
+
+// Function to register a self-serve kiosk, limiting its principal to kiosk sessions
+#[ic_cdk::update]
+fn register_kiosk(principal: Principal, name: String) -> Result<Kiosk, Error> {
+    ensure_admin()?;
+    journal_call("register_kiosk");
+    if principal == Principal::anonymous() || ic_cdk::api::is_controller(&principal) {
+        return Err(Error::InvalidOperation {
+            msg: "A kiosk must be a signed-in principal that is not an admin.".to_string(),
+        });
+    }
+    if name.trim().is_empty() || name.len() > MAX_LABEL_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Kiosk names must be non-empty and at most {} bytes long.",
+                MAX_LABEL_LENGTH
+            ),
+        });
+    }
+    let kiosk = Kiosk {
+        principal,
+        name,
+        created_at: time(),
+    };
+    KIOSKS.with(|service| {
+        service
+            .borrow_mut()
+            .insert(PrincipalKey(principal), kiosk.clone())
+    });
+    Ok(kiosk)
+}
+
+// Function to grant a principal read-only access to the reporting queries
+#[ic_cdk::update]
+fn add_viewer(principal: Principal, name: String) -> Result<Viewer, Error> {
+    ensure_admin()?;
+    journal_call("add_viewer");
+    if principal == Principal::anonymous()
+        || ic_cdk::api::is_controller(&principal)
+        || is_kiosk(&principal)
+    {
+        return Err(Error::InvalidOperation {
+            msg: "A viewer must be a signed-in principal that is not an admin or a kiosk."
+                .to_string(),
+        });
+    }
+    if name.trim().is_empty() || name.len() > MAX_LABEL_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Viewer names must be non-empty and at most {} bytes long.",
+                MAX_LABEL_LENGTH
+            ),
+        });
+    }
+    let viewer = Viewer {
+        principal,
+        name,
+        created_at: time(),
+    };
+    VIEWERS.with(|service| {
+        service
+            .borrow_mut()
+            .insert(PrincipalKey(principal), viewer.clone())
+    });
+    Ok(viewer)
+}
+
+// Function to revoke a viewer's access
+#[ic_cdk::update]
+fn remove_viewer(principal: Principal) -> Result<Viewer, Error> {
+    ensure_admin()?;
+    journal_call("remove_viewer");
+    VIEWERS
+        .with(|service| service.borrow_mut().remove(&PrincipalKey(principal)))
+        .ok_or(Error::NotFound {
+            msg: format!("{} is not a registered viewer", principal),
+        })
+}
+
+// Query function to list the viewers
+#[ic_cdk::query]
+fn list_viewers() -> Result<Vec<Viewer>, Error> {
+    ensure_admin()?;
+    Ok(VIEWERS.with(|service| service.borrow().iter().map(|(_, viewer)| viewer).collect()))
+}
+
+// Function to register a device a staff member can work from
+#[ic_cdk::update]
+fn register_device(
+    principal: Principal,
+    device_id: String,
+    label: String,
+) -> Result<StaffDevices, Error> {
+    ensure_controller()?;
+    journal_call("register_device");
+    if principal == Principal::anonymous() {
+        return Err(Error::InvalidOperation {
+            msg: "Devices cannot be registered for the anonymous principal.".to_string(),
+        });
+    }
+    if device_id.trim().is_empty() || device_id.len() > MAX_EXTERNAL_ID_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Device identifiers must be non-empty and at most {} bytes long.",
+                MAX_EXTERNAL_ID_LENGTH
+            ),
+        });
+    }
+    if label.len() > MAX_LABEL_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Device labels can be at most {} bytes long.",
+                MAX_LABEL_LENGTH
+            ),
+        });
+    }
+    let mut devices = STAFF_DEVICES
+        .with(|service| service.borrow().get(&PrincipalKey(principal)))
+        .unwrap_or(StaffDevices {
+            principal,
+            devices: Vec::new(),
+            active_device_id: None,
+            active_since: None,
+        });
+    if devices
+        .devices
+        .iter()
+        .any(|device| device.device_id == device_id)
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Device {} is already registered for {}",
+                device_id, principal
+            ),
+        });
+    }
+    if devices.devices.len() >= MAX_DEVICES_PER_PRINCIPAL {
+        return Err(Error::CapacityExceeded {
+            msg: format!(
+                "At most {} devices can be registered per principal.",
+                MAX_DEVICES_PER_PRINCIPAL
+            ),
+        });
+    }
+    devices.devices.push(StaffDevice {
+        device_id,
+        label,
+        registered_by: caller(),
+        registered_at: time(),
+        revoked_at: None,
+    });
+    STAFF_DEVICES.with(|service| {
+        service
+            .borrow_mut()
+            .insert(PrincipalKey(principal), devices.clone())
+    });
+    Ok(devices)
+}
+
+// Function to revoke a staff member's device; it stays listed for the forensic trail
+#[ic_cdk::update]
+fn revoke_device(principal: Principal, device_id: String) -> Result<StaffDevices, Error> {
+    ensure_controller()?;
+    journal_call("revoke_device");
+    let mut devices = STAFF_DEVICES
+        .with(|service| service.borrow().get(&PrincipalKey(principal)))
+        .ok_or(Error::NotFound {
+            msg: format!("{} has no registered devices", principal),
+        })?;
+    let device = devices
+        .devices
+        .iter_mut()
+        .find(|device| device.device_id == device_id && device.revoked_at.is_none())
+        .ok_or(Error::NotFound {
+            msg: format!("{} has no active device {}", principal, device_id),
+        })?;
+    device.revoked_at = Some(time());
+    STAFF_DEVICES.with(|service| {
+        service
+            .borrow_mut()
+            .insert(PrincipalKey(principal), devices.clone())
+    });
+    Ok(devices)
+}
+
+// Function to require admin update calls to come from a registered device, or stop requiring it
+#[ic_cdk::update]
+fn set_device_binding(required: bool) -> Result<bool, Error> {
+    ensure_controller()?;
+    journal_call("set_device_binding");
+    update_config(|config| config.require_staff_devices = required.then_some(true))?;
+    Ok(required)
+}
+
+// Query function to list the registered devices of every staff member
+#[ic_cdk::query]
+fn list_devices() -> Result<Vec<StaffDevices>, Error> {
+    ensure_admin()?;
+    Ok(STAFF_DEVICES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, devices)| devices)
+            .collect()
+    }))
+}
+
+// Function to unregister a kiosk, ending its sessions
+#[ic_cdk::update]
+fn remove_kiosk(principal: Principal) -> Result<Kiosk, Error> {
+    ensure_admin()?;
+    journal_call("remove_kiosk");
+    let kiosk = KIOSKS
+        .with(|service| service.borrow_mut().remove(&PrincipalKey(principal)))
+        .ok_or(Error::NotFound {
+            msg: format!("{} is not a registered kiosk", principal),
+        })?;
+    KIOSK_SESSIONS.with(|service| {
+        let ended: Vec<u64> = service
+            .borrow()
+            .iter()
+            .filter(|(_, session)| session.kiosk == principal)
+            .map(|(key, _)| key)
+            .collect();
+        let mut sessions = service.borrow_mut();
+        for key in ended {
+            sessions.remove(&key);
+        }
+    });
+    Ok(kiosk)
+}
+
+// Function for a kiosk to start a customer session that expires on its own
+#[ic_cdk::update]
+fn start_kiosk_session() -> Result<KioskSession, Error> {
+    ensure_not_in_maintenance()?;
+    journal_call("start_kiosk_session");
+    let kiosk = caller();
+    if !is_kiosk(&kiosk) {
+        return Err(Error::Unauthorized {
+            msg: "Only registered kiosks can start kiosk sessions.".to_string(),
+        });
+    }
+    let mut key = next_random()?;
+    while KIOSK_SESSIONS.with(|service| service.borrow().contains_key(&key)) {
+        key = next_random()?;
+    }
+    let now = time();
+    let session = KioskSession {
+        token: format!("{:016x}", key),
+        kiosk,
+        started_at: now,
+        expires_at: now.saturating_add(KIOSK_SESSION_TTL_NS),
+    };
+    KIOSK_SESSIONS.with(|service| service.borrow_mut().insert(key, session.clone()));
+    Ok(session)
+}
+
+// Helper function to get the key of the caller's unexpired kiosk session with a token
+fn active_kiosk_session(token: &str) -> Result<u64, Error> {
+    let now = time();
+    u64::from_str_radix(token, 16)
+        .ok()
+        .filter(|key| {
+            KIOSK_SESSIONS
+                .with(|service| service.borrow().get(key))
+                .is_some_and(|session| session.kiosk == caller() && session.expires_at > now)
+        })
+        .ok_or(Error::Unauthorized {
+            msg: "The kiosk session is invalid or has expired.".to_string(),
+        })
+}
+
+// Function for a kiosk to end a session before it expires
+#[ic_cdk::update]
+fn end_kiosk_session(token: String) -> Result<(), Error> {
+    let key = active_kiosk_session(&token)?;
+    journal_call("end_kiosk_session");
+    KIOSK_SESSIONS.with(|service| service.borrow_mut().remove(&key));
+    Ok(())
+}
+
+// Function for a kiosk to place an order in a session, paid in cash on pickup
+#[ic_cdk::update]
+fn place_kiosk_order(payload: KioskOrderPayload) -> Result<Order, Error> {
+    ensure_caller_allowed(Endpoint::PlaceKioskOrder)?;
+    journal_call("place_kiosk_order");
+    let key = active_kiosk_session(&payload.token)?;
+    validate_notes(&payload.notes)?;
+
+    let kiosk = caller();
+    let lines = check_order_lines(&kiosk, &payload.lines)?;
+    let priced = price_order(&kiosk, lines);
+    commit_order(kiosk, priced, payload.notes, None, None, Some(key))
+}
+
+// Query function to list the kiosk sessions that have not expired
+#[ic_cdk::query]
+fn list_active_sessions() -> Result<Vec<KioskSession>, Error> {
+    ensure_admin()?;
+    let now = time();
+    Ok(KIOSK_SESSIONS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, session)| session)
+            .filter(|session| session.expires_at > now)
+            .collect()
+    }))
+}
+
+// Function to drop kiosk sessions that have expired
+fn purge_expired_kiosk_sessions() {
+    let now = time();
+    KIOSK_SESSIONS.with(|service| {
+        let expired: Vec<u64> = service
+            .borrow()
+            .iter()
+            .filter(|(_, session)| session.expires_at <= now)
+            .map(|(key, _)| key)
+            .collect();
+        let mut sessions = service.borrow_mut();
+        for key in expired {
+            sessions.remove(&key);
+        }
+    });
+}
+
+// Helper function to validate a notifier channel payload
+fn validate_notifier_channel_payload(payload: &NotifierChannelPayload) -> Result<(), Error> {
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_LABEL_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Channel names must be non-empty and at most {} bytes long.",
+                MAX_LABEL_LENGTH
+            ),
+        });
+    }
+    let url = match &payload.kind {
+        ChannelKind::Webhook { url } => url.clone(),
+        ChannelKind::Discord { webhook_url } => webhook_url.clone(),
+        ChannelKind::Telegram { bot_token, chat_id } => {
+            if bot_token.is_empty() || chat_id.is_empty() {
+                return Err(Error::InvalidOperation {
+                    msg: "Telegram channels need a bot token and a chat id.".to_string(),
+                });
+            }
+            telegram_url(bot_token)
+        }
+    };
+    if !url.starts_with("https://") || url.len() > MAX_NOTIFIER_URL_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Notifier URLs must use HTTPS and be at most {} bytes long.",
+                MAX_NOTIFIER_URL_LENGTH
+            ),
+        });
+    }
+    if payload.template.as_ref().is_some_and(|template| {
+        template.trim().is_empty() || template.len() > MAX_NOTIFIER_TEMPLATE_LENGTH
+    }) {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Message templates must be non-empty and at most {} bytes long.",
+                MAX_NOTIFIER_TEMPLATE_LENGTH
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Helper function to build the Bot API URL sending a Telegram message
+fn telegram_url(bot_token: &str) -> String {
+    format!("https://api.telegram.org/bot{}/sendMessage", bot_token)
+}
+
+// Helper function to hide the secrets of a channel before returning it
+fn redacted_channel(mut channel: NotifierChannel) -> NotifierChannel {
+    channel.kind = match channel.kind {
+        ChannelKind::Telegram { chat_id, .. } => ChannelKind::Telegram {
+            bot_token: "***".to_string(),
+            chat_id,
+        },
+        ChannelKind::Discord { .. } => ChannelKind::Discord {
+            webhook_url: "https://discord.com/api/webhooks/***".to_string(),
+        },
+        kind => kind,
+    };
+    channel
+}
+
+// Function to add a channel notifying staff about orders
+#[ic_cdk::update]
+fn add_notifier_channel(payload: NotifierChannelPayload) -> Result<NotifierChannel, Error> {
+    ensure_admin()?;
+    journal_call("add_notifier_channel");
+    validate_notifier_channel_payload(&payload)?;
+    if NOTIFIER_CHANNELS.with(|service| service.borrow().len()) >= MAX_NOTIFIER_CHANNELS {
+        return Err(Error::CapacityExceeded {
+            msg: format!(
+                "At most {} notifier channels can exist.",
+                MAX_NOTIFIER_CHANNELS
+            ),
+        });
+    }
+    let id = generate_unique_id(|id| {
+        NOTIFIER_CHANNELS.with(|service| service.borrow().contains_key(&id))
+    })?;
+    let channel = NotifierChannel {
+        id,
+        name: payload.name,
+        kind: payload.kind,
+        events: payload.events,
+        enabled: payload.enabled.unwrap_or(true),
+        template: payload
+            .template
+            .unwrap_or_else(|| DEFAULT_NOTIFIER_TEMPLATE.to_string()),
+        last_sent_at: None,
+        last_error: None,
+        created_at: time(),
+        alerts: payload.alerts,
+    };
+    NOTIFIER_CHANNELS.with(|service| service.borrow_mut().insert(id, channel.clone()));
+    Ok(redacted_channel(channel))
+}
+
+// Function to replace the settings of a notifier channel
+#[ic_cdk::update]
+fn update_notifier_channel(
+    id: u64,
+    payload: NotifierChannelPayload,
+) -> Result<NotifierChannel, Error> {
+    ensure_admin()?;
+    journal_call("update_notifier_channel");
+    validate_notifier_channel_payload(&payload)?;
+    NOTIFIER_CHANNELS.with(|service| {
+        let mut channels = service.borrow_mut();
+        let mut channel = channels.get(&id).ok_or(Error::NotFound {
+            msg: format!("A notifier channel with id={} was not found", id),
+        })?;
+        channel.name = payload.name;
+        channel.kind = payload.kind;
+        channel.events = payload.events;
+        if let Some(enabled) = payload.enabled {
+            channel.enabled = enabled;
+        }
+        if let Some(template) = payload.template {
+            channel.template = template;
+        }
+        if let Some(alerts) = payload.alerts {
+            channel.alerts = Some(alerts);
+        }
+        channels.insert(id, channel.clone());
+        Ok(redacted_channel(channel))
+    })
+}
+
+// Function to remove a notifier channel
+#[ic_cdk::update]
+fn remove_notifier_channel(id: u64) -> Result<NotifierChannel, Error> {
+    ensure_admin()?;
+    journal_call("remove_notifier_channel");
+    NOTIFIER_CHANNELS
+        .with(|service| service.borrow_mut().remove(&id))
+        .map(redacted_channel)
+        .ok_or(Error::NotFound {
+            msg: format!("A notifier channel with id={} was not found", id),
+        })
+}
+
+// Query function to list the notifier channels, with their secrets hidden
+#[ic_cdk::query]
+fn list_notifier_channels() -> Result<Vec<NotifierChannel>, Error> {
+    ensure_admin()?;
+    Ok(NOTIFIER_CHANNELS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, channel)| redacted_channel(channel))
+            .collect()
+    }))
+}
+
+// Helper function to fill a message template with the details of an order
+fn render_template(template: &str, event: NotifierEvent, order: &Order) -> String {
+    let items: u64 = order.lines.iter().map(|line| line.quantity as u64).sum();
+    let event = match event {
+        NotifierEvent::OrderPlaced => "order",
+        NotifierEvent::OrderPaid => "payment",
+    };
+    template
+        .replace("{event}", event)
+        .replace("{order_id}", &order.id.to_string())
+        .replace(
+            "{order_number}",
+            order.order_number.as_deref().unwrap_or_default(),
+        )
+        .replace("{items}", &items.to_string())
+        .replace("{total}", &order.total.to_string())
+        .replace("{customer}", &order.customer.to_text())
+}
+
+// Helper function to build the HTTPS request delivering a message to a channel
+fn channel_request(
+    channel: &NotifierChannel,
+    event: NotifierEvent,
+    order: &Order,
+) -> outcall::CanisterHttpRequestArgument {
+    let text = render_template(&channel.template, event, order);
+    let webhook = serde_json::json!({
+        "event": format!("{:?}", event),
+        "order_id": order.id,
+        "total": order.total,
+        "text": text,
+    });
+    channel_post(channel, text, webhook, format!("{:?}-{}", event, order.id))
+}
+
+// Helper function to build the HTTPS request posting a message to a channel; generic webhooks
+// get the structured body, chat channels only the text
+fn channel_post(
+    channel: &NotifierChannel,
+    text: String,
+    webhook: serde_json::Value,
+    idempotency_key: String,
+) -> outcall::CanisterHttpRequestArgument {
+    let (url, body) = match &channel.kind {
+        ChannelKind::Webhook { url } => (url.clone(), webhook),
+        ChannelKind::Telegram { bot_token, chat_id } => (
+            telegram_url(bot_token),
+            serde_json::json!({ "chat_id": chat_id, "text": text }),
+        ),
+        ChannelKind::Discord { webhook_url } => {
+            (webhook_url.clone(), serde_json::json!({ "content": text }))
+        }
+    };
+    outcall::CanisterHttpRequestArgument {
+        url,
+        max_response_bytes: Some(NOTIFIER_MAX_RESPONSE_BYTES),
+        method: outcall::HttpMethod::POST,
+        headers: vec![
+            outcall::HttpHeader {
+                name: "Content-Type".to_string(),
+                value: "application/json".to_string(),
+            },
+            // Every replica sends the request; receivers can drop the copies with this key
+            outcall::HttpHeader {
+                name: "Idempotency-Key".to_string(),
+                value: idempotency_key,
+            },
+        ],
+        body: Some(body.to_string().into_bytes()),
+        transform: Some(outcall::TransformContext::from_name(
+            "transform_outcall_response".to_string(),
+            vec![],
+        )),
+    }
+}
+
+// Query function used by the replicas to agree on an outcall response, keeping only its status
+#[ic_cdk::query]
+fn transform_outcall_response(args: outcall::TransformArgs) -> outcall::HttpResponse {
+    outcall::HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body: vec![],
+    }
+}
+
+// Function to deliver a message to a channel and record the outcome on it
+async fn send_to_channel(channel_id: u64, event: NotifierEvent, order: Order) -> Result<(), Error> {
+    let channel = NOTIFIER_CHANNELS
+        .with(|service| service.borrow().get(&channel_id))
+        .ok_or(Error::NotFound {
+            msg: format!("A notifier channel with id={} was not found", channel_id),
+        })?;
+    post_to_channel(channel_id, channel_request(&channel, event, &order)).await
+}
+
+// Function to make a channel request and record the outcome on the channel
+async fn post_to_channel(
+    channel_id: u64,
+    request: outcall::CanisterHttpRequestArgument,
+) -> Result<(), Error> {
+    let result = match outcall::http_request(request, NOTIFIER_OUTCALL_CYCLES).await {
+        Ok((response,)) if response.status >= 200u32 && response.status < 300u32 => Ok(()),
+        Ok((response,)) => Err(Error::ExternalCallFailed {
+            msg: format!(
+                "Channel {} answered with status {}",
+                channel_id, response.status
+            ),
+        }),
+        Err((code, msg)) => Err(Error::ExternalCallFailed {
+            msg: format!(
+                "Failed to reach channel {} ({:?}): {}",
+                channel_id, code, msg
+            ),
+        }),
+    };
+
+    NOTIFIER_CHANNELS.with(|service| {
+        let mut channels = service.borrow_mut();
+        if let Some(mut channel) = channels.get(&channel_id) {
+            match &result {
+                Ok(()) => {
+                    channel.last_sent_at = Some(time());
+                    channel.last_error = None;
+                }
+                Err(Error::ExternalCallFailed { msg }) => channel.last_error = Some(msg.clone()),
+                Err(_) => {}
+            }
+            channels.insert(channel_id, channel);
+        }
+    });
+    result
+}
+
+// Function to queue a message to every enabled channel subscribed to an order event
+fn notify_staff(event: NotifierEvent, order: &Order) {
+    let channels: Vec<u64> = NOTIFIER_CHANNELS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, channel)| channel.enabled && channel.events.contains(&event))
+            .map(|(id, _)| id)
+            .collect()
+    });
+    for channel_id in channels {
+        enqueue_effect(OutboxEffect::StaffNotification {
+            channel_id,
+            event,
+            order_id: order.id,
+        });
+    }
+}
+
+// Function to send a sample message to a channel, e.g. after setting it up
+#[ic_cdk::update]
+async fn test_notifier_channel(id: u64) -> Result<(), Error> {
+    ensure_admin()?;
+    journal_call("test_notifier_channel");
+    let sample = Order {
+        id: 0,
+        customer: caller(),
+        lines: vec![OrderLine {
+            product_id: 0,
+            quantity: 1,
+            unit_price: 0,
+            options: None,
+            fulfilled: None,
+            unfulfillable: None,
+        }],
+        subtotal: 0,
+        discount: 0,
+        total: 0,
+        promotion_id: None,
+        status: OrderStatus::Placed,
+        nft_receipt: None,
+        notes: None,
+        quote_id: None,
+        created_at: time(),
+        updated_at: None,
+        applied_rules: None,
+        payment: None,
+        account_owner: None,
+        kiosk_session: None,
+        shift_id: None,
+        refund_due: None,
+        schedule: None,
+        promised_ready_at: None,
+        ready_at: None,
+        prep_minutes: None,
+        cancellation: None,
+        order_number: None,
+        tenders: None,
+    };
+    send_to_channel(id, NotifierEvent::OrderPlaced, sample).await
+}
+
+// Helper function to list a product's waitlist, first to be served first
+fn waitlist_of(product_id: u64) -> Vec<((u64, u64), WaitlistEntry)> {
+    WAITLIST.with(|service| {
+        service
+            .borrow()
+            .range((product_id, 0)..=(product_id, u64::MAX))
+            .collect()
+    })
+}
+
+// Helper function to drop the waitlist of a product
+fn remove_waitlist(product_id: u64) {
+    for (key, _) in waitlist_of(product_id) {
+        WAITLIST.with(|service| service.borrow_mut().remove(&key));
+    }
+}
+
+// Helper function to sum the units set aside for a principal from a product's waitlist
+fn claimable_quantity(holder: Principal, product_id: u64) -> u32 {
+    let now = time();
+    RESERVATIONS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, reservation)| {
+                reservation.from_waitlist == Some(true)
+                    && reservation.holder == holder
+                    && reservation.product_id == product_id
+                    && is_reservation_active(reservation, now)
+            })
+            .map(|(_, reservation)| reservation.quantity)
+            .sum()
+    })
+}
+
+// Helper function to drop the waitlist reservations of a principal once they order the product
+fn claim_waitlist_reservations(holder: Principal, product_id: u64) {
+    for reservation in reservations_of(product_id) {
+        if reservation.from_waitlist == Some(true) && reservation.holder == holder {
+            remove_reservation(reservation.id);
+        }
+    }
+}
+
+// Function to set returning stock aside for the waitlist, strictly in the order customers joined;
+// each customer gets a reservation they must claim by ordering within the claim window
+fn allocate_waitlist(product_id: u64) {
+    let Some(product) = _get_product(&product_id) else {
+        return;
+    };
+    let reserved = reserved_quantities().get(&product_id).copied().unwrap_or(0);
+    let mut available = availability_of(&product, reserved).available;
+    let now = time();
+    for (key, entry) in waitlist_of(product_id) {
+        if entry.quantity as i64 > available {
+            break;
+        }
+        let Ok(id) = generate_unique_id(|id| {
+            RESERVATIONS.with(|service| service.borrow().contains_key(&id))
+        }) else {
+            return;
+        };
+        let reservation = Reservation {
+            id,
+            product_id,
+            quantity: entry.quantity,
+            holder: entry.principal,
+            created_at: now,
+            expires_at: Some(now.saturating_add(WAITLIST_CLAIM_WINDOW_NS)),
+            from_waitlist: Some(true),
+        };
+        insert_reservation(reservation);
+        WAITLIST.with(|service| service.borrow_mut().remove(&key));
+        available -= entry.quantity as i64;
+        notify(
+            entry.principal,
+            NotificationKind::BackInStock { product_id },
+            format!(
+                "{} x {} are set aside for you for {} hours. Place an order to claim them.",
+                entry.quantity,
+                product.name,
+                WAITLIST_CLAIM_WINDOW_NS / 3_600_000_000_000
+            ),
+        );
+    }
+}
+
+// Function to join the waitlist of a product that cannot cover the requested quantity
+#[ic_cdk::update]
+fn join_waitlist(product_id: u64, quantity: u32) -> Result<WaitlistPosition, Error> {
+    ensure_caller_allowed(Endpoint::WatchProduct)?;
+    journal_call("join_waitlist");
+    if quantity == 0 {
+        return Err(Error::InvalidOperation {
+            msg: "Waitlist quantities must be greater than zero.".to_string(),
+        });
+    }
+    let availability = get_availability(product_id)?;
+    let product = _get_product(&product_id).expect("availability implies the product exists");
+    check_order_quantity(&product, quantity)?;
+    let waitlist = waitlist_of(product_id);
+    if waitlist.is_empty() && (quantity as i64) <= availability.available {
+        return Err(Error::InvalidOperation {
+            msg: format!("Product with id={} is in stock", product_id),
+        });
+    }
+    if waitlist
+        .iter()
+        .any(|(_, entry)| entry.principal == caller())
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "You are already on the waitlist of product id={}",
+                product_id
+            ),
+        });
+    }
+    if waitlist.len() >= MAX_WAITLIST_LENGTH {
+        return Err(Error::CapacityExceeded {
+            msg: format!(
+                "The waitlist of product id={} is full ({} customers).",
+                product_id, MAX_WAITLIST_LENGTH
+            ),
+        });
+    }
+
+    let seq = WAITLIST_SEQ.with(|counter| {
+        let seq = *counter.borrow().get();
+        counter
+            .borrow_mut()
+            .set(seq + 1)
+            .expect("Cannot advance the waitlist counter");
+        seq
+    });
+    let entry = WaitlistEntry {
+        product_id,
+        principal: caller(),
+        quantity,
+        joined_at: time(),
+    };
+    WAITLIST.with(|service| service.borrow_mut().insert((product_id, seq), entry));
+    get_my_waitlist_position(product_id)
+}
+
+// Function to leave the waitlist of a product
+#[ic_cdk::update]
+fn leave_waitlist(product_id: u64) -> Result<WaitlistEntry, Error> {
+    ensure_caller_allowed(Endpoint::WatchProduct)?;
+    journal_call("leave_waitlist");
+    let (key, entry) = waitlist_of(product_id)
+        .into_iter()
+        .find(|(_, entry)| entry.principal == caller())
+        .ok_or(Error::NotFound {
+            msg: format!("You are not on the waitlist of product id={}", product_id),
+        })?;
+    WAITLIST.with(|service| service.borrow_mut().remove(&key));
+    // The customers behind may fit in the stock left now
+    allocate_waitlist(product_id);
+    Ok(entry)
+}
+
+// Query function to get the caller's position on a product's waitlist
+#[ic_cdk::query]
+fn get_my_waitlist_position(product_id: u64) -> Result<WaitlistPosition, Error> {
+    let mut units_ahead = 0u64;
+    for (position, (_, entry)) in waitlist_of(product_id).into_iter().enumerate() {
+        if entry.principal == caller() {
+            return Ok(WaitlistPosition {
+                product_id,
+                quantity: entry.quantity,
+                position: position as u32 + 1,
+                units_ahead,
+                joined_at: entry.joined_at,
+            });
+        }
+        units_ahead += entry.quantity as u64;
+    }
+    Err(Error::NotFound {
+        msg: format!("You are not on the waitlist of product id={}", product_id),
+    })
+}
+
+// Query function to list a product's waitlist in serving order
+#[ic_cdk::query]
+fn list_waitlist(product_id: u64) -> Result<Vec<WaitlistEntry>, Error> {
+    ensure_admin()?;
+    Ok(waitlist_of(product_id)
+        .into_iter()
+        .map(|(_, entry)| entry)
+        .collect())
+}
+
+// Helper function to validate the option groups of a configurable product
+fn validate_option_groups(groups: &[OptionGroup]) -> Result<(), Error> {
+    if groups.len() > MAX_OPTION_GROUPS {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "A product can have at most {} option groups.",
+                MAX_OPTION_GROUPS
+            ),
+        });
+    }
+    let invalid_name = |name: &str| name.trim().is_empty() || name.len() > MAX_LABEL_LENGTH;
+    let mut group_names = HashSet::new();
+    let mut choice_groups = HashMap::new();
+    for group in groups {
+        if invalid_name(&group.name) || !group_names.insert(group.name.as_str()) {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Option group names must be unique, non-empty and at most {} bytes long.",
+                    MAX_LABEL_LENGTH
+                ),
+            });
+        }
+        if group.choices.is_empty() || group.choices.len() > MAX_OPTION_CHOICES {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Option group '{}' must have between 1 and {} choices.",
+                    group.name, MAX_OPTION_CHOICES
+                ),
+            });
+        }
+        if group.max_selections == 0 || group.max_selections as usize > group.choices.len() {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Option group '{}' must allow between 1 and {} selections.",
+                    group.name,
+                    group.choices.len()
+                ),
+            });
+        }
+        for choice in &group.choices {
+            if invalid_name(&choice.name)
+                || choice_groups
+                    .insert(choice.name.as_str(), group.name.as_str())
+                    .is_some()
+            {
+                return Err(Error::InvalidOperation {
+                    msg: format!(
+                        "Option choice names must be unique across groups, non-empty and at most {} bytes long.",
+                        MAX_LABEL_LENGTH
+                    ),
+                });
+            }
+        }
+    }
+    for group in groups {
+        for choice in &group.choices {
+            if choice.excludes.len() > MAX_OPTION_EXCLUSIONS {
+                return Err(Error::InvalidOperation {
+                    msg: format!(
+                        "Option choice '{}' can exclude at most {} other choices.",
+                        choice.name, MAX_OPTION_EXCLUSIONS
+                    ),
+                });
+            }
+            for excluded in &choice.excludes {
+                match choice_groups.get(excluded.as_str()) {
+                    Some(excluded_group) if *excluded_group != group.name => {}
+                    _ => {
+                        return Err(Error::InvalidOperation {
+                            msg: format!(
+                                "Option choice '{}' excludes '{}', which is not a choice of another group",
+                                choice.name, excluded
+                            ),
+                        })
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Function to set the options a product can be configured with; an empty list removes them
+#[ic_cdk::update]
+fn set_product_options(
+    product_id: u64,
+    groups: Vec<OptionGroup>,
+) -> Result<Option<OptionSchema>, Error> {
+    ensure_admin()?;
+    journal_call("set_product_options");
+    if _get_product(&product_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("A product with id={} was not found", product_id),
+        });
+    }
+    validate_option_groups(&groups)?;
+    if groups.is_empty() {
+        PRODUCT_OPTIONS.with(|service| service.borrow_mut().remove(&product_id));
+        return Ok(None);
+    }
+    let schema = OptionSchema {
+        product_id,
+        groups,
+        updated_at: time(),
+    };
+    PRODUCT_OPTIONS.with(|service| service.borrow_mut().insert(product_id, schema.clone()));
+    Ok(Some(schema))
+}
+
+// Query function to get the options a product can be configured with
+#[ic_cdk::query]
+fn get_product_options(product_id: u64) -> Result<Option<OptionSchema>, Error> {
+    _get_visible_product(&product_id).ok_or(Error::NotFound {
+        msg: format!("A product with id={} was not found", product_id),
+    })?;
+    Ok(PRODUCT_OPTIONS.with(|service| service.borrow().get(&product_id)))
+}
+
+// Function to check selections against a product's option schema, returning the selected
+// choices with their price deltas in schema order
+fn resolve_configuration(
+    product: &Product,
+    selections: &[OptionSelection],
+) -> Result<Vec<PricedOption>, Error> {
+    let groups = PRODUCT_OPTIONS
+        .with(|service| service.borrow().get(&product.id))
+        .map(|schema| schema.groups)
+        .unwrap_or_default();
+    for (index, selection) in selections.iter().enumerate() {
+        let Some(group) = groups.iter().find(|group| group.name == selection.group) else {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Product id={} has no option group '{}'",
+                    product.id, selection.group
+                ),
+            });
+        };
+        if selections[..index]
+            .iter()
+            .any(|other| other.group == selection.group)
+        {
+            return Err(Error::InvalidOperation {
+                msg: format!("Option group '{}' is selected more than once", group.name),
+            });
+        }
+        if selection.choices.is_empty() || selection.choices.len() > group.max_selections as usize {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Option group '{}' takes between 1 and {} choices.",
+                    group.name, group.max_selections
+                ),
+            });
+        }
+    }
+
+    let mut options: Vec<(&OptionChoice, PricedOption)> = Vec::new();
+    for group in &groups {
+        let Some(selection) = selections.iter().find(|s| s.group == group.name) else {
+            if group.required {
+                return Err(Error::InvalidOperation {
+                    msg: format!(
+                        "A choice of '{}' is required for product id={}",
+                        group.name, product.id
+                    ),
+                });
+            }
+            continue;
+        };
+        for (index, name) in selection.choices.iter().enumerate() {
+            let choice = group
+                .choices
+                .iter()
+                .find(|choice| choice.name == *name)
+                .ok_or(Error::InvalidOperation {
+                    msg: format!("Option group '{}' has no choice '{}'", group.name, name),
+                })?;
+            if selection.choices[..index].contains(name) {
+                return Err(Error::InvalidOperation {
+                    msg: format!("Option choice '{}' is selected more than once", name),
+                });
+            }
+            options.push((
+                choice,
+                PricedOption {
+                    group: group.name.clone(),
+                    choice: choice.name.clone(),
+                    price_delta: choice.price_delta,
+                },
+            ));
+        }
+    }
+    for (choice, _) in &options {
+        if let Some((_, conflict)) = options
+            .iter()
+            .find(|(_, other)| choice.excludes.contains(&other.choice))
+        {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "'{}' cannot be combined with '{}'",
+                    choice.name, conflict.choice
+                ),
+            });
+        }
+    }
+    Ok(options.into_iter().map(|(_, option)| option).collect())
+}
+
+// Helper function to add the price deltas of selected options to a base unit price
+fn configured_unit_price(base_price: u64, options: &[PricedOption]) -> Result<u64, Error> {
+    let price = options.iter().fold(base_price as i128, |price, option| {
+        price + option.price_delta as i128
+    });
+    u64::try_from(price).map_err(|_| Error::InvalidOperation {
+        msg: "The selected options bring the price below zero.".to_string(),
+    })
+}
+
+// Helper function to turn the options of an order line back into the selections they came from
+fn selections_of(options: &[PricedOption]) -> Vec<OptionSelection> {
+    let mut selections: Vec<OptionSelection> = Vec::new();
+    for option in options {
+        match selections
+            .iter_mut()
+            .find(|selection| selection.group == option.group)
+        {
+            Some(selection) => selection.choices.push(option.choice.clone()),
+            None => selections.push(OptionSelection {
+                group: option.group.clone(),
+                choices: vec![option.choice.clone()],
+            }),
+        }
+    }
+    selections
+}
+
+// Query function to validate a product configuration and get its itemized unit price
+#[ic_cdk::query]
+fn price_configuration(
+    product_id: u64,
+    selections: Vec<OptionSelection>,
+) -> Result<ConfiguredPrice, Error> {
+    let product = _get_visible_product(&product_id).ok_or(Error::NotFound {
+        msg: format!("A product with id={} was not found", product_id),
+    })?;
+    let options = resolve_configuration(&product, &selections)?;
+    let base_price = tier_price_of(product_id, effective_tier(&caller()))
+        .map_or(product.price(), |price| price.unit_price);
+    Ok(ConfiguredPrice {
+        product_id,
+        base_price,
+        unit_price: configured_unit_price(base_price, &options)?,
+        options,
+    })
+}
+
+// Helper function to validate a subscription payload, resolving its payment token
+fn validate_subscription_payload(
+    payload: &SubscriptionPayload,
+) -> Result<SubscriptionPayment, Error> {
+    validate_notes(&payload.notes)?;
+    if payload.items.is_empty() || payload.items.len() > MAX_SUBSCRIPTION_ITEMS {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "A subscription must have between 1 and {} items.",
+                MAX_SUBSCRIPTION_ITEMS
+            ),
+        });
+    }
+    if payload.cadence_days == 0 || payload.cadence_days > MAX_SUBSCRIPTION_CADENCE_DAYS {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Subscriptions must repeat every 1 to {} days.",
+                MAX_SUBSCRIPTION_CADENCE_DAYS
+            ),
+        });
+    }
+    for (index, item) in payload.items.iter().enumerate() {
+        if item.quantity == 0 {
+            return Err(Error::InvalidOperation {
+                msg: "Subscription quantities must be greater than zero.".to_string(),
+            });
+        }
+        if payload.items[..index]
+            .iter()
+            .any(|other| other.product_id == item.product_id)
+        {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Product id={} appears in more than one item",
+                    item.product_id
+                ),
+            });
+        }
+        // Stock is only checked when an order is due
+        let product = _get_visible_product(&item.product_id).ok_or(Error::NotFound {
+            msg: format!("A product with id={} was not found", item.product_id),
+        })?;
+        check_order_quantity(&product, item.quantity)?;
+        resolve_configuration(&product, item.selections.as_deref().unwrap_or(&[]))?;
+    }
+    match &payload.payment_token {
+        Some(symbol) => Ok(SubscriptionPayment::Token {
+            symbol: accepted_token_by_symbol(symbol)?.symbol,
+        }),
+        None => Ok(SubscriptionPayment::PayOnPickup),
+    }
+}
+
+// Helper function to get a subscription of the caller
+fn _get_my_subscription(id: u64) -> Result<Subscription, Error> {
+    SUBSCRIPTIONS
+        .with(|service| service.borrow().get(&id))
+        .filter(|subscription| subscription.customer == caller())
+        .ok_or(Error::NotFound {
+            msg: format!("A subscription with id={} was not found", id),
+        })
+}
+
+// Helper function to store a subscription
+fn save_subscription(subscription: &Subscription) {
+    SUBSCRIPTIONS.with(|service| {
+        service
+            .borrow_mut()
+            .insert(subscription.id, subscription.clone())
+    });
+}
+
+// Function to subscribe to a recurring order
+#[ic_cdk::update]
+fn create_subscription(payload: SubscriptionPayload) -> Result<Subscription, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    journal_call("create_subscription");
+    let customer = caller();
+    if customer == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Anonymous callers cannot subscribe. Please sign in first.".to_string(),
+        });
+    }
+    let payment = validate_subscription_payload(&payload)?;
+    let subscriptions = SUBSCRIPTIONS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, subscription)| {
+                subscription.customer == customer
+                    && subscription.status != SubscriptionStatus::Cancelled
+            })
+            .count()
+    });
+    if subscriptions >= MAX_SUBSCRIPTIONS_PER_CUSTOMER {
+        return Err(Error::CapacityExceeded {
+            msg: format!(
+                "A customer can have at most {} subscriptions.",
+                MAX_SUBSCRIPTIONS_PER_CUSTOMER
+            ),
+        });
+    }
+
+    let id =
+        generate_unique_id(|id| SUBSCRIPTIONS.with(|service| service.borrow().contains_key(&id)))?;
+    let now = time();
+    let subscription = Subscription {
+        id,
+        customer,
+        items: payload.items,
+        cadence_days: payload.cadence_days,
+        payment,
+        notes: payload.notes,
+        status: SubscriptionStatus::Active,
+        next_run_at: payload.start_at.unwrap_or(now).max(now),
+        consecutive_failures: 0,
+        last_order_id: None,
+        last_error: None,
+        created_at: now,
+        updated_at: None,
+    };
+    save_subscription(&subscription);
+    Ok(subscription)
+}
+
+// Function to change the items, cadence or payment of one of the caller's subscriptions
+#[ic_cdk::update]
+fn update_subscription(id: u64, payload: SubscriptionPayload) -> Result<Subscription, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    journal_call("update_subscription");
+    let mut subscription = _get_my_subscription(id)?;
+    if subscription.status == SubscriptionStatus::Cancelled {
+        return Err(Error::InvalidOperation {
+            msg: format!("Subscription with id={} is cancelled", id),
+        });
+    }
+    subscription.payment = validate_subscription_payload(&payload)?;
+    subscription.items = payload.items;
+    subscription.cadence_days = payload.cadence_days;
+    subscription.notes = payload.notes;
+    if let Some(start_at) = payload.start_at {
+        subscription.next_run_at = start_at.max(time());
+    }
+    subscription.updated_at = Some(time());
+    save_subscription(&subscription);
+    Ok(subscription)
+}
+
+// Function to change the status of one of the caller's subscriptions
+fn set_subscription_status(
+    mut subscription: Subscription,
+    status: SubscriptionStatus,
+) -> Result<Subscription, Error> {
+    if subscription.status == SubscriptionStatus::Cancelled {
+        return Err(Error::InvalidOperation {
+            msg: format!("Subscription with id={} is cancelled", subscription.id),
+        });
+    }
+    let now = time();
+    if status == SubscriptionStatus::Active {
+        // Runs missed while paused are skipped rather than placed at once
+        subscription.next_run_at = subscription.next_run_at.max(now);
+        subscription.consecutive_failures = 0;
+    }
+    subscription.status = status;
+    subscription.updated_at = Some(now);
+    save_subscription(&subscription);
+    Ok(subscription)
+}
+
+// Function to pause one of the caller's subscriptions
+#[ic_cdk::update]
+fn pause_subscription(id: u64) -> Result<Subscription, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    let subscription = _get_my_subscription(id)?;
+    journal_call("pause_subscription");
+    set_subscription_status(subscription, SubscriptionStatus::Paused)
+}
+
+// Function to resume one of the caller's paused subscriptions
+#[ic_cdk::update]
+fn resume_subscription(id: u64) -> Result<Subscription, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    let subscription = _get_my_subscription(id)?;
+    journal_call("resume_subscription");
+    set_subscription_status(subscription, SubscriptionStatus::Active)
+}
+
+// Function to cancel one of the caller's subscriptions for good
+#[ic_cdk::update]
+fn cancel_subscription(id: u64) -> Result<Subscription, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    let subscription = _get_my_subscription(id)?;
+    journal_call("cancel_subscription");
+    set_subscription_status(subscription, SubscriptionStatus::Cancelled)
+}
+
+// Query function to list the caller's subscriptions
+#[ic_cdk::query]
+fn list_my_subscriptions() -> Vec<Subscription> {
+    let customer = caller();
+    SUBSCRIPTIONS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, subscription)| subscription)
+            .filter(|subscription| subscription.customer == customer)
+            .collect()
+    })
+}
+
+// Query function to list every subscription, optionally only those in a status
+#[ic_cdk::query]
+fn list_subscriptions(status: Option<SubscriptionStatus>) -> Result<Vec<Subscription>, Error> {
+    ensure_admin()?;
+    Ok(SUBSCRIPTIONS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, subscription)| subscription)
+            .filter(|subscription| status.is_none_or(|status| subscription.status == status))
+            .collect()
+    }))
+}
+
+// Function to place the orders of subscriptions that are due, run by the background job
+fn run_due_subscriptions() {
+    let now = time();
+    let due: Vec<Subscription> = SUBSCRIPTIONS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, subscription)| subscription)
+            .filter(|subscription| {
+                subscription.status == SubscriptionStatus::Active && subscription.next_run_at <= now
+            })
+            .take(SUBSCRIPTION_RUN_BATCH)
+            .collect()
+    });
+    for mut subscription in due {
+        // Runs missed while the canister was stopped are skipped rather than placed at once
+        let cadence_ns = subscription.cadence_days as u64 * NANOS_PER_DAY;
+        while subscription.next_run_at <= now {
+            subscription.next_run_at += cadence_ns;
+        }
+        subscription.updated_at = Some(now);
+        match place_subscription_order(&subscription) {
+            Ok(order) => {
+                subscription.last_order_id = Some(order.id);
+                if order.payment.is_some() {
+                    enqueue_effect(OutboxEffect::SubscriptionPayment {
+                        subscription_id: subscription.id,
+                        order_id: order.id,
+                    });
+                } else {
+                    subscription.consecutive_failures = 0;
+                    subscription.last_error = None;
+                }
+            }
+            Err(error) => record_subscription_failure(&mut subscription, error.message()),
+        }
+        save_subscription(&subscription);
+    }
+}
+
+// Helper function to place the order of one run of a subscription, taking its stock
+fn place_subscription_order(subscription: &Subscription) -> Result<Order, Error> {
+    let requested: Vec<OrderLinePayload> = subscription
+        .items
+        .iter()
+        .map(|item| OrderLinePayload {
+            product_id: item.product_id,
+            quantity: item.quantity,
+            selections: item.selections.clone(),
+        })
+        .collect();
+    let lines = check_order_lines(&subscription.customer, &requested)?;
+    let priced = price_order(&subscription.customer, lines);
+    let payment = match &subscription.payment {
+        SubscriptionPayment::PayOnPickup => None,
+        SubscriptionPayment::Token { symbol } => {
+            let token = accepted_token_by_symbol(symbol)?;
+            Some(OrderPayment {
+                amount: token_amount(&token, priced.subtotal - priced.discount),
+                symbol: token.symbol,
+                ledger: token.ledger,
+                escrow: None,
+            })
+        }
+    };
+    let notes = Some(format!("Subscription #{}", subscription.id));
+    commit_order(
+        subscription.customer,
+        priced,
+        subscription.notes.clone().or(notes),
+        None,
+        payment,
+        None,
+    )
+}
+
+// Helper function to count a failed run, pausing the subscription after too many in a row
+fn record_subscription_failure(subscription: &mut Subscription, msg: &str) {
+    subscription.consecutive_failures += 1;
+    subscription.last_error = Some(msg.to_string());
+    if subscription.consecutive_failures >= MAX_SUBSCRIPTION_FAILURES {
+        subscription.status = SubscriptionStatus::Paused;
+        notify(
+            subscription.customer,
+            NotificationKind::SubscriptionPaused {
+                subscription_id: subscription.id,
+            },
+            format!(
+                "Your subscription #{} was paused after {} failed orders: {}",
+                subscription.id, subscription.consecutive_failures, msg
+            ),
+        );
+    }
+}
+
+// Function to pull the payment of a subscription order from the customer's account into the
+// order's escrow; the order is cancelled when the ledger refuses the transfer
+async fn collect_subscription_payment(
+    subscription_id: u64,
+    order_id: u64,
+    final_attempt: bool,
+) -> Result<(), (bool, String)> {
+    let outcome = match transfer_subscription_payment(order_id).await {
+        Ok(()) => mark_payment_held(order_id).map(|_| ()),
+        // Calls that did not reach the ledger are retried from the outbox
+        Err((true, msg)) if !final_attempt => return Err((true, msg)),
+        Err((_, msg)) => {
+            let _ = do_update_order_status(order_id, OrderStatus::Cancelled, ic_cdk::id());
+            Err(Error::ExternalCallFailed { msg })
+        }
+    };
+    if let Some(mut subscription) =
+        SUBSCRIPTIONS.with(|service| service.borrow().get(&subscription_id))
+    {
+        match &outcome {
+            Ok(()) => {
+                subscription.consecutive_failures = 0;
+                subscription.last_error = None;
+            }
+            Err(error) => record_subscription_failure(&mut subscription, error.message()),
+        }
+        subscription.updated_at = Some(time());
+        save_subscription(&subscription);
+    }
+    outcome.map_err(|error| (false, error.message().to_string()))
+}
+
+// Helper function to make the ICRC-2 transfer paying a subscription order; failures that can
+// be retried are flagged
+async fn transfer_subscription_payment(order_id: u64) -> Result<(), (bool, String)> {
+    let order = _get_order(&order_id).ok_or((false, "Order not found".to_string()))?;
+    let payment = order
+        .payment
+        .ok_or((false, "Order is not paid with a token".to_string()))?;
+    let escrow = payment
+        .escrow
+        .ok_or((false, "Order has no escrow".to_string()))?;
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: order.customer,
+            subaccount: None,
+        },
+        to: Account {
+            owner: ic_cdk::id(),
+            subaccount: Some(escrow.subaccount),
+        },
+        amount: payment.amount,
+        fee: None,
+        memo: Some(order_id.to_be_bytes().to_vec()),
+        created_at_time: Some(ledger_time(order.created_at)),
+    };
+    let result: Result<(Result<Nat, TransferFromError>,), _> =
+        ic_cdk::call(payment.ledger, "icrc2_transfer_from", (args,)).await;
+    match result {
+        Ok((Ok(_),)) | Ok((Err(TransferFromError::Duplicate { .. }),)) => Ok(()),
+        Ok((Err(error),)) => Err((false, format!("Ledger rejected the payment: {:?}", error))),
+        Err((code, msg)) => Err((
+            true,
+            format!("Failed to call the ledger ({:?}): {}", code, msg),
+        )),
+    }
+}
+
+// Helper function to find the open shift of a staff member
+fn open_shift_of(staff: &Principal) -> Option<Shift> {
+    SHIFTS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, shift)| shift)
+            .find(|shift| shift.staff == *staff && shift.closed_at.is_none())
+    })
+}
+
+// Helper function to get the shift a change made by `actor` belongs to: their own open shift,
+// or else the longest-running open shift, e.g. for orders placed by customers
+fn current_shift(actor: &Principal) -> Option<Shift> {
+    open_shift_of(actor).or_else(|| {
+        SHIFTS.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .map(|(_, shift)| shift)
+                .filter(|shift| shift.closed_at.is_none())
+                .min_by_key(|shift| shift.opened_at)
+        })
+    })
+}
+
+// Function to record a stock change against the current shift; changes made while no shift
+// is open are not logged
+fn record_stock_movement(
+    actor: &Principal,
+    product_id: u64,
+    kind: StockMovementKind,
+    quantity_delta: i64,
+    value: i64,
+    order_id: Option<u64>,
+) {
+    store_stock_movement(StockMovement {
+        product_id,
+        kind,
+        quantity_delta,
+        value,
+        by: *actor,
+        order_id,
+        at: time(),
+        display_delta: None,
+    });
+}
+
+// Function to record a stock movement in the shift open for its actor, if any
+fn store_stock_movement(movement: StockMovement) {
+    let Some(shift) = current_shift(&movement.by) else {
+        return;
+    };
+    let seq = STOCK_MOVEMENT_SEQ.with(|counter| {
+        let seq = *counter.borrow().get();
+        counter
+            .borrow_mut()
+            .set(seq + 1)
+            .expect("Cannot advance the stock movement counter");
+        seq
+    });
+    STOCK_MOVEMENTS.with(|service| service.borrow_mut().insert((shift.id, seq), movement));
+}
+
+// Function for a staff member to start their shift
+#[ic_cdk::update]
+fn open_shift() -> Result<Shift, Error> {
+    ensure_caller_allowed(Endpoint::AddQuantity)?;
+    journal_call("open_shift");
+    let staff = caller();
+    if staff == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Anonymous callers cannot open a shift. Please sign in first.".to_string(),
+        });
+    }
+    if let Some(shift) = open_shift_of(&staff) {
+        return Err(Error::InvalidOperation {
+            msg: format!("Shift with id={} is already open", shift.id),
+        });
+    }
+    let id = generate_unique_id(|id| SHIFTS.with(|service| service.borrow().contains_key(&id)))?;
+    let shift = Shift {
+        id,
+        staff,
+        opened_at: time(),
+        closed_at: None,
+    };
+    SHIFTS.with(|service| service.borrow_mut().insert(id, shift.clone()));
+    Ok(shift)
+}
+
+// Function for a staff member to end their shift, returning its report
+#[ic_cdk::update]
+fn close_shift() -> Result<ShiftReport, Error> {
+    ensure_caller_allowed(Endpoint::AddQuantity)?;
+    journal_call("close_shift");
+    let mut shift = open_shift_of(&caller()).ok_or(Error::NotFound {
+        msg: "The caller has no open shift".to_string(),
+    })?;
+    shift.closed_at = Some(time());
+    SHIFTS.with(|service| service.borrow_mut().insert(shift.id, shift.clone()));
+    Ok(build_shift_report(shift))
+}
+
+// Query function to get the caller's open shift, if any
+#[ic_cdk::query]
+fn get_my_shift() -> Option<Shift> {
+    open_shift_of(&caller())
+}
+
+// Query function to list shifts, optionally only the open ones, most recent first
+#[ic_cdk::query]
+fn list_shifts(open_only: bool) -> Result<Vec<Shift>, Error> {
+    ensure_admin()?;
+    let mut shifts: Vec<Shift> = SHIFTS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, shift)| shift)
+            .filter(|shift| !open_only || shift.closed_at.is_none())
+            .collect()
+    });
+    shifts.sort_by_key(|shift| std::cmp::Reverse(shift.opened_at));
+    Ok(shifts)
+}
+
+// Helper function to summarize the stock movements and orders of a shift
+fn build_shift_report(shift: Shift) -> ShiftReport {
+    let mut products: Vec<ShiftProductSummary> = Vec::new();
+    let mut sales_total = 0;
+    STOCK_MOVEMENTS.with(|service| {
+        for (_, movement) in service.borrow().range((shift.id, 0)..=(shift.id, u64::MAX)) {
+            let index = match products
+                .iter()
+                .position(|summary| summary.product_id == movement.product_id)
+            {
+                Some(index) => index,
+                None => {
+                    products.push(ShiftProductSummary {
+                        product_id: movement.product_id,
+                        ..Default::default()
+                    });
+                    products.len() - 1
+                }
+            };
+            let summary = &mut products[index];
+            let units = movement.quantity_delta.unsigned_abs();
+            match movement.kind {
+                StockMovementKind::Restock => summary.restocked += units,
+                StockMovementKind::Waste => summary.wasted += units,
+                StockMovementKind::Adjustment => summary.adjusted += movement.quantity_delta,
+                StockMovementKind::Sale => summary.sold += units,
+                StockMovementKind::Cancellation => summary.cancelled += units,
+            }
+            sales_total += movement.value;
+        }
+    });
+    products.sort_by_key(|summary| summary.product_id);
+    let orders = ORDERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, order)| order.shift_id == Some(shift.id))
+            .count() as u64
+    });
+    ShiftReport {
+        orders,
+        sales_total,
+        units_sold: products
+            .iter()
+            .map(|summary| summary.sold.saturating_sub(summary.cancelled))
+            .sum(),
+        units_wasted: products.iter().map(|summary| summary.wasted).sum(),
+        units_restocked: products.iter().map(|summary| summary.restocked).sum(),
+        units_adjusted: products.iter().map(|summary| summary.adjusted).sum(),
+        products,
+        shift,
+    }
+}
+
+// Query function to summarize a shift's sales, waste and adjustments; staff can see their own
+// shifts
+#[ic_cdk::query]
+fn shift_report(shift_id: u64) -> Result<ShiftReport, Error> {
+    let shift = SHIFTS
+        .with(|service| service.borrow().get(&shift_id))
+        .filter(|shift| shift.staff == caller() || ensure_admin().is_ok())
+        .ok_or(Error::NotFound {
+            msg: format!("A shift with id={} was not found", shift_id),
+        })?;
+    Ok(build_shift_report(shift))
+}
+
+// Helper function to record who started or finished preparing an order
+fn record_prep_step(order_id: u64, status: OrderStatus, actor: Principal, now: u64) {
+    if !matches!(status, OrderStatus::Preparing | OrderStatus::Ready) {
+        return;
+    }
+    ORDER_PREP.with(|service| {
+        let mut prep = service.borrow().get(&order_id).unwrap_or_default();
+        if status == OrderStatus::Preparing {
+            prep.started_at = Some(now);
+            prep.started_by = Some(actor);
+        } else {
+            prep.ready_at = Some(now);
+            prep.ready_by = Some(actor);
+        }
+        service.borrow_mut().insert(order_id, prep);
+    });
+}
+
+// Query function to report, per staff member, the shifts worked, orders prepared and their
+// average prep time, waste recorded and stock adjustments made within a period; orders count for
+// whoever marked them ready
+#[ic_cdk::query]
+fn staff_report(period: ReportPeriod) -> Result<StaffReport, Error> {
+    ensure_admin()?;
+    if period.from > period.to {
+        return Err(Error::InvalidOperation {
+            msg: "A report period cannot end before it starts.".to_string(),
+        });
+    }
+    let now = time();
+    let mut staff: HashMap<Principal, StaffPerformance> = HashMap::new();
+    let mut prep_totals: HashMap<Principal, (u64, u64)> = HashMap::new();
+    SHIFTS.with(|service| {
+        for (_, shift) in service.borrow().iter() {
+            let start = shift.opened_at.max(period.from);
+            let end = shift.closed_at.unwrap_or(now).min(period.to);
+            if start >= end {
+                continue;
+            }
+            let performance = staff_entry(&mut staff, shift.staff);
+            performance.shifts += 1;
+            performance.shift_ns += end - start;
+        }
+    });
+    ORDER_PREP.with(|service| {
+        for (_, prep) in service.borrow().iter() {
+            let (Some(ready_at), Some(ready_by)) = (prep.ready_at, prep.ready_by) else {
+                continue;
+            };
+            if !(period.from..period.to).contains(&ready_at) {
+                continue;
+            }
+            staff_entry(&mut staff, ready_by).orders_prepared += 1;
+            if let Some(started_at) = prep.started_at.filter(|started_at| *started_at <= ready_at) {
+                let totals = prep_totals.entry(ready_by).or_default();
+                totals.0 += ready_at - started_at;
+                totals.1 += 1;
+            }
+        }
+    });
+    STOCK_MOVEMENTS.with(|service| {
+        for (_, movement) in service.borrow().iter() {
+            if !(period.from..period.to).contains(&movement.at) {
+                continue;
+            }
+            match movement.kind {
+                StockMovementKind::Waste => {
+                    let units = movement.quantity_delta.unsigned_abs();
+                    let unit_cost = _get_product(&movement.product_id)
+                        .and_then(|product| product.unit_cost)
+                        .unwrap_or(0);
+                    let performance = staff_entry(&mut staff, movement.by);
+                    performance.units_wasted += units;
+                    performance.waste_cost = performance
+                        .waste_cost
+                        .saturating_add(units.saturating_mul(unit_cost));
+                }
+                StockMovementKind::Adjustment => {
+                    let performance = staff_entry(&mut staff, movement.by);
+                    performance.adjustments += 1;
+                    performance.units_adjusted += movement.quantity_delta;
+                }
+                _ => {}
+            }
+        }
+    });
+    let mut staff: Vec<StaffPerformance> = staff
+        .into_values()
+        .map(|mut performance| {
+            performance.average_prep_ns = prep_totals
+                .get(&performance.staff)
+                .and_then(|(total, count)| total.checked_div(*count));
+            performance
+        })
+        .collect();
+    staff.sort_by_key(|performance| performance.staff);
+    Ok(StaffReport { period, staff })
+}
+
+// Helper function to get the report entry of a staff member, adding an empty one first
+fn staff_entry(
+    staff: &mut HashMap<Principal, StaffPerformance>,
+    principal: Principal,
+) -> &mut StaffPerformance {
+    staff.entry(principal).or_insert_with(|| StaffPerformance {
+        staff: principal,
+        shifts: 0,
+        shift_ns: 0,
+        orders_prepared: 0,
+        average_prep_ns: None,
+        units_wasted: 0,
+        waste_cost: 0,
+        adjustments: 0,
+        units_adjusted: 0,
+    })
+}
+
+// Helper function to check whether a product is age-restricted
+fn is_age_restricted(product: &Product) -> bool {
+    product.age_restricted == Some(true)
+}
+
+// Helper function to refuse age-restricted products to customers not attested as adults
+fn check_age_restriction(customer: &Principal, product: &Product) -> Result<(), Error> {
+    if !is_age_restricted(product)
+        || AGE_ATTESTATIONS.with(|service| service.borrow().contains_key(&PrincipalKey(*customer)))
+    {
+        return Ok(());
+    }
+    Err(Error::Other {
+        kind: "AgeVerificationRequired".to_string(),
+        msg: format!(
+            "Product id={} is age-restricted. Please have staff verify your age before ordering it.",
+            product.id
+        ),
+    })
+}
+
+// Function for staff to attest that a customer is an adult, or to withdraw the attestation
+#[ic_cdk::update]
+fn set_adult_attestation(
+    principal: Principal,
+    adult: bool,
+) -> Result<Option<AgeAttestation>, Error> {
+    ensure_admin()?;
+    journal_call("set_adult_attestation");
+    let key = PrincipalKey(principal);
+    if !adult {
+        AGE_ATTESTATIONS.with(|service| service.borrow_mut().remove(&key));
+        return Ok(None);
+    }
+    let attestation = AgeAttestation {
+        principal,
+        attested_by: caller(),
+        attested_at: time(),
+    };
+    AGE_ATTESTATIONS.with(|service| service.borrow_mut().insert(key, attestation.clone()));
+    Ok(Some(attestation))
+}
+
+// Query function to get the caller's adult attestation, if any
+#[ic_cdk::query]
+fn get_my_age_attestation() -> Option<AgeAttestation> {
+    AGE_ATTESTATIONS.with(|service| service.borrow().get(&PrincipalKey(caller())))
+}
+
+// Helper function to get the units of each line still to be fulfilled
+fn open_lines(order: &Order) -> Vec<OrderLine> {
+    order
+        .lines
+        .iter()
+        .map(|line| OrderLine {
+            quantity: line.quantity - line.fulfilled.unwrap_or(0) - line.unfulfillable.unwrap_or(0),
+            ..line.clone()
+        })
+        .filter(|line| line.quantity > 0)
+        .collect()
+}
+
+// Helper function to get the total of an order once unfulfillable lines are refunded
+fn amount_due(order: &Order) -> u64 {
+    order.total - order.refund_due.unwrap_or(0)
+}
+
+// Helper function to convert the refund due on an order into base units of its payment token
+fn partial_refund_amount(order: &Order, payment: &OrderPayment) -> Nat {
+    let refund_due = order.refund_due.unwrap_or(0);
+    if refund_due == 0 || order.total == 0 {
+        return Nat::from(0u64);
+    }
+    payment.amount.clone() * Nat::from(refund_due) / Nat::from(order.total)
+}
+
+// Helper function to convert the cancellation fee of an order into base units of its payment
+// token
+fn cancellation_fee_amount(order: &Order, payment: &OrderPayment) -> Nat {
+    let fee = order
+        .cancellation
+        .as_ref()
+        .map_or(0, |cancellation| cancellation.fee);
+    if fee == 0 || order.total == 0 {
+        return Nat::from(0u64);
+    }
+    payment.amount.clone() * Nat::from(fee) / Nat::from(order.total)
+}
+
+// Helper function to check fulfillment lines against an order's open units
+fn check_fulfillment_lines(order: &Order, lines: &[FulfillmentLinePayload]) -> Result<(), Error> {
+    if !matches!(
+        order.status,
+        OrderStatus::Placed | OrderStatus::Preparing | OrderStatus::Ready
+    ) {
+        return Err(Error::InvalidOperation {
+            msg: format!("Order with id={} is {:?}", order.id, order.status),
+        });
+    }
+    if lines.is_empty() {
+        return Err(Error::InvalidOperation {
+            msg: "At least one line must be given.".to_string(),
+        });
+    }
+    let open = open_lines(order);
+    for (index, line) in lines.iter().enumerate() {
+        if lines[..index]
+            .iter()
+            .any(|other| other.product_id == line.product_id)
+        {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Product id={} appears in more than one line",
+                    line.product_id
+                ),
+            });
+        }
+        let remaining = open
+            .iter()
+            .find(|open| open.product_id == line.product_id)
+            .map_or(0, |open| open.quantity);
+        if line.quantity == 0 || line.quantity > remaining {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Between 1 and {} units of product id={} are left to fulfill in order id={}",
+                    remaining, line.product_id, order.id
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Function for staff to hand over part of an order now, the rest following later
+#[ic_cdk::update]
+fn fulfill_order_lines(order_id: u64, lines: Vec<FulfillmentLinePayload>) -> Result<Order, Error> {
+    ensure_admin()?;
+    journal_call("fulfill_order_lines");
+    let mut order = _get_order(&order_id).ok_or(Error::NotFound {
+        msg: format!("An order with id={} was not found", order_id),
+    })?;
+    check_fulfillment_lines(&order, &lines)?;
+    for line in &lines {
+        if let Some(order_line) = order
+            .lines
+            .iter_mut()
+            .find(|order_line| order_line.product_id == line.product_id)
+        {
+            order_line.fulfilled = Some(order_line.fulfilled.unwrap_or(0) + line.quantity);
+        }
+    }
+    order.updated_at = Some(time());
+    store_order(order.clone());
+
+    let remaining: u32 = open_lines(&order).iter().map(|line| line.quantity).sum();
+    notify(
+        order.customer,
+        NotificationKind::OrderReady { order_id },
+        format!(
+            "Part of your order #{} is ready; {} items are still to come.",
+            order_id, remaining
+        ),
+    );
+    Ok(order)
+}
+
+// Function for staff to give up on some units of an order, refunding them to the customer
+#[ic_cdk::update]
+fn mark_lines_unfulfillable(
+    order_id: u64,
+    lines: Vec<FulfillmentLinePayload>,
+) -> Result<Order, Error> {
+    ensure_admin()?;
+    journal_call("mark_lines_unfulfillable");
+    let mut order = _get_order(&order_id).ok_or(Error::NotFound {
+        msg: format!("An order with id={} was not found", order_id),
+    })?;
+    check_fulfillment_lines(&order, &lines)?;
+    let now = time();
+    let mut given_up = Vec::new();
+    for line in &lines {
+        if let Some(order_line) = order
+            .lines
+            .iter_mut()
+            .find(|order_line| order_line.product_id == line.product_id)
+        {
+            order_line.unfulfillable = Some(order_line.unfulfillable.unwrap_or(0) + line.quantity);
+            given_up.push(OrderLine {
+                quantity: line.quantity,
+                ..order_line.clone()
+            });
+        }
+    }
+    // The order-wide discount is shared by the lines in proportion to their value
+    let lines_total: u64 = order
+        .lines
+        .iter()
+        .map(|line| line.unit_price * line.unfulfillable.unwrap_or(0) as u64)
+        .sum();
+    let refund_due = if order.subtotal == 0 {
+        0
+    } else {
+        (lines_total as u128 * order.total as u128 / order.subtotal as u128) as u64
+    };
+    let refunded_now = refund_due - order.refund_due.unwrap_or(0);
+    order.refund_due = Some(refund_due);
+    order.updated_at = Some(now);
+    let refunded_to_balances = refund_tenders(&mut order, refunded_now);
+    store_order(order.clone());
+    record_sales(&given_up, -1, now);
+
+    let escrow_status = order
+        .payment
+        .as_ref()
+        .and_then(|payment| payment.escrow.as_ref())
+        .map(|escrow| escrow.status);
+    post_transfer(
+        format!("Unfulfillable lines of order #{} refunded", order_id),
+        Some(order_id),
+        LedgerAccount::Refunds,
+        match escrow_status {
+            Some(EscrowStatus::Held) => LedgerAccount::Cash,
+            _ => LedgerAccount::Receivables,
+        },
+        refunded_now - refunded_to_balances,
+    );
+    Ok(order)
+}
+
+// Query function to get the fulfillment progress of an order; customers can only see their own
+#[ic_cdk::query]
+fn get_order_fulfillment(order_id: u64) -> Result<OrderFulfillment, Error> {
+    let order = get_order(order_id)?;
+    let lines = order
+        .lines
+        .iter()
+        .map(|line| {
+            let fulfilled = line.fulfilled.unwrap_or(0);
+            let unfulfillable = line.unfulfillable.unwrap_or(0);
+            LineFulfillment {
+                product_id: line.product_id,
+                quantity: line.quantity,
+                fulfilled,
+                unfulfillable,
+                remaining: match order.status {
+                    OrderStatus::Cancelled | OrderStatus::PickedUp | OrderStatus::Delivered => 0,
+                    _ => line.quantity - fulfilled - unfulfillable,
+                },
+            }
+        })
+        .collect();
+    Ok(OrderFulfillment {
+        order_id,
+        status: order.status,
+        lines,
+        refund_due: order.refund_due.unwrap_or(0),
+        amount_due: amount_due(&order),
+    })
+}
+
+// Helper function to get the first day of a month counted from January 1970, in days since the
+// Unix epoch
+fn first_day_of_month(month: u64) -> u64 {
+    // Days-from-civil conversion of the proleptic Gregorian calendar
+    let year = 1970 + (month / 12) as i64;
+    let month = (month % 12) as i64 + 1;
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    (era * 146_097 + doe - 719_468) as u64
+}
+
+// Helper function to convert days since the Unix epoch into months since January 1970
+fn month_of_day(day: u64) -> u64 {
+    let (year, month, _) = civil_date(day);
+    ((year - 1970) * 12 + month - 1) as u64
+}
+
+// Helper function to convert days since the Unix epoch into a (year, month, day of month) date
+fn civil_date(day: u64) -> (i64, i64, i64) {
+    // Civil-from-days conversion of the proleptic Gregorian calendar
+    let z = day as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, doy - (153 * mp + 2) / 5 + 1)
+}
+
+// Helper function to convert a (year, month, day of month) date into days since the Unix epoch
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Days-from-civil conversion of the proleptic Gregorian calendar, the inverse of civil_date
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+// Helper function to check whether a year has a 29th of February
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+// Function to apply the retention policy, or with `dry_run` only report what it would purge;
+// real runs that purge something are recorded in the retention audit
+fn apply_retention(dry_run: bool) -> RetentionReport {
+    let now = time();
+    let policy = CONFIG
+        .with(|config| config.borrow().get().retention)
+        .unwrap_or_default();
+    let mut report = RetentionReport {
+        at: now,
+        ..Default::default()
+    };
+
+    if let Some(days) = policy.customer_data_after_days {
+        let cutoff = now.saturating_sub(days as u64 * NANOS_PER_DAY);
+        let mut expired: Vec<Principal> = CUSTOMERS.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .map(|(_, customer)| customer)
+                .filter(|customer| customer.last_order_at < cutoff)
+                .map(|customer| customer.principal)
+                .take(RETENTION_CUSTOMER_BATCH + 1)
+                .collect()
+        });
+        if expired.len() > RETENTION_CUSTOMER_BATCH {
+            expired.pop();
+            report.more_pending = true;
+        }
+        report.customers_purged = expired.len() as u32;
+        let expired: HashSet<Principal> = expired.into_iter().collect();
+        for principal in &expired {
+            let key = PrincipalKey(*principal);
+            let notifications = notification_keys(*principal);
+            report.notifications_removed += notifications.len() as u32;
+            if AGE_ATTESTATIONS.with(|service| service.borrow().contains_key(&key)) {
+                report.attestations_removed += 1;
+            }
+            if !dry_run {
+                CUSTOMERS.with(|service| service.borrow_mut().remove(&key));
+                AGE_ATTESTATIONS.with(|service| service.borrow_mut().remove(&key));
+                BIRTHDAYS.with(|service| service.borrow_mut().remove(&key));
+                forget_receipt_email(&key);
+                NOTIFICATIONS.with(|service| {
+                    let mut service = service.borrow_mut();
+                    for key in notifications {
+                        service.remove(&key);
+                    }
+                });
+            }
+        }
+        if !expired.is_empty() {
+            // Orders are kept for the books; only the free-text notes may hold personal data
+            let scrubbed: Vec<Order> = ORDERS.with(|service| {
+                service
+                    .borrow()
+                    .iter()
+                    .map(|(_, order)| order)
+                    .filter(|order| expired.contains(&order.customer) && order.notes.is_some())
+                    .collect()
+            });
+            report.orders_scrubbed = scrubbed.len() as u32;
+            if !dry_run {
+                ORDERS.with(|service| {
+                    let mut orders = service.borrow_mut();
+                    for mut order in scrubbed {
+                        order.notes = None;
+                        orders.insert(order.id, order);
+                    }
+                });
+            }
+        }
+    }
+
+    if let Some(days) = policy.daily_sales_after_days {
+        let cutoff_day = (now / NANOS_PER_DAY).saturating_sub(days as u64);
+        let mut expired: Vec<((u64, u64), DailySales)> = DAILY_SALES.with(|service| {
+            service
+                .borrow()
+                .range(..(cutoff_day, 0))
+                .take(RETENTION_SALES_BATCH + 1)
+                .collect()
+        });
+        if expired.len() > RETENTION_SALES_BATCH {
+            expired.pop();
+            report.more_pending = true;
+        }
+        report.daily_sales_rolled_up = expired.len() as u32;
+        if !dry_run {
+            for ((day, product_id), sales) in expired {
+                let key = (month_of_day(day), product_id);
+                MONTHLY_SALES.with(|service| {
+                    let mut monthly = service.borrow_mut();
+                    let mut totals = monthly.get(&key).unwrap_or_default();
+                    totals.units += sales.units;
+                    totals.revenue += sales.revenue;
+                    monthly.insert(key, totals);
+                });
+                DAILY_SALES.with(|service| service.borrow_mut().remove(&(day, product_id)));
+            }
+        }
+    }
+
+    let purged = report.customers_purged > 0 || report.daily_sales_rolled_up > 0;
+    if !dry_run && purged {
+        RETENTION_AUDIT.with(|service| {
+            let mut audit = service.borrow_mut();
+            audit.insert(now, report.clone());
+            while audit.len() > MAX_RETENTION_AUDIT_ENTRIES {
+                let Some((oldest, _)) = audit.iter().next() else {
+                    break;
+                };
+                audit.remove(&oldest);
+            }
+        });
+    }
+    report
+}
+
+// Function to set the retention policy applied by the expiry sweep
+#[ic_cdk::update]
+fn set_retention_policy(policy: RetentionPolicy) -> Result<RetentionPolicy, Error> {
+    ensure_admin()?;
+    journal_call("set_retention_policy");
+    if policy.customer_data_after_days == Some(0) || policy.daily_sales_after_days == Some(0) {
+        return Err(Error::InvalidOperation {
+            msg: "Retention periods must be at least one day.".to_string(),
+        });
+    }
+    update_config(|config| config.retention = Some(policy))?;
+    Ok(policy)
+}
+
+// Query function to get the retention policy in effect
+#[ic_cdk::query]
+fn get_retention_policy() -> Result<RetentionPolicy, Error> {
+    ensure_admin()?;
+    Ok(CONFIG
+        .with(|config| config.borrow().get().retention)
+        .unwrap_or_default())
+}
+
+// Query function to preview what the next run of the retention policy would purge
+#[ic_cdk::query]
+fn preview_retention() -> Result<RetentionReport, Error> {
+    ensure_admin()?;
+    Ok(apply_retention(true))
+}
+
+// Query function to list the audit of retention runs, most recent first
+#[ic_cdk::query]
+fn list_retention_audit() -> Result<Vec<RetentionReport>, Error> {
+    ensure_admin()?;
+    Ok(RETENTION_AUDIT.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, report)| report)
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .collect()
+    }))
+}
+
+// Query function to get the monthly sales rollups between two months (inclusive, counted from
+// January 1970), optionally for a single product
+#[ic_cdk::query]
+fn get_monthly_sales(
+    from_month: u64,
+    to_month: u64,
+    product_id: Option<u64>,
+) -> Result<Vec<MonthlySalesReport>, Error> {
+    ensure_viewer()?;
+    Ok(MONTHLY_SALES.with(|service| {
+        service
+            .borrow()
+            .range((from_month, 0)..=(to_month, u64::MAX))
+            .filter(|((_, id), _)| product_id.is_none_or(|product_id| *id == product_id))
+            .map(|((month, product_id), sales)| MonthlySalesReport {
+                year: 1970 + (month / 12) as u32,
+                month: (month % 12) as u32 + 1,
+                product_id,
+                units: sales.units,
+                revenue: sales.revenue,
+            })
+            .collect()
+    }))
+}
+
+// Query function to export every record tied to the caller
+#[ic_cdk::query]
+fn export_my_data() -> MyDataExport {
+    let principal = caller();
+    let key = PrincipalKey(principal);
+    let sub_principals = sub_principals_of(principal)
+        .into_iter()
+        .filter_map(|sub| SPENDING_LIMITS.with(|service| service.borrow().get(&PrincipalKey(sub))))
+        .collect();
+    MyDataExport {
+        principal,
+        exported_at: time(),
+        profile: _get_customer(&principal),
+        age_attestation: AGE_ATTESTATIONS.with(|service| service.borrow().get(&key)),
+        orders: ORDERS.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .filter(|(_, order)| order.customer == principal)
+                .map(|(_, order)| order)
+                .collect()
+        }),
+        quotes: QUOTES.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .filter(|(_, quote)| quote.customer == principal)
+                .map(|(_, quote)| quote)
+                .collect()
+        }),
+        reservations: RESERVATIONS.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .filter(|(_, reservation)| reservation.holder == principal)
+                .map(|(_, reservation)| reservation)
+                .collect()
+        }),
+        subscriptions: list_my_subscriptions(),
+        tickets: TICKETS.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .filter(|(_, ticket)| ticket.customer == principal)
+                .map(|(_, ticket)| ticket)
+                .collect()
+        }),
+        notifications: NOTIFICATIONS.with(|service| {
+            service
+                .borrow()
+                .range((key, 0)..=(key, u64::MAX))
+                .map(|(_, notification)| notification)
+                .collect()
+        }),
+        watched_products: STOCK_WATCHERS.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .filter(|((_, watcher), _)| *watcher == key)
+                .map(|((product_id, _), _)| product_id)
+                .collect()
+        }),
+        waitlists: WAITLIST.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .filter(|(_, entry)| entry.principal == principal)
+                .map(|(_, entry)| entry)
+                .collect()
+        }),
+        spending_limit: SPENDING_LIMITS.with(|service| service.borrow().get(&key)),
+        tier: CUSTOMER_TIERS.with(|service| service.borrow().get(&key)),
+        cart: CARTS.with(|service| service.borrow().get(&key)),
+        checkout_sessions: CHECKOUT_SESSIONS.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .filter(|(_, session)| session.customer == principal)
+                .map(|(_, session)| session)
+                .collect()
+        }),
+        order_messages: ORDER_THREADS.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .filter(|(_, thread)| thread.customer == principal)
+                .flat_map(|(order_id, _)| {
+                    ORDER_MESSAGES.with(|messages| {
+                        messages
+                            .borrow()
+                            .range((order_id, 0)..=(order_id, u64::MAX))
+                            .map(|(_, message)| message)
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect()
+        }),
+        sub_principals,
+        shifts: SHIFTS.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .filter(|(_, shift)| shift.staff == principal)
+                .map(|(_, shift)| shift)
+                .collect()
+        }),
+        returns: customer_returns(principal),
+        account: linked_account(&principal),
+        language: language_of(&principal),
+        balance: TENDER_BALANCES.with(|service| service.borrow().get(&key)),
+        disputes: customer_disputes(principal),
+        birthday: BIRTHDAYS.with(|service| service.borrow().get(&key)),
+        receipt_email: CUSTOMER_EMAILS.with(|service| service.borrow().get(&key)),
+    }
+}
+
+// Function to delete the caller's account: personal records are removed and orders and tickets,
+// kept for the books, are anonymized; refused while an order is open or a payment is settling
+#[ic_cdk::update]
+fn delete_my_account() -> Result<AccountDeletion, Error> {
+    ensure_not_in_maintenance()?;
+    journal_call("delete_my_account");
+    let principal = caller();
+    if principal == Principal::anonymous() || is_kiosk(&principal) {
+        return Err(Error::Unauthorized {
+            msg: "Only signed-in customers can delete their account.".to_string(),
+        });
+    }
+    let account = linked_account(&principal);
+    if account
+        .as_ref()
+        .is_some_and(|account| account.primary == principal)
+    {
+        return Err(Error::InvalidOperation {
+            msg: "Make another linked principal primary, or unlink the others, before deleting \
+                  the account."
+                .to_string(),
+        });
+    }
+    let key = PrincipalKey(principal);
+    let orders: Vec<Order> = ORDERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, order)| order.customer == principal)
+            .map(|(_, order)| order)
+            .collect()
+    });
+    for order in &orders {
+        let settling = order
+            .payment
+            .as_ref()
+            .and_then(|payment| payment.escrow.as_ref())
+            .is_some_and(|escrow| {
+                matches!(
+                    escrow.status,
+                    EscrowStatus::Releasing | EscrowStatus::Refunding
+                )
+            });
+        let open = matches!(
+            order.status,
+            OrderStatus::Placed | OrderStatus::Preparing | OrderStatus::Ready
+        );
+        if open || settling {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Order id={} is still open or its payment is settling. Please wait until it completes.",
+                    order.id
+                ),
+            });
+        }
+    }
+    if TENDER_BALANCES
+        .with(|service| service.borrow().get(&key))
+        .is_some_and(|balance| balance.store_credit > 0)
+    {
+        return Err(Error::InvalidOperation {
+            msg: "Spend the store credit left on the account before deleting it.".to_string(),
+        });
+    }
+    let returns = customer_returns(principal);
+    if let Some(open) = returns.iter().find(|request| {
+        matches!(
+            request.status,
+            ReturnStatus::Requested | ReturnStatus::Approved
+        )
+    }) {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Return id={} is still open. Please wait until it completes.",
+                open.id
+            ),
+        });
+    }
+    let disputes = customer_disputes(principal);
+    if let Some(open) = disputes
+        .iter()
+        .find(|dispute| dispute.status != DisputeStatus::Resolved)
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Dispute id={} is still open. Please wait until it is resolved.",
+                open.id
+            ),
+        });
+    }
+
+    let mut deletion = AccountDeletion::default();
+    ORDERS.with(|service| {
+        let mut service = service.borrow_mut();
+        for mut order in orders {
+            order.customer = Principal::anonymous();
+            order.notes = None;
+            service.insert(order.id, order);
+            deletion.orders_anonymized += 1;
+        }
+    });
+    let threads: Vec<OrderThread> = ORDER_THREADS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, thread)| thread)
+            .filter(|thread| thread.customer == principal)
+            .collect()
+    });
+    for mut thread in threads {
+        let order_id = thread.order_id;
+        ORDER_MESSAGES.with(|service| {
+            let written: Vec<OrderMessage> = service
+                .borrow()
+                .range((order_id, 0)..=(order_id, u64::MAX))
+                .map(|(_, message)| message)
+                .filter(|message| !message.from_staff)
+                .collect();
+            let mut messages = service.borrow_mut();
+            for mut message in written {
+                message.author = Principal::anonymous();
+                message.text = String::new();
+                messages.insert((order_id, message.seq), message);
+            }
+        });
+        thread.customer = Principal::anonymous();
+        thread.customer_unread = 0;
+        ORDER_THREADS.with(|service| service.borrow_mut().insert(order_id, thread));
+    }
+    TICKETS.with(|service| {
+        let tickets: Vec<Ticket> = service
+            .borrow()
+            .iter()
+            .map(|(_, ticket)| ticket)
+            .filter(|ticket| ticket.customer == principal)
+            .collect();
+        let mut service = service.borrow_mut();
+        for mut ticket in tickets {
+            ticket.customer = Principal::anonymous();
+            ticket.text = String::new();
+            ticket.status = TicketStatus::Closed;
+            service.insert(ticket.id, ticket);
+            deletion.tickets_anonymized += 1;
+        }
+    });
+    RETURNS.with(|service| {
+        let mut service = service.borrow_mut();
+        for mut request in returns {
+            request.customer = Principal::anonymous();
+            request.reason = String::new();
+            request.photos.clear();
+            for event in request.events.iter_mut() {
+                if event.by == principal {
+                    event.by = Principal::anonymous();
+                }
+            }
+            service.insert(request.id, request);
+            deletion.returns_anonymized += 1;
+        }
+    });
+    // Resolved disputes stay for the audit trail, without what identifies the customer
+    DISPUTES.with(|service| {
+        let mut service = service.borrow_mut();
+        for mut dispute in disputes {
+            dispute.customer = Principal::anonymous();
+            dispute.reason = String::new();
+            for evidence in dispute.evidence.iter_mut() {
+                if evidence.by == principal {
+                    evidence.by = Principal::anonymous();
+                    evidence.reference = String::new();
+                    evidence.note = None;
+                }
+            }
+            for event in dispute.events.iter_mut() {
+                if event.by == principal {
+                    event.by = Principal::anonymous();
+                }
+            }
+            if dispute.opened_by == principal {
+                dispute.opened_by = Principal::anonymous();
+            }
+            service.insert(dispute.id, dispute);
+            deletion.disputes_anonymized += 1;
+        }
+    });
+
+    // Redeemed codes stay issued, without their redeemer
+    ISSUED_CODES.with(|service| {
+        let codes: Vec<IssuedCode> = service
+            .borrow()
+            .iter()
+            .map(|(_, code)| code)
+            .filter(|code| code.redeemed_by == Some(principal))
+            .collect();
+        let mut service = service.borrow_mut();
+        for mut code in codes {
+            code.redeemed_by = Some(Principal::anonymous());
+            service.insert(CodeKey(code.code.clone()), code);
+        }
+    });
+
+    // Reviews stay with their anonymized orders
+    ORDER_REVIEWS.with(|service| {
+        let reviews: Vec<OrderReview> = service
+            .borrow()
+            .iter()
+            .map(|(_, review)| review)
+            .filter(|review| review.customer == principal)
+            .collect();
+        let mut service = service.borrow_mut();
+        for mut review in reviews {
+            review.customer = Principal::anonymous();
+            service.insert(review.order_id, review);
+        }
+    });
+
+    let mut removed = 0;
+    let quotes: Vec<u64> = QUOTES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, quote)| quote.customer == principal)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    let reservations: Vec<Reservation> = RESERVATIONS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, reservation)| reservation.holder == principal)
+            .map(|(_, reservation)| reservation)
+            .collect()
+    });
+    let subscriptions: Vec<u64> = list_my_subscriptions()
+        .iter()
+        .map(|subscription| subscription.id)
+        .collect();
+    let watches: Vec<(u64, PrincipalKey)> = STOCK_WATCHERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|((_, watcher), _)| *watcher == key)
+            .map(|(watch, _)| watch)
+            .collect()
+    });
+    let waitlists: Vec<(u64, u64)> = WAITLIST.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, entry)| entry.principal == principal)
+            .map(|(entry, _)| entry)
+            .collect()
+    });
+    for id in &quotes {
+        QUOTES.with(|service| service.borrow_mut().remove(id));
+    }
+    for reservation in &reservations {
+        remove_reservation(reservation.id);
+        allocate_waitlist(reservation.product_id);
+    }
+    for id in &subscriptions {
+        SUBSCRIPTIONS.with(|service| service.borrow_mut().remove(id));
+    }
+    for watch in &watches {
+        STOCK_WATCHERS.with(|service| service.borrow_mut().remove(watch));
+    }
+    for entry in &waitlists {
+        WAITLIST.with(|service| service.borrow_mut().remove(entry));
+    }
+    let notifications = notification_keys(principal);
+    NOTIFICATIONS.with(|service| {
+        let mut service = service.borrow_mut();
+        for key in &notifications {
+            service.remove(key);
+        }
+    });
+    removed += quotes.len()
+        + reservations.len()
+        + subscriptions.len()
+        + watches.len()
+        + waitlists.len()
+        + notifications.len();
+
+    for sub in sub_principals_of(principal) {
+        OWNER_SUB_PRINCIPALS.with(|service| service.borrow_mut().remove(&(key, PrincipalKey(sub))));
+        SPENDING_LIMITS.with(|service| service.borrow_mut().remove(&PrincipalKey(sub)));
+        removed += 1;
+    }
+    if let Some(limit) = SPENDING_LIMITS.with(|service| service.borrow_mut().remove(&key)) {
+        OWNER_SUB_PRINCIPALS.with(|service| {
+            service
+                .borrow_mut()
+                .remove(&(PrincipalKey(limit.owner), key))
+        });
+        removed += 1;
+    }
+    if let Some(account) = account {
+        detach_principal(account, &principal);
+        removed += 1;
+    }
+    removed += LINK_CHALLENGES
+        .with(|service| service.borrow_mut().remove(&key))
+        .is_some() as usize;
+    removed += CUSTOMERS
+        .with(|service| service.borrow_mut().remove(&key))
+        .is_some() as usize;
+    removed += AGE_ATTESTATIONS
+        .with(|service| service.borrow_mut().remove(&key))
+        .is_some() as usize;
+    removed += CUSTOMER_TIERS
+        .with(|service| service.borrow_mut().remove(&key))
+        .is_some() as usize;
+    removed += CARTS
+        .with(|service| service.borrow_mut().remove(&key))
+        .is_some() as usize;
+    removed += LANGUAGE_PREFERENCES
+        .with(|service| service.borrow_mut().remove(&key))
+        .is_some() as usize;
+    removed += TENDER_BALANCES
+        .with(|service| service.borrow_mut().remove(&key))
+        .is_some() as usize;
+    removed += ORDER_VELOCITY
+        .with(|service| service.borrow_mut().remove(&key))
+        .is_some() as usize;
+    removed += PENDING_COUPONS
+        .with(|service| service.borrow_mut().remove(&key))
+        .is_some() as usize;
+    removed += BIRTHDAYS
+        .with(|service| service.borrow_mut().remove(&key))
+        .is_some() as usize;
+    removed += forget_receipt_email(&key) as usize;
+    CHECKOUT_SESSIONS.with(|service| {
+        let sessions: Vec<u64> = service
+            .borrow()
+            .iter()
+            .filter(|(_, session)| session.customer == principal)
+            .map(|(id, _)| id)
+            .collect();
+        let mut service = service.borrow_mut();
+        for id in sessions {
+            service.remove(&id);
+            removed += 1;
+        }
+    });
+    deletion.records_removed = removed as u32;
+    Ok(deletion)
+}
+
+// Helper function to get the tier a customer is priced at; everyone is retail while tier pricing
+// is disabled
+fn effective_tier(customer: &Principal) -> CustomerTier {
+    let enabled = CONFIG.with(|config| config.borrow().get().tier_pricing_enabled == Some(true));
+    if !enabled {
+        return CustomerTier::Retail;
+    }
+    CUSTOMER_TIERS
+        .with(|service| service.borrow().get(&PrincipalKey(*customer)))
+        .map(|assignment| assignment.tier)
+        .unwrap_or_default()
+}
+
+// Helper function to get a product's price list entry for a tier; retail has none
+fn tier_price_of(product_id: u64, tier: CustomerTier) -> Option<TierPrice> {
+    if tier == CustomerTier::Retail {
+        return None;
+    }
+    TIER_PRICES.with(|service| service.borrow().get(&(product_id, tier.code())))
+}
+
+// Function to check an ordered quantity against the minimum of the customer's tier
+fn check_tier_minimum(
+    product: &Product,
+    tier_price: Option<&TierPrice>,
+    quantity: u32,
+) -> Result<(), Error> {
+    match tier_price.and_then(|price| price.min_order_qty.map(|min| (price.tier, min))) {
+        Some((tier, min_order_qty)) if quantity < min_order_qty => {
+            Err(Error::InvalidOperation {
+                msg: format!(
+                    "'{}' (id={}) must be ordered in quantities of at least {} at {:?} prices. Requested: {}",
+                    product.name, product.id, min_order_qty, tier, quantity
+                ),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+// Helper function to remove the tier prices of a product
+fn remove_tier_prices(product_id: u64) {
+    TIER_PRICES.with(|service| {
+        let keys: Vec<(u64, u8)> = service
+            .borrow()
+            .range((product_id, u8::MIN)..=(product_id, u8::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        let mut prices = service.borrow_mut();
+        for key in keys {
+            prices.remove(&key);
+        }
+    });
+}
+
+// Function to turn tier pricing on or off for the whole shop
+#[ic_cdk::update]
+fn set_tier_pricing_enabled(enabled: bool) -> Result<bool, Error> {
+    ensure_admin()?;
+    journal_call("set_tier_pricing_enabled");
+    update_config(|config| config.tier_pricing_enabled = Some(enabled))?;
+    Ok(enabled)
+}
+
+// Function to assign a customer to a tier; assigning retail removes the assignment
+#[ic_cdk::update]
+fn set_customer_tier(
+    principal: Principal,
+    tier: CustomerTier,
+) -> Result<Option<TierAssignment>, Error> {
+    ensure_admin()?;
+    journal_call("set_customer_tier");
+    let key = PrincipalKey(principal);
+    if tier == CustomerTier::Retail {
+        CUSTOMER_TIERS.with(|service| service.borrow_mut().remove(&key));
+        return Ok(None);
+    }
+    let assignment = TierAssignment {
+        principal,
+        tier,
+        assigned_by: caller(),
+        assigned_at: time(),
+    };
+    CUSTOMER_TIERS.with(|service| service.borrow_mut().insert(key, assignment.clone()));
+    Ok(Some(assignment))
+}
+
+// Query function to get the tier the caller is priced at
+#[ic_cdk::query]
+fn get_my_tier() -> CustomerTier {
+    effective_tier(&caller())
+}
+
+// Query function to list the customers assigned to a tier
+#[ic_cdk::query]
+fn list_tier_customers(tier: CustomerTier) -> Result<Vec<TierAssignment>, Error> {
+    ensure_viewer()?;
+    Ok(CUSTOMER_TIERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, assignment)| assignment)
+            .filter(|assignment| assignment.tier == tier)
+            .collect()
+    }))
+}
+
+// Function to set or, with no payload, remove a product's price for a tier
+#[ic_cdk::update]
+fn set_tier_price(
+    product_id: u64,
+    tier: CustomerTier,
+    payload: Option<TierPricePayload>,
+) -> Result<Option<TierPrice>, Error> {
+    ensure_admin()?;
+    journal_call("set_tier_price");
+    if tier == CustomerTier::Retail {
+        return Err(Error::InvalidOperation {
+            msg: "Retail customers pay the product price; update the product instead.".to_string(),
+        });
+    }
+    if _get_product(&product_id).is_none() {
+        return Err(Error::NotFound {
+            msg: format!("A product with id={} was not found", product_id),
+        });
+    }
+    let key = (product_id, tier.code());
+    let Some(payload) = payload else {
+        TIER_PRICES.with(|service| service.borrow_mut().remove(&key));
+        return Ok(None);
+    };
+    if payload.min_order_qty == Some(0) {
+        return Err(Error::InvalidOperation {
+            msg: "Tier minimum order quantities must be greater than zero.".to_string(),
+        });
+    }
+    let price = TierPrice {
+        product_id,
+        tier,
+        unit_price: payload.unit_price,
+        min_order_qty: payload.min_order_qty,
+        updated_at: time(),
+    };
+    TIER_PRICES.with(|service| service.borrow_mut().insert(key, price.clone()));
+    Ok(Some(price))
+}
+
+// Query function to list a product's tier prices
+#[ic_cdk::query]
+fn list_tier_prices(product_id: u64) -> Result<Vec<TierPrice>, Error> {
+    ensure_admin()?;
+    Ok(TIER_PRICES.with(|service| {
+        service
+            .borrow()
+            .range((product_id, 0)..=(product_id, u8::MAX))
+            .map(|(_, price)| price)
+            .collect()
+    }))
+}
+
+// Helper function to turn entries into report rows until the slice runs out of rows or
+// instructions; also returns the key to resume after when the entries were not exhausted
+fn report_slice<V>(
+    entries: impl Iterator<Item = ((u64, u64), V)>,
+    mut to_row: impl FnMut((u64, u64), V) -> Option<ReportRow>,
+    totals: &mut ReportTotals,
+) -> (Vec<ReportRow>, Option<(u64, u64)>) {
+    let started = ic_cdk::api::instruction_counter();
+    let mut rows = Vec::new();
+    for (key, value) in entries {
+        if let Some(row) = to_row(key, value) {
+            totals.add(&row);
+            rows.push(row);
+        }
+        if rows.len() >= REPORT_STEP_MAX_ROWS
+            || ic_cdk::api::instruction_counter() - started > REPORT_STEP_INSTRUCTIONS
+        {
+            return (rows, Some(key));
+        }
+    }
+    (rows, None)
+}
+
+// Query function to generate one bounded slice of a long-range report; start without a cursor
+// and call again with the returned cursor until none is returned
+#[ic_cdk::query]
+fn report_step(request: ReportRequest, cursor: Option<ReportCursor>) -> Result<ReportStep, Error> {
+    ensure_viewer()?;
+    let mut totals = cursor.map(|cursor| cursor.totals).unwrap_or_default();
+    let after = cursor.map(|cursor| cursor.after);
+    let (rows, next) = match request {
+        ReportRequest::Sales {
+            from_day,
+            to_day,
+            product_id,
+        } => {
+            if from_day > to_day {
+                return Err(Error::InvalidOperation {
+                    msg: "A report period cannot end before it starts.".to_string(),
+                });
+            }
+            let start = after.map_or(Bound::Included((from_day, 0)), Bound::Excluded);
+            DAILY_SALES.with(|service| {
+                report_slice(
+                    service
+                        .borrow()
+                        .range((start, Bound::Included((to_day, u64::MAX)))),
+                    |(day, id), sales| {
+                        product_id
+                            .is_none_or(|product_id| id == product_id)
+                            .then_some(ReportRow::Sales(DailySalesReport {
+                                day,
+                                product_id: id,
+                                units: sales.units,
+                                revenue: sales.revenue,
+                            }))
+                    },
+                    &mut totals,
+                )
+            })
+        }
+        ReportRequest::Ledger { account, from, to } => {
+            if from > to {
+                return Err(Error::InvalidOperation {
+                    msg: "A report period cannot end before it starts.".to_string(),
+                });
+            }
+            let start = after.map_or_else(|| first_entry_at_or_after(from), |(id, _)| id + 1);
+            let end = first_entry_at_or_after(to).max(start);
+            JOURNAL.with(|service| {
+                report_slice(
+                    service
+                        .borrow()
+                        .range(start..end)
+                        .map(|(id, entry)| ((id, 0), entry)),
+                    |_, entry| statement_line(entry, account).map(ReportRow::Ledger),
+                    &mut totals,
+                )
+            })
+        }
+        ReportRequest::StockMovements {
+            shift_id,
+            product_id,
+        } => {
+            let start = after.map_or(Bound::Included((shift_id, 0)), Bound::Excluded);
+            STOCK_MOVEMENTS.with(|service| {
+                report_slice(
+                    service
+                        .borrow()
+                        .range((start, Bound::Included((shift_id, u64::MAX)))),
+                    |_, movement| {
+                        product_id
+                            .is_none_or(|product_id| movement.product_id == product_id)
+                            .then_some(ReportRow::StockMovement(movement))
+                    },
+                    &mut totals,
+                )
+            })
+        }
+    };
+    Ok(ReportStep {
+        rows,
+        totals,
+        next_cursor: next.map(|after| ReportCursor { after, totals }),
+    })
+}
+
+// Helper function to get the idle time after which draft carts are purged
+fn cart_ttl_ns() -> u64 {
+    CONFIG
+        .with(|config| config.borrow().get().cart_ttl_seconds)
+        .unwrap_or(DEFAULT_CART_TTL_SECONDS)
+        .saturating_mul(1_000_000_000)
+}
+
+// Function to set how long draft carts may sit idle before they are purged
+#[ic_cdk::update]
+fn set_cart_ttl(seconds: u64) -> Result<u64, Error> {
+    ensure_admin()?;
+    journal_call("set_cart_ttl");
+    if seconds == 0 {
+        return Err(Error::InvalidOperation {
+            msg: "The cart TTL must be greater than zero.".to_string(),
+        });
+    }
+    update_config(|config| config.cart_ttl_seconds = Some(seconds))?;
+    Ok(seconds)
+}
+
+// Function to drop the draft carts idle for longer than the cart TTL
+fn purge_idle_carts() -> u64 {
+    let cutoff = time().saturating_sub(cart_ttl_ns());
+    CARTS.with(|service| {
+        let idle: Vec<PrincipalKey> = service
+            .borrow()
+            .iter()
+            .filter(|(_, cart)| cart.last_activity_at < cutoff)
+            .map(|(key, _)| key)
+            .collect();
+        let mut carts = service.borrow_mut();
+        for key in &idle {
+            carts.remove(key);
+        }
+        idle.len() as u64
+    })
+}
+
+// Helper function to check that the caller can keep a cart; the anonymous principal is shared
+// by every signed-out visitor, so it cannot
+fn cart_owner() -> Result<Principal, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    if caller() == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Sign in to keep a cart.".to_string(),
+        });
+    }
+    Ok(account_holder())
+}
+
+// Helper function to get the units of a product a customer can order right now
+fn orderable_quantity(customer: &Principal, product: &Product) -> u32 {
+    let reserved = reserved_quantities().get(&product.id).copied().unwrap_or(0);
+    let available = availability_of(product, reserved).available
+        + claimable_quantity(*customer, product.id) as i64;
+    available.clamp(0, u32::MAX as i64) as u32
+}
+
+// Helper function to get the unit price a customer is offered for a configured product
+fn cart_unit_price(
+    customer: &Principal,
+    product: &Product,
+    selections: &[OptionSelection],
+) -> Result<u64, Error> {
+    let options = resolve_configuration(product, selections)?;
+    let base_price = tier_price_of(product.id, effective_tier(customer))
+        .map_or(product.price(), |price| price.unit_price);
+    configured_unit_price(base_price, &options)
+}
+
+// Helper function to get a product a customer can put in a cart
+fn cart_product(customer: &Principal, product_id: u64) -> Option<Product> {
+    _get_product(&product_id)
+        .map(|product| with_effective_status(product, time()))
+        .filter(|product| is_visible(product, ic_cdk::api::is_controller(customer)))
+}
+
+// Function to put a product in the caller's cart, replacing its line if already there; a zero
+// quantity removes the line
+#[ic_cdk::update]
+fn set_cart_line(payload: OrderLinePayload) -> Result<Cart, Error> {
+    let owner = cart_owner()?;
+    journal_call("set_cart_line");
+    let now = time();
+    let key = PrincipalKey(owner);
+    let mut cart = CARTS
+        .with(|service| service.borrow().get(&key))
+        .unwrap_or(Cart {
+            owner,
+            lines: Vec::new(),
+            created_at: now,
+            last_activity_at: now,
+        });
+    cart.lines
+        .retain(|line| line.product_id != payload.product_id);
+    if payload.quantity > 0 {
+        let product = cart_product(&owner, payload.product_id).ok_or(Error::NotFound {
+            msg: format!("A product with id={} was not found", payload.product_id),
+        })?;
+        check_order_quantity(&product, payload.quantity)?;
+        let available = orderable_quantity(&owner, &product);
+        if payload.quantity > available {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Not enough stock for product id={}. Available: {}, Requested: {}",
+                    product.id, available, payload.quantity
+                ),
+            });
+        }
+        let selections = payload
+            .selections
+            .filter(|selections| !selections.is_empty());
+        let unit_price = cart_unit_price(&owner, &product, selections.as_deref().unwrap_or(&[]))?;
+        if cart.lines.len() >= MAX_ORDER_LINES {
+            return Err(Error::CapacityExceeded {
+                msg: format!("A cart can hold at most {} lines.", MAX_ORDER_LINES),
+            });
+        }
+        cart.lines.push(CartLine {
+            product_id: product.id,
+            quantity: payload.quantity,
+            selections,
+            unit_price,
+            added_at: now,
+        });
+        let selected: usize = cart
+            .lines
+            .iter()
+            .flat_map(|line| line.selections.iter().flatten())
+            .map(|selection| selection.choices.len())
+            .sum();
+        if selected > MAX_OPTIONS_PER_ORDER {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "A cart can select at most {} options in total.",
+                    MAX_OPTIONS_PER_ORDER
+                ),
+            });
+        }
+    }
+    cart.last_activity_at = now;
+    CARTS.with(|service| service.borrow_mut().insert(key, cart.clone()));
+    Ok(cart)
+}
+
+// Query function to get the caller's draft cart as last saved; use `revive_cart` to bring its
+// stock and prices up to date
+#[ic_cdk::query]
+fn get_my_cart() -> Option<Cart> {
+    CARTS.with(|service| service.borrow().get(&PrincipalKey(account_holder())))
+}
+
+// Function to empty the caller's cart
+#[ic_cdk::update]
+fn clear_my_cart() -> Result<(), Error> {
+    let owner = cart_owner()?;
+    journal_call("clear_my_cart");
+    CARTS.with(|service| service.borrow_mut().remove(&PrincipalKey(owner)));
+    Ok(())
+}
+
+// Function to revalidate the caller's cart against the current stock and prices, e.g. when a
+// customer comes back to it: lines that can no longer be ordered are dropped, quantities are cut
+// to what is in stock and prices are refreshed, and every change is reported
+#[ic_cdk::update]
+fn revive_cart() -> Result<CartRevival, Error> {
+    let owner = cart_owner()?;
+    journal_call("revive_cart");
+    let key = PrincipalKey(owner);
+    let mut cart = CARTS
+        .with(|service| service.borrow().get(&key))
+        .ok_or(Error::NotFound {
+            msg: "You have no cart.".to_string(),
+        })?;
+    let mut changes = Vec::new();
+    let mut lines = Vec::with_capacity(cart.lines.len());
+    for mut line in cart.lines {
+        let Some(product) = cart_product(&owner, line.product_id) else {
+            changes.push(CartChange::Removed {
+                product_id: line.product_id,
+                reason: "The product is no longer available.".to_string(),
+            });
+            continue;
+        };
+        let unit_price =
+            match cart_unit_price(&owner, &product, line.selections.as_deref().unwrap_or(&[])) {
+                Ok(unit_price) => unit_price,
+                Err(error) => {
+                    changes.push(CartChange::Removed {
+                        product_id: line.product_id,
+                        reason: error.message().to_string(),
+                    });
+                    continue;
+                }
+            };
+        let available = orderable_quantity(&owner, &product);
+        if line.quantity > available {
+            if available == 0 || check_order_quantity(&product, available).is_err() {
+                changes.push(CartChange::Removed {
+                    product_id: line.product_id,
+                    reason: "Not enough stock left.".to_string(),
+                });
+                continue;
+            }
+            changes.push(CartChange::QuantityReduced {
+                product_id: line.product_id,
+                from: line.quantity,
+                to: available,
+            });
+            line.quantity = available;
+        }
+        if unit_price != line.unit_price {
+            changes.push(CartChange::PriceChanged {
+                product_id: line.product_id,
+                from: line.unit_price,
+                to: unit_price,
+            });
+            line.unit_price = unit_price;
+        }
+        lines.push(line);
+    }
+    cart.lines = lines;
+    cart.last_activity_at = time();
+    CARTS.with(|service| service.borrow_mut().insert(key, cart.clone()));
+    Ok(CartRevival { cart, changes })
+}
+
+// Function to place an order for the lines of the caller's cart, emptying it once placed
+#[ic_cdk::update]
+fn checkout_cart(
+    notes: Option<String>,
+    payment_token: Option<String>,
+    tenders: Option<Vec<TenderRequest>>,
+) -> Result<Order, Error> {
+    let owner = cart_owner()?;
+    journal_call("checkout_cart");
+    ensure_payments_open()?;
+    let key = PrincipalKey(owner);
+    let cart = CARTS
+        .with(|service| service.borrow().get(&key))
+        .filter(|cart| !cart.lines.is_empty())
+        .ok_or(Error::InvalidOperation {
+            msg: "Your cart is empty.".to_string(),
+        })?;
+    let order = place_caller_order(OrderPayload {
+        lines: cart
+            .lines
+            .into_iter()
+            .map(|line| OrderLinePayload {
+                product_id: line.product_id,
+                quantity: line.quantity,
+                selections: line.selections,
+            })
+            .collect(),
+        notes,
+        payment_token,
+        tenders,
+    })?;
+    CARTS.with(|service| service.borrow_mut().remove(&key));
+    Ok(order)
+}
+
+// Helper function to read a counter, keeping the name it is reported under
+fn health_count(name: &str, count: u64) -> HealthCount {
+    HealthCount {
+        name: name.to_string(),
+        count,
+    }
+}
+
+// Helper function to get the values of the sequence counters
+fn health_counters() -> Vec<HealthCount> {
+    vec![
+        health_count("ids", ID_COUNTER.with(|counter| *counter.borrow().get())),
+        health_count(
+            "sale_events",
+            SALE_EVENT_SEQ.with(|counter| *counter.borrow().get()),
+        ),
+        health_count(
+            "notifications",
+            NOTIFICATION_SEQ.with(|counter| *counter.borrow().get()),
+        ),
+        health_count(
+            "journal",
+            JOURNAL_SEQ.with(|counter| *counter.borrow().get()),
+        ),
+        health_count(
+            "product_versions",
+            PRODUCT_VERSION_SEQ.with(|counter| *counter.borrow().get()),
+        ),
+        health_count(
+            "waitlist",
+            WAITLIST_SEQ.with(|counter| *counter.borrow().get()),
+        ),
+        health_count(
+            "stock_movements",
+            STOCK_MOVEMENT_SEQ.with(|counter| *counter.borrow().get()),
+        ),
+        health_count(
+            "catalog_version",
+            CATALOG_VERSION.with(|counter| *counter.borrow().get()),
+        ),
+        health_count("outbox", OUTBOX_SEQ.with(|counter| *counter.borrow().get())),
+    ]
+}
+
+// Helper function to count the entities of each stable map
+fn health_entities() -> Vec<HealthCount> {
+    vec![
+        health_count("products", STORAGE.with(|service| service.borrow().len())),
+        health_count(
+            "product_names",
+            PRODUCT_NAMES.with(|service| service.borrow().len()),
+        ),
+        health_count(
+            "product_categories",
+            PRODUCT_CATEGORIES.with(|service| service.borrow().len()),
+        ),
+        health_count("orders", ORDERS.with(|service| service.borrow().len())),
+        health_count(
+            "customers",
+            CUSTOMERS.with(|service| service.borrow().len()),
+        ),
+        health_count(
+            "reservations",
+            RESERVATIONS.with(|service| service.borrow().len()),
+        ),
+        health_count("quotes", QUOTES.with(|service| service.borrow().len())),
+        health_count(
+            "promotions",
+            PROMOTIONS.with(|service| service.borrow().len()),
+        ),
+        health_count(
+            "pricing_rules",
+            PRICING_RULES.with(|service| service.borrow().len()),
+        ),
+        health_count(
+            "experiments",
+            EXPERIMENTS.with(|service| service.borrow().len()),
+        ),
+        health_count("tickets", TICKETS.with(|service| service.borrow().len())),
+        health_count(
+            "notifications",
+            NOTIFICATIONS.with(|service| service.borrow().len()),
+        ),
+        health_count(
+            "sale_events",
+            SALE_EVENTS.with(|service| service.borrow().len()),
+        ),
+        health_count("journal", JOURNAL.with(|service| service.borrow().len())),
+        health_count(
+            "product_versions",
+            PRODUCT_VERSIONS.with(|service| service.borrow().len()),
+        ),
+        health_count("waitlist", WAITLIST.with(|service| service.borrow().len())),
+        health_count(
+            "subscriptions",
+            SUBSCRIPTIONS.with(|service| service.borrow().len()),
+        ),
+        health_count("shifts", SHIFTS.with(|service| service.borrow().len())),
+        health_count(
+            "stock_movements",
+            STOCK_MOVEMENTS.with(|service| service.borrow().len()),
+        ),
+        health_count(
+            "spending_limits",
+            SPENDING_LIMITS.with(|service| service.borrow().len()),
+        ),
+        health_count(
+            "sub_principals",
+            OWNER_SUB_PRINCIPALS.with(|service| service.borrow().len()),
+        ),
+        health_count(
+            "pending_settlements",
+            PENDING_SETTLEMENTS.with(|service| service.borrow().len()),
+        ),
+        health_count(
+            "tier_prices",
+            TIER_PRICES.with(|service| service.borrow().len()),
+        ),
+        health_count("carts", CARTS.with(|service| service.borrow().len())),
+        health_count("outbox", OUTBOX.with(|service| service.borrow().len())),
+    ]
+}
+
+// Helper function to find counters behind the keys they issued; only maps keyed by what their
+// counter issues are checked, through their last key, so the pre-upgrade hook stays cheap.
+// Counters of the other maps and the indexes are checked with the invariants
+fn health_issues() -> Vec<String> {
+    let mut issues = Vec::new();
+    let mut check_counter = |name: &str, counter: u64, issued: Option<u64>| {
+        if let Some(issued) = issued.filter(|issued| *issued >= counter) {
+            issues.push(format!(
+                "The {} counter is at {} but id {} was already issued",
+                name, counter, issued
+            ));
+        }
+    };
+    if CONFIG.with(|config| config.borrow().get().id_strategy) == IdStrategy::Sequential {
+        let counter = ID_COUNTER.with(|counter| *counter.borrow().get());
+        let issued = [
+            STORAGE.with(|service| service.borrow().last_key_value().map(|(id, _)| id)),
+            ORDERS.with(|service| service.borrow().last_key_value().map(|(id, _)| id)),
+            RESERVATIONS.with(|service| service.borrow().last_key_value().map(|(id, _)| id)),
+            QUOTES.with(|service| service.borrow().last_key_value().map(|(id, _)| id)),
+            TICKETS.with(|service| service.borrow().last_key_value().map(|(id, _)| id)),
+        ];
+        check_counter("id", counter, issued.into_iter().flatten().max());
+    }
+    check_counter(
+        "sale event",
+        SALE_EVENT_SEQ.with(|counter| *counter.borrow().get()),
+        SALE_EVENTS.with(|service| service.borrow().last_key_value().map(|(seq, _)| seq)),
+    );
+    check_counter(
+        "journal",
+        JOURNAL_SEQ.with(|counter| *counter.borrow().get()),
+        JOURNAL.with(|service| service.borrow().last_key_value().map(|(id, _)| id)),
+    );
+    check_counter(
+        "outbox",
+        OUTBOX_SEQ.with(|counter| *counter.borrow().get()),
+        OUTBOX.with(|service| service.borrow().last_key_value().map(|(id, _)| id)),
+    );
+    if issues.len() > MAX_HEALTH_ISSUES {
+        let more = issues.len() - MAX_HEALTH_ISSUES;
+        issues.truncate(MAX_HEALTH_ISSUES);
+        issues.push(format!("... and {} more", more));
+    }
+    issues
+}
+
+// Function to take a health snapshot of the stable state
+fn health_snapshot() -> HealthSnapshot {
+    let counters = health_counters();
+    let entities = health_entities();
+    let checksum = fnv1a(Encode!(&counters, &entities).unwrap_or_default().iter());
+    HealthSnapshot {
+        taken_at: time(),
+        counters,
+        entities,
+        issues: health_issues(),
+        checksum,
+    }
+}
+
+// Helper function to persist the upgrade health
+fn store_upgrade_health(health: UpgradeHealth) {
+    UPGRADE_HEALTH.with(|cell| {
+        if cell.borrow_mut().set(health).is_err() {
+            ic_cdk::println!("Cannot store the upgrade health");
+        }
+    });
+}
+
+// Function to compare the state after an upgrade with the snapshot taken before it; counts
+// kept by only one of the two releases are not compared
+fn verify_upgrade() {
+    let pre_upgrade = UPGRADE_HEALTH.with(|cell| cell.borrow().get().pre_upgrade.clone());
+    let post_upgrade = health_snapshot();
+    let mut mismatches = Vec::new();
+    if let Some(pre_upgrade) = &pre_upgrade {
+        let changed = |before: &[HealthCount], after: &[HealthCount], kind: &str| {
+            before
+                .iter()
+                .filter_map(|count| {
+                    let now = after.iter().find(|other| other.name == count.name)?;
+                    (now.count != count.count).then(|| {
+                        format!(
+                            "The {} {} changed from {} to {}",
+                            count.name, kind, count.count, now.count
+                        )
+                    })
+                })
+                .collect::<Vec<_>>()
+        };
+        mismatches.extend(changed(
+            &pre_upgrade.counters,
+            &post_upgrade.counters,
+            "counter",
+        ));
+        mismatches.extend(changed(
+            &pre_upgrade.entities,
+            &post_upgrade.entities,
+            "count",
+        ));
+        let same_layout = |before: &[HealthCount], after: &[HealthCount]| {
+            before.len() == after.len()
+                && before
+                    .iter()
+                    .zip(after)
+                    .all(|(before, after)| before.name == after.name)
+        };
+        if mismatches.is_empty()
+            && same_layout(&pre_upgrade.counters, &post_upgrade.counters)
+            && same_layout(&pre_upgrade.entities, &post_upgrade.entities)
+            && pre_upgrade.checksum != post_upgrade.checksum
+        {
+            mismatches.push("The state checksum changed across the upgrade".to_string());
+        }
+    }
+    for issue in mismatches.iter().chain(&post_upgrade.issues) {
+        ic_cdk::println!("Upgrade health: {}", issue);
+    }
+    store_upgrade_health(UpgradeHealth {
+        pre_upgrade,
+        post_upgrade: Some(post_upgrade),
+        mismatches,
+    });
+}
+
+// Query function for operators to check the stable state before requesting an upgrade; the
+// same snapshot is persisted by the pre-upgrade hook
+#[ic_cdk::query]
+fn pre_upgrade_health_check() -> Result<HealthSnapshot, Error> {
+    ensure_admin()?;
+    Ok(health_snapshot())
+}
+
+// Query function to get the health snapshots taken around the last upgrade and the counts that
+// changed across it
+#[ic_cdk::query]
+fn get_upgrade_health() -> Result<UpgradeHealth, Error> {
+    ensure_admin()?;
+    Ok(UPGRADE_HEALTH.with(|cell| cell.borrow().get().clone()))
+}
+
+// Helper function to describe a product the name index has no entry for; a product whose name
+// collides with another one's cannot be indexed and is not reported
+fn missing_name_entry(product: &Product) -> Option<String> {
+    product_id_by_name(&product.name)
+        .is_none()
+        .then(|| format!("Product id={} is missing from the name index", product.id))
+}
+
+// Helper function to describe a name index entry not matching its product
+fn orphaned_name_entry(name: &ProductName, id: u64) -> Option<String> {
+    match _get_product(&id) {
+        Some(product) if ProductName::normalize(&product.name) == *name => None,
+        Some(_) => Some(format!(
+            "The name index maps '{}' to product id={}, which is named otherwise",
+            name.0, id
+        )),
+        None => Some(format!(
+            "The name index maps '{}' to missing product id={}",
+            name.0, id
+        )),
+    }
+}
+
+// Helper function to describe a spending limit whose owner does not list the sub-principal
+fn missing_sub_principal_entry(limit: &SpendingLimit) -> Option<String> {
+    let key = (PrincipalKey(limit.owner), PrincipalKey(limit.principal));
+    (!OWNER_SUB_PRINCIPALS.with(|service| service.borrow().contains_key(&key))).then(|| {
+        format!(
+            "Sub-principal {} is missing from the sub-principals of {}",
+            limit.principal, limit.owner
+        )
+    })
+}
+
+// Helper function to describe a sub-principal entry without a matching spending limit
+fn orphaned_sub_principal_entry(owner: &PrincipalKey, sub: &PrincipalKey) -> Option<String> {
+    SPENDING_LIMITS
+        .with(|service| service.borrow().get(sub))
+        .is_none_or(|limit| limit.owner != owner.0)
+        .then(|| {
+            format!(
+                "Sub-principal {} of {} has no matching spending limit",
+                sub.0, owner.0
+            )
+        })
+}
+
+// Helper function to check whether an order's escrowed payment is waiting to be settled
+fn awaits_settlement(order: &Order) -> bool {
+    order
+        .payment
+        .as_ref()
+        .and_then(|payment| payment.escrow.as_ref())
+        .is_some_and(|escrow| {
+            matches!(
+                escrow.status,
+                EscrowStatus::Releasing | EscrowStatus::Refunding
+            )
+        })
+}
+
+// Helper function to describe an order awaiting settlement that would never be retried
+fn missing_settlement_entry(order: &Order) -> Option<String> {
+    (awaits_settlement(order)
+        && !PENDING_SETTLEMENTS.with(|service| service.borrow().contains_key(&order.id)))
+    .then(|| format!("Order id={} awaits settlement but is not queued", order.id))
+}
+
+// Helper function to describe a queued settlement whose order does not await one
+fn orphaned_settlement_entry(order_id: u64) -> Option<String> {
+    match _get_order(&order_id) {
+        Some(order) if awaits_settlement(&order) => None,
+        Some(_) => Some(format!(
+            "A settlement is queued for order id={}, which does not await one",
+            order_id
+        )),
+        None => Some(format!(
+            "A settlement is queued for missing order id={}",
+            order_id
+        )),
+    }
+}
+
+// Function to check an index against the records it is derived from
+fn verify_index(kind: IndexKind) -> IndexReport {
+    let mut missing = Vec::new();
+    let mut orphaned = Vec::new();
+    let entries =
+        match kind {
+            IndexKind::ProductNames => {
+                STORAGE.with(|service| {
+                    missing.extend(
+                        service
+                            .borrow()
+                            .iter()
+                            .filter_map(|(_, product)| missing_name_entry(&product)),
+                    )
+                });
+                PRODUCT_NAMES.with(|service| {
+                    let names = service.borrow();
+                    orphaned.extend(
+                        names
+                            .iter()
+                            .filter_map(|(name, id)| orphaned_name_entry(&name, id)),
+                    );
+                    names.len()
+                })
+            }
+            IndexKind::SubPrincipals => {
+                SPENDING_LIMITS.with(|service| {
+                    missing.extend(
+                        service
+                            .borrow()
+                            .iter()
+                            .filter_map(|(_, limit)| missing_sub_principal_entry(&limit)),
+                    )
+                });
+                OWNER_SUB_PRINCIPALS.with(|service| {
+                    let links = service.borrow();
+                    orphaned.extend(links.iter().filter_map(|((owner, sub), _)| {
+                        orphaned_sub_principal_entry(&owner, &sub)
+                    }));
+                    links.len()
+                })
+            }
+            IndexKind::PendingSettlements => {
+                ORDERS.with(|service| {
+                    missing.extend(
+                        service
+                            .borrow()
+                            .iter()
+                            .filter_map(|(_, order)| missing_settlement_entry(&order)),
+                    )
+                });
+                PENDING_SETTLEMENTS.with(|service| {
+                    let pending = service.borrow();
+                    orphaned.extend(
+                        pending
+                            .iter()
+                            .filter_map(|(order_id, _)| orphaned_settlement_entry(order_id)),
+                    );
+                    pending.len()
+                })
+            }
+        };
+    IndexReport {
+        kind,
+        entries,
+        missing: missing.len() as u64,
+        orphaned: orphaned.len() as u64,
+        samples: missing
+            .into_iter()
+            .chain(orphaned)
+            .take(MAX_HEALTH_ISSUES)
+            .collect(),
+    }
+}
+
+// Query function to check every secondary index for missing and orphaned entries
+#[ic_cdk::query]
+fn verify_indexes() -> Result<Vec<IndexReport>, Error> {
+    ensure_admin()?;
+    Ok(IndexKind::ALL.into_iter().map(verify_index).collect())
+}
+
+// Helper function to get the next batch of a map after an encoded key
+fn batch_after<K, V>(map: &impl EntryMap<K, V>, cursor: &Option<Vec<u8>>) -> Vec<(K, V)>
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    let start = cursor.as_ref().map_or(Bound::Unbounded, |bytes| {
+        Bound::Excluded(K::from_bytes(Cow::Borrowed(bytes.as_slice())))
+    });
+    map.entries_from(start, INDEX_REBUILD_BATCH)
+}
+
+// Helper function to encode a map key as an index rebuild cursor
+fn encoded_key<K: Storable>(key: &K) -> Vec<u8> {
+    key.to_bytes().into_owned()
+}
+
+// Function to go through one batch of an index rebuild, returning the keys gone through and
+// counting the entries it added or removed
+fn rebuild_index_batch(rebuild: &mut IndexRebuild) -> Vec<Vec<u8>> {
+    let mut keys = Vec::new();
+    match (rebuild.kind, rebuild.phase) {
+        (IndexKind::ProductNames, RebuildPhase::Records) => {
+            for (id, product) in
+                STORAGE.with(|service| batch_after(&*service.borrow(), &rebuild.cursor))
+            {
+                if missing_name_entry(&product).is_some() {
+                    index_product_name(None, &product);
+                    rebuild.added += 1;
+                }
+                keys.push(encoded_key(&id));
+            }
+        }
+        (IndexKind::ProductNames, RebuildPhase::Entries) => {
+            for (name, id) in
+                PRODUCT_NAMES.with(|service| batch_after(&*service.borrow(), &rebuild.cursor))
+            {
+                if orphaned_name_entry(&name, id).is_some() {
+                    PRODUCT_NAMES.with(|service| service.borrow_mut().remove(&name));
+                    rebuild.removed += 1;
+                }
+                keys.push(encoded_key(&name));
+            }
+        }
+        (IndexKind::SubPrincipals, RebuildPhase::Records) => {
+            for (sub, limit) in
+                SPENDING_LIMITS.with(|service| batch_after(&*service.borrow(), &rebuild.cursor))
+            {
+                if missing_sub_principal_entry(&limit).is_some() {
+                    OWNER_SUB_PRINCIPALS.with(|service| {
+                        service
+                            .borrow_mut()
+                            .insert((PrincipalKey(limit.owner), sub), ())
+                    });
+                    rebuild.added += 1;
+                }
+                keys.push(encoded_key(&sub));
+            }
+        }
+        (IndexKind::SubPrincipals, RebuildPhase::Entries) => {
+            for (link, _) in OWNER_SUB_PRINCIPALS
+                .with(|service| batch_after(&*service.borrow(), &rebuild.cursor))
+            {
+                if orphaned_sub_principal_entry(&link.0, &link.1).is_some() {
+                    OWNER_SUB_PRINCIPALS.with(|service| service.borrow_mut().remove(&link));
+                    rebuild.removed += 1;
+                }
+                keys.push(encoded_key(&link));
+            }
+        }
+        (IndexKind::PendingSettlements, RebuildPhase::Records) => {
+            for (id, order) in
+                ORDERS.with(|service| batch_after(&*service.borrow(), &rebuild.cursor))
+            {
+                if missing_settlement_entry(&order).is_some() {
+                    PENDING_SETTLEMENTS.with(|service| service.borrow_mut().insert(id, ()));
+                    rebuild.added += 1;
+                }
+                keys.push(encoded_key(&id));
+            }
+        }
+        (IndexKind::PendingSettlements, RebuildPhase::Entries) => {
+            for (order_id, _) in
+                PENDING_SETTLEMENTS.with(|service| batch_after(&*service.borrow(), &rebuild.cursor))
+            {
+                if orphaned_settlement_entry(order_id).is_some() {
+                    PENDING_SETTLEMENTS.with(|service| service.borrow_mut().remove(&order_id));
+                    rebuild.removed += 1;
+                }
+                keys.push(encoded_key(&order_id));
+            }
+        }
+        (_, RebuildPhase::Done) => {}
+    }
+    keys
+}
+
+// Function to repair an index one batch per call: missing entries are added for the records
+// first, then orphaned entries are removed; call again until the phase is `Done`. The index
+// stays in use meanwhile, so it is repaired in place rather than cleared
+#[ic_cdk::update]
+fn rebuild_index(kind: IndexKind) -> Result<IndexRebuild, Error> {
+    ensure_admin()?;
+    journal_call("rebuild_index");
+    let now = time();
+    let mut rebuild = INDEX_REBUILDS
+        .with(|service| service.borrow().get(&kind.code()))
+        .filter(|rebuild| rebuild.phase != RebuildPhase::Done)
+        .unwrap_or(IndexRebuild {
+            kind,
+            phase: RebuildPhase::Records,
+            cursor: None,
+            added: 0,
+            removed: 0,
+            started_at: now,
+            completed_at: None,
+        });
+    let keys = rebuild_index_batch(&mut rebuild);
+    if keys.len() < INDEX_REBUILD_BATCH {
+        rebuild.cursor = None;
+        rebuild.phase = match rebuild.phase {
+            RebuildPhase::Records => RebuildPhase::Entries,
+            _ => RebuildPhase::Done,
+        };
+        if rebuild.phase == RebuildPhase::Done {
+            rebuild.completed_at = Some(now);
+        }
+    } else {
+        rebuild.cursor = keys.into_iter().last();
+    }
+    INDEX_REBUILDS.with(|service| service.borrow_mut().insert(kind.code(), rebuild.clone()));
+    Ok(rebuild)
+}
+
+// Helper function to get an order whose thread the caller may read and post to, and whether
+// the caller takes part as staff rather than as the customer
+fn order_for_thread(order_id: u64) -> Result<(Order, bool), Error> {
+    let order = _get_order(&order_id)
+        .filter(|order| is_own_record(&order.customer) || ensure_admin().is_ok())
+        .ok_or(Error::NotFound {
+            msg: format!("An order with id={} was not found", order_id),
+        })?;
+    let from_staff = !is_own_record(&order.customer);
+    Ok((order, from_staff))
+}
+
+// Function to post a message in the thread of an order, e.g. to clarify the details of a
+// custom cake; the other side sees it as unread in their notification inbox
+#[ic_cdk::update]
+fn post_order_message(order_id: u64, text: String) -> Result<OrderMessage, Error> {
+    ensure_caller_allowed(Endpoint::CreateTicket)?;
+    journal_call("post_order_message");
+    if text.trim().is_empty() || text.len() > MAX_ORDER_MESSAGE_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Messages must be non-empty and at most {} bytes long.",
+                MAX_ORDER_MESSAGE_LENGTH
+            ),
+        });
+    }
+    let (order, from_staff) = order_for_thread(order_id)?;
+    if order.customer == Principal::anonymous() {
+        return Err(Error::InvalidOperation {
+            msg: format!("Order id={} has no customer to talk to", order_id),
+        });
+    }
+    let now = time();
+    let mut thread = ORDER_THREADS
+        .with(|service| service.borrow().get(&order_id))
+        .unwrap_or(OrderThread {
+            order_id,
+            customer: order.customer,
+            message_count: 0,
+            last_message_at: now,
+            customer_unread: 0,
+            staff_unread: 0,
+        });
+    if thread.message_count >= MAX_ORDER_MESSAGES {
+        return Err(Error::CapacityExceeded {
+            msg: format!(
+                "An order thread can have at most {} messages.",
+                MAX_ORDER_MESSAGES
+            ),
+        });
+    }
+    let message = OrderMessage {
+        order_id,
+        seq: thread.message_count,
+        author: caller(),
+        from_staff,
+        text,
+        posted_at: now,
+    };
+    thread.message_count += 1;
+    thread.last_message_at = now;
+    if from_staff {
+        thread.customer_unread += 1;
+    } else {
+        thread.staff_unread += 1;
+    }
+    ORDER_MESSAGES.with(|service| {
+        service
+            .borrow_mut()
+            .insert((order_id, message.seq), message.clone())
+    });
+    ORDER_THREADS.with(|service| service.borrow_mut().insert(order_id, thread));
+    Ok(message)
+}
+
+// Query function to get the messages of an order's thread, oldest first
+#[ic_cdk::query]
+fn get_order_messages(order_id: u64) -> Result<Vec<OrderMessage>, Error> {
+    order_for_thread(order_id)?;
+    Ok(ORDER_MESSAGES.with(|service| {
+        service
+            .borrow()
+            .range((order_id, 0)..=(order_id, u64::MAX))
+            .map(|(_, message)| message)
+            .collect()
+    }))
+}
+
+// Function to mark an order's thread as read by the caller's side, returning how many messages
+// were unread
+#[ic_cdk::update]
+fn mark_order_messages_read(order_id: u64) -> Result<u32, Error> {
+    ensure_caller_allowed(Endpoint::CreateTicket)?;
+    let (_, from_staff) = order_for_thread(order_id)?;
+    journal_call("mark_order_messages_read");
+    let Some(mut thread) = ORDER_THREADS.with(|service| service.borrow().get(&order_id)) else {
+        return Ok(0);
+    };
+    let unread = if from_staff {
+        std::mem::take(&mut thread.staff_unread)
+    } else {
+        std::mem::take(&mut thread.customer_unread)
+    };
+    ORDER_THREADS.with(|service| service.borrow_mut().insert(order_id, thread));
+    Ok(unread)
+}
+
+// Helper function to get the latest version sequence of a product, reading its history when
+// the product was last written before versions were tracked per product
+fn product_version(product_id: u64) -> u64 {
+    PRODUCT_VERSION_HEADS
+        .with(|service| service.borrow().get(&product_id))
+        .or_else(|| {
+            PRODUCT_VERSIONS.with(|service| {
+                service
+                    .borrow()
+                    .range((product_id, 0)..=(product_id, u64::MAX))
+                    .map(|((_, seq), _)| seq)
+                    .last()
+            })
+        })
+        .unwrap_or(0)
+}
+
+// Query function to get the stock of every visible product with its version, for storefronts
+// polling every few seconds: when `known_digest` matches, only the digest is returned, and
+// otherwise only products whose version changed need to be fetched again
+#[ic_cdk::query]
+fn stock_digest(known_digest: Option<u64>) -> StockDigest {
+    let now = time();
+    let caller_is_admin = ensure_admin().is_ok();
+    let reserved = reserved_quantities();
+    let entries: Vec<(u64, u32, u64)> = STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, product)| with_effective_status(product, now))
+            .filter(|product| is_listed(product, caller_is_admin))
+            .map(|product| {
+                let held = reserved.get(&product.id).copied().unwrap_or(0);
+                let available = availability_of(&product, held).available;
+                (
+                    product.id,
+                    available.clamp(0, u32::MAX as i64) as u32,
+                    product_version(product.id),
+                )
+            })
+            .collect()
+    });
+    let digest = fnv1a(Encode!(&entries).unwrap_or_default().iter());
+    let unchanged = known_digest == Some(digest);
+    StockDigest {
+        entries: if unchanged { Vec::new() } else { entries },
+        digest,
+        unchanged,
+    }
+}
+
+// Helper function to get what a surprise box of products worth `subtotal` costs
+fn surprise_box_price(subtotal: u64) -> u64 {
+    round_price(subtotal - subtotal * SURPRISE_BOX_PERCENT_OFF / 100).min(subtotal)
+}
+
+// Helper function to list the products that can go in a surprise box now, with the units
+// available and the time left before their best-before
+fn surprise_box_candidates(customer: &Principal, now: u64) -> Vec<(Product, u32, u64)> {
+    visible_products(|product| {
+        product.status() == ProductStatus::Published
+            && product.price() > 0
+            && !is_age_restricted(product)
+            && product.best_before.is_some_and(|best_before| {
+                best_before > now && best_before - now <= SURPRISE_BOX_WINDOW_NS
+            })
+    })
+    .into_iter()
+    .filter(|product| {
+        check_order_quantity(product, 1).is_ok() && resolve_configuration(product, &[]).is_ok()
+    })
+    .filter_map(|product| {
+        let available = orderable_quantity(customer, &product);
+        let time_left = product.best_before.unwrap_or(now) - now;
+        (available > 0).then_some((product, available, time_left))
+    })
+    .collect()
+}
+
+// Function to fill a box with near-expiry products up to a budget and order it for the caller
+// at the surprise box discount; products are drawn at random with on-canister randomness,
+// favouring those closest to their best-before, and the order takes their units out of stock
+#[ic_cdk::update]
+async fn build_surprise_box(budget: u64) -> Result<Order, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    journal_call("build_surprise_box");
+    let customer = account_holder();
+    let (seed,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, msg)| Error::ExternalCallFailed {
+            msg: format!("Could not get randomness: {:?} {}", code, msg),
+        })?;
+    let mut state = seed
+        .iter()
+        .take(8)
+        .fold(0u64, |state, byte| (state << 8) | *byte as u64);
+    let mut draw_random = move || {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    };
+
+    let now = time();
+    let mut candidates = surprise_box_candidates(&customer, now);
+    let mut picked: Vec<OrderLinePayload> = Vec::new();
+    let mut subtotal = 0;
+    for _ in 0..SURPRISE_BOX_MAX_UNITS {
+        let room = picked.len() < MAX_ORDER_LINES;
+        let fits: Vec<usize> = candidates
+            .iter()
+            .enumerate()
+            .filter(|(_, (product, left, _))| {
+                *left > 0
+                    && surprise_box_price(subtotal + product.price()) <= budget
+                    && (room || picked.iter().any(|line| line.product_id == product.id))
+            })
+            .map(|(index, _)| index)
+            .collect();
+        // Products closest to their best-before weigh up to ten times more
+        let weight = |index: &usize| {
+            let time_left = candidates[*index].2;
+            1 + (SURPRISE_BOX_WINDOW_NS - time_left) * 9 / SURPRISE_BOX_WINDOW_NS
+        };
+        let total_weight: u64 = fits.iter().map(weight).sum();
+        if total_weight == 0 {
+            break;
+        }
+        let mut draw = draw_random() % total_weight;
+        let Some(index) = fits.iter().copied().find(|index| {
+            let weight = weight(index);
+            if draw < weight {
+                true
+            } else {
+                draw -= weight;
+                false
+            }
+        }) else {
+            break;
+        };
+        let (product, left, _) = &mut candidates[index];
+        *left -= 1;
+        subtotal += product.price();
+        match picked.iter_mut().find(|line| line.product_id == product.id) {
+            Some(line) => line.quantity += 1,
+            None => picked.push(OrderLinePayload {
+                product_id: product.id,
+                quantity: 1,
+                selections: None,
+            }),
+        }
+    }
+    if picked.is_empty() {
+        return Err(Error::NotFound {
+            msg: "No near-expiry products fit this budget right now.".to_string(),
+        });
+    }
+
+    let mut lines = check_order_lines(&customer, &picked)?;
+    for line in lines.iter_mut() {
+        // Boxes are priced from the regular price, whatever the customer's tier
+        if let Some(product) = _get_product(&line.product_id) {
+            line.unit_price = product.price();
+        }
+    }
+    let subtotal: u64 = lines
+        .iter()
+        .map(|line| line.unit_price * line.quantity as u64)
+        .sum();
+    let discount = subtotal - surprise_box_price(subtotal);
+    if subtotal - discount > budget {
+        return Err(Error::InvalidOperation {
+            msg: "Prices changed while the box was being filled. Please try again.".to_string(),
+        });
+    }
+    let priced = PricedOrder {
+        lines,
+        subtotal,
+        discount,
+        promotion_id: None,
+        applied_rules: Vec::new(),
+        experiments: Vec::new(),
+    };
+    let order = commit_order(
+        customer,
+        priced,
+        Some(format!("Surprise box ({}% off)", SURPRISE_BOX_PERCENT_OFF)),
+        None,
+        None,
+        None,
+    )?;
+    screen_order(&order);
+    Ok(order)
+}
+
+// Helper function to check that the caller may post storage-condition readings
+fn ensure_sensor_bridge() -> Result<(), Error> {
+    let caller = caller();
+    let bridges = CONFIG.with(|config| config.borrow().get().sensor_bridges.clone());
+    if ensure_admin().is_ok() || bridges.is_some_and(|bridges| bridges.contains(&caller)) {
+        Ok(())
+    } else {
+        Err(Error::Unauthorized {
+            msg: "Only staff and registered sensor bridges can post readings.".to_string(),
+        })
+    }
+}
+
+// Function to set the IoT bridges allowed to post storage-condition readings
+#[ic_cdk::update]
+fn set_sensor_bridges(bridges: Vec<Principal>) -> Result<Vec<Principal>, Error> {
+    ensure_admin()?;
+    journal_call("set_sensor_bridges");
+    if bridges.len() > MAX_SENSOR_BRIDGES {
+        return Err(Error::CapacityExceeded {
+            msg: format!(
+                "At most {} sensor bridges can be registered.",
+                MAX_SENSOR_BRIDGES
+            ),
+        });
+    }
+    if bridges.contains(&Principal::anonymous()) {
+        return Err(Error::InvalidOperation {
+            msg: "The anonymous principal cannot be a sensor bridge.".to_string(),
+        });
+    }
+    update_config(|config| config.sensor_bridges = Some(bridges.clone()))?;
+    Ok(bridges)
+}
+
+// Function to set, or with no range remove, the temperatures a location must stay within
+#[ic_cdk::update]
+fn set_storage_range(
+    location: Location,
+    range: Option<StorageRange>,
+) -> Result<Option<StorageRange>, Error> {
+    ensure_admin()?;
+    journal_call("set_storage_range");
+    let Some(range) = range else {
+        STORAGE_RANGES.with(|service| service.borrow_mut().remove(&location.code()));
+        return Ok(None);
+    };
+    if range.min_decidegrees > range.max_decidegrees {
+        return Err(Error::InvalidOperation {
+            msg: "The minimum temperature cannot be above the maximum.".to_string(),
+        });
+    }
+    STORAGE_RANGES.with(|service| service.borrow_mut().insert(location.code(), range));
+    Ok(Some(range))
+}
+
+// Helper function to get the open exception of a location, if any
+fn open_storage_exception(location: Location) -> Option<StorageException> {
+    STORAGE_EXCEPTIONS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, exception)| exception)
+            .find(|exception| exception.location == location && exception.ended_at.is_none())
+    })
+}
+
+// Helper function to list the stock held at a location
+fn batches_at(location: Location) -> Vec<AffectedBatch> {
+    STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter_map(|(id, product)| {
+                let quantity = location_quantity(&product, location);
+                (quantity > 0).then_some(AffectedBatch {
+                    product_id: id,
+                    quantity,
+                })
+            })
+            .take(MAX_EXCEPTION_BATCHES)
+            .collect()
+    })
+}
+
+// Function to record a storage-condition reading; a reading out of the location's range opens
+// an exception listing the stock kept there, or extends the open one, and the first reading
+// back in range closes it
+#[ic_cdk::update]
+fn post_condition_reading(payload: ConditionReadingPayload) -> Result<ConditionReading, Error> {
+    ensure_sensor_bridge()?;
+    journal_call("post_condition_reading");
+    let now = time();
+    let recorded_at = payload.recorded_at.unwrap_or(now);
+    if recorded_at > now.saturating_add(MAX_READING_CLOCK_SKEW_NS) {
+        return Err(Error::InvalidOperation {
+            msg: "Readings cannot be timestamped in the future.".to_string(),
+        });
+    }
+    let range = STORAGE_RANGES.with(|service| service.borrow().get(&payload.location.code()));
+    let in_range = range.map(|range| range.contains(payload.temperature_decidegrees));
+    let reading = ConditionReading {
+        location: payload.location,
+        temperature_decidegrees: payload.temperature_decidegrees,
+        recorded_at,
+        posted_by: caller(),
+        posted_at: now,
+        in_range,
+    };
+
+    let seq = CONDITION_READING_SEQ.with(|counter| {
+        let seq = *counter.borrow().get();
+        counter
+            .borrow_mut()
+            .set(seq + 1)
+            .expect("Cannot advance the condition reading counter");
+        seq
+    });
+    let code = payload.location.code();
+    CONDITION_READINGS.with(|service| {
+        let mut readings = service.borrow_mut();
+        let existing: Vec<(u8, u64)> = readings
+            .range((code, 0)..=(code, u64::MAX))
+            .map(|(key, _)| key)
+            .collect();
+        let overflow = (existing.len() + 1).saturating_sub(MAX_CONDITION_READINGS_PER_LOCATION);
+        for key in existing.iter().take(overflow) {
+            readings.remove(key);
+        }
+        readings.insert((code, seq), reading.clone());
+    });
+
+    let open = open_storage_exception(payload.location);
+    match (range, in_range, open) {
+        (Some(range), Some(false), None) => {
+            let id = generate_unique_id(|id| {
+                STORAGE_EXCEPTIONS.with(|service| service.borrow().contains_key(&id))
+            })?;
+            let exception = StorageException {
+                id,
+                location: payload.location,
+                range,
+                started_at: recorded_at,
+                ended_at: None,
+                min_decidegrees: payload.temperature_decidegrees,
+                max_decidegrees: payload.temperature_decidegrees,
+                out_of_range_readings: 1,
+                batches: batches_at(payload.location),
+                resolution: None,
+                resolved_by: None,
+                resolved_at: None,
+            };
+            STORAGE_EXCEPTIONS.with(|service| service.borrow_mut().insert(id, exception));
+        }
+        (_, Some(false), Some(mut exception)) => {
+            exception.min_decidegrees = exception
+                .min_decidegrees
+                .min(payload.temperature_decidegrees);
+            exception.max_decidegrees = exception
+                .max_decidegrees
+                .max(payload.temperature_decidegrees);
+            exception.out_of_range_readings += 1;
+            // Stock moved in while the location was out of range is affected too
+            for batch in batches_at(payload.location) {
+                match exception
+                    .batches
+                    .iter_mut()
+                    .find(|existing| existing.product_id == batch.product_id)
+                {
+                    Some(existing) => existing.quantity = existing.quantity.max(batch.quantity),
+                    None => exception.batches.push(batch),
+                }
+            }
+            exception.batches.truncate(MAX_EXCEPTION_BATCHES);
+            STORAGE_EXCEPTIONS.with(|service| service.borrow_mut().insert(exception.id, exception));
+        }
+        (_, Some(true), Some(mut exception)) => {
+            exception.ended_at = Some(recorded_at);
+            STORAGE_EXCEPTIONS.with(|service| service.borrow_mut().insert(exception.id, exception));
+        }
+        _ => {}
+    }
+    Ok(reading)
+}
+
+// Function for staff to record what was done with the stock affected by an exception, e.g. that
+// it was checked or discarded
+#[ic_cdk::update]
+fn resolve_storage_exception(id: u64, resolution: String) -> Result<StorageException, Error> {
+    ensure_admin()?;
+    journal_call("resolve_storage_exception");
+    validate_notes(&Some(resolution.clone()))?;
+    let mut exception = STORAGE_EXCEPTIONS
+        .with(|service| service.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("A storage exception with id={} was not found", id),
+        })?;
+    exception.resolution = Some(resolution);
+    exception.resolved_by = Some(caller());
+    exception.resolved_at = Some(time());
+    STORAGE_EXCEPTIONS.with(|service| service.borrow_mut().insert(id, exception.clone()));
+    Ok(exception)
+}
+
+// Query function to list the readings of a location taken in `[from, to)`
+#[ic_cdk::query]
+fn list_condition_readings(
+    location: Location,
+    from: u64,
+    to: u64,
+) -> Result<Vec<ConditionReading>, Error> {
+    ensure_admin()?;
+    let code = location.code();
+    Ok(CONDITION_READINGS.with(|service| {
+        service
+            .borrow()
+            .range((code, 0)..=(code, u64::MAX))
+            .map(|(_, reading)| reading)
+            .filter(|reading| reading.recorded_at >= from && reading.recorded_at < to)
+            .collect()
+    }))
+}
+
+// Query function to list the storage exceptions, optionally only those not resolved yet
+#[ic_cdk::query]
+fn list_storage_exceptions(unresolved_only: bool) -> Result<Vec<StorageException>, Error> {
+    ensure_admin()?;
+    Ok(STORAGE_EXCEPTIONS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, exception)| exception)
+            .filter(|exception| !unresolved_only || exception.resolved_at.is_none())
+            .collect()
+    }))
+}
+
+// Query function to list the storage exceptions a product's stock was affected by
+#[ic_cdk::query]
+fn get_product_custody(product_id: u64) -> Result<Vec<StorageException>, Error> {
+    ensure_admin()?;
+    Ok(STORAGE_EXCEPTIONS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, exception)| exception)
+            .filter(|exception| {
+                exception
+                    .batches
+                    .iter()
+                    .any(|batch| batch.product_id == product_id)
+            })
+            .collect()
+    }))
+}
+
+// Query function to report the storage conditions of every location in `[from, to)`, for
+// compliance audits
+#[ic_cdk::query]
+fn condition_report(from: u64, to: u64) -> Result<Vec<LocationConditionReport>, Error> {
+    ensure_viewer()?;
+    if from > to {
+        return Err(Error::InvalidOperation {
+            msg: "A report period cannot end before it starts.".to_string(),
+        });
+    }
+    let exceptions: Vec<StorageException> = STORAGE_EXCEPTIONS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, exception)| exception)
+            .filter(|exception| {
+                exception.started_at < to
+                    && exception.ended_at.is_none_or(|ended_at| ended_at >= from)
+            })
+            .collect()
+    });
+    Ok([
+        Location::FrontShelf,
+        Location::BackFridge,
+        Location::Freezer,
+    ]
+    .into_iter()
+    .map(|location| {
+        let readings = list_condition_readings(location, from, to).unwrap_or_default();
+        LocationConditionReport {
+            location,
+            range: STORAGE_RANGES.with(|service| service.borrow().get(&location.code())),
+            readings: readings.len() as u32,
+            out_of_range_readings: readings
+                .iter()
+                .filter(|reading| reading.in_range == Some(false))
+                .count() as u32,
+            min_decidegrees: readings
+                .iter()
+                .map(|reading| reading.temperature_decidegrees)
+                .min(),
+            max_decidegrees: readings
+                .iter()
+                .map(|reading| reading.temperature_decidegrees)
+                .max(),
+            exceptions: exceptions
+                .iter()
+                .filter(|exception| exception.location == location)
+                .cloned()
+                .collect(),
+        }
+    })
+    .collect())
+}
+
+// Helper function to mark an open checkout session past its expiry as expired
+fn with_checkout_expiry(mut session: CheckoutSession, now: u64) -> CheckoutSession {
+    if session.status == CheckoutStatus::Open && session.expires_at <= now {
+        session.status = CheckoutStatus::Expired;
+    }
+    session
+}
+
+// Helper function to release the stock held by a checkout session
+fn release_checkout_reservations(session: &CheckoutSession) -> Vec<Reservation> {
+    session
+        .reservation_ids
+        .iter()
+        .filter_map(|id| remove_reservation(*id))
+        .collect()
+}
+
+// Helper function to put back the holds of a checkout session when placing its order failed
+fn restore_checkout_reservations(released: Vec<Reservation>) {
+    for reservation in released {
+        insert_reservation(reservation);
+    }
+}
+
+// Function to drop checkout sessions that ended or expired over a day ago
+fn purge_old_checkout_sessions() {
+    let cutoff = time().saturating_sub(CHECKOUT_SESSION_RETENTION_NS);
+    CHECKOUT_SESSIONS.with(|service| {
+        let old: Vec<u64> = service
+            .borrow()
+            .iter()
+            .filter(|(_, session)| session.updated_at.max(session.expires_at) <= cutoff)
+            .map(|(id, _)| id)
+            .collect();
+        let mut sessions = service.borrow_mut();
+        for id in old {
+            sessions.remove(&id);
+        }
+    });
+}
+
+// Helper function to get the caller's open checkout session by id
+fn open_checkout(session_id: u64) -> Result<CheckoutSession, Error> {
+    let session = CHECKOUT_SESSIONS
+        .with(|service| service.borrow().get(&session_id))
+        .filter(|session| is_own_record(&session.customer))
+        .map(|session| with_checkout_expiry(session, time()))
+        .ok_or(Error::NotFound {
+            msg: format!("A checkout session with id={} was not found", session_id),
+        })?;
+    if session.status != CheckoutStatus::Open {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Checkout session id={} is {:?}; begin a new checkout",
+                session_id, session.status
+            ),
+        });
+    }
+    Ok(session)
+}
+
+// Helper function to apply a step to the caller's open checkout session
+fn update_checkout(
+    mut session: CheckoutSession,
+    change: impl FnOnce(&mut CheckoutSession) -> Result<(), Error>,
+) -> Result<CheckoutSession, Error> {
+    change(&mut session)?;
+    session.updated_at = time();
+    CHECKOUT_SESSIONS.with(|service| service.borrow_mut().insert(session.id, session.clone()));
+    Ok(session)
+}
+
+// Function to begin a checkout for the given lines, or for the caller's cart when omitted: the
+// prices are locked and the stock is reserved until the session expires. A session the caller
+// still has open is cancelled
+#[ic_cdk::update]
+fn begin_checkout(lines: Option<Vec<OrderLinePayload>>) -> Result<CheckoutSession, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    journal_call("begin_checkout");
+    if caller() == Principal::anonymous() {
+        return Err(Error::Unauthorized {
+            msg: "Sign in to check out.".to_string(),
+        });
+    }
+    let customer = account_holder();
+    let from_cart = lines.is_none();
+    let requested = match lines {
+        Some(lines) => lines,
+        None => CARTS
+            .with(|service| service.borrow().get(&PrincipalKey(customer)))
+            .map(|cart| {
+                cart.lines
+                    .into_iter()
+                    .map(|line| OrderLinePayload {
+                        product_id: line.product_id,
+                        quantity: line.quantity,
+                        selections: line.selections,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+    };
+    if let Some(mut previous) = get_my_checkout() {
+        release_checkout_reservations(&previous);
+        previous.status = CheckoutStatus::Cancelled;
+        previous.updated_at = time();
+        CHECKOUT_SESSIONS.with(|service| service.borrow_mut().insert(previous.id, previous));
+    }
+    let lines = check_order_lines(&customer, &requested)?;
+    let priced = price_order(&customer, lines);
+
+    let now = time();
+    let expires_at = now.saturating_add(CHECKOUT_SESSION_TTL_NS);
+    let mut reservation_ids = Vec::with_capacity(priced.lines.len());
+    for line in &priced.lines {
+        // Waitlist units set aside for the customer already hold their share of the stock
+        let quantity = line
+            .quantity
+            .saturating_sub(claimable_quantity(customer, line.product_id));
+        if quantity == 0 {
+            continue;
+        }
+        let id = generate_unique_id(|id| {
+            RESERVATIONS.with(|service| service.borrow().contains_key(&id))
+        })?;
+        let reservation = Reservation {
+            id,
+            product_id: line.product_id,
+            quantity,
+            holder: customer,
+            created_at: now,
+            expires_at: Some(expires_at),
+            from_waitlist: None,
+        };
+        insert_reservation(reservation);
+        reservation_ids.push(id);
+    }
+    let id = generate_unique_id(|id| {
+        CHECKOUT_SESSIONS.with(|service| service.borrow().contains_key(&id))
+    })?;
+    let session = CheckoutSession {
+        id,
+        customer,
+        lines: priced.lines,
+        subtotal: priced.subtotal,
+        discount: priced.discount,
+        promotion_id: priced.promotion_id,
+        applied_rules: priced.applied_rules,
+        experiments: priced.experiments,
+        reservation_ids,
+        from_cart,
+        delivery_address: None,
+        slot_start: None,
+        payment_method: None,
+        notes: None,
+        status: CheckoutStatus::Open,
+        order_id: None,
+        created_at: now,
+        updated_at: now,
+        expires_at,
+        tenders: None,
+    };
+    CHECKOUT_SESSIONS.with(|service| service.borrow_mut().insert(id, session.clone()));
+    Ok(session)
+}
+
+// Helper function to validate one field of a delivery address
+fn validate_address_field(name: &str, value: &str) -> Result<(), Error> {
+    if value.trim().is_empty() || value.len() > MAX_ADDRESS_FIELD_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "The {} must be non-empty and at most {} bytes long.",
+                name, MAX_ADDRESS_FIELD_LENGTH
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Function to set the delivery address of a checkout; no address means the order is picked up
+#[ic_cdk::update]
+fn set_checkout_address(
+    session_id: u64,
+    address: Option<DeliveryAddress>,
+) -> Result<CheckoutSession, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    let session = open_checkout(session_id)?;
+    journal_call("set_checkout_address");
+    if let Some(address) = &address {
+        validate_address_field("recipient", &address.recipient)?;
+        validate_address_field("address line", &address.line1)?;
+        validate_address_field("city", &address.city)?;
+        validate_address_field("postal code", &address.postal_code)?;
+        for (name, value) in [
+            ("second address line", &address.line2),
+            ("phone", &address.phone),
+        ] {
+            if let Some(value) = value {
+                validate_address_field(name, value)?;
+            }
+        }
+    }
+    update_checkout(session, |session| {
+        session.delivery_address = address;
+        Ok(())
+    })
+}
+
+// Function to choose the pickup or delivery slot of a checkout
+#[ic_cdk::update]
+fn set_checkout_slot(session_id: u64, slot_start: u64) -> Result<CheckoutSession, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    let session = open_checkout(session_id)?;
+    journal_call("set_checkout_slot");
+    let now = time();
+    if slot_start <= now || slot_start > now.saturating_add(MAX_SLOT_ADVANCE_NS) {
+        return Err(Error::InvalidOperation {
+            msg: "Slots must start in the future and at most 14 days ahead.".to_string(),
+        });
+    }
+    update_checkout(session, |session| {
+        ensure_day_capacity(
+            slot_start / NANOS_PER_DAY,
+            order_prep_minutes(&session.lines),
+        )?;
+        session.slot_start = Some(slot_start);
+        Ok(())
+    })
+}
+
+// Function to choose how a checkout is paid, optionally with points and store credit paying
+// part of it, and optionally add order notes
+#[ic_cdk::update]
+fn set_checkout_payment(
+    session_id: u64,
+    method: PaymentMethod,
+    notes: Option<String>,
+    tenders: Option<Vec<TenderRequest>>,
+) -> Result<CheckoutSession, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    let session = open_checkout(session_id)?;
+    journal_call("set_checkout_payment");
+    validate_notes(&notes)?;
+    if let PaymentMethod::Token { symbol } = &method {
+        accepted_token_by_symbol(symbol)?;
+    }
+    update_checkout(session, |session| {
+        // Checked now so the step reports a short balance, and again when finalizing
+        split_tenders(
+            &session.customer,
+            session.subtotal - session.discount,
+            tenders.as_deref().unwrap_or_default(),
+            Tender::PayOnPickup,
+        )?;
+        session.payment_method = Some(method);
+        session.notes = notes;
+        session.tenders = tenders;
+        Ok(())
+    })
+}
+
+// Function to place the order of a checkout once the slot and payment steps are done, at the
+// prices locked when it began
+#[ic_cdk::update]
+fn finalize_checkout(session_id: u64) -> Result<Order, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    journal_call("finalize_checkout");
+    ensure_payments_open()?;
+    let mut session = open_checkout(session_id)?;
+    let (Some(slot_start), Some(method)) = (session.slot_start, session.payment_method.clone())
+    else {
+        return Err(Error::InvalidOperation {
+            msg: "Choose a slot and a payment method before finalizing the checkout.".to_string(),
+        });
+    };
+    let token = match &method {
+        PaymentMethod::Token { symbol } => Some(accepted_token_by_symbol(symbol)?),
+        PaymentMethod::PayOnPickup => None,
+    };
+    // Other orders may have taken the slot's day since it was chosen
+    ensure_day_capacity(
+        slot_start / NANOS_PER_DAY,
+        order_prep_minutes(&session.lines),
+    )?;
+
+    let rest = token
+        .as_ref()
+        .map_or(Tender::PayOnPickup, |token| Tender::Token {
+            symbol: token.symbol.clone(),
+        });
+    let tenders = split_tenders(
+        &session.customer,
+        session.subtotal - session.discount,
+        session.tenders.as_deref().unwrap_or_default(),
+        rest,
+    )?;
+
+    // The session's own holds are released first so the stock they kept counts as available
+    let released = release_checkout_reservations(&session);
+    let requested: Vec<OrderLinePayload> = session
+        .lines
+        .iter()
+        .map(|line| OrderLinePayload {
+            product_id: line.product_id,
+            quantity: line.quantity,
+            selections: line.options.as_deref().map(selections_of),
+        })
+        .collect();
+    if let Err(error) = check_order_lines(&session.customer, &requested) {
+        restore_checkout_reservations(released);
+        return Err(error);
+    }
+    let priced = PricedOrder {
+        lines: session.lines.clone(),
+        subtotal: session.subtotal,
+        discount: session.discount,
+        promotion_id: session.promotion_id,
+        applied_rules: session.applied_rules.clone(),
+        experiments: session.experiments.clone(),
+    };
+    let due = remaining_due(&tenders, session.subtotal - session.discount);
+    let payment = token.filter(|_| due > 0).map(|token| OrderPayment {
+        amount: token_amount(&token, due),
+        symbol: token.symbol,
+        ledger: token.ledger,
+        escrow: None,
+    });
+    // Until the order is placed the session stays open, so it keeps its holds on failure
+    let mut order = match commit_order(
+        session.customer,
+        priced,
+        session.notes.clone(),
+        None,
+        payment,
+        None,
+    ) {
+        Ok(order) => order,
+        Err(error) => {
+            restore_checkout_reservations(released);
+            return Err(error);
+        }
+    };
+    apply_tenders(&mut order, tenders);
+    screen_order(&order);
+    order.schedule = Some(OrderSchedule {
+        slot_start,
+        delivery_address: session.delivery_address.clone(),
+    });
+    // Nothing is handed over before the chosen slot
+    order.promised_ready_at = order.promised_ready_at.max(Some(slot_start));
+    store_order(order.clone());
+    if session.from_cart {
+        CARTS.with(|service| service.borrow_mut().remove(&PrincipalKey(session.customer)));
+    }
+    session.status = CheckoutStatus::Finalized;
+    session.order_id = Some(order.id);
+    session.updated_at = time();
+    CHECKOUT_SESSIONS.with(|service| service.borrow_mut().insert(session_id, session));
+    Ok(order)
+}
+
+// Function to abandon a checkout, releasing the stock it held
+#[ic_cdk::update]
+fn cancel_checkout(session_id: u64) -> Result<CheckoutSession, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    let session = open_checkout(session_id)?;
+    journal_call("cancel_checkout");
+    let session = update_checkout(session, |session| {
+        session.status = CheckoutStatus::Cancelled;
+        Ok(())
+    })?;
+    release_checkout_reservations(&session);
+    Ok(session)
+}
+
+// Query function to get one of the caller's checkout sessions
+#[ic_cdk::query]
+fn get_checkout(session_id: u64) -> Result<CheckoutSession, Error> {
+    CHECKOUT_SESSIONS
+        .with(|service| service.borrow().get(&session_id))
+        .filter(|session| is_own_record(&session.customer))
+        .map(|session| with_checkout_expiry(session, time()))
+        .ok_or(Error::NotFound {
+            msg: format!("A checkout session with id={} was not found", session_id),
+        })
+}
+
+// Query function to get the caller's open checkout session, to resume it
+#[ic_cdk::query]
+fn get_my_checkout() -> Option<CheckoutSession> {
+    let principals = account_principals(&caller());
+    let now = time();
+    CHECKOUT_SESSIONS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, session)| with_checkout_expiry(session, now))
+            .find(|session| {
+                principals.contains(&session.customer) && session.status == CheckoutStatus::Open
+            })
+    })
+}
+
+// Function to queue an effect in the outbox and start dispatching it; the entry is committed
+// with the state change that caused it, and retried by the background job when it fails
+fn enqueue_effect(effect: OutboxEffect) {
+    let id = OUTBOX_SEQ.with(|counter| {
+        let id = *counter.borrow().get();
+        counter
+            .borrow_mut()
+            .set(id + 1)
+            .expect("Cannot advance the outbox counter");
+        id
+    });
+    let now = time();
+    let entry = OutboxEntry {
+        id,
+        effect,
+        status: OutboxStatus::Pending,
+        attempts: 0,
+        created_at: now,
+        next_attempt_at: now,
+        last_attempt_at: None,
+        delivered_at: None,
+        last_error: None,
+    };
+    OUTBOX.with(|service| service.borrow_mut().insert(id, entry));
+    ic_cdk::spawn(dispatch_effect(id));
+}
+
+// Function to make one delivery attempt of an outbox effect and record its outcome
+async fn dispatch_effect(id: u64) {
+    if !OUTBOX_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(id)) {
+        return;
+    }
+    let Some(entry) = OUTBOX
+        .with(|service| service.borrow().get(&id))
+        .filter(|entry| entry.status == OutboxStatus::Pending)
+        // Subscription charges wait, without using up attempts, while payments are paused
+        .filter(|entry| {
+            !matches!(entry.effect, OutboxEffect::SubscriptionPayment { .. })
+                || payments_pause().is_none()
+        })
+    else {
+        OUTBOX_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&id));
+        return;
+    };
+    let final_attempt = entry.attempts + 1 >= MAX_OUTBOX_ATTEMPTS;
+    let outcome = match entry.effect {
+        OutboxEffect::StaffNotification {
+            channel_id,
+            event,
+            order_id,
+        } => match _get_order(&order_id) {
+            Some(order) => send_to_channel(channel_id, event, order)
+                .await
+                .map_err(|error| {
+                    let retry = matches!(error, Error::ExternalCallFailed { .. });
+                    (retry, error.message().to_string())
+                }),
+            None => Err((false, format!("Order id={} was not found", order_id))),
+        },
+        OutboxEffect::SubscriptionPayment {
+            subscription_id,
+            order_id,
+        } => collect_subscription_payment(subscription_id, order_id, final_attempt).await,
+    };
+    OUTBOX_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&id));
+
+    let Some(mut entry) = OUTBOX.with(|service| service.borrow().get(&id)) else {
+        return;
+    };
+    let now = time();
+    entry.attempts += 1;
+    entry.last_attempt_at = Some(now);
+    match outcome {
+        Ok(()) => {
+            entry.status = OutboxStatus::Delivered;
+            entry.delivered_at = Some(now);
+            entry.last_error = None;
+        }
+        Err((retry, msg)) => {
+            if retry && !final_attempt {
+                let delay = OUTBOX_RETRY_BASE_NS
+                    .saturating_mul(1 << (entry.attempts - 1).min(16))
+                    .min(OUTBOX_RETRY_MAX_NS);
+                entry.next_attempt_at = now.saturating_add(delay);
+            } else {
+                entry.status = OutboxStatus::Failed;
+            }
+            entry.last_error = Some(msg);
+        }
+    }
+    OUTBOX.with(|service| service.borrow_mut().insert(id, entry));
+}
+
+// Function to retry a batch of outbox effects that are due
+fn dispatch_outbox() {
+    let now = time();
+    let paused = payments_pause().is_some();
+    let due: Vec<u64> = OUTBOX.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, entry)| {
+                entry.status == OutboxStatus::Pending && entry.next_attempt_at <= now
+            })
+            .filter(|(_, entry)| {
+                !(paused && matches!(entry.effect, OutboxEffect::SubscriptionPayment { .. }))
+            })
+            .map(|(id, _)| id)
+            .take(OUTBOX_DISPATCH_BATCH)
+            .collect()
+    });
+    for id in due {
+        ic_cdk::spawn(dispatch_effect(id));
+    }
+    let receipts: Vec<u64> = RECEIPT_DELIVERIES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, delivery)| {
+                delivery.status == OutboxStatus::Pending && delivery.next_attempt_at <= now
+            })
+            .map(|(order_id, _)| order_id)
+            .take(OUTBOX_DISPATCH_BATCH)
+            .collect()
+    });
+    for order_id in receipts {
+        ic_cdk::spawn(send_receipt_email(order_id));
+    }
+}
+
+// Function to drop outbox effects and receipt emails delivered over a week ago; failed ones are
+// kept for operators
+fn purge_delivered_outbox() {
+    let cutoff = time().saturating_sub(OUTBOX_RETENTION_NS);
+    RECEIPT_DELIVERIES.with(|service| {
+        let delivered: Vec<u64> = service
+            .borrow()
+            .iter()
+            .filter(|(_, delivery)| delivery.delivered_at.is_some_and(|at| at <= cutoff))
+            .map(|(order_id, _)| order_id)
+            .collect();
+        let mut deliveries = service.borrow_mut();
+        for order_id in delivered {
+            deliveries.remove(&order_id);
+        }
+    });
+    OUTBOX.with(|service| {
+        let delivered: Vec<u64> = service
+            .borrow()
+            .iter()
+            .filter(|(_, entry)| entry.delivered_at.is_some_and(|at| at <= cutoff))
+            .map(|(id, _)| id)
+            .collect();
+        let mut outbox = service.borrow_mut();
+        for id in delivered {
+            outbox.remove(&id);
+        }
+    });
+}
+
+// Query function for operators to list the outbox, optionally only the effects in one status
+#[ic_cdk::query]
+fn list_outbox(status: Option<OutboxStatus>) -> Result<Vec<OutboxEntry>, Error> {
+    ensure_admin()?;
+    Ok(OUTBOX.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| status.is_none_or(|status| entry.status == status))
+            .collect()
+    }))
+}
+
+// Helper function to record the current update call in the call journal; it must run before
+// the first await, while the arguments of the call can still be read
+fn journal_call(method: &str) {
+    let args_hash = Sha256::digest(ic_cdk::api::call::arg_data_raw()).to_vec();
+    append_call_record(
+        method.to_string(),
+        caller(),
+        Some(args_hash),
+        call_device(&caller()),
+    );
+}
+
+// Function to append an entry to the call journal, chained to the previous entry by its hash,
+// and certify the new head
+fn append_call_record(
+    method: String,
+    caller: Principal,
+    args_hash: Option<Vec<u8>>,
+    device_id: Option<String>,
+) {
+    let now = time();
+    CALL_JOURNAL.with(|service| {
+        let mut journal = service.borrow_mut();
+        let (seq, prev_hash) = match journal.last_key_value() {
+            Some((seq, last)) => (seq + 1, last.hash),
+            None => (0, vec![0; 32]),
+        };
+        let hash = call_record_hash(
+            seq, &method, &caller, now, &args_hash, &device_id, &prev_hash,
+        );
+        ic_cdk::api::set_certified_data(&hash);
+        journal.insert(
+            seq,
+            CallRecord {
+                seq,
+                method,
+                caller,
+                at: now,
+                args_hash,
+                prev_hash,
+                hash,
+                device_id,
+            },
+        );
+    });
+}
+
+// Helper function to hash a journal entry: SHA-256 over the previous hash, the sequence number,
+// the length-prefixed method and caller, the time and, when set, the arguments hash and the
+// length-prefixed device; integers are big-endian and lengths are 4 bytes
+fn call_record_hash(
+    seq: u64,
+    method: &str,
+    caller: &Principal,
+    at: u64,
+    args_hash: &Option<Vec<u8>>,
+    device_id: &Option<String>,
+    prev_hash: &[u8],
+) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(prev_hash);
+    hasher.update(seq.to_be_bytes());
+    hasher.update((method.len() as u32).to_be_bytes());
+    hasher.update(method.as_bytes());
+    hasher.update((caller.as_slice().len() as u32).to_be_bytes());
+    hasher.update(caller.as_slice());
+    hasher.update(at.to_be_bytes());
+    if let Some(args_hash) = args_hash {
+        hasher.update(args_hash);
+    }
+    if let Some(device_id) = device_id {
+        hasher.update((device_id.len() as u32).to_be_bytes());
+        hasher.update(device_id.as_bytes());
+    }
+    hasher.finalize().to_vec()
+}
+
+// Query function to get the latest entry of the call journal with the certificate over its
+// hash, for auditors to check the entries they read against it
+#[ic_cdk::query]
+fn get_journal_head() -> Option<JournalHead> {
+    CALL_JOURNAL.with(|service| {
+        service
+            .borrow()
+            .last_key_value()
+            .map(|(seq, last)| JournalHead {
+                seq,
+                hash: last.hash,
+                certificate: ic_cdk::api::data_certificate(),
+            })
+    })
+}
+
+// Query function to read the call journal from sequence number `from`
+#[ic_cdk::query]
+fn get_journal_range(from: u64, limit: u32) -> Result<Vec<CallRecord>, Error> {
+    ensure_viewer()?;
+    Ok(CALL_JOURNAL.with(|service| {
+        service
+            .borrow()
+            .range(from..)
+            .take(limit.min(MAX_JOURNAL_PAGE) as usize)
+            .map(|(_, record)| record)
+            .collect()
+    }))
+}
+
+// Function to copy the products and orders changed since the previous snapshot, started by the
+// expiry sweep once per snapshot interval. It goes through as many batches as its instruction
+// budget allows per sweep and carries on with the next sweep; the snapshot point is recorded
+// once every record is copied, after which copies no restore can need any more are dropped
+fn take_snapshots() {
+    let now = time();
+    let started = ic_cdk::api::instruction_counter();
+    let mut run = match SNAPSHOT_STATE.with(|cell| cell.borrow().get().run.clone()) {
+        Some(run) => run,
+        None => {
+            let last =
+                SNAPSHOT_POINTS.with(|service| service.borrow().last_key_value().map(|(at, _)| at));
+            if last.is_some_and(|last| last.saturating_add(SNAPSHOT_INTERVAL_NS) > now) {
+                return;
+            }
+            SnapshotRun {
+                started_at: now,
+                since: last,
+                phase: SnapshotPhase::Products,
+                cursor: None,
+                products: 0,
+                orders: 0,
+            }
+        }
+    };
+    let since = run.since;
+    let changed_since = |changed_at: u64| since.is_none_or(|since| changed_at > since);
+    let cutoff = run.started_at.saturating_sub(RESTORE_WINDOW_NS);
+    let mut finished = false;
+    while !finished && ic_cdk::api::instruction_counter() - started < SNAPSHOT_INSTRUCTIONS {
+        let start = run.cursor.map_or(Bound::Unbounded, Bound::Excluded);
+        let ids: Vec<u64> = match run.phase {
+            SnapshotPhase::Products => {
+                let batch: Vec<(u64, Product)> =
+                    STORAGE.with(|service| service.borrow().entries_from(start, SNAPSHOT_BATCH));
+                PRODUCT_SNAPSHOTS.with(|service| {
+                    let mut snapshots = service.borrow_mut();
+                    for (id, product) in &batch {
+                        if changed_since(product.updated_at.unwrap_or(product.created_at)) {
+                            // Keyed by the time of the copy, which may be after the start
+                            snapshots.insert((*id, now), product.clone());
+                            run.products += 1;
+                        }
+                    }
+                });
+                batch.into_iter().map(|(id, _)| id).collect()
+            }
+            SnapshotPhase::Orders => {
+                let batch: Vec<(u64, Order)> =
+                    ORDERS.with(|service| service.borrow().entries_from(start, SNAPSHOT_BATCH));
+                ORDER_SNAPSHOTS.with(|service| {
+                    let mut snapshots = service.borrow_mut();
+                    for (id, order) in &batch {
+                        if changed_since(order.updated_at.unwrap_or(order.created_at)) {
+                            snapshots.insert((*id, now), order.clone());
+                            run.orders += 1;
+                        }
+                    }
+                });
+                batch.into_iter().map(|(id, _)| id).collect()
+            }
+            SnapshotPhase::PruneProducts => PRODUCT_SNAPSHOTS.with(|service| {
+                prune_snapshot_copies(&mut service.borrow_mut(), run.cursor, cutoff)
+            }),
+            SnapshotPhase::PruneOrders => ORDER_SNAPSHOTS.with(|service| {
+                prune_snapshot_copies(&mut service.borrow_mut(), run.cursor, cutoff)
+            }),
+        };
+        if ids.len() == SNAPSHOT_BATCH {
+            run.cursor = ids.last().copied();
+            continue;
+        }
+        run.cursor = None;
+        match run.phase {
+            SnapshotPhase::Products => run.phase = SnapshotPhase::Orders,
+            SnapshotPhase::Orders => {
+                let point = SnapshotPoint {
+                    taken_at: run.started_at,
+                    products: run.products,
+                    orders: run.orders,
+                };
+                SNAPSHOT_POINTS.with(|service| service.borrow_mut().insert(run.started_at, point));
+                run.phase = SnapshotPhase::PruneProducts;
+            }
+            SnapshotPhase::PruneProducts => run.phase = SnapshotPhase::PruneOrders,
+            SnapshotPhase::PruneOrders => {
+                SNAPSHOT_POINTS.with(|service| {
+                    let old: Vec<u64> =
+                        service.borrow().range(..cutoff).map(|(at, _)| at).collect();
+                    let mut points = service.borrow_mut();
+                    for at in old {
+                        points.remove(&at);
+                    }
+                });
+                finished = true;
+            }
+        }
+    }
+    let state = SnapshotState {
+        run: (!finished).then_some(run),
+    };
+    SNAPSHOT_STATE.with(|cell| {
+        cell.borrow_mut()
+            .set(state)
+            .expect("Cannot store the snapshot progress")
+    });
+}
+
+// Helper function to get up to `limit` distinct record ids after `after` of a map keyed by
+// record id and time, skipping over the other entries of each record
+fn record_ids_after<V: BoundedStorable>(
+    map: &StableBTreeMap<(u64, u64), V, Memory>,
+    after: Option<u64>,
+    limit: usize,
+) -> Vec<u64> {
+    let mut ids = Vec::new();
+    let mut next = after.map_or(Some(0), |after| after.checked_add(1));
+    while let Some(from) = next.filter(|_| ids.len() < limit) {
+        let Some(((id, _), _)) = map.range((from, 0)..).next() else {
+            break;
+        };
+        ids.push(id);
+        next = id.checked_add(1);
+    }
+    ids
+}
+
+// Helper function to drop, for a batch of the records after `after`, the copies taken before
+// `cutoff` except the latest of each, which restores within the window still start from;
+// returns the ids of the records gone through
+fn prune_snapshot_copies<V: BoundedStorable>(
+    copies: &mut StableBTreeMap<(u64, u64), V, Memory>,
+    after: Option<u64>,
+    cutoff: u64,
+) -> Vec<u64> {
+    let ids = record_ids_after(copies, after, SNAPSHOT_BATCH);
+    for id in &ids {
+        let old: Vec<(u64, u64)> = copies
+            .range((*id, 0)..=(*id, cutoff))
+            .map(|(key, _)| key)
+            .collect();
+        for key in old.iter().rev().skip(1) {
+            copies.remove(key);
+        }
+    }
+    ids
+}
+
+// Helper function to find a product as it was at `at`, from its history and snapshots: `None`
+// when neither reaches back that far, `Some(None)` when it was removed by then
+fn product_as_of(id: u64, at: u64) -> Option<Option<Product>> {
+    let version = PRODUCT_VERSIONS.with(|service| {
+        service
+            .borrow()
+            .range((id, 0)..=(id, u64::MAX))
+            .map(|(_, version)| version)
+            .filter(|version| version.at <= at)
+            .last()
+    });
+    let snapshot = PRODUCT_SNAPSHOTS
+        .with(|service| service.borrow().range((id, 0)..=(id, at)).last())
+        .map(|((_, taken_at), product)| (taken_at, product));
+    match (version, snapshot) {
+        (Some(version), Some((taken_at, product))) if taken_at > version.at => Some(Some(product)),
+        (Some(version), _) => Some((version.removed != Some(true)).then_some(version.product)),
+        (None, Some((_, product))) => Some(Some(product)),
+        (None, None) => None,
+    }
+}
+
+// Helper function to get the settlement state of an order's escrow
+fn escrow_status(order: &Order) -> Option<EscrowStatus> {
+    order
+        .payment
+        .as_ref()
+        .and_then(|payment| payment.escrow.as_ref())
+        .map(|escrow| escrow.status)
+}
+
+// Function to go through one batch of a restore of products and orders as they were at
+// `target_at`, or with `dry_run` only count what would change, bounded in records and
+// instructions; moves `progress` past the batch and adds to `summary`
+fn restore_batch(
+    target_at: u64,
+    dry_run: bool,
+    progress: &mut RestoreProgress,
+    summary: &mut RestoreSummary,
+) {
+    let now = time();
+    let started = ic_cdk::api::instruction_counter();
+    let ids: Vec<u64> = match progress.phase {
+        RestorePhase::Products => restore_product_ids(progress.cursor, SNAPSHOT_BATCH),
+        RestorePhase::Orders => {
+            let start = progress.cursor.map_or(Bound::Unbounded, Bound::Excluded);
+            ORDERS.with(|service| {
+                service
+                    .borrow()
+                    .range((start, Bound::Unbounded))
+                    .take(SNAPSHOT_BATCH)
+                    .map(|(id, _)| id)
+                    .collect()
+            })
+        }
+        RestorePhase::Done => return,
+    };
+    let exhausted = ids.len() < SNAPSHOT_BATCH;
+    let mut stopped_early = false;
+    for id in ids {
+        if ic_cdk::api::instruction_counter() - started > SNAPSHOT_INSTRUCTIONS {
+            stopped_early = true;
+            break;
+        }
+        progress.cursor = Some(id);
+        if progress.phase == RestorePhase::Products {
+            restore_product(id, target_at, dry_run, now, summary);
+        } else {
+            restore_order(id, target_at, dry_run, now, summary);
+        }
+    }
+    if exhausted && !stopped_early {
+        progress.cursor = None;
+        progress.phase = match progress.phase {
+            RestorePhase::Products => RestorePhase::Orders,
+            _ => RestorePhase::Done,
+        };
+    }
+}
+
+// Helper function to get up to `limit` ids after `after` of the products a restore goes
+// through: those that exist and those with a history or snapshot, which may have been removed
+fn restore_product_ids(after: Option<u64>, limit: usize) -> Vec<u64> {
+    let start = after.map_or(Bound::Unbounded, Bound::Excluded);
+    let mut ids: std::collections::BTreeSet<u64> = STORAGE.with(|service| {
+        service
+            .borrow()
+            .range((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(id, _)| id)
+            .collect()
+    });
+    ids.extend(PRODUCT_VERSIONS.with(|service| record_ids_after(&service.borrow(), after, limit)));
+    ids.extend(PRODUCT_SNAPSHOTS.with(|service| record_ids_after(&service.borrow(), after, limit)));
+    ids.into_iter().take(limit).collect()
+}
+
+// Function to restore a product as it was at `target_at`, or with `dry_run` only count the
+// change. It gets back its details but keeps its current stock, which is real; a product
+// created since is archived
+fn restore_product(id: u64, target_at: u64, dry_run: bool, now: u64, summary: &mut RestoreSummary) {
+    let current = _get_product(&id);
+    let restored = match (product_as_of(id, target_at), &current) {
+        (Some(Some(old)), Some(current)) => {
+            let restored = Product {
+                quantity: current.quantity,
+                updated_at: current.updated_at,
+                ..old
+            };
+            if product_fields(&restored) == product_fields(current) {
+                return;
+            }
+            summary.products_restored += 1;
+            restored
+        }
+        (Some(Some(old)), None) => {
+            summary.products_recreated += 1;
+            Product { quantity: 0, ..old }
+        }
+        (None, Some(current)) if current.created_at <= target_at => {
+            summary.products_unavailable += 1;
+            return;
+        }
+        (_, Some(current)) => {
+            if current.status() == ProductStatus::Archived {
+                return;
+            }
+            summary.products_archived += 1;
+            Product {
+                status: Some(ProductStatus::Archived),
+                ..current.clone()
+            }
+        }
+        (_, None) => return,
+    };
+    if ensure_unique_name(&restored.name, Some(id)).is_err() {
+        // Another product took the name since the target
+        summary.products_unavailable += 1;
+        return;
+    }
+    if !dry_run {
+        let restored = Product {
+            updated_at: Some(now),
+            ..restored
+        };
+        STORAGE.with(|service| service.borrow_mut().insert(id, restored.clone()));
+        index_product_name(
+            current.as_ref().map(|current| current.name.as_str()),
+            &restored,
+        );
+        index_product_category(current.as_ref().map(|current| &current.category), &restored);
+        record_product_version(&restored);
+    }
+}
+
+// Function to restore an order to its latest snapshot at or before `target_at`, keeping its
+// payment, or with `dry_run` only count the change; an order whose payment settled since or
+// placed since is left as it is
+fn restore_order(id: u64, target_at: u64, dry_run: bool, now: u64, summary: &mut RestoreSummary) {
+    let Some(current) = _get_order(&id) else {
+        return;
+    };
+    if current.created_at > target_at {
+        summary.orders_kept += 1;
+        return;
+    }
+    let Some(((_, taken_at), old)) = ORDER_SNAPSHOTS.with(|service| {
+        service
+            .borrow()
+            .range((current.id, 0)..=(current.id, target_at))
+            .last()
+    }) else {
+        return;
+    };
+    if current.updated_at.unwrap_or(current.created_at) <= taken_at {
+        return;
+    }
+    let settling = matches!(
+        escrow_status(&current),
+        Some(
+            EscrowStatus::Releasing
+                | EscrowStatus::Released
+                | EscrowStatus::Refunding
+                | EscrowStatus::Refunded
+        )
+    );
+    if settling && escrow_status(&current) != escrow_status(&old) {
+        summary.orders_skipped += 1;
+        return;
+    }
+    summary.orders_restored += 1;
+    if dry_run {
+        return;
+    }
+    // The cancellation returned the order's units to stock, so they are taken out again
+    if current.status == OrderStatus::Cancelled && old.status != OrderStatus::Cancelled {
+        for line in open_lines(&old) {
+            if let Some(mut product) = _get_product(&line.product_id) {
+                let expected = product.quantity;
+                let taken = expected.min(line.quantity);
+                if take_stock(&mut product, expected, taken).is_err() {
+                    continue;
+                }
+                product.updated_at = Some(now);
+                do_insert(&product);
+                record_stock_movement(
+                    &caller(),
+                    product.id,
+                    StockMovementKind::Adjustment,
+                    -(taken as i64),
+                    0,
+                    Some(current.id),
+                );
+            }
+        }
+    }
+    let restored = Order {
+        order_number: current.order_number,
+        payment: current.payment,
+        nft_receipt: current.nft_receipt,
+        updated_at: Some(now),
+        ..old
+    };
+    store_order(restored);
+}
+
+// Helper function to check that a restore target is within the restore window
+fn validate_restore_target(target_at: u64) -> Result<(), Error> {
+    let now = time();
+    if target_at >= now || target_at < now.saturating_sub(RESTORE_WINDOW_NS) {
+        return Err(Error::InvalidOperation {
+            msg: "Restores can only target a past moment within the last 30 days.".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Query function to report what restoring the state as of `target_at` would change, one batch
+// at a time from `progress`
+#[ic_cdk::query]
+fn preview_restore(
+    target_at: u64,
+    progress: Option<RestoreProgress>,
+) -> Result<RestorePreview, Error> {
+    ensure_admin()?;
+    validate_restore_target(target_at)?;
+    let mut progress = progress.unwrap_or_default();
+    let mut summary = RestoreSummary {
+        target_at,
+        ..Default::default()
+    };
+    restore_batch(target_at, true, &mut progress, &mut summary);
+    Ok(RestorePreview { summary, progress })
+}
+
+// Function to request restoring products and orders as they were at `target_at`; its preview
+// is counted in batches with `continue_restore`, after which a second admin must approve it
+// with `approve_restore`
+#[ic_cdk::update]
+fn restore_to(target_at: u64) -> Result<RestorePlan, Error> {
+    ensure_admin()?;
+    journal_call("restore_to");
+    validate_restore_target(target_at)?;
+    let id =
+        generate_unique_id(|id| RESTORE_PLANS.with(|service| service.borrow().contains_key(&id)))?;
+    let now = time();
+    let mut plan = RestorePlan {
+        id,
+        target_at,
+        requested_by: caller(),
+        requested_at: now,
+        expires_at: now.saturating_add(PENDING_ACTION_TTL_NS),
+        status: ActionStatus::Pending,
+        decided_by: None,
+        decided_at: None,
+        preview: RestoreSummary {
+            target_at,
+            ..Default::default()
+        },
+        applied: None,
+        progress: Some(RestoreProgress::default()),
+        last_batch_at: None,
+        completed_at: None,
+    };
+    continue_restore_plan(&mut plan, now);
+    RESTORE_PLANS.with(|service| service.borrow_mut().insert(id, plan.clone()));
+    Ok(plan)
+}
+
+// Helper function to mark a pending restore past its expiry as expired
+fn with_restore_expiry(mut plan: RestorePlan, now: u64) -> RestorePlan {
+    if plan.status == ActionStatus::Pending && plan.expires_at <= now {
+        plan.status = ActionStatus::Expired;
+    }
+    plan
+}
+
+// Helper function to get a restore a second admin may still decide on
+fn decidable_restore(id: u64) -> Result<RestorePlan, Error> {
+    let plan = RESTORE_PLANS
+        .with(|service| service.borrow().get(&id))
+        .map(|plan| with_restore_expiry(plan, time()))
+        .ok_or(Error::NotFound {
+            msg: format!("A restore with id={} was not found", id),
+        })?;
+    if plan.status != ActionStatus::Pending {
+        return Err(Error::InvalidOperation {
+            msg: format!("Restore with id={} is {:?}", id, plan.status),
+        });
+    }
+    if plan.requested_by == caller() {
+        return Err(Error::Unauthorized {
+            msg: "A restore must be approved or rejected by a different admin.".to_string(),
+        });
+    }
+    Ok(plan)
+}
+
+// Helper function to go through the next batch of a plan: of its preview while it is pending,
+// then of the restore itself once approved
+fn continue_restore_plan(plan: &mut RestorePlan, now: u64) {
+    let mut progress = plan.progress();
+    if plan.status == ActionStatus::Approved {
+        let applied = plan.applied.get_or_insert(RestoreSummary {
+            target_at: plan.target_at,
+            ..Default::default()
+        });
+        restore_batch(plan.target_at, false, &mut progress, applied);
+        if progress.phase == RestorePhase::Done {
+            plan.completed_at = Some(now);
+        }
+    } else {
+        restore_batch(plan.target_at, true, &mut progress, &mut plan.preview);
+    }
+    plan.progress = Some(progress);
+    plan.last_batch_at = Some(now);
+}
+
+// Helper function to get the approved restore still being carried out, if any
+fn active_restore() -> Option<RestorePlan> {
+    RESTORE_PLANS.with(|service| {
+        service.borrow().iter().map(|(_, plan)| plan).find(|plan| {
+            plan.status == ActionStatus::Approved && plan.progress().phase != RestorePhase::Done
+        })
+    })
+}
+
+// Function to go through the next batch of a restore and report its progress: of the preview
+// while it is pending, so that the approving admin sees every change it makes, then of the
+// restore itself once approved; call again until the phase is `Done`
+#[ic_cdk::update]
+fn continue_restore(id: u64) -> Result<RestorePlan, Error> {
+    ensure_admin()?;
+    journal_call("continue_restore");
+    let now = time();
+    let mut plan = RESTORE_PLANS
+        .with(|service| service.borrow().get(&id))
+        .map(|plan| with_restore_expiry(plan, now))
+        .ok_or(Error::NotFound {
+            msg: format!("A restore with id={} was not found", id),
+        })?;
+    let continuable = matches!(plan.status, ActionStatus::Pending | ActionStatus::Approved)
+        && plan.progress().phase != RestorePhase::Done;
+    if !continuable {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Restore with id={} is {:?} and has nothing left to go through",
+                id, plan.status
+            ),
+        });
+    }
+    continue_restore_plan(&mut plan, now);
+    RESTORE_PLANS.with(|service| service.borrow_mut().insert(id, plan.clone()));
+    Ok(plan)
+}
+
+// Function to approve a restore requested by another admin once its preview is complete, and
+// carry out its first batch; the rest is carried out with `continue_restore`
+#[ic_cdk::update]
+fn approve_restore(id: u64) -> Result<RestorePlan, Error> {
+    ensure_admin()?;
+    journal_call("approve_restore");
+    let mut plan = decidable_restore(id)?;
+    validate_restore_target(plan.target_at)?;
+    if plan.progress().phase != RestorePhase::Done {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "The preview of restore id={} is not complete; continue it first",
+                id
+            ),
+        });
+    }
+    if let Some(active) = active_restore() {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "The restore with id={} is still being carried out",
+                active.id
+            ),
+        });
+    }
+    let now = time();
+    plan.status = ActionStatus::Approved;
+    plan.decided_by = Some(caller());
+    plan.decided_at = Some(now);
+    plan.progress = Some(RestoreProgress::default());
+    continue_restore_plan(&mut plan, now);
+    RESTORE_PLANS.with(|service| service.borrow_mut().insert(id, plan.clone()));
+    Ok(plan)
+}
+
+// Function to reject a restore requested by another admin
+#[ic_cdk::update]
+fn reject_restore(id: u64) -> Result<RestorePlan, Error> {
+    ensure_admin()?;
+    journal_call("reject_restore");
+    let mut plan = decidable_restore(id)?;
+    plan.status = ActionStatus::Rejected;
+    plan.decided_by = Some(caller());
+    plan.decided_at = Some(time());
+    RESTORE_PLANS.with(|service| service.borrow_mut().insert(id, plan.clone()));
+    Ok(plan)
+}
+
+// Query function to list the restores requested, the latest first
+#[ic_cdk::query]
+fn list_restore_plans() -> Result<Vec<RestorePlan>, Error> {
+    ensure_admin()?;
+    let now = time();
+    let mut plans: Vec<RestorePlan> = RESTORE_PLANS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, plan)| with_restore_expiry(plan, now))
+            .collect()
+    });
+    plans.sort_by_key(|plan| std::cmp::Reverse(plan.requested_at));
+    Ok(plans)
+}
+
+// Query function to list the snapshot points restores can start from
+#[ic_cdk::query]
+fn list_snapshots() -> Result<Vec<SnapshotPoint>, Error> {
+    ensure_admin()?;
+    Ok(SNAPSHOT_POINTS.with(|service| service.borrow().iter().map(|(_, point)| point).collect()))
+}
+
+// Helper function to check a supplier's details before saving them
+fn validate_supplier(payload: &SupplierPayload) -> Result<(), Error> {
+    if payload.name.trim().is_empty() || payload.name.len() > MAX_PRODUCT_NAME_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Supplier names must be between 1 and {} bytes long.",
+                MAX_PRODUCT_NAME_LENGTH
+            ),
+        });
+    }
+    if payload
+        .contact
+        .as_ref()
+        .is_some_and(|contact| contact.len() > MAX_ADDRESS_FIELD_LENGTH)
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Supplier contacts can be at most {} bytes long.",
+                MAX_ADDRESS_FIELD_LENGTH
+            ),
+        });
+    }
+    if payload.lead_time_days > MAX_SUPPLIER_LEAD_TIME_DAYS {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Lead times can be at most {} days.",
+                MAX_SUPPLIER_LEAD_TIME_DAYS
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Function to register a supplier products can be reordered from
+#[ic_cdk::update]
+fn add_supplier(payload: SupplierPayload) -> Result<Supplier, Error> {
+    ensure_admin()?;
+    journal_call("add_supplier");
+    validate_supplier(&payload)?;
+    let id = generate_unique_id(|id| SUPPLIERS.with(|service| service.borrow().contains_key(&id)))?;
+    let supplier = Supplier {
+        id,
+        name: payload.name,
+        contact: payload.contact,
+        lead_time_days: payload.lead_time_days,
+        created_at: time(),
+        updated_at: None,
+    };
+    SUPPLIERS.with(|service| service.borrow_mut().insert(id, supplier.clone()));
+    Ok(supplier)
+}
+
+// Function to update the details of a supplier
+#[ic_cdk::update]
+fn update_supplier(id: u64, payload: SupplierPayload) -> Result<Supplier, Error> {
+    ensure_admin()?;
+    journal_call("update_supplier");
+    validate_supplier(&payload)?;
+    let mut supplier =
+        SUPPLIERS
+            .with(|service| service.borrow().get(&id))
+            .ok_or(Error::NotFound {
+                msg: format!("A supplier with id={} was not found", id),
+            })?;
+    supplier.name = payload.name;
+    supplier.contact = payload.contact;
+    supplier.lead_time_days = payload.lead_time_days;
+    supplier.updated_at = Some(time());
+    SUPPLIERS.with(|service| service.borrow_mut().insert(id, supplier.clone()));
+    Ok(supplier)
+}
+
+// Query function to list the suppliers
+#[ic_cdk::query]
+fn list_suppliers() -> Result<Vec<Supplier>, Error> {
+    ensure_viewer()?;
+    Ok(SUPPLIERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, supplier)| supplier)
+            .collect()
+    }))
+}
+
+// Function to set the supplier a product is reordered from, or with none stop reordering it
+#[ic_cdk::update]
+fn set_preferred_supplier(product_id: u64, supplier_id: Option<u64>) -> Result<Product, Error> {
+    ensure_admin()?;
+    journal_call("set_preferred_supplier");
+    let mut product = _get_product(&product_id).ok_or(Error::NotFound {
+        msg: format!("A product with id={} was not found", product_id),
+    })?;
+    if let Some(supplier_id) = supplier_id {
+        if !SUPPLIERS.with(|service| service.borrow().contains_key(&supplier_id)) {
+            return Err(Error::NotFound {
+                msg: format!("A supplier with id={} was not found", supplier_id),
+            });
+        }
+    }
+    product.preferred_supplier_id = supplier_id;
+    product.updated_at = Some(time());
+    do_insert(&product);
+    Ok(product)
+}
+
+// Function to configure how purchase orders are drafted, or with none stop drafting them
+#[ic_cdk::update]
+fn set_auto_reorder(
+    settings: Option<AutoReorderConfig>,
+) -> Result<Option<AutoReorderConfig>, Error> {
+    ensure_admin()?;
+    journal_call("set_auto_reorder");
+    if let Some(settings) = settings {
+        if settings.history_days == 0 || settings.history_days > MAX_REORDER_HISTORY_DAYS {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Forecasts average over 1 to {} days of sales.",
+                    MAX_REORDER_HISTORY_DAYS
+                ),
+            });
+        }
+        if settings.cover_days > MAX_REORDER_COVER_DAYS {
+            return Err(Error::InvalidOperation {
+                msg: format!("Orders can cover at most {} days.", MAX_REORDER_COVER_DAYS),
+            });
+        }
+    }
+    update_config(|config| config.auto_reorder = settings)?;
+    Ok(settings)
+}
+
+// Helper function to forecast the units of a product sold over `days`, from its average daily
+// sales over the last `history_days` full days
+fn forecast_units(product_id: u64, history_days: u32, days: u32) -> u32 {
+    let today = time() / NANOS_PER_DAY;
+    let from = today.saturating_sub(history_days as u64);
+    let sold: i64 = DAILY_SALES.with(|service| {
+        let rollups = service.borrow();
+        (from..today)
+            .filter_map(|day| rollups.get(&(day, product_id)))
+            .map(|sales| sales.units)
+            .sum()
+    });
+    let sold = sold.max(0) as u64;
+    (sold * days as u64)
+        .div_ceil(history_days.max(1) as u64)
+        .min(u32::MAX as u64) as u32
+}
+
+// Function to draft purchase orders for products whose stock for sale fell below their reorder
+// threshold, with their preferred supplier; products already in a draft, or in an order
+// approved within their supplier's lead time, are left out. Returns the drafts changed
+fn draft_purchase_orders() -> Vec<PurchaseOrder> {
+    let Some(settings) = CONFIG.with(|config| config.borrow().get().auto_reorder) else {
+        return Vec::new();
+    };
+    let now = time();
+    let suppliers: HashMap<u64, Supplier> =
+        SUPPLIERS.with(|service| service.borrow().iter().collect());
+    let mut drafts: HashMap<u64, PurchaseOrder> = HashMap::new();
+    let mut on_order: HashSet<u64> = HashSet::new();
+    PURCHASE_ORDERS.with(|service| {
+        for (_, order) in service.borrow().iter() {
+            let lead_time = suppliers
+                .get(&order.supplier_id)
+                .map_or(0, |supplier| supplier.lead_time_days as u64);
+            // A delivery date the supplier posted replaces the lead time
+            let expected_at = order
+                .supplier_response
+                .as_ref()
+                .and_then(|response| response.expected_delivery_at);
+            let pending = match order.status {
+                PurchaseOrderStatus::Draft => true,
+                PurchaseOrderStatus::Approved => expected_at
+                    .or(order
+                        .decided_at
+                        .map(|at| at.saturating_add(lead_time * NANOS_PER_DAY)))
+                    .is_some_and(|at| at > now),
+                PurchaseOrderStatus::Rejected => false,
+            };
+            if pending {
+                on_order.extend(order.lines.iter().map(|line| line.product_id));
+            }
+            if order.status == PurchaseOrderStatus::Draft
+                && order.lines.len() < MAX_PURCHASE_ORDER_LINES
+            {
+                drafts.insert(order.supplier_id, order);
+            }
+        }
+    });
+
+    let reserved = reserved_quantities();
+    let mut changed: Vec<u64> = Vec::new();
+    let products: Vec<Product> = STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| product.status() != ProductStatus::Archived)
+            .filter(|product| !on_order.contains(&product.id))
+            .collect()
+    });
+    for product in products {
+        let (Some(supplier_id), Some(threshold)) = (
+            product.preferred_supplier_id,
+            product
+                .settings
+                .as_ref()
+                .and_then(|settings| settings.reorder_threshold),
+        ) else {
+            continue;
+        };
+        let Some(supplier) = suppliers.get(&supplier_id) else {
+            continue;
+        };
+        let available =
+            availability_of(&product, reserved.get(&product.id).copied().unwrap_or(0)).available;
+        if available >= threshold as i64 {
+            continue;
+        }
+        let forecast = forecast_units(
+            product.id,
+            settings.history_days,
+            supplier.lead_time_days + settings.cover_days,
+        );
+        let quantity = (threshold as i64 + forecast as i64 - available).clamp(1, u32::MAX as i64);
+        let mut draft = match drafts.remove(&supplier_id) {
+            Some(draft) => draft,
+            None => {
+                let Ok(id) = generate_unique_id(|id| {
+                    PURCHASE_ORDERS.with(|service| service.borrow().contains_key(&id))
+                }) else {
+                    break;
+                };
+                PurchaseOrder {
+                    id,
+                    supplier_id,
+                    lines: Vec::new(),
+                    status: PurchaseOrderStatus::Draft,
+                    created_at: now,
+                    updated_at: None,
+                    decided_by: None,
+                    decided_at: None,
+                    supplier_response: None,
+                }
+            }
+        };
+        draft.lines.push(PurchaseOrderLine {
+            product_id: product.id,
+            quantity: quantity as u32,
+            available,
+            reorder_threshold: threshold,
+            forecast_units: forecast,
+        });
+        draft.updated_at = Some(now);
+        PURCHASE_ORDERS.with(|service| service.borrow_mut().insert(draft.id, draft.clone()));
+        if !changed.contains(&draft.id) {
+            changed.push(draft.id);
+        }
+        if draft.lines.len() < MAX_PURCHASE_ORDER_LINES {
+            drafts.insert(supplier_id, draft);
+        }
+    }
+    PURCHASE_ORDERS.with(|service| {
+        let orders = service.borrow();
+        changed.iter().filter_map(|id| orders.get(id)).collect()
+    })
+}
+
+// Function to draft purchase orders for products running low now instead of waiting for the
+// hourly job
+#[ic_cdk::update]
+fn draft_reorders() -> Result<Vec<PurchaseOrder>, Error> {
+    ensure_admin()?;
+    journal_call("draft_reorders");
+    Ok(draft_purchase_orders())
+}
+
+// Query function to list purchase orders, optionally only those with a status, the latest first
+#[ic_cdk::query]
+fn list_purchase_orders(status: Option<PurchaseOrderStatus>) -> Result<Vec<PurchaseOrder>, Error> {
+    ensure_admin()?;
+    let mut orders: Vec<PurchaseOrder> = PURCHASE_ORDERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, order)| order)
+            .filter(|order| status.is_none_or(|status| order.status == status))
+            .collect()
+    });
+    orders.sort_by_key(|order| std::cmp::Reverse(order.created_at));
+    Ok(orders)
+}
+
+// Helper function to approve or reject a draft purchase order
+fn decide_purchase_order(id: u64, status: PurchaseOrderStatus) -> Result<PurchaseOrder, Error> {
+    let mut order = PURCHASE_ORDERS
+        .with(|service| service.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("A purchase order with id={} was not found", id),
+        })?;
+    if order.status != PurchaseOrderStatus::Draft {
+        return Err(Error::InvalidOperation {
+            msg: format!("Purchase order with id={} is {:?}", id, order.status),
+        });
+    }
+    order.status = status;
+    order.decided_by = Some(caller());
+    order.decided_at = Some(time());
+    PURCHASE_ORDERS.with(|service| service.borrow_mut().insert(id, order.clone()));
+    Ok(order)
+}
+
+// Function to approve a draft purchase order so it can be sent to the supplier
+#[ic_cdk::update]
+fn approve_purchase_order(id: u64) -> Result<PurchaseOrder, Error> {
+    ensure_admin()?;
+    journal_call("approve_purchase_order");
+    decide_purchase_order(id, PurchaseOrderStatus::Approved)
+}
+
+// Function to reject a draft purchase order; its products are drafted again while still low
+#[ic_cdk::update]
+fn reject_purchase_order(id: u64) -> Result<PurchaseOrder, Error> {
+    ensure_admin()?;
+    journal_call("reject_purchase_order");
+    decide_purchase_order(id, PurchaseOrderStatus::Rejected)
+}
+
+// Helper function to get the supplier a principal acts for in the supplier portal
+fn linked_supplier(principal: &Principal) -> Option<u64> {
+    SUPPLIER_PRINCIPALS.with(|service| service.borrow().get(&PrincipalKey(*principal)))
+}
+
+// Helper function to restrict an endpoint to supplier portal principals, giving their supplier
+fn ensure_supplier() -> Result<u64, Error> {
+    linked_supplier(&caller()).ok_or(Error::Unauthorized {
+        msg: "Only suppliers can use the supplier portal.".to_string(),
+    })
+}
+
+// Function to let a principal act for a supplier in the supplier portal, or with none revoke it
+#[ic_cdk::update]
+fn set_supplier_principal(principal: Principal, supplier_id: Option<u64>) -> Result<(), Error> {
+    ensure_admin()?;
+    journal_call("set_supplier_principal");
+    let key = PrincipalKey(principal);
+    let Some(supplier_id) = supplier_id else {
+        SUPPLIER_PRINCIPALS.with(|service| service.borrow_mut().remove(&key));
+        return Ok(());
+    };
+    if principal == Principal::anonymous()
+        || ic_cdk::api::is_controller(&principal)
+        || is_kiosk(&principal)
+        || is_viewer(&principal)
+    {
+        return Err(Error::InvalidOperation {
+            msg: "A supplier must be a signed-in principal that is not an admin, a kiosk or a viewer."
+                .to_string(),
+        });
+    }
+    if !SUPPLIERS.with(|service| service.borrow().contains_key(&supplier_id)) {
+        return Err(Error::NotFound {
+            msg: format!("A supplier with id={} was not found", supplier_id),
+        });
+    }
+    SUPPLIER_PRINCIPALS.with(|service| service.borrow_mut().insert(key, supplier_id));
+    Ok(())
+}
+
+// Query function to list the principals acting for a supplier in the supplier portal
+#[ic_cdk::query]
+fn list_supplier_principals(supplier_id: u64) -> Result<Vec<Principal>, Error> {
+    ensure_admin()?;
+    Ok(SUPPLIER_PRINCIPALS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, id)| *id == supplier_id)
+            .map(|(key, _)| key.0)
+            .collect()
+    }))
+}
+
+// Helper function to list the ids of the products linked to a supplier as their preferred one
+fn supplier_product_ids(supplier_id: u64) -> HashSet<u64> {
+    STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, product)| product.preferred_supplier_id == Some(supplier_id))
+            .map(|(id, _)| id)
+            .collect()
+    })
+}
+
+// Helper function to get an approved purchase order of the caller's supplier, with only the
+// lines of the products still linked to it
+fn supplier_purchase_order(supplier_id: u64, id: u64) -> Result<PurchaseOrder, Error> {
+    let mut order = PURCHASE_ORDERS
+        .with(|service| service.borrow().get(&id))
+        .filter(|order| {
+            order.supplier_id == supplier_id && order.status == PurchaseOrderStatus::Approved
+        })
+        .ok_or(Error::NotFound {
+            msg: format!("A purchase order with id={} was not found", id),
+        })?;
+    let linked = supplier_product_ids(supplier_id);
+    order.lines.retain(|line| linked.contains(&line.product_id));
+    Ok(order)
+}
+
+// Query function for a supplier to see the stock of the products linked to them
+#[ic_cdk::query]
+fn my_supplier_stock() -> Result<Vec<SupplierStockLevel>, Error> {
+    let supplier_id = ensure_supplier()?;
+    let reserved = reserved_quantities();
+    Ok(STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| product.preferred_supplier_id == Some(supplier_id))
+            .map(|product| SupplierStockLevel {
+                product_id: product.id,
+                available: availability_of(
+                    &product,
+                    reserved.get(&product.id).copied().unwrap_or(0),
+                )
+                .available,
+                reorder_threshold: product
+                    .settings
+                    .as_ref()
+                    .and_then(|settings| settings.reorder_threshold),
+                quantity: product.quantity,
+                name: product.name,
+            })
+            .collect()
+    }))
+}
+
+// Query function for a supplier to list the purchase orders approved for them, the latest first
+#[ic_cdk::query]
+fn my_supplier_purchase_orders() -> Result<Vec<PurchaseOrder>, Error> {
+    let supplier_id = ensure_supplier()?;
+    let linked = supplier_product_ids(supplier_id);
+    let mut orders: Vec<PurchaseOrder> = PURCHASE_ORDERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, order)| order)
+            .filter(|order| {
+                order.supplier_id == supplier_id && order.status == PurchaseOrderStatus::Approved
+            })
+            .map(|mut order| {
+                order.lines.retain(|line| linked.contains(&line.product_id));
+                order
+            })
+            .collect()
+    });
+    orders.sort_by_key(|order| std::cmp::Reverse(order.created_at));
+    Ok(orders)
+}
+
+// Function for a supplier to acknowledge an approved purchase order, optionally posting when
+// they expect to deliver it; posting again moves the date
+#[ic_cdk::update]
+fn acknowledge_purchase_order(
+    id: u64,
+    expected_delivery_at: Option<u64>,
+) -> Result<PurchaseOrder, Error> {
+    ensure_not_in_maintenance()?;
+    let supplier_id = ensure_supplier()?;
+    journal_call("acknowledge_purchase_order");
+    supplier_purchase_order(supplier_id, id)?;
+    let now = time();
+    if expected_delivery_at.is_some_and(|at| at <= now) {
+        return Err(Error::InvalidOperation {
+            msg: "The expected delivery date must be in the future.".to_string(),
+        });
+    }
+    let mut order = PURCHASE_ORDERS
+        .with(|service| service.borrow().get(&id))
+        .expect("the purchase order was just found");
+    let response = order.supplier_response.get_or_insert(SupplierResponse {
+        acknowledged_by: caller(),
+        acknowledged_at: now,
+        expected_delivery_at: None,
+        delivery_posted_at: None,
+    });
+    if expected_delivery_at.is_some() {
+        response.expected_delivery_at = expected_delivery_at;
+        response.delivery_posted_at = Some(now);
+    }
+    order.updated_at = Some(now);
+    PURCHASE_ORDERS.with(|service| service.borrow_mut().insert(id, order));
+    supplier_purchase_order(supplier_id, id)
+}
+
+// Helper function to list the return requests of a customer
+fn customer_returns(customer: Principal) -> Vec<ReturnRequest> {
+    RETURNS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, request)| request)
+            .filter(|request| request.customer == customer)
+            .collect()
+    })
+}
+
+// Helper function to check the reason and photos of a return request
+fn validate_return_details(reason: &str, photos: &[ReturnPhoto]) -> Result<(), Error> {
+    if reason.trim().is_empty() || reason.len() > MAX_NOTES_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "The reason for a return must be non-empty and at most {} bytes long.",
+                MAX_NOTES_LENGTH
+            ),
+        });
+    }
+    if photos.len() > MAX_RETURN_PHOTOS {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "At most {} photos can be attached to a return.",
+                MAX_RETURN_PHOTOS
+            ),
+        });
+    }
+    for photo in photos {
+        if photo.reference.trim().is_empty()
+            || photo.reference.len() > MAX_NOTIFIER_URL_LENGTH
+            || !photo.content_type.starts_with("image/")
+            || photo.content_type.len() > MAX_LABEL_LENGTH
+        {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Photos need an image content type and a reference of at most {} bytes.",
+                    MAX_NOTIFIER_URL_LENGTH
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Helper function to check that the lines of a return were handed to the customer and are not
+// part of another return of the order that is still open or was refunded
+fn check_return_lines(order: &Order, lines: &[ReturnLine]) -> Result<(), Error> {
+    if lines.is_empty() || lines.len() > MAX_ORDER_LINES {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "A return must have between 1 and {} lines.",
+                MAX_ORDER_LINES
+            ),
+        });
+    }
+    let mut returned: HashMap<u64, u32> = HashMap::new();
+    RETURNS.with(|service| {
+        for (_, request) in service.borrow().iter() {
+            if request.order_id != order.id || request.status == ReturnStatus::Rejected {
+                continue;
+            }
+            for line in &request.lines {
+                *returned.entry(line.product_id).or_insert(0) += line.quantity;
+            }
+        }
+    });
+    for line in lines {
+        let delivered = order
+            .lines
+            .iter()
+            .find(|order_line| order_line.product_id == line.product_id)
+            .map_or(0, |order_line| {
+                order_line.quantity - order_line.unfulfillable.unwrap_or(0)
+            });
+        let returnable =
+            delivered.saturating_sub(returned.get(&line.product_id).copied().unwrap_or(0));
+        if line.quantity == 0 || line.quantity > returnable {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Only {} units of product id={} can still be returned",
+                    returnable, line.product_id
+                ),
+            });
+        }
+        *returned.entry(line.product_id).or_insert(0) += line.quantity;
+    }
+    Ok(())
+}
+
+// Function for customers to request returning units of a picked-up or delivered order, with
+// the reason and photos of the goods; staff decide on it before any refund
+#[ic_cdk::update]
+fn request_return(payload: ReturnRequestPayload) -> Result<ReturnRequest, Error> {
+    ensure_caller_allowed(Endpoint::CreateTicket)?;
+    journal_call("request_return");
+    validate_return_details(&payload.reason, &payload.photos)?;
+    let customer = caller();
+    let order = _get_order(&payload.order_id)
+        .filter(|order| is_own_record(&order.customer))
+        .ok_or(Error::NotFound {
+            msg: format!("An order with id={} was not found", payload.order_id),
+        })?;
+    if !matches!(order.status, OrderStatus::PickedUp | OrderStatus::Delivered) {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Order id={} is {:?}; only picked-up or delivered orders can be returned",
+                order.id, order.status
+            ),
+        });
+    }
+    check_return_lines(&order, &payload.lines)?;
+
+    let id = generate_unique_id(|id| RETURNS.with(|service| service.borrow().contains_key(&id)))?;
+    let now = time();
+    let request = ReturnRequest {
+        id,
+        order_id: order.id,
+        customer,
+        lines: payload.lines,
+        reason: payload.reason,
+        photos: payload.photos,
+        status: ReturnStatus::Requested,
+        events: vec![ReturnEvent {
+            status: ReturnStatus::Requested,
+            by: customer,
+            at: now,
+            note: None,
+        }],
+        refund_amount: None,
+        restocked: None,
+        created_at: now,
+        updated_at: None,
+    };
+    RETURNS.with(|service| service.borrow_mut().insert(id, request.clone()));
+    Ok(request)
+}
+
+// Helper function to move a return request from one status to the next, recording the step
+fn advance_return(
+    id: u64,
+    from: ReturnStatus,
+    to: ReturnStatus,
+    note: Option<String>,
+) -> Result<ReturnRequest, Error> {
+    if note
+        .as_ref()
+        .is_some_and(|note| note.len() > MAX_NOTES_LENGTH)
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!("Notes can be at most {} bytes long.", MAX_NOTES_LENGTH),
+        });
+    }
+    let mut request = RETURNS
+        .with(|service| service.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("A return with id={} was not found", id),
+        })?;
+    if request.status != from {
+        return Err(Error::InvalidOperation {
+            msg: format!("Return id={} is {:?}", id, request.status),
+        });
+    }
+    let now = time();
+    request.status = to;
+    request.events.push(ReturnEvent {
+        status: to,
+        by: caller(),
+        at: now,
+        note,
+    });
+    request.updated_at = Some(now);
+    Ok(request)
+}
+
+// Function for staff to approve a return request so it can be refunded
+#[ic_cdk::update]
+fn approve_return(id: u64, note: Option<String>) -> Result<ReturnRequest, Error> {
+    ensure_admin()?;
+    journal_call("approve_return");
+    let request = advance_return(id, ReturnStatus::Requested, ReturnStatus::Approved, note)?;
+    RETURNS.with(|service| service.borrow_mut().insert(id, request.clone()));
+    Ok(request)
+}
+
+// Function for staff to reject a return request; its units can be requested again
+#[ic_cdk::update]
+fn reject_return(id: u64, note: Option<String>) -> Result<ReturnRequest, Error> {
+    ensure_admin()?;
+    journal_call("reject_return");
+    let request = advance_return(id, ReturnStatus::Requested, ReturnStatus::Rejected, note)?;
+    RETURNS.with(|service| service.borrow_mut().insert(id, request.clone()));
+    Ok(request)
+}
+
+// Function for staff to refund an approved return, sharing the order-wide discount in
+// proportion to the lines' value, and with `restock` to put the returned units back in stock.
+// The refund is booked against cash; the money goes back to the customer outside the canister
+#[ic_cdk::update]
+fn refund_return(id: u64, restock: bool) -> Result<ReturnRequest, Error> {
+    ensure_admin()?;
+    journal_call("refund_return");
+    let mut request = advance_return(id, ReturnStatus::Approved, ReturnStatus::Refunded, None)?;
+    let order = _get_order(&request.order_id).ok_or(Error::NotFound {
+        msg: format!("An order with id={} was not found", request.order_id),
+    })?;
+    let returned: Vec<OrderLine> = request
+        .lines
+        .iter()
+        .filter_map(|line| {
+            order
+                .lines
+                .iter()
+                .find(|order_line| order_line.product_id == line.product_id)
+                .map(|order_line| OrderLine {
+                    quantity: line.quantity,
+                    ..order_line.clone()
+                })
+        })
+        .collect();
+    let lines_total: u64 = returned
+        .iter()
+        .map(|line| line.unit_price * line.quantity as u64)
+        .sum();
+    let refund_amount = if order.subtotal == 0 {
+        0
+    } else {
+        (lines_total as u128 * order.total as u128 / order.subtotal as u128) as u64
+    };
+    let now = time();
+    request.refund_amount = Some(refund_amount);
+    request.restocked = Some(restock);
+    RETURNS.with(|service| service.borrow_mut().insert(id, request.clone()));
+    let mut refunded_to_balances = 0;
+    if order.tenders.is_some() {
+        let mut order = order.clone();
+        refunded_to_balances = refund_tenders(&mut order, refund_amount);
+        store_order(order);
+    }
+    record_sales(&returned, -1, now);
+    post_transfer(
+        format!("Return #{} of order #{} refunded", id, order.id),
+        Some(order.id),
+        LedgerAccount::Refunds,
+        LedgerAccount::Cash,
+        refund_amount - refunded_to_balances,
+    );
+
+    if restock {
+        for line in &request.lines {
+            let Some(mut product) = _get_product(&line.product_id) else {
+                continue;
+            };
+            let previous_quantity = product.quantity;
+            product.quantity = product.quantity.saturating_add(line.quantity);
+            product.updated_at = Some(now);
+            do_insert(&product);
+            record_stock_movement(
+                &caller(),
+                product.id,
+                StockMovementKind::Restock,
+                line.quantity as i64,
+                0,
+                Some(order.id),
+            );
+            allocate_waitlist(product.id);
+            notify_if_back_in_stock(&product, previous_quantity);
+        }
+    }
+    Ok(request)
+}
+
+// Query function to retrieve a return request; customers can only see their own
+#[ic_cdk::query]
+fn get_return(id: u64) -> Result<ReturnRequest, Error> {
+    RETURNS
+        .with(|service| service.borrow().get(&id))
+        .filter(|request| is_own_record(&request.customer) || ensure_admin().is_ok())
+        .ok_or(Error::NotFound {
+            msg: format!("A return with id={} was not found", id),
+        })
+}
+
+// Query function to list the caller's return requests
+#[ic_cdk::query]
+fn list_my_returns() -> Vec<ReturnRequest> {
+    account_principals(&caller())
+        .into_iter()
+        .flat_map(customer_returns)
+        .collect()
+}
+
+// Query function to list the return requests in a status, or all of them, for staff
+#[ic_cdk::query]
+fn list_returns(status: Option<ReturnStatus>) -> Result<Vec<ReturnRequest>, Error> {
+    ensure_admin()?;
+    Ok(RETURNS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, request)| request)
+            .filter(|request| status.is_none_or(|status| request.status == status))
+            .collect()
+    }))
+}
+
+// Helper function to round a computed price by the configured rules
+fn round_price(price: u64) -> u64 {
+    match CONFIG.with(|config| config.borrow().get().price_rounding.clone()) {
+        Some(rounding) => round_price_with(&rounding, price),
+        None => price,
+    }
+}
+
+// Helper function to round a price to the nearest multiple of the increment, and then to the
+// nearest price with the ending
+fn round_price_with(rounding: &PriceRounding, price: u64) -> u64 {
+    let mut price = price;
+    // Near u64::MAX, a rounding step that would overflow leaves the price as it is
+    if let Some(increment) = rounding.increment.filter(|increment| *increment > 1) {
+        if let Some(raised) = price.checked_add(increment / 2) {
+            price = raised / increment * increment;
+        }
+    }
+    if let Some(ending) = rounding.ending {
+        if price >= 100 {
+            let unit = price / 100 * 100;
+            // The nearest price with the ending is within a unit of this one, ties going lower
+            price = [unit.checked_sub(100), Some(unit), unit.checked_add(100)]
+                .into_iter()
+                .flatten()
+                .filter_map(|unit| unit.checked_add(ending))
+                .min_by_key(|candidate| (candidate.abs_diff(price), *candidate))
+                .unwrap_or(price);
+        }
+    }
+    price
+}
+
+// Helper function to check price rounding rules
+fn validate_price_rounding(rounding: &PriceRounding) -> Result<(), Error> {
+    if rounding
+        .increment
+        .is_some_and(|increment| increment == 0 || increment > MAX_ROUNDING_INCREMENT)
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Rounding increments must be between 1 and {}.",
+                MAX_ROUNDING_INCREMENT
+            ),
+        });
+    }
+    if rounding.ending.is_some_and(|ending| ending > 99) {
+        return Err(Error::InvalidOperation {
+            msg: "Price endings are in cents and at most 99.".to_string(),
+        });
+    }
+    if rounding.token_decimals.len() as u64 > MAX_ACCEPTED_TOKENS {
+        return Err(Error::InvalidOperation {
+            msg: format!("At most {} token rules can be set.", MAX_ACCEPTED_TOKENS),
+        });
+    }
+    for rule in &rounding.token_decimals {
+        if rule.symbol.trim().is_empty()
+            || rule.symbol.len() > MAX_TOKEN_SYMBOL_LENGTH
+            || rule.decimals > MAX_TOKEN_DECIMALS
+        {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Token rules need a symbol of at most {} bytes and at most {} decimals.",
+                    MAX_TOKEN_SYMBOL_LENGTH, MAX_TOKEN_DECIMALS
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Function to set how computed prices are rounded, or with none stop rounding them
+#[ic_cdk::update]
+fn set_price_rounding(rounding: Option<PriceRounding>) -> Result<Option<PriceRounding>, Error> {
+    ensure_admin()?;
+    journal_call("set_price_rounding");
+    if let Some(rounding) = &rounding {
+        validate_price_rounding(rounding)?;
+    }
+    update_config(|config| config.price_rounding = rounding.clone())?;
+    Ok(rounding)
+}
+
+// Query function to get the price rounding rules in effect
+#[ic_cdk::query]
+fn get_price_rounding() -> Option<PriceRounding> {
+    CONFIG.with(|config| config.borrow().get().price_rounding.clone())
+}
+
+// Query function to preview rounding rules on sample prices, or on every product's price when
+// none are given, before setting them
+#[ic_cdk::query]
+fn preview_price_rounding(
+    rounding: PriceRounding,
+    prices: Vec<u64>,
+) -> Result<Vec<RoundingPreview>, Error> {
+    ensure_admin()?;
+    validate_price_rounding(&rounding)?;
+    if prices.len() > MAX_ROUNDING_PREVIEW_PRICES {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "At most {} prices can be previewed at once.",
+                MAX_ROUNDING_PREVIEW_PRICES
+            ),
+        });
+    }
+    let samples: Vec<(Option<u64>, u64)> = if prices.is_empty() {
+        STORAGE.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .map(|(id, product)| (Some(id), product.price()))
+                .collect()
+        })
+    } else {
+        prices.into_iter().map(|price| (None, price)).collect()
+    };
+    Ok(samples
+        .into_iter()
+        .map(|(product_id, price)| RoundingPreview {
+            product_id,
+            price,
+            rounded: round_price_with(&rounding, price),
+        })
+        .collect())
+}
+
+// Helper function to render an order as one line of JSON for accounting systems; keys are sorted
+// so the same order always renders the same bytes
+fn order_json_line(order: &Order) -> String {
+    let lines: Vec<serde_json::Value> = order
+        .lines
+        .iter()
+        .map(|line| {
+            serde_json::json!({
+                "product_id": line.product_id,
+                "quantity": line.quantity,
+                "unit_price": line.unit_price,
+                "unfulfillable": line.unfulfillable.unwrap_or(0),
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "id": order.id,
+        "order_number": order.order_number,
+        "customer": order.customer.to_text(),
+        "created_at": order.created_at,
+        "status": format!("{:?}", order.status),
+        "lines": lines,
+        "subtotal": order.subtotal,
+        "discount": order.discount,
+        "total": order.total,
+        "refund_due": order.refund_due.unwrap_or(0),
+        "promotion_id": order.promotion_id,
+        "payment": order.payment.as_ref().map(|payment| serde_json::json!({
+            "symbol": payment.symbol,
+            "amount": payment.amount.0.to_string(),
+            "escrow": payment.escrow.as_ref().map(|escrow| format!("{:?}", escrow.status)),
+        })),
+    })
+    .to_string()
+}
+
+// Helper function to render the orders placed in a period as chunks of JSON lines, oldest first
+fn order_export_chunks(period: &ReportPeriod) -> Vec<String> {
+    let mut orders: Vec<Order> = ORDERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, order)| order)
+            .filter(|order| (period.from..period.to).contains(&order.created_at))
+            .collect()
+    });
+    orders.sort_by_key(|order| (order.created_at, order.id));
+    let mut chunks: Vec<String> = orders
+        .chunks(ORDER_EXPORT_CHUNK_ORDERS)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .map(|order| order_json_line(order) + "\n")
+                .collect()
+        })
+        .collect();
+    if chunks.is_empty() {
+        chunks.push(String::new());
+    }
+    chunks
+}
+
+// Helper function to hash an order export manifest: SHA-256 over the export id, the period,
+// the order count, the chunk count and each chunk hash; integers are big-endian, counts 4 bytes
+fn order_export_hash(
+    id: u64,
+    period: &ReportPeriod,
+    order_count: u32,
+    chunk_hashes: &[Vec<u8>],
+) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(id.to_be_bytes());
+    hasher.update(period.from.to_be_bytes());
+    hasher.update(period.to.to_be_bytes());
+    hasher.update(order_count.to_be_bytes());
+    hasher.update((chunk_hashes.len() as u32).to_be_bytes());
+    for chunk_hash in chunk_hashes {
+        hasher.update(chunk_hash);
+    }
+    hasher.finalize().to_vec()
+}
+
+// Function to export the orders placed in a closed period as newline-delimited JSON, one chunk
+// per call. Chunk 0 starts a new export whose manifest hash is recorded in the certified call
+// journal; later chunks come from the caller's latest export of the period and are refused if
+// the orders changed since it started
+#[ic_cdk::update]
+fn export_orders_jsonl(period: ReportPeriod, chunk: u32) -> Result<OrderExportChunk, Error> {
+    ensure_viewer()?;
+    journal_call("export_orders_jsonl");
+    if period.from >= period.to || period.to > time() {
+        return Err(Error::InvalidOperation {
+            msg: "An export covers a non-empty period that has already ended.".to_string(),
+        });
+    }
+    let chunks = order_export_chunks(&period);
+    if chunks.len() > MAX_ORDER_EXPORT_CHUNKS {
+        return Err(Error::CapacityExceeded {
+            msg: format!(
+                "The period has more than {} orders. Please export a shorter period.",
+                MAX_ORDER_EXPORT_CHUNKS * ORDER_EXPORT_CHUNK_ORDERS
+            ),
+        });
+    }
+    let chunk_hashes: Vec<Vec<u8>> = chunks
+        .iter()
+        .map(|jsonl| Sha256::digest(jsonl.as_bytes()).to_vec())
+        .collect();
+
+    let manifest = if chunk == 0 {
+        let id = generate_unique_id(|id| {
+            ORDER_EXPORTS.with(|service| service.borrow().contains_key(&id))
+        })?;
+        let order_count = chunks
+            .iter()
+            .map(|jsonl| jsonl.lines().count())
+            .sum::<usize>() as u32;
+        let hash = order_export_hash(id, &period, order_count, &chunk_hashes);
+        append_call_record(
+            format!("order_export:{}", id),
+            caller(),
+            Some(hash.clone()),
+            call_device(&caller()),
+        );
+        let manifest = OrderExportManifest {
+            id,
+            period,
+            order_count,
+            chunk_hashes,
+            hash,
+            journal_seq: CALL_JOURNAL
+                .with(|service| service.borrow().last_key_value().map(|(seq, _)| seq))
+                .unwrap_or(0),
+            created_by: caller(),
+            created_at: time(),
+        };
+        ORDER_EXPORTS.with(|service| service.borrow_mut().insert(id, manifest.clone()));
+        manifest
+    } else {
+        let manifest = ORDER_EXPORTS
+            .with(|service| {
+                service
+                    .borrow()
+                    .iter()
+                    .map(|(_, manifest)| manifest)
+                    .filter(|manifest| {
+                        manifest.created_by == caller()
+                            && manifest.period.from == period.from
+                            && manifest.period.to == period.to
+                    })
+                    .max_by_key(|manifest| manifest.created_at)
+            })
+            .ok_or(Error::NotFound {
+                msg: "No export of this period was started. Please start from chunk 0.".to_string(),
+            })?;
+        if manifest.chunk_hashes != chunk_hashes {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Orders of the period changed since export id={} started. Please start again from chunk 0.",
+                    manifest.id
+                ),
+            });
+        }
+        manifest
+    };
+    let jsonl = chunks
+        .into_iter()
+        .nth(chunk as usize)
+        .ok_or(Error::NotFound {
+            msg: format!(
+                "Export id={} has {} chunks",
+                manifest.id,
+                manifest.chunk_hashes.len()
+            ),
+        })?;
+    Ok(OrderExportChunk {
+        export_id: manifest.id,
+        chunk,
+        chunk_count: manifest.chunk_hashes.len() as u32,
+        chunk_hash: manifest.chunk_hashes[chunk as usize].clone(),
+        manifest_hash: manifest.hash,
+        jsonl,
+    })
+}
+
+// Query function to get the manifest of an order export, to check imported chunks against it
+#[ic_cdk::query]
+fn get_order_export(id: u64) -> Result<OrderExportManifest, Error> {
+    ensure_viewer()?;
+    ORDER_EXPORTS
+        .with(|service| service.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("An order export with id={} was not found", id),
+        })
+}
+
+// Helper function to get the customer account a principal is linked into
+fn linked_account(principal: &Principal) -> Option<CustomerAccount> {
+    ACCOUNT_MEMBERS
+        .with(|service| service.borrow().get(&PrincipalKey(*principal)))
+        .and_then(|id| CUSTOMER_ACCOUNTS.with(|service| service.borrow().get(&id)))
+}
+
+// Helper function to list the principals sharing an account with the given one, itself included
+fn account_principals(principal: &Principal) -> Vec<Principal> {
+    linked_account(principal).map_or_else(|| vec![*principal], |account| account.principals)
+}
+
+// Helper function to get the principal the caller's cart, checkouts and new orders are kept
+// under: the primary principal of their account, or the caller when not linked
+fn account_holder() -> Principal {
+    let caller = caller();
+    linked_account(&caller).map_or(caller, |account| account.primary)
+}
+
+// Helper function to check that a record kept under the given customer belongs to the caller's
+// account
+fn is_own_record(customer: &Principal) -> bool {
+    account_principals(&caller()).contains(customer)
+}
+
+// Helper function to check that a principal can be linked into a customer account
+fn check_linkable(principal: &Principal) -> Result<(), Error> {
+    if *principal == Principal::anonymous() || is_kiosk(principal) {
+        return Err(Error::Unauthorized {
+            msg: "Only signed-in customers can link principals.".to_string(),
+        });
+    }
+    if SPENDING_LIMITS.with(|service| service.borrow().contains_key(&PrincipalKey(*principal))) {
+        return Err(Error::InvalidOperation {
+            msg: "Sub-principals buy on behalf of their owner and cannot be linked.".to_string(),
+        });
+    }
+    Ok(())
+}
+
+// Helper function to take a principal out of its account; the account is closed once a single
+// principal is left
+fn detach_principal(
+    mut account: CustomerAccount,
+    principal: &Principal,
+) -> Option<CustomerAccount> {
+    account.principals.retain(|member| member != principal);
+    ACCOUNT_MEMBERS.with(|service| service.borrow_mut().remove(&PrincipalKey(*principal)));
+    if account.principals.len() <= 1 {
+        ACCOUNT_MEMBERS.with(|service| {
+            let mut members = service.borrow_mut();
+            for member in &account.principals {
+                members.remove(&PrincipalKey(*member));
+            }
+        });
+        CUSTOMER_ACCOUNTS.with(|service| service.borrow_mut().remove(&account.id));
+        return None;
+    }
+    account.updated_at = Some(time());
+    CUSTOMER_ACCOUNTS.with(|service| service.borrow_mut().insert(account.id, account.clone()));
+    Some(account)
+}
+
+// Function to start linking another principal into the caller's account: the returned code is
+// entered on the other device within ten minutes
+#[ic_cdk::update]
+async fn start_principal_link() -> Result<LinkChallenge, Error> {
+    ensure_not_in_maintenance()?;
+    journal_call("start_principal_link");
+    let initiator = caller();
+    check_linkable(&initiator)?;
+    if linked_account(&initiator)
+        .is_some_and(|account| account.principals.len() >= MAX_LINKED_PRINCIPALS)
+    {
+        return Err(Error::CapacityExceeded {
+            msg: format!(
+                "An account can link at most {} principals.",
+                MAX_LINKED_PRINCIPALS
+            ),
+        });
+    }
+    let (seed,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, msg)| Error::ExternalCallFailed {
+            msg: format!("Could not get randomness: {:?} {}", code, msg),
+        })?;
+    let code: String = seed
+        .iter()
+        .take(5)
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    let expires_at = time() + LINK_CHALLENGE_TTL_NS;
+    let challenge = LinkChallengeRecord {
+        code_hash: Sha256::digest(code.as_bytes()).to_vec(),
+        expires_at,
+        failed_attempts: 0,
+    };
+    LINK_CHALLENGES.with(|service| {
+        service
+            .borrow_mut()
+            .insert(PrincipalKey(initiator), challenge)
+    });
+    Ok(LinkChallenge { code, expires_at })
+}
+
+// Function to link the caller into the account of the principal that started the link, with the
+// code it was shown; a cart the caller kept carries over when the account has none
+#[ic_cdk::update]
+fn complete_principal_link(initiator: Principal, code: String) -> Result<CustomerAccount, Error> {
+    ensure_not_in_maintenance()?;
+    journal_call("complete_principal_link");
+    let principal = caller();
+    check_linkable(&principal)?;
+    if principal == initiator {
+        return Err(Error::InvalidOperation {
+            msg: "Complete the link from the principal being linked.".to_string(),
+        });
+    }
+    if linked_account(&principal).is_some() {
+        return Err(Error::InvalidOperation {
+            msg: "This principal is already linked into an account; unlink it first.".to_string(),
+        });
+    }
+    let key = PrincipalKey(initiator);
+    let now = time();
+    let challenge = LINK_CHALLENGES
+        .with(|service| service.borrow().get(&key))
+        .filter(|challenge| challenge.expires_at > now);
+    let Some(mut challenge) = challenge else {
+        LINK_CHALLENGES.with(|service| service.borrow_mut().remove(&key));
+        return Err(Error::NotFound {
+            msg: format!("No pending link was started by principal {}", initiator),
+        });
+    };
+    if Sha256::digest(code.trim().to_lowercase().as_bytes()).as_slice() != challenge.code_hash {
+        challenge.failed_attempts += 1;
+        LINK_CHALLENGES.with(|service| {
+            let mut challenges = service.borrow_mut();
+            if challenge.failed_attempts >= MAX_LINK_ATTEMPTS {
+                challenges.remove(&key);
+            } else {
+                challenges.insert(key, challenge);
+            }
+        });
+        return Err(Error::Unauthorized {
+            msg: "The link code is not valid.".to_string(),
+        });
+    }
+    LINK_CHALLENGES.with(|service| service.borrow_mut().remove(&key));
+    check_linkable(&initiator)?;
+
+    let mut account = match linked_account(&initiator) {
+        Some(mut account) => {
+            account.updated_at = Some(now);
+            account
+        }
+        None => CustomerAccount {
+            id: generate_unique_id(|id| {
+                CUSTOMER_ACCOUNTS.with(|service| service.borrow().contains_key(&id))
+            })?,
+            primary: initiator,
+            principals: vec![initiator],
+            created_at: now,
+            updated_at: None,
+        },
+    };
+    if account.principals.len() >= MAX_LINKED_PRINCIPALS {
+        return Err(Error::CapacityExceeded {
+            msg: format!(
+                "An account can link at most {} principals.",
+                MAX_LINKED_PRINCIPALS
+            ),
+        });
+    }
+    account.principals.push(principal);
+    CUSTOMER_ACCOUNTS.with(|service| service.borrow_mut().insert(account.id, account.clone()));
+    ACCOUNT_MEMBERS.with(|service| {
+        let mut members = service.borrow_mut();
+        members.insert(PrincipalKey(initiator), account.id);
+        members.insert(PrincipalKey(principal), account.id);
+    });
+    CARTS.with(|service| {
+        let mut carts = service.borrow_mut();
+        if !carts.contains_key(&PrincipalKey(account.primary)) {
+            if let Some(mut cart) = carts.remove(&PrincipalKey(principal)) {
+                cart.owner = account.primary;
+                carts.insert(PrincipalKey(account.primary), cart);
+            }
+        }
+    });
+    Ok(account)
+}
+
+// Function to unlink a principal from the caller's account: any principal can unlink itself and
+// the primary one can unlink the others; the primary principal goes last
+#[ic_cdk::update]
+fn unlink_principal(principal: Principal) -> Result<Option<CustomerAccount>, Error> {
+    ensure_not_in_maintenance()?;
+    journal_call("unlink_principal");
+    let caller = caller();
+    let account = linked_account(&caller)
+        .filter(|account| account.principals.contains(&principal))
+        .ok_or(Error::NotFound {
+            msg: format!("Principal {} is not linked into your account", principal),
+        })?;
+    if principal != caller && account.primary != caller {
+        return Err(Error::Unauthorized {
+            msg: "Only the primary principal can unlink other principals.".to_string(),
+        });
+    }
+    if principal == account.primary {
+        return Err(Error::InvalidOperation {
+            msg: "Make another principal primary, or unlink the others, before unlinking the \
+                  primary principal."
+                .to_string(),
+        });
+    }
+    Ok(detach_principal(account, &principal))
+}
+
+// Function to hand the account over to another linked principal, which then keeps the cart and
+// new orders; refused while a checkout is open
+#[ic_cdk::update]
+fn set_primary_principal(principal: Principal) -> Result<CustomerAccount, Error> {
+    ensure_not_in_maintenance()?;
+    journal_call("set_primary_principal");
+    let caller = caller();
+    let mut account = linked_account(&caller).ok_or(Error::NotFound {
+        msg: "The caller is not linked into an account".to_string(),
+    })?;
+    if account.primary != caller {
+        return Err(Error::Unauthorized {
+            msg: "Only the primary principal can hand over the account.".to_string(),
+        });
+    }
+    if !account.principals.contains(&principal) {
+        return Err(Error::NotFound {
+            msg: format!("Principal {} is not linked into your account", principal),
+        });
+    }
+    let now = time();
+    let checkout_open = CHECKOUT_SESSIONS.with(|service| {
+        service.borrow().iter().any(|(_, session)| {
+            session.customer == caller
+                && with_checkout_expiry(session, now).status == CheckoutStatus::Open
+        })
+    });
+    if checkout_open {
+        return Err(Error::InvalidOperation {
+            msg: "Finish or cancel the open checkout before handing over the account.".to_string(),
+        });
+    }
+    CARTS.with(|service| {
+        let mut carts = service.borrow_mut();
+        if let Some(mut cart) = carts.remove(&PrincipalKey(caller)) {
+            cart.owner = principal;
+            carts.insert(PrincipalKey(principal), cart);
+        }
+    });
+    account.primary = principal;
+    account.updated_at = Some(now);
+    CUSTOMER_ACCOUNTS.with(|service| service.borrow_mut().insert(account.id, account.clone()));
+    Ok(account)
+}
+
+// Query function to get the account the caller is linked into
+#[ic_cdk::query]
+fn get_my_account() -> Option<CustomerAccount> {
+    linked_account(&caller())
+}
+
+// Helper function to work out the margin of a product at its current price
+fn margin_of(product: &Product) -> ProductMargin {
+    let margin = product
+        .unit_cost
+        .map(|cost| product.price() as i64 - cost as i64);
+    ProductMargin {
+        product_id: product.id,
+        price: product.price(),
+        unit_cost: product.unit_cost,
+        margin,
+        margin_bps: margin
+            .filter(|_| product.price() > 0)
+            .map(|margin| margin * 10_000 / product.price() as i64),
+    }
+}
+
+// Function to set what a unit of a product costs the bakery; only admins see it
+#[ic_cdk::update]
+fn set_product_cost(product_id: u64, unit_cost: Option<u64>) -> Result<ProductMargin, Error> {
+    ensure_admin()?;
+    journal_call("set_product_cost");
+    let mut product = _get_product(&product_id).ok_or(Error::NotFound {
+        msg: format!("A product with id={} was not found", product_id),
+    })?;
+    product.unit_cost = unit_cost;
+    product.updated_at = Some(time());
+    STORAGE.with(|service| service.borrow_mut().insert(product_id, product.clone()));
+    record_product_version(&product);
+    Ok(margin_of(&product))
+}
+
+// Query function to list the cost and margin of every product, lowest margin first, with
+// products without a cost at the end, one page at a time
+#[ic_cdk::query]
+fn list_product_margins(page: Option<PageRequest>) -> Result<MarginPage, Error> {
+    ensure_admin()?;
+    let page = page.unwrap_or_default();
+    let mut margins: Vec<ProductMargin> = STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, product)| margin_of(&product))
+            .collect()
+    });
+    margins.sort_by_key(|margin| {
+        (
+            margin.margin_bps.is_none(),
+            margin.margin_bps,
+            margin.product_id,
+        )
+    });
+    let first = page.cursor.map_or(0, |cursor| cursor.saturating_add(1));
+    let (margins, next_cursor) = take_page(
+        (0u64..)
+            .zip(margins)
+            .skip(first.min(usize::MAX as u64) as usize),
+        &page,
+    );
+    Ok(MarginPage {
+        margins,
+        next_cursor,
+    })
+}
+
+// Helper function to get a stocktake by its ID
+fn _get_stocktake(id: &u64) -> Result<Stocktake, Error> {
+    STOCKTAKES
+        .with(|service| service.borrow().get(id))
+        .ok_or(Error::NotFound {
+            msg: format!("A stocktake with id={} was not found", id),
+        })
+}
+
+// Helper function to check that the caller counts for a stocktake still being counted
+fn ensure_counter(stocktake: &Stocktake) -> Result<(), Error> {
+    if !stocktake.counters.contains(&caller()) && ensure_admin().is_err() {
+        return Err(Error::Unauthorized {
+            msg: format!(
+                "The caller does not count for stocktake id={}",
+                stocktake.id
+            ),
+        });
+    }
+    if stocktake.status != StocktakeStatus::Counting {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Stocktake id={} is {:?}; counts can no longer change",
+                stocktake.id, stocktake.status
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Helper function to build the count sheet of a stocktake, with the recorded quantities only
+// when the stocktake is not blind or the caller is an admin
+fn stocktake_sheet(stocktake: &Stocktake) -> StocktakeSheet {
+    let show_expected = !stocktake.blind || ensure_admin().is_ok();
+    StocktakeSheet {
+        id: stocktake.id,
+        blind: stocktake.blind,
+        status: stocktake.status,
+        lines: stocktake
+            .product_ids
+            .iter()
+            .map(|product_id| {
+                let product = _get_product(product_id);
+                StocktakeSheetLine {
+                    product_id: *product_id,
+                    name: product
+                        .as_ref()
+                        .map(|product| product.name.clone())
+                        .unwrap_or_default(),
+                    counted: stocktake
+                        .counts
+                        .iter()
+                        .find(|count| count.product_id == *product_id)
+                        .map(|count| count.counted),
+                    expected: product
+                        .filter(|_| show_expected)
+                        .map(|product| product.quantity),
+                }
+            })
+            .collect(),
+    }
+}
+
+// Function to start a stocktake of the given products, counted by the given staff; one
+// stocktake runs at a time
+#[ic_cdk::update]
+fn start_stocktake(payload: StocktakePayload) -> Result<Stocktake, Error> {
+    ensure_admin()?;
+    journal_call("start_stocktake");
+    let running = STOCKTAKES.with(|service| {
+        service.borrow().iter().any(|(_, stocktake)| {
+            matches!(
+                stocktake.status,
+                StocktakeStatus::Counting | StocktakeStatus::Submitted
+            )
+        })
+    });
+    if running {
+        return Err(Error::InvalidOperation {
+            msg: "Another stocktake is still running; approve or reject it first.".to_string(),
+        });
+    }
+    if payload.counters.is_empty()
+        || payload.counters.contains(&Principal::anonymous())
+        || payload.counters.len() > MAX_STOCKTAKE_COUNTERS
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "A stocktake needs between 1 and {} signed-in counters.",
+                MAX_STOCKTAKE_COUNTERS
+            ),
+        });
+    }
+    let mut product_ids = match payload.product_ids {
+        Some(product_ids) => {
+            for product_id in &product_ids {
+                _get_product(product_id).ok_or(Error::NotFound {
+                    msg: format!("A product with id={} was not found", product_id),
+                })?;
+            }
+            product_ids
+        }
+        None => STORAGE.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .filter(|(_, product)| product.status() != ProductStatus::Archived)
+                .map(|(id, _)| id)
+                .collect()
+        }),
+    };
+    product_ids.sort_unstable();
+    product_ids.dedup();
+    if product_ids.is_empty() || product_ids.len() > MAX_STOCKTAKE_PRODUCTS {
+        return Err(Error::CapacityExceeded {
+            msg: format!(
+                "A stocktake covers between 1 and {} products.",
+                MAX_STOCKTAKE_PRODUCTS
+            ),
+        });
+    }
+
+    let id =
+        generate_unique_id(|id| STOCKTAKES.with(|service| service.borrow().contains_key(&id)))?;
+    let stocktake = Stocktake {
+        id,
+        blind: payload.blind,
+        product_ids,
+        counters: payload.counters,
+        status: StocktakeStatus::Counting,
+        counts: Vec::new(),
+        lines: Vec::new(),
+        started_by: caller(),
+        started_at: time(),
+        submitted_by: None,
+        submitted_at: None,
+        decided_by: None,
+        decided_at: None,
+        reason: None,
+    };
+    STOCKTAKES.with(|service| service.borrow_mut().insert(id, stocktake.clone()));
+    Ok(stocktake)
+}
+
+// Query function to get the count sheet of a stocktake, for its counters and admins
+#[ic_cdk::query]
+fn get_stocktake_sheet(id: u64) -> Result<StocktakeSheet, Error> {
+    let stocktake = _get_stocktake(&id)?;
+    if !stocktake.counters.contains(&caller()) && ensure_viewer().is_err() {
+        return Err(Error::Unauthorized {
+            msg: format!("The caller does not count for stocktake id={}", id),
+        });
+    }
+    Ok(stocktake_sheet(&stocktake))
+}
+
+// Function to record counts for a stocktake, replacing earlier counts of the same products; it
+// stays open in maintenance mode, when the shop is closed for counting
+#[ic_cdk::update]
+fn record_stock_counts(id: u64, counts: Vec<StockCountPayload>) -> Result<StocktakeSheet, Error> {
+    let mut stocktake = _get_stocktake(&id)?;
+    ensure_counter(&stocktake)?;
+    journal_call("record_stock_counts");
+    let now = time();
+    for count in counts {
+        if !stocktake.product_ids.contains(&count.product_id) {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Product id={} is not part of stocktake id={}",
+                    count.product_id, id
+                ),
+            });
+        }
+        stocktake
+            .counts
+            .retain(|counted| counted.product_id != count.product_id);
+        stocktake.counts.push(StockCount {
+            product_id: count.product_id,
+            counted: count.counted,
+            by: caller(),
+            at: now,
+        });
+    }
+    STOCKTAKES.with(|service| service.borrow_mut().insert(id, stocktake.clone()));
+    Ok(stocktake_sheet(&stocktake))
+}
+
+// Function to submit the counts of a stocktake: the discrepancies with the recorded stock are
+// computed and wait for a manager; products left uncounted are not adjusted
+#[ic_cdk::update]
+fn submit_stocktake(id: u64) -> Result<Stocktake, Error> {
+    let mut stocktake = _get_stocktake(&id)?;
+    ensure_counter(&stocktake)?;
+    journal_call("submit_stocktake");
+    if stocktake.counts.is_empty() {
+        return Err(Error::InvalidOperation {
+            msg: format!("Stocktake id={} has no counts yet", id),
+        });
+    }
+    stocktake.lines = stocktake
+        .counts
+        .iter()
+        .map(|count| {
+            let expected = _get_product(&count.product_id).map_or(0, |product| product.quantity);
+            StocktakeLine {
+                product_id: count.product_id,
+                expected,
+                counted: count.counted,
+                discrepancy: count.counted as i64 - expected as i64,
+            }
+        })
+        .collect();
+    stocktake.status = StocktakeStatus::Submitted;
+    stocktake.submitted_by = Some(caller());
+    stocktake.submitted_at = Some(time());
+    STOCKTAKES.with(|service| service.borrow_mut().insert(id, stocktake.clone()));
+    Ok(stocktake)
+}
+
+// Helper function to take a submitted stocktake to a decision; counters cannot decide on their
+// own counts
+fn decide_stocktake(id: u64) -> Result<Stocktake, Error> {
+    let mut stocktake = _get_stocktake(&id)?;
+    if stocktake.status != StocktakeStatus::Submitted {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Stocktake id={} is {:?}; only submitted stocktakes can be decided",
+                id, stocktake.status
+            ),
+        });
+    }
+    let counted_by_caller = stocktake.counts.iter().any(|count| count.by == caller());
+    if counted_by_caller || stocktake.submitted_by == Some(caller()) {
+        return Err(Error::Unauthorized {
+            msg: "A stocktake is approved by a manager who did not count it.".to_string(),
+        });
+    }
+    stocktake.decided_by = Some(caller());
+    stocktake.decided_at = Some(time());
+    Ok(stocktake)
+}
+
+// Function to approve a submitted stocktake, adjusting each product by its discrepancy; sales
+// made since the submission are kept
+#[ic_cdk::update]
+fn approve_stocktake(id: u64) -> Result<Stocktake, Error> {
+    ensure_admin()?;
+    journal_call("approve_stocktake");
+    let mut stocktake = decide_stocktake(id)?;
+    for line in stocktake.lines.iter().filter(|line| line.discrepancy != 0) {
+        let Some(mut product) = _get_product(&line.product_id) else {
+            continue;
+        };
+        let previous_quantity = product.quantity;
+        product.quantity =
+            (product.quantity as i64 + line.discrepancy).clamp(0, u32::MAX as i64) as u32;
+        if product.display_units.is_some() {
+            product.display_units = Some(display_units_of(&product)).filter(|display| *display > 0);
+        }
+        product.updated_at = Some(time());
+        do_insert(&product);
+        record_stock_movement(
+            &caller(),
+            product.id,
+            StockMovementKind::Adjustment,
+            product.quantity as i64 - previous_quantity as i64,
+            0,
+            None,
+        );
+        allocate_waitlist(product.id);
+        notify_if_back_in_stock(&product, previous_quantity);
+    }
+    stocktake.status = StocktakeStatus::Approved;
+    STOCKTAKES.with(|service| service.borrow_mut().insert(id, stocktake.clone()));
+    Ok(stocktake)
+}
+
+// Function to reject a submitted stocktake, leaving the stock as recorded; a recount starts a
+// new stocktake
+#[ic_cdk::update]
+fn reject_stocktake(id: u64, reason: String) -> Result<Stocktake, Error> {
+    ensure_admin()?;
+    journal_call("reject_stocktake");
+    validate_notes(&Some(reason.clone()))?;
+    let mut stocktake = decide_stocktake(id)?;
+    stocktake.status = StocktakeStatus::Rejected;
+    stocktake.reason = Some(reason);
+    STOCKTAKES.with(|service| service.borrow_mut().insert(id, stocktake.clone()));
+    Ok(stocktake)
+}
+
+// Query function to list the stocktakes, newest first
+#[ic_cdk::query]
+fn list_stocktakes() -> Result<Vec<Stocktake>, Error> {
+    ensure_viewer()?;
+    let mut stocktakes: Vec<Stocktake> = STOCKTAKES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, stocktake)| stocktake)
+            .collect()
+    });
+    stocktakes.sort_by_key(|stocktake| std::cmp::Reverse(stocktake.started_at));
+    Ok(stocktakes)
+}
+
+// Helper function to mark open quotes past their expiry as expired
+fn expire_stale_quotes(now: u64) -> u32 {
+    QUOTES.with(|service| {
+        let stale: Vec<Quote> = service
+            .borrow()
+            .iter()
+            .map(|(_, quote)| quote)
+            .filter(|quote| quote.status == QuoteStatus::Open && quote.expires_at <= now)
+            .collect();
+        let mut quotes = service.borrow_mut();
+        for quote in &stale {
+            quotes.insert(quote.id, with_quote_expiry(quote.clone(), now));
+        }
+        stale.len() as u32
+    })
+}
+
+// Helper function to list the products with stock that pass their best-before within a day
+fn expiring_items(now: u64) -> Vec<ExpiringItem> {
+    let mut expiring: Vec<ExpiringItem> = STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, product)| product)
+            .filter(|product| product.status() == ProductStatus::Published && product.quantity > 0)
+            .filter_map(|product| {
+                let best_before = product.best_before.filter(|best_before| {
+                    *best_before > now && *best_before - now <= NANOS_PER_DAY
+                })?;
+                Some(ExpiringItem {
+                    product_id: product.id,
+                    name: product.name,
+                    units: product.quantity,
+                    best_before,
+                })
+            })
+            .collect()
+    });
+    expiring.sort_by_key(|item| (item.best_before, item.product_id));
+    expiring
+}
+
+// Function to close a day that is over: the pending sales are rolled up and the day's totals
+// frozen into the report, the daily snapshot is taken, idle carts and stale quotes are expired
+// and products about to pass their best-before are flagged for discounting
+fn close_day(day: u64) -> DailyClose {
+    for _ in 0..MAX_CLOSE_ROLLUP_BATCHES {
+        if roll_up_sales_events() == 0 {
+            break;
+        }
+    }
+    let now = time();
+    let sales: Vec<DailySalesReport> = DAILY_SALES.with(|service| {
+        service
+            .borrow()
+            .range((day, 0)..=(day, u64::MAX))
+            .map(|((day, product_id), sales)| DailySalesReport {
+                day,
+                product_id,
+                units: sales.units,
+                revenue: sales.revenue,
+            })
+            .collect()
+    });
+    let day_range = day * NANOS_PER_DAY..(day + 1) * NANOS_PER_DAY;
+    let orders = ORDERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter(|(_, order)| day_range.contains(&order.created_at))
+            .count() as u32
+    });
+    take_snapshots();
+    let close = DailyClose {
+        day,
+        closed_at: now,
+        orders,
+        units: sales.iter().map(|line| line.units).sum(),
+        revenue: sales.iter().map(|line| line.revenue).sum(),
+        sales_truncated: sales.len() > MAX_CLOSE_SALES_LINES,
+        sales: sales.into_iter().take(MAX_CLOSE_SALES_LINES).collect(),
+        snapshot_at: SNAPSHOT_POINTS
+            .with(|service| service.borrow().last_key_value().map(|(at, _)| at)),
+        carts_purged: purge_idle_carts(),
+        quotes_expired: expire_stale_quotes(now),
+        expiring: expiring_items(now),
+    };
+    DAILY_CLOSES.with(|service| service.borrow_mut().insert(day, close.clone()));
+    close
+}
+
+// Function to close the previous day once it is over, run by the expiry sweep
+fn close_previous_day() {
+    let Some(day) = (time() / NANOS_PER_DAY).checked_sub(1) else {
+        return;
+    };
+    if !DAILY_CLOSES.with(|service| service.borrow().contains_key(&day)) {
+        close_day(day);
+    }
+}
+
+// Function to close a past day that was missed, e.g. while the canister was stopped; days are
+// closed once
+#[ic_cdk::update]
+fn close_missed_day(day: u64) -> Result<DailyClose, Error> {
+    ensure_admin()?;
+    journal_call("close_missed_day");
+    if day >= time() / NANOS_PER_DAY {
+        return Err(Error::InvalidOperation {
+            msg: format!("Day {} is not over yet", day),
+        });
+    }
+    if DAILY_CLOSES.with(|service| service.borrow().contains_key(&day)) {
+        return Err(Error::InvalidOperation {
+            msg: format!("Day {} is already closed", day),
+        });
+    }
+    Ok(close_day(day))
+}
+
+// Query function to get the close report of a day, counted in days since the Unix epoch (UTC)
+#[ic_cdk::query]
+fn get_daily_close(day: u64) -> Result<DailyClose, Error> {
+    ensure_viewer()?;
+    DAILY_CLOSES
+        .with(|service| service.borrow().get(&day))
+        .ok_or(Error::NotFound {
+            msg: format!("Day {} has not been closed", day),
+        })
+}
+
+// Helper function to check the cancellation policies an admin sets
+fn validate_cancellation_policies(policies: &[CancellationPolicy]) -> Result<(), Error> {
+    if policies.len() > MAX_CANCELLATION_POLICIES {
+        return Err(Error::CapacityExceeded {
+            msg: format!(
+                "At most {} cancellation policies can be set.",
+                MAX_CANCELLATION_POLICIES
+            ),
+        });
+    }
+    for (index, policy) in policies.iter().enumerate() {
+        if policy.window_hours == 0 || policy.window_hours > MAX_CANCELLATION_WINDOW_HOURS {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Cancellation windows must be between 1 and {} hours.",
+                    MAX_CANCELLATION_WINDOW_HOURS
+                ),
+            });
+        }
+        if policy.fee_bps.is_some_and(|fee_bps| fee_bps > 10_000) {
+            return Err(Error::InvalidOperation {
+                msg: "Cancellation fees can be at most 10000 basis points.".to_string(),
+            });
+        }
+        if policies[..index].iter().any(|other| {
+            other.category == policy.category && other.window_hours == policy.window_hours
+        }) {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "{:?} has two policies with a {} hour window.",
+                    policy.category, policy.window_hours
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+// Function to set the cancellation policies of the categories, replacing the ones in effect
+#[ic_cdk::update]
+fn set_cancellation_policies(
+    policies: Vec<CancellationPolicy>,
+) -> Result<Vec<CancellationPolicy>, Error> {
+    ensure_admin()?;
+    journal_call("set_cancellation_policies");
+    validate_cancellation_policies(&policies)?;
+    update_config(|config| {
+        config.cancellation_policies = (!policies.is_empty()).then(|| policies.clone())
+    })?;
+    Ok(policies)
+}
+
+// Query function to list the cancellation policies in effect
+#[ic_cdk::query]
+fn get_cancellation_policies() -> Vec<CancellationPolicy> {
+    CONFIG.with(|config| {
+        config
+            .borrow()
+            .get()
+            .cancellation_policies
+            .clone()
+            .unwrap_or_default()
+    })
+}
+
+// Helper function to get when an order is due: its slot, or the time it was promised for
+fn order_due_at(order: &Order) -> u64 {
+    order
+        .schedule
+        .as_ref()
+        .map(|schedule| schedule.slot_start)
+        .or(order.promised_ready_at)
+        .unwrap_or(order.created_at)
+}
+
+// Function to work out the terms of cancelling an order now: for each category of its open
+// lines the policy with the shortest window the order is due within applies; fails when one of
+// them does not allow cancelling
+fn cancellation_terms(order: &Order, now: u64) -> Result<OrderCancellation, Error> {
+    if !matches!(order.status, OrderStatus::Placed | OrderStatus::Preparing) {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Order with id={} is {:?} and can no longer be cancelled",
+                order.id, order.status
+            ),
+        });
+    }
+    let policies = get_cancellation_policies();
+    let due_at = order_due_at(order);
+    let mut by_category: Vec<(Category, u64)> = Vec::new();
+    for line in open_lines(order) {
+        // Removed products have no category left to apply a policy of
+        let Some(product) = _get_product(&line.product_id) else {
+            continue;
+        };
+        let value = line.unit_price * line.quantity as u64;
+        match by_category
+            .iter_mut()
+            .find(|(category, _)| *category == product.category)
+        {
+            Some((_, lines_value)) => *lines_value += value,
+            None => by_category.push((product.category, value)),
+        }
+    }
+    let mut applied = Vec::new();
+    for (category, lines_value) in by_category {
+        let Some(policy) = policies
+            .iter()
+            .filter(|policy| {
+                policy.category == category
+                    && now.saturating_add(policy.window_hours as u64 * 3_600 * 1_000_000_000)
+                        >= due_at
+            })
+            .min_by_key(|policy| policy.window_hours)
+        else {
+            continue;
+        };
+        let Some(fee_bps) = policy.fee_bps else {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "{:?} orders cannot be cancelled within {} hours of being due",
+                    category, policy.window_hours
+                ),
+            });
+        };
+        applied.push(AppliedCancellationPolicy {
+            category,
+            window_hours: policy.window_hours,
+            fee_bps,
+            lines_value,
+            fee: lines_value * fee_bps as u64 / 10_000,
+        });
+    }
+    // Orders paid in full with points and store credit are held from the start
+    let held = match order.payment.as_ref() {
+        Some(payment) => payment
+            .escrow
+            .as_ref()
+            .is_some_and(|escrow| escrow.status == EscrowStatus::Held),
+        None => order.tenders.is_some() && remaining_due(&order.tenders, order.total) == 0,
+    };
+    let due = amount_due(order);
+    let fee = if held {
+        applied
+            .iter()
+            .map(|policy| policy.fee)
+            .sum::<u64>()
+            .min(due)
+    } else {
+        0
+    };
+    Ok(OrderCancellation {
+        cancelled_at: now,
+        by: caller(),
+        fee,
+        refund: if held { due - fee } else { 0 },
+        policies: applied,
+    })
+}
+
+// Helper function to get an order the caller may cancel
+fn cancellable_order(id: u64) -> Result<Order, Error> {
+    _get_order(&id)
+        .filter(|order| is_own_record(&order.customer) || ensure_admin().is_ok())
+        .ok_or(Error::NotFound {
+            msg: format!("An order with id={} was not found", id),
+        })
+}
+
+// Query function to preview the fee and refund of cancelling an order now
+#[ic_cdk::query]
+fn preview_cancellation(id: u64) -> Result<OrderCancellation, Error> {
+    cancellation_terms(&cancellable_order(id)?, time())
+}
+
+// Function to cancel an order for its customer under the cancellation policies: the fee is kept
+// from the held payment, the rest is refunded and the policies applied are recorded on the order
+#[ic_cdk::update]
+fn cancel_order(id: u64) -> Result<Order, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    journal_call("cancel_order");
+    let order = cancellable_order(id)?;
+    let terms = cancellation_terms(&order, time())?;
+    let previous = order.clone();
+    let cancelled = Order {
+        cancellation: Some(terms),
+        ..order
+    };
+    store_order(cancelled);
+    do_update_order_status(id, OrderStatus::Cancelled, caller()).inspect_err(|_| {
+        store_order(previous);
+    })
+}
+
+// Helper function to read the current time from the clock
+fn time() -> u64 {
+    CLOCK.with(|clock| clock.borrow().now())
+}
+
+// Helper function to convert a clock time into the system time ledgers check transfer
+// creation times against
+fn ledger_time(at: u64) -> u64 {
+    CLOCK.with(|clock| clock.borrow().system_time_at(at))
+}
+
+// Function to move the clock of a staging canister by an offset from the system time, to try
+// out expiries, slots and promotions; zero puts it back on the system time. Timers are re-armed
+// on the new clock. Only canisters installed with `staging` can move their clock
+#[ic_cdk::update]
+fn set_clock_offset(offset_seconds: i64) -> Result<ClockStatus, Error> {
+    ensure_admin()?;
+    journal_call("set_clock_offset");
+    if CONFIG.with(|config| config.borrow().get().staging) != Some(true) {
+        return Err(Error::InvalidOperation {
+            msg: "Only staging canisters can move their clock.".to_string(),
+        });
+    }
+    if offset_seconds.unsigned_abs() > MAX_CLOCK_OFFSET_SECONDS as u64 {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "The clock can be moved by at most {} seconds.",
+                MAX_CLOCK_OFFSET_SECONDS
+            ),
+        });
+    }
+    update_config(|config| {
+        config.clock_offset_seconds = (offset_seconds != 0).then_some(offset_seconds)
+    })?;
+    CLOCK.with(|clock| *clock.borrow_mut() = Clock::from_config());
+    schedule_next_job();
+    Ok(get_clock())
+}
+
+// Query function to get the time the shop runs on and how far it is from the system time
+#[ic_cdk::query]
+fn get_clock() -> ClockStatus {
+    let clock = CLOCK.with(|clock| *clock.borrow());
+    ClockStatus {
+        now: clock.now(),
+        system_time: system_time(),
+        offset_seconds: clock.offset_ns / 1_000_000_000,
+    }
+}
+
+// Helper function to fill a translation with the values a message template matched
+fn fill_translation(translation: &str, values: &[&str]) -> String {
+    let mut filled = translation.to_string();
+    for (index, value) in values.iter().enumerate() {
+        filled = filled.replace(&format!("{{{}}}", index), value);
+    }
+    let mut parts = filled.split("{}");
+    let mut result = parts.next().unwrap_or_default().to_string();
+    for (index, part) in parts.enumerate() {
+        result.push_str(values.get(index).copied().unwrap_or_default());
+        result.push_str(part);
+    }
+    result
+}
+
+// Helper function to match a message against a template, returning the values standing in for
+// its `{}` placeholders
+fn match_template<'a>(template: &str, message: &'a str) -> Option<Vec<&'a str>> {
+    let literals: Vec<&str> = template.split("{}").collect();
+    let mut rest = message.strip_prefix(literals[0])?;
+    let mut values = Vec::new();
+    for (index, literal) in literals.iter().enumerate().skip(1) {
+        let end = if index == literals.len() - 1 {
+            // The last literal ends the message, so the value runs up to it
+            rest.len().checked_sub(literal.len())?
+        } else if literal.is_empty() {
+            0
+        } else {
+            rest.find(literal)?
+        };
+        values.push(&rest[..end]);
+        rest = &rest[end..];
+        rest = rest.strip_prefix(literal)?;
+    }
+    rest.is_empty().then_some(values)
+}
+
+// Helper function to get the language a principal reads messages in
+fn language_of(principal: &Principal) -> Option<String> {
+    LANGUAGE_PREFERENCES
+        .with(|service| service.borrow().get(&PrincipalKey(*principal)))
+        .map(|preference| preference.lang)
+}
+
+// Function to translate a message into the language of a principal with the message catalog;
+// messages without a translation are left in English
+fn localize(principal: &Principal, message: &str) -> String {
+    let Some(lang) = language_of(principal) else {
+        return message.to_string();
+    };
+    MESSAGE_CATALOG.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.lang == lang)
+            .find_map(|entry| {
+                match_template(&entry.template, message)
+                    .map(|values| fill_translation(&entry.translation, &values))
+            })
+            .unwrap_or_else(|| message.to_string())
+    })
+}
+
+// Helper function to check a language tag, e.g. "fr" or "pt-BR"
+fn validate_language_tag(lang: &str) -> Result<(), Error> {
+    let valid = (2..=MAX_LANGUAGE_TAG_LENGTH).contains(&lang.len())
+        && lang.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !valid {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Language tags are 2 to {} letters, digits or dashes, e.g. \"fr\" or \"pt-BR\".",
+                MAX_LANGUAGE_TAG_LENGTH
+            ),
+        });
+    }
+    Ok(())
+}
+
+// Function to set the language the caller reads error and notification messages in, or with
+// none go back to English
+#[ic_cdk::update]
+fn set_my_language(lang: Option<String>) -> Result<Option<String>, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    journal_call("set_my_language");
+    let key = PrincipalKey(caller());
+    match &lang {
+        Some(lang) => {
+            validate_language_tag(lang)?;
+            let preference = LanguagePreference {
+                lang: lang.clone(),
+                updated_at: time(),
+            };
+            LANGUAGE_PREFERENCES.with(|service| service.borrow_mut().insert(key, preference));
+        }
+        None => {
+            LANGUAGE_PREFERENCES.with(|service| service.borrow_mut().remove(&key));
+        }
+    }
+    Ok(lang)
+}
+
+// Query function to get the language the caller reads messages in
+#[ic_cdk::query]
+fn get_my_language() -> Option<String> {
+    language_of(&caller())
+}
+
+// Function to add, change or with no translation remove the translation of a message template
+// into a language
+#[ic_cdk::update]
+fn set_translation(payload: TranslationPayload) -> Result<Option<MessageTranslation>, Error> {
+    ensure_admin()?;
+    journal_call("set_translation");
+    validate_language_tag(&payload.lang)?;
+    let too_long = |text: &str| text.is_empty() || text.len() > MAX_MESSAGE_TEMPLATE_LENGTH;
+    if too_long(&payload.template) || payload.translation.as_deref().is_some_and(too_long) {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Templates and translations must be non-empty and at most {} bytes long.",
+                MAX_MESSAGE_TEMPLATE_LENGTH
+            ),
+        });
+    }
+    if payload.template.replace("{}", "").is_empty() || payload.template.contains("{}{}") {
+        return Err(Error::InvalidOperation {
+            msg: "Templates need text around their placeholders.".to_string(),
+        });
+    }
+    let existing = MESSAGE_CATALOG.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .find(|entry| entry.lang == payload.lang && entry.template == payload.template)
+    });
+    let Some(translation) = payload.translation else {
+        if let Some(existing) = existing {
+            MESSAGE_CATALOG.with(|service| service.borrow_mut().remove(&existing.id));
+        }
+        return Ok(None);
+    };
+    let id = match &existing {
+        Some(existing) => existing.id,
+        None => {
+            if MESSAGE_CATALOG.with(|service| service.borrow().len()) >= MAX_TRANSLATIONS {
+                return Err(Error::CapacityExceeded {
+                    msg: format!("At most {} translations can be kept.", MAX_TRANSLATIONS),
+                });
+            }
+            generate_unique_id(|id| {
+                MESSAGE_CATALOG.with(|service| service.borrow().contains_key(&id))
+            })?
+        }
+    };
+    let entry = MessageTranslation {
+        id,
+        lang: payload.lang,
+        template: payload.template,
+        translation,
+        updated_by: caller(),
+        updated_at: time(),
+    };
+    MESSAGE_CATALOG.with(|service| service.borrow_mut().insert(id, entry.clone()));
+    Ok(Some(entry))
+}
+
+// Query function to list the translations of the message catalog, optionally of one language
+#[ic_cdk::query]
+fn list_translations(lang: Option<String>) -> Result<Vec<MessageTranslation>, Error> {
+    ensure_viewer()?;
+    Ok(MESSAGE_CATALOG.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, entry)| entry)
+            .filter(|entry| lang.as_ref().is_none_or(|lang| entry.lang == *lang))
+            .collect()
+    }))
+}
+
+// Helper function to render an order number pattern for a time: {YYYY}, {YY}, {MM} and {DD}
+// are replaced by the date, and {SEQ} or {SEQ:width} by the zero-padded sequence number, or
+// kept as {SEQ} without one, which names the period the sequence counts in
+fn render_order_number(format: &str, at: u64, seq: Option<u64>) -> Result<String, Error> {
+    let invalid = |msg: &str| Error::InvalidOperation {
+        msg: format!("Invalid order number pattern: {}", msg),
+    };
+    let (year, month, day) = civil_date(at / NANOS_PER_DAY);
+    let mut rendered = String::new();
+    let mut sequences = 0;
+    let mut rest = format;
+    while let Some(c) = rest.chars().next() {
+        if c != '{' {
+            if !(c.is_ascii_alphanumeric() || "-_/.#".contains(c)) {
+                return Err(invalid(
+                    "use letters, digits, - _ / . # and the {YYYY}, {YY}, {MM}, {DD} and {SEQ} placeholders.",
+                ));
+            }
+            rendered.push(c);
+            rest = &rest[1..];
+            continue;
+        }
+        let end = rest
+            .find('}')
+            .ok_or_else(|| invalid("a placeholder is not closed."))?;
+        match &rest[1..end] {
+            "YYYY" => rendered.push_str(&format!("{:04}", year)),
+            "YY" => rendered.push_str(&format!("{:02}", year.rem_euclid(100))),
+            "MM" => rendered.push_str(&format!("{:02}", month)),
+            "DD" => rendered.push_str(&format!("{:02}", day)),
+            token if token == "SEQ" || token.starts_with("SEQ:") => {
+                let width = match token.strip_prefix("SEQ:") {
+                    Some(width) => width
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|width| (1..=MAX_ORDER_NUMBER_SEQ_WIDTH).contains(width))
+                        .ok_or_else(|| {
+                            invalid(&format!(
+                                "sequence widths run from 1 to {}.",
+                                MAX_ORDER_NUMBER_SEQ_WIDTH
+                            ))
+                        })?,
+                    None => 1,
+                };
+                match seq {
+                    Some(seq) => rendered.push_str(&format!("{:0width$}", seq, width = width)),
+                    None => rendered.push_str("{SEQ}"),
+                }
+                sequences += 1;
+            }
+            token => return Err(invalid(&format!("unknown placeholder {{{}}}.", token))),
+        }
+        rest = &rest[end + 1..];
+    }
+    if sequences != 1 {
+        return Err(invalid("it needs exactly one {SEQ} placeholder."));
+    }
+    Ok(rendered)
+}
+
+// Helper function to get the pattern order numbers are rendered with
+fn order_number_format() -> String {
+    CONFIG
+        .with(|config| config.borrow().get().order_number_format.clone())
+        .unwrap_or_else(|| DEFAULT_ORDER_NUMBER_FORMAT.to_string())
+}
+
+// Function to issue the next order number of the period `at` falls in; numbers already taken,
+// e.g. under an earlier pattern, are skipped
+fn next_order_number(at: u64) -> Result<String, Error> {
+    let format = order_number_format();
+    let period = OrderNumber::normalize(&render_order_number(&format, at, None)?);
+    loop {
+        let seq = ORDER_NUMBER_SEQUENCES.with(|service| {
+            let mut sequences = service.borrow_mut();
+            let seq = sequences.get(&period).unwrap_or(0) + 1;
+            sequences.insert(period.clone(), seq);
+            seq
+        });
+        let number = render_order_number(&format, at, Some(seq))?;
+        let taken = ORDER_NUMBERS.with(|service| {
+            service
+                .borrow()
+                .contains_key(&OrderNumber::normalize(&number))
+        });
+        if !taken {
+            return Ok(number);
+        }
+    }
+}
+
+// Function to set the pattern of new order numbers, or with none go back to the default; returns
+// what the next number would look like
+#[ic_cdk::update]
+fn set_order_number_format(format: Option<String>) -> Result<String, Error> {
+    ensure_admin()?;
+    journal_call("set_order_number_format");
+    if let Some(format) = &format {
+        let longest = render_order_number(format, time(), Some(0))?;
+        if longest.len() > MAX_ORDER_NUMBER_LENGTH {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Order numbers must be at most {} bytes long.",
+                    MAX_ORDER_NUMBER_LENGTH
+                ),
+            });
+        }
+    }
+    update_config(|config| config.order_number_format = format)?;
+    let format = order_number_format();
+    let now = time();
+    let period = OrderNumber::normalize(&render_order_number(&format, now, None)?);
+    let seq = ORDER_NUMBER_SEQUENCES.with(|service| service.borrow().get(&period).unwrap_or(0));
+    render_order_number(&format, now, Some(seq + 1))
+}
+
+// Query function to get the pattern of new order numbers
+#[ic_cdk::query]
+fn get_order_number_format() -> Result<String, Error> {
+    ensure_viewer()?;
+    Ok(order_number_format())
+}
+
+// Query function to look up an order by its order number, case-insensitively
+#[ic_cdk::query]
+fn get_order_by_number(number: String) -> Result<Order, Error> {
+    ORDER_NUMBERS
+        .with(|service| service.borrow().get(&OrderNumber::normalize(&number)))
+        .and_then(|id| _get_order(&id))
+        .filter(|order| is_own_record(&order.customer) || ensure_admin().is_ok())
+        .ok_or(Error::NotFound {
+            msg: format!("An order with number {} was not found", number),
+        })
+}
+
+// Helper function to get the record types the config has written in the compact encoding
+fn compact_entities() -> Vec<StoredEntity> {
+    CONFIG
+        .with(|config| config.borrow().get().compact_entities.clone())
+        .unwrap_or_default()
+}
+
+// Helper function to get the encoding a record type is written in
+fn storage_format(entity: StoredEntity) -> StorageFormat {
+    if COMPACT_ENTITIES.with(|entities| entities.borrow().contains(&entity)) {
+        StorageFormat::Compact
+    } else {
+        StorageFormat::Candid
+    }
+}
+
+// Helper function to write a record in the compact encoding, behind its format tag
+fn compact_encode<T: serde::Serialize>(value: &T) -> Result<Vec<u8>, CompactError> {
+    let mut serializer = CompactSerializer {
+        out: vec![COMPACT_FORMAT_TAG],
+    };
+    value.serialize(&mut serializer)?;
+    Ok(serializer.out)
+}
+
+// Helper function to read a record written in the compact encoding
+fn compact_decode<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T, CompactError> {
+    let mut deserializer = CompactDeserializer {
+        input: bytes
+            .strip_prefix(&[COMPACT_FORMAT_TAG])
+            .ok_or_else(|| CompactError("Missing the compact format tag".to_string()))?,
+    };
+    T::deserialize(&mut deserializer)
+}
+
+// Function to encode a record in the format of its record type; records that would not fit
+// their bound in the compact encoding, e.g. orders with many lines, stay in Candid
+fn encode_record<T>(entity: StoredEntity, value: &T) -> Vec<u8>
+where
+    T: candid::CandidType + serde::Serialize + BoundedStorable,
+{
+    if storage_format(entity) == StorageFormat::Compact {
+        if let Ok(bytes) = compact_encode(value) {
+            if bytes.len() <= T::MAX_SIZE as usize {
+                return bytes;
+            }
+        }
+    }
+    Encode!(value).unwrap()
+}
+
+// Function to decode a record in whichever format it was written, so that both can be read
+// during and after a migration
+fn decode_record<T>(bytes: &[u8]) -> T
+where
+    T: candid::CandidType + serde::de::DeserializeOwned,
+{
+    if bytes.first() == Some(&COMPACT_FORMAT_TAG) {
+        compact_decode(bytes).unwrap()
+    } else {
+        Decode!(bytes, T).unwrap()
+    }
+}
+
+// Function to choose the encoding new writes of a record type use; existing records are
+// re-encoded with `migrate_storage_format`. Switch back to Candid and migrate before
+// downgrading to a release without the compact encoding
+#[ic_cdk::update]
+fn set_storage_format(entity: StoredEntity, format: StorageFormat) -> Result<(), Error> {
+    ensure_admin()?;
+    journal_call("set_storage_format");
+    update_config(|config| {
+        let mut entities = config.compact_entities.take().unwrap_or_default();
+        entities.retain(|compact| *compact != entity);
+        if format == StorageFormat::Compact {
+            entities.push(entity);
+        }
+        config.compact_entities = (!entities.is_empty()).then_some(entities);
+    })?;
+    COMPACT_ENTITIES.with(|entities| *entities.borrow_mut() = compact_entities());
+    Ok(())
+}
+
+// Query function to get the encoding each hot record type is written in
+#[ic_cdk::query]
+fn get_storage_formats() -> Result<Vec<(StoredEntity, StorageFormat)>, Error> {
+    ensure_viewer()?;
+    Ok([StoredEntity::Products, StoredEntity::Orders]
+        .into_iter()
+        .map(|entity| (entity, storage_format(entity)))
+        .collect())
+}
+
+// Helper function to rewrite the records of a map after a cursor in the current format
+fn rewrite_batch<V: BoundedStorable>(
+    map: &RefCell<impl EntryMap<u64, V>>,
+    cursor: Option<u64>,
+) -> (u32, Option<u64>) {
+    let start = cursor.map_or(Bound::Unbounded, Bound::Excluded);
+    let batch: Vec<(u64, V)> = map.borrow().entries_from(start, STORAGE_MIGRATION_BATCH);
+    let next_cursor = (batch.len() == STORAGE_MIGRATION_BATCH)
+        .then(|| batch.last().map(|(id, _)| *id))
+        .flatten();
+    let rewritten = batch.len() as u32;
+    for (id, record) in batch {
+        map.borrow_mut().put(id, record);
+    }
+    (rewritten, next_cursor)
+}
+
+// Function to re-encode a batch of records of a record type in its current format; call again
+// with the returned cursor until it is not set
+#[ic_cdk::update]
+fn migrate_storage_format(
+    entity: StoredEntity,
+    cursor: Option<u64>,
+) -> Result<StorageMigration, Error> {
+    ensure_admin()?;
+    journal_call("migrate_storage_format");
+    let (rewritten, next_cursor) = match entity {
+        StoredEntity::Products => STORAGE.with(|service| rewrite_batch(service, cursor)),
+        StoredEntity::Orders => ORDERS.with(|service| rewrite_batch(service, cursor)),
+    };
+    Ok(StorageMigration {
+        entity,
+        format: storage_format(entity),
+        rewritten,
+        next_cursor,
+    })
+}
+
+// Helper function to measure both encodings on a sample of records, checking that each reads
+// back from the compact encoding as it reads from Candid
+fn benchmark_records<T>(entity: StoredEntity, records: Vec<T>) -> StorageBenchmark
+where
+    T: candid::CandidType + serde::Serialize + serde::de::DeserializeOwned,
+{
+    let counter = || ic_cdk::api::performance_counter(0);
+    let cost = |format: StorageFormat| {
+        let start = counter();
+        let encoded: Vec<Vec<u8>> = records
+            .iter()
+            .map(|record| match format {
+                StorageFormat::Candid => Encode!(record).unwrap(),
+                StorageFormat::Compact => compact_encode(record).unwrap(),
+            })
+            .collect();
+        let encoded_at = counter();
+        let decoded: Vec<T> = encoded.iter().map(|bytes| decode_record(bytes)).collect();
+        let decoded_at = counter();
+        let cost = FormatCost {
+            format,
+            bytes: encoded.iter().map(|bytes| bytes.len() as u64).sum(),
+            encode_instructions: encoded_at - start,
+            decode_instructions: decoded_at - encoded_at,
+        };
+        (cost, decoded)
+    };
+    let (candid, _) = cost(StorageFormat::Candid);
+    let (compact, decoded) = cost(StorageFormat::Compact);
+    let mismatches = records
+        .iter()
+        .zip(&decoded)
+        .filter(|(record, decoded)| Encode!(*record).unwrap() != Encode!(*decoded).unwrap())
+        .count() as u32;
+    StorageBenchmark {
+        entity,
+        records: records.len() as u32,
+        candid,
+        compact,
+        mismatches,
+    }
+}
+
+// Query function to compare the instructions and bytes both encodings take on the first records
+// of a record type, e.g. before switching it to the compact encoding
+#[ic_cdk::query]
+fn benchmark_storage_formats(
+    entity: StoredEntity,
+    records: u32,
+) -> Result<StorageBenchmark, Error> {
+    ensure_viewer()?;
+    let take = records.min(MAX_STORAGE_BENCHMARK_RECORDS) as usize;
+    Ok(match entity {
+        StoredEntity::Products => benchmark_records(
+            entity,
+            STORAGE.with(|service| service.borrow().iter().take(take).map(|(_, p)| p).collect()),
+        ),
+        StoredEntity::Orders => benchmark_records(
+            entity,
+            ORDERS.with(|service| service.borrow().iter().take(take).map(|(_, o)| o).collect()),
+        ),
+    })
+}
+
+// Helper function to get the published catalog version and its build hook delivery state
+fn published_catalog() -> PublishedCatalog {
+    PUBLISHED_CATALOG.with(|cell| cell.borrow().get().clone())
+}
+
+// Helper function to persist the published catalog version and its delivery state
+fn store_published_catalog(published: PublishedCatalog) {
+    PUBLISHED_CATALOG.with(|cell| {
+        cell.borrow_mut()
+            .set(published)
+            .expect("Cannot store the published catalog")
+    });
+}
+
+// Helper function to hash what a statically generated storefront shows of the listed products;
+// stock levels are left out, storefronts read them live
+fn published_catalog_digest(now: u64) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    STORAGE.with(|service| {
+        for (_, product) in service.borrow().iter() {
+            let product = with_effective_status(product, now);
+            if !is_listed(&product, false) {
+                continue;
+            }
+            let fields = serde_json::json!([
+                product.id,
+                product.name,
+                product.category,
+                product.price,
+                product.tags,
+                product.allergens,
+                is_age_restricted(&product),
+                product.featured_rank,
+            ]);
+            hasher.update(fields.to_string().as_bytes());
+            hasher.update(b"\n");
+        }
+    });
+    hasher.finalize().to_vec()
+}
+
+// Function to compare the published catalog with its last digest, bumping its version when it
+// changed, and to call the build hook while it has not accepted the current version
+fn check_published_catalog() {
+    let now = time();
+    let mut published = published_catalog();
+    published.check_at = None;
+    let digest = published_catalog_digest(now);
+    if published.digest.is_empty() {
+        // First check after the upgrade that introduced the version: nothing to rebuild yet
+        published.notified_version = published.version;
+    } else if published.digest != digest {
+        published.version += 1;
+        published.changed_at = Some(now);
+        published.attempts = 0;
+        CATALOG_VERSION.with(|counter| {
+            let version = *counter.borrow().get();
+            counter
+                .borrow_mut()
+                .set(version + 1)
+                .expect("Cannot advance the catalog version");
+        });
+    }
+    published.digest = digest;
+    let hook = CONFIG.with(|config| config.borrow().get().build_hook_url.clone());
+    let due = published.notified_version < published.version
+        && published.attempts < MAX_BUILD_HOOK_ATTEMPTS
+        && !BUILD_HOOK_IN_FLIGHT.with(|in_flight| *in_flight.borrow());
+    let version = published.version;
+    store_published_catalog(published);
+    if let (Some(url), true) = (hook, due) {
+        ic_cdk::spawn(call_build_hook(url, version));
+    }
+}
+
+// Function to POST a published catalog version to the build hook, retrying later on failure
+async fn call_build_hook(url: String, version: u64) {
+    BUILD_HOOK_IN_FLIGHT.with(|in_flight| *in_flight.borrow_mut() = true);
+    let body = serde_json::json!({
+        "event": "catalog.published",
+        "published_version": version,
+        "catalog_version": CATALOG_VERSION.with(|counter| *counter.borrow().get()),
+    });
+    let request = outcall::CanisterHttpRequestArgument {
+        url,
+        max_response_bytes: Some(NOTIFIER_MAX_RESPONSE_BYTES),
+        method: outcall::HttpMethod::POST,
+        headers: vec![
+            outcall::HttpHeader {
+                name: "Content-Type".to_string(),
+                value: "application/json".to_string(),
+            },
+            // Every replica sends the request; the hook can drop the copies with this key
+            outcall::HttpHeader {
+                name: "Idempotency-Key".to_string(),
+                value: format!("catalog-{}", version),
+            },
+        ],
+        body: Some(body.to_string().into_bytes()),
+        transform: Some(outcall::TransformContext::from_name(
+            "transform_outcall_response".to_string(),
+            vec![],
+        )),
+    };
+    let result = match outcall::http_request(request, NOTIFIER_OUTCALL_CYCLES).await {
+        Ok((response,)) if response.status >= 200u32 && response.status < 300u32 => Ok(()),
+        Ok((response,)) => Err(format!(
+            "Build hook answered with status {}",
+            response.status
+        )),
+        Err((code, msg)) => Err(format!(
+            "Failed to reach the build hook ({:?}): {}",
+            code, msg
+        )),
+    };
+    BUILD_HOOK_IN_FLIGHT.with(|in_flight| *in_flight.borrow_mut() = false);
+
+    let now = time();
+    let mut published = published_catalog();
+    published.last_attempt_at = Some(now);
+    match result {
+        Ok(()) => {
+            published.notified_version = published.notified_version.max(version);
+            published.attempts = 0;
+            published.last_error = None;
+        }
+        Err(msg) => {
+            published.attempts += 1;
+            published.last_error = Some(msg);
+        }
+    }
+    // A change made during the call, or a failed call, is picked up by another check
+    if published.notified_version < published.version
+        && published.attempts < MAX_BUILD_HOOK_ATTEMPTS
+        && published.check_at.is_none()
+    {
+        published.check_at = Some(now + BUILD_HOOK_RETRY_NS);
+    }
+    store_published_catalog(published);
+    schedule_next_job();
+}
+
+// Function to set the URL POSTed to when the published catalog changes, or with none to stop
+// calling it; its next check calls a new hook right away
+#[ic_cdk::update]
+fn set_build_hook(url: Option<String>) -> Result<(), Error> {
+    ensure_admin()?;
+    journal_call("set_build_hook");
+    if url
+        .as_ref()
+        .is_some_and(|url| !url.starts_with("https://") || url.len() > MAX_NOTIFIER_URL_LENGTH)
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Build hook URLs must use HTTPS and be at most {} bytes long.",
+                MAX_NOTIFIER_URL_LENGTH
+            ),
+        });
+    }
+    update_config(|config| config.build_hook_url = url)?;
+    let mut published = published_catalog();
+    published.attempts = 0;
+    store_published_catalog(published);
+    Ok(())
+}
+
+// Function to have the build hook called for the current published catalog version, e.g. to
+// rebuild the storefront after a failed build
+#[ic_cdk::update]
+fn trigger_build_hook() -> Result<BuildHookStatus, Error> {
+    ensure_admin()?;
+    journal_call("trigger_build_hook");
+    let mut published = published_catalog();
+    published.notified_version = published.version.saturating_sub(1);
+    published.attempts = 0;
+    store_published_catalog(published);
+    check_published_catalog();
+    get_build_hook_status()
+}
+
+// Query function to get the build hook host and the delivery of published catalog versions
+#[ic_cdk::query]
+fn get_build_hook_status() -> Result<BuildHookStatus, Error> {
+    ensure_viewer()?;
+    let host = CONFIG
+        .with(|config| config.borrow().get().build_hook_url.clone())
+        .map(|url| {
+            url.trim_start_matches("https://")
+                .split('/')
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        });
+    let mut catalog = published_catalog();
+    catalog.digest.clear();
+    Ok(BuildHookStatus { host, catalog })
+}
+
+// Query function to get the version of the published catalog, which changes only with what
+// storefronts show of the listed products
+#[ic_cdk::query]
+fn get_published_catalog_version() -> u64 {
+    published_catalog().version
+}
+
+// Helper function to get the principal whose balances pay for a customer's orders
+fn balance_holder(customer: &Principal) -> Principal {
+    linked_account(customer).map_or(*customer, |account| account.primary)
+}
+
+// Helper function to get the store credit and points of a principal
+fn tender_balance(holder: &Principal) -> TenderBalance {
+    TENDER_BALANCES
+        .with(|service| service.borrow().get(&PrincipalKey(*holder)))
+        .unwrap_or_default()
+}
+
+// Helper function to add to (or with a negative amount take from) a balance; fails rather than
+// going below zero
+fn change_balance(
+    holder: &Principal,
+    tender: &Tender,
+    amount: i64,
+) -> Result<TenderBalance, Error> {
+    let mut balance = tender_balance(holder);
+    let held = match tender {
+        Tender::Points => &mut balance.points,
+        Tender::StoreCredit => &mut balance.store_credit,
+        _ => {
+            return Err(Error::InvalidOperation {
+                msg: "Only points and store credit are kept as balances.".to_string(),
+            })
+        }
+    };
+    *held = held
+        .checked_add_signed(amount)
+        .ok_or(Error::InvalidOperation {
+            msg: format!("The {:?} balance is {}.", tender, held),
+        })?;
+    balance.updated_at = time();
+    TENDER_BALANCES.with(|service| {
+        service
+            .borrow_mut()
+            .insert(PrincipalKey(*holder), balance.clone())
+    });
+    Ok(balance)
+}
+
+// Function to split an order total across the requested tenders in a fixed order, points first,
+// then store credit, whatever order they were requested in, leaving the rest to `rest`; nothing
+// is taken from the balances yet
+fn split_tenders(
+    customer: &Principal,
+    total: u64,
+    requests: &[TenderRequest],
+    rest: Tender,
+) -> Result<Option<OrderTenders>, Error> {
+    if requests.is_empty() {
+        return Ok(None);
+    }
+    for (index, request) in requests.iter().enumerate() {
+        if !matches!(request.tender, Tender::Points | Tender::StoreCredit) {
+            return Err(Error::InvalidOperation {
+                msg: "Only points and store credit can pay part of an order; the rest is paid \
+                      with the payment token or at the counter."
+                    .to_string(),
+            });
+        }
+        if requests[..index]
+            .iter()
+            .any(|earlier| earlier.tender == request.tender)
+        {
+            return Err(Error::InvalidOperation {
+                msg: format!("{:?} is requested more than once.", request.tender),
+            });
+        }
+    }
+    let balance = tender_balance(&balance_holder(customer));
+    let mut remaining = total;
+    let mut tenders = Vec::new();
+    for tender in [Tender::Points, Tender::StoreCredit] {
+        let Some(request) = requests.iter().find(|request| request.tender == tender) else {
+            continue;
+        };
+        let (held, unit_value) = match tender {
+            Tender::Points => {
+                let point_value = CONFIG
+                    .with(|config| config.borrow().get().point_value)
+                    .filter(|value| *value > 0)
+                    .ok_or(Error::InvalidOperation {
+                        msg: "Points cannot be used to pay at the moment.".to_string(),
+                    })?;
+                (balance.points, point_value)
+            }
+            _ => (balance.store_credit, 1),
+        };
+        if request.amount > held {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Only {} of the {} {:?} requested are available.",
+                    held, request.amount, tender
+                ),
+            });
+        }
+        // Points only pay in whole points, so they never cover more than what is left
+        let units = request.amount.min(remaining / unit_value);
+        if units == 0 {
+            continue;
+        }
+        remaining -= units * unit_value;
+        tenders.push(OrderTender {
+            tender,
+            units,
+            value: units * unit_value,
+            refunded_units: 0,
+        });
+    }
+    if remaining > 0 {
+        tenders.push(OrderTender {
+            tender: rest,
+            units: remaining,
+            value: remaining,
+            refunded_units: 0,
+        });
+    }
+    Ok(Some(OrderTenders {
+        tenders,
+        refunded: 0,
+    }))
+}
+
+// Helper function to get the part of an order total left to the payment token or the counter
+fn remaining_due(tenders: &Option<OrderTenders>, total: u64) -> u64 {
+    tenders.as_ref().map_or(total, |split| {
+        split
+            .tenders
+            .iter()
+            .filter(|tender| matches!(tender.tender, Tender::Token { .. } | Tender::PayOnPickup))
+            .map(|tender| tender.value)
+            .sum()
+    })
+}
+
+// Function to take the points and store credit of a split from the customer's balances and
+// record the tenders on the order; the split was checked in the same call. What they pay is
+// booked from the store credit account, leaving the rest of the order receivable
+fn apply_tenders(order: &mut Order, tenders: Option<OrderTenders>) {
+    let Some(tenders) = tenders else {
+        return;
+    };
+    let holder = balance_holder(&order.customer);
+    for tender in &tenders.tenders {
+        if matches!(tender.tender, Tender::Points | Tender::StoreCredit) {
+            // Checked against the balances earlier in the same call
+            let _ = change_balance(&holder, &tender.tender, -(tender.units as i64));
+            post_transfer(
+                format!("Order #{} paid with {:?}", order.id, tender.tender),
+                Some(order.id),
+                LedgerAccount::StoreCredit,
+                LedgerAccount::Receivables,
+                tender.value,
+            );
+        }
+    }
+    order.tenders = Some(tenders);
+    store_order(order.clone());
+}
+
+// Function to refund an amount across the tenders of an order in proportion to their share of
+// the total. Each tender's refunded units follow the running total refunded, so repeated partial
+// refunds round the same as one refund of their sum and a full refund returns every unit. Points
+// and store credit go back to the balances and are booked back to the store credit account; the
+// token share is refunded from the escrow, whose amounts are already proportional. Returns the
+// value given back to the balances, which the caller leaves out of its own refund entry
+fn refund_tenders(order: &mut Order, amount: u64) -> u64 {
+    let total = order.total;
+    let customer = order.customer;
+    let order_id = order.id;
+    let Some(split) = order.tenders.as_mut() else {
+        return 0;
+    };
+    if amount == 0 || total == 0 {
+        return 0;
+    }
+    split.refunded = split.refunded.saturating_add(amount).min(total);
+    let holder = balance_holder(&customer);
+    let mut to_balances = 0;
+    for tender in split.tenders.iter_mut() {
+        let refunded = (tender.units as u128 * split.refunded as u128 / total as u128) as u64;
+        let back = refunded.saturating_sub(tender.refunded_units);
+        tender.refunded_units = tender.refunded_units.max(refunded);
+        if back > 0 && matches!(tender.tender, Tender::Points | Tender::StoreCredit) {
+            let _ = change_balance(&holder, &tender.tender, back as i64);
+            // Every unit of a tender is worth the same share of its value
+            to_balances += back * (tender.value / tender.units);
+        }
+    }
+    post_transfer(
+        format!("Order #{} refunded to the customer's balances", order_id),
+        Some(order_id),
+        LedgerAccount::Refunds,
+        LedgerAccount::StoreCredit,
+        to_balances,
+    );
+    to_balances
+}
+
+// Helper function to get what is still to be paid with the payment token or at the counter for
+// an order, after refunds
+fn cash_due(order: &Order) -> u64 {
+    match &order.tenders {
+        Some(split) => split
+            .tenders
+            .iter()
+            .filter(|tender| matches!(tender.tender, Tender::Token { .. } | Tender::PayOnPickup))
+            .map(|tender| tender.units - tender.refunded_units)
+            .sum(),
+        None => amount_due(order),
+    }
+}
+
+// Function to grant or take back store credit or points, e.g. for a gift card sold at the
+// counter or a goodwill gesture. Store credit is booked as sold for cash; points are only booked
+// once spent
+#[ic_cdk::update]
+fn adjust_balance(payload: BalanceAdjustmentPayload) -> Result<TenderBalance, Error> {
+    ensure_admin()?;
+    journal_call("adjust_balance");
+    let balance = change_balance(
+        &balance_holder(&payload.customer),
+        &payload.tender,
+        payload.amount,
+    )?;
+    if payload.tender == Tender::StoreCredit {
+        let memo = format!("Store credit of {} adjusted", payload.customer.to_text());
+        let amount = payload.amount.unsigned_abs();
+        if payload.amount > 0 {
+            post_transfer(
+                memo,
+                None,
+                LedgerAccount::Cash,
+                LedgerAccount::StoreCredit,
+                amount,
+            );
+        } else {
+            post_transfer(
+                memo,
+                None,
+                LedgerAccount::StoreCredit,
+                LedgerAccount::Cash,
+                amount,
+            );
+        }
+    }
+    Ok(balance)
+}
+
+// Function to set the value of a loyalty point in the smallest currency unit, or with none stop
+// accepting points
+#[ic_cdk::update]
+fn set_point_value(value: Option<u64>) -> Result<(), Error> {
+    ensure_admin()?;
+    journal_call("set_point_value");
+    update_config(|config| config.point_value = value.filter(|value| *value > 0))?;
+    Ok(())
+}
+
+// Query function to get the caller's store credit and points
+#[ic_cdk::query]
+fn get_my_balance() -> TenderBalance {
+    tender_balance(&account_holder())
+}
+
+// Query function to get a customer's store credit and points
+#[ic_cdk::query]
+fn get_customer_balance(customer: Principal) -> Result<TenderBalance, Error> {
+    ensure_viewer()?;
+    Ok(tender_balance(&balance_holder(&customer)))
+}
+
+// Helper function to list the disputes about a customer's orders
+fn customer_disputes(customer: Principal) -> Vec<Dispute> {
+    DISPUTES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, dispute)| dispute)
+            .filter(|dispute| dispute.customer == customer)
+            .collect()
+    })
+}
+
+// Helper function to check a note written on a dispute
+fn validate_dispute_note(note: &Option<String>) -> Result<(), Error> {
+    if note
+        .as_ref()
+        .is_some_and(|note| note.len() > MAX_NOTES_LENGTH)
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!("Notes can be at most {} bytes long.", MAX_NOTES_LENGTH),
+        });
+    }
+    Ok(())
+}
+
+// Helper function to get a dispute the caller is a party to; staff are party to every dispute
+fn party_dispute(id: u64) -> Result<Dispute, Error> {
+    DISPUTES
+        .with(|service| service.borrow().get(&id))
+        .filter(|dispute| is_own_record(&dispute.customer) || ensure_admin().is_ok())
+        .ok_or(Error::NotFound {
+            msg: format!("A dispute with id={} was not found", id),
+        })
+}
+
+// Function to open a dispute about an order's payment. Customers open them about their own
+// orders, staff about any order, e.g. for a chargeback raised with the ledger's operator
+#[ic_cdk::update]
+fn open_dispute(payload: DisputePayload) -> Result<Dispute, Error> {
+    ensure_caller_allowed(Endpoint::CreateTicket)?;
+    journal_call("open_dispute");
+    if payload.reason.trim().is_empty() || payload.reason.len() > MAX_NOTES_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "The reason for a dispute must be non-empty and at most {} bytes long.",
+                MAX_NOTES_LENGTH
+            ),
+        });
+    }
+    let order = _get_order(&payload.order_id)
+        .filter(|order| is_own_record(&order.customer) || ensure_admin().is_ok())
+        .ok_or(Error::NotFound {
+            msg: format!("An order with id={} was not found", payload.order_id),
+        })?;
+    let payment = order.payment.as_ref().ok_or(Error::InvalidOperation {
+        msg: format!(
+            "Order id={} was not paid with a token; there is no transfer to dispute",
+            order.id
+        ),
+    })?;
+    let block_index = payload
+        .block_index
+        .or_else(|| {
+            payment
+                .escrow
+                .as_ref()
+                .and_then(|escrow| escrow.block_index.clone())
+        })
+        .ok_or(Error::InvalidOperation {
+            msg: "Give the block index of the disputed transfer.".to_string(),
+        })?;
+    let amount = payload.amount.unwrap_or(order.total);
+    if amount == 0 || amount > order.total {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "The disputed amount must be between 1 and the order total of {}.",
+                order.total
+            ),
+        });
+    }
+    if let Some(open) = customer_disputes(order.customer)
+        .iter()
+        .find(|dispute| dispute.order_id == order.id && dispute.status != DisputeStatus::Resolved)
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!("Dispute id={} about this order is still open", open.id),
+        });
+    }
+
+    let id = generate_unique_id(|id| DISPUTES.with(|service| service.borrow().contains_key(&id)))?;
+    let now = time();
+    let dispute = Dispute {
+        id,
+        order_id: order.id,
+        customer: order.customer,
+        ledger: payment.ledger,
+        block_index,
+        amount,
+        reason: payload.reason,
+        status: DisputeStatus::Open,
+        evidence: Vec::new(),
+        events: vec![DisputeEvent {
+            status: DisputeStatus::Open,
+            by: caller(),
+            at: now,
+            note: None,
+        }],
+        outcome: None,
+        refund_amount: None,
+        opened_by: caller(),
+        created_at: now,
+        updated_at: None,
+    };
+    DISPUTES.with(|service| service.borrow_mut().insert(id, dispute.clone()));
+    Ok(dispute)
+}
+
+// Function for either side of a dispute to add a piece of evidence until it is resolved
+#[ic_cdk::update]
+fn submit_dispute_evidence(id: u64, payload: DisputeEvidencePayload) -> Result<Dispute, Error> {
+    ensure_caller_allowed(Endpoint::CreateTicket)?;
+    journal_call("submit_dispute_evidence");
+    if payload.reference.trim().is_empty() || payload.reference.len() > MAX_NOTIFIER_URL_LENGTH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Evidence needs a reference of at most {} bytes.",
+                MAX_NOTIFIER_URL_LENGTH
+            ),
+        });
+    }
+    validate_dispute_note(&payload.note)?;
+    let mut dispute = party_dispute(id)?;
+    if dispute.status == DisputeStatus::Resolved {
+        return Err(Error::InvalidOperation {
+            msg: format!("Dispute id={} is already resolved", id),
+        });
+    }
+    if dispute.evidence.len() >= MAX_DISPUTE_EVIDENCE {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "At most {} pieces of evidence can be added to a dispute.",
+                MAX_DISPUTE_EVIDENCE
+            ),
+        });
+    }
+    let now = time();
+    dispute.evidence.push(DisputeEvidence {
+        reference: payload.reference,
+        note: payload.note,
+        by: caller(),
+        at: now,
+    });
+    if dispute.status == DisputeStatus::Open {
+        dispute.status = DisputeStatus::EvidenceSubmitted;
+        dispute.events.push(DisputeEvent {
+            status: DisputeStatus::EvidenceSubmitted,
+            by: caller(),
+            at: now,
+            note: None,
+        });
+    }
+    dispute.updated_at = Some(now);
+    DISPUTES.with(|service| service.borrow_mut().insert(id, dispute.clone()));
+    Ok(dispute)
+}
+
+// Function for staff to resolve a dispute. A customer win refunds `refund_amount` (the disputed
+// amount when omitted) and books it against cash; the money goes back to the customer outside
+// the canister, as for returns
+#[ic_cdk::update]
+fn resolve_dispute(
+    id: u64,
+    outcome: DisputeOutcome,
+    refund_amount: Option<u64>,
+    note: Option<String>,
+) -> Result<Dispute, Error> {
+    ensure_admin()?;
+    journal_call("resolve_dispute");
+    validate_dispute_note(&note)?;
+    let mut dispute = party_dispute(id)?;
+    if dispute.status == DisputeStatus::Resolved {
+        return Err(Error::InvalidOperation {
+            msg: format!("Dispute id={} is already resolved", id),
+        });
+    }
+    let refund = match outcome {
+        DisputeOutcome::CustomerWon => refund_amount.unwrap_or(dispute.amount),
+        _ => 0,
+    };
+    if refund > dispute.amount
+        || (outcome != DisputeOutcome::CustomerWon && refund_amount.is_some())
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Only a customer win refunds, and at most the disputed amount of {}.",
+                dispute.amount
+            ),
+        });
+    }
+    let now = time();
+    dispute.status = DisputeStatus::Resolved;
+    dispute.outcome = Some(outcome);
+    dispute.refund_amount = Some(refund);
+    dispute.events.push(DisputeEvent {
+        status: DisputeStatus::Resolved,
+        by: caller(),
+        at: now,
+        note,
+    });
+    dispute.updated_at = Some(now);
+    DISPUTES.with(|service| service.borrow_mut().insert(id, dispute.clone()));
+    if refund > 0 {
+        post_transfer(
+            format!(
+                "Dispute #{} of order #{} resolved for the customer",
+                id, dispute.order_id
+            ),
+            Some(dispute.order_id),
+            LedgerAccount::Refunds,
+            LedgerAccount::Cash,
+            refund,
+        );
+    }
+    Ok(dispute)
+}
+
+// Query function to retrieve a dispute; customers can only see their own
+#[ic_cdk::query]
+fn get_dispute(id: u64) -> Result<Dispute, Error> {
+    party_dispute(id)
+}
+
+// Query function to list the disputes about the caller's orders
+#[ic_cdk::query]
+fn list_my_disputes() -> Vec<Dispute> {
+    account_principals(&caller())
+        .into_iter()
+        .flat_map(customer_disputes)
+        .collect()
+}
+
+// Query function to list the disputes in a status, or all of them, oldest first
+#[ic_cdk::query]
+fn list_disputes(status: Option<DisputeStatus>) -> Result<Vec<Dispute>, Error> {
+    ensure_viewer()?;
+    let mut disputes: Vec<Dispute> = DISPUTES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, dispute)| dispute)
+            .filter(|dispute| status.is_none_or(|status| dispute.status == status))
+            .collect()
+    });
+    disputes.sort_by_key(|dispute| (dispute.created_at, dispute.id));
+    Ok(disputes)
+}
+
+// Query function to report on the disputes opened within a period: where they stand, how the
+// resolved ones ended and how long they took
+#[ic_cdk::query]
+fn dispute_report(period: ReportPeriod) -> Result<DisputeReport, Error> {
+    ensure_viewer()?;
+    if period.from > period.to {
+        return Err(Error::InvalidOperation {
+            msg: "A report period cannot end before it starts.".to_string(),
+        });
+    }
+    let mut report = DisputeReport {
+        period,
+        opened: 0,
+        open: 0,
+        evidence_submitted: 0,
+        resolved: 0,
+        customer_won: 0,
+        shop_won: 0,
+        withdrawn: 0,
+        disputed_amount: 0,
+        refunded_amount: 0,
+        average_resolution_ns: None,
+    };
+    let mut total_resolution: u64 = 0;
+    DISPUTES.with(|service| {
+        for (_, dispute) in service.borrow().iter() {
+            if !(period.from..period.to).contains(&dispute.created_at) {
+                continue;
+            }
+            report.opened += 1;
+            report.disputed_amount += dispute.amount;
+            match dispute.status {
+                DisputeStatus::Open => report.open += 1,
+                DisputeStatus::EvidenceSubmitted => report.evidence_submitted += 1,
+                DisputeStatus::Resolved => report.resolved += 1,
+            }
+            match dispute.outcome {
+                Some(DisputeOutcome::CustomerWon) => report.customer_won += 1,
+                Some(DisputeOutcome::ShopWon) => report.shop_won += 1,
+                Some(DisputeOutcome::Withdrawn) => report.withdrawn += 1,
+                None => continue,
+            }
+            report.refunded_amount += dispute.refund_amount.unwrap_or(0);
+            total_resolution += dispute
+                .updated_at
+                .unwrap_or(dispute.created_at)
+                .saturating_sub(dispute.created_at);
+        }
+    });
+    report.average_resolution_ns = total_resolution.checked_div(report.resolved);
+    Ok(report)
+}
+
+// Helper function to get the day a birthday falls on in a year; 29 February birthdays are
+// celebrated on the 28th outside leap years
+fn birthday_in(birthday: Birthday, year: i64) -> i64 {
+    let day = if birthday.month == 2 && birthday.day == 29 && !is_leap_year(year) {
+        28
+    } else {
+        birthday.day as i64
+    };
+    days_from_civil(year, birthday.month as i64, day)
+}
+
+// Function to give the configured reward to customers whose birthday is today, or was within
+// the grace period and not rewarded yet. The reward and the year it was issued for are written
+// in the same message, so re-running the job never issues a year's reward twice. Only customers
+// who have ordered before are rewarded, and only for a birthday set before the day
+fn issue_birthday_rewards() {
+    let Some(reward) = CONFIG.with(|config| config.borrow().get().birthday_reward) else {
+        return;
+    };
+    let now = time();
+    let today = (now / NANOS_PER_DAY) as i64;
+    let (year, _, _) = civil_date(today as u64);
+    let due: Vec<(PrincipalKey, CustomerBirthday, i64)> = BIRTHDAYS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .filter_map(|(key, record)| {
+                let birthday = record.birthday?;
+                // A birthday late in December is still in its grace period early in January
+                let year = [year, year - 1].into_iter().find(|year| {
+                    let day = birthday_in(birthday, *year);
+                    (day..day + BIRTHDAY_REWARD_GRACE_DAYS).contains(&today)
+                        && record.set_at < day as u64 * NANOS_PER_DAY
+                })?;
+                (record.rewarded_year < Some(year)).then_some((key, record, year))
+            })
+            .collect()
+    });
+    for (key, mut record, year) in due {
+        if _get_customer(&key.0).is_none() {
+            continue;
+        }
+        // Points and store credit are given together or not at all; a reward that cannot be
+        // given is tried again on the next run
+        if let Err(error) = give_birthday_reward(&key.0, &reward) {
+            ic_cdk::println!(
+                "Cannot give the birthday reward of {}: {}",
+                key.0.to_text(),
+                error.message()
+            );
+            continue;
+        }
+        if reward.store_credit > 0 {
+            post_transfer(
+                format!("Birthday store credit of {}", key.0.to_text()),
+                None,
+                LedgerAccount::Promotions,
+                LedgerAccount::StoreCredit,
+                reward.store_credit,
+            );
+        }
+        record.rewarded_year = Some(year);
+        record.rewarded_at = Some(now);
+        BIRTHDAYS.with(|service| service.borrow_mut().insert(key, record));
+    }
+}
+
+// Helper function to add a birthday reward to a customer's balances, taking the points back when
+// the store credit cannot be given
+fn give_birthday_reward(holder: &Principal, reward: &BirthdayReward) -> Result<(), Error> {
+    let too_large = |_| Error::InvalidOperation {
+        msg: "The birthday reward is too large.".to_string(),
+    };
+    let points = i64::try_from(reward.points).map_err(too_large)?;
+    let store_credit = i64::try_from(reward.store_credit).map_err(too_large)?;
+    if points > 0 {
+        change_balance(holder, &Tender::Points, points)?;
+    }
+    if store_credit > 0 {
+        if let Err(error) = change_balance(holder, &Tender::StoreCredit, store_credit) {
+            if points > 0 {
+                let _ = change_balance(holder, &Tender::Points, -points);
+            }
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+// Function to set the caller's birthday for a yearly reward, or with none remove it; it is kept
+// for the account holder of linked devices
+#[ic_cdk::update]
+fn set_my_birthday(birthday: Option<Birthday>) -> Result<Option<CustomerBirthday>, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    journal_call("set_my_birthday");
+    if let Some(birthday) = birthday {
+        // Checked against a leap year so that 29 February is accepted
+        let valid = (1..=12).contains(&birthday.month)
+            && birthday.day >= 1
+            && civil_date(birthday_in(birthday, 2000) as u64).2 == birthday.day as i64;
+        if !valid {
+            return Err(Error::InvalidOperation {
+                msg: "A birthday needs a month from 1 to 12 and a day in that month.".to_string(),
+            });
+        }
+    }
+    let key = PrincipalKey(account_holder());
+    let existing = BIRTHDAYS.with(|service| service.borrow().get(&key));
+    let rewarded_year = existing.as_ref().and_then(|record| record.rewarded_year);
+    if birthday.is_none() && rewarded_year.is_none() {
+        BIRTHDAYS.with(|service| service.borrow_mut().remove(&key));
+        return Ok(None);
+    }
+    if existing
+        .as_ref()
+        .is_some_and(|record| record.birthday == birthday)
+    {
+        return Ok(existing);
+    }
+    let record = CustomerBirthday {
+        birthday,
+        set_at: time(),
+        rewarded_year,
+        rewarded_at: existing.and_then(|record| record.rewarded_at),
+    };
+    BIRTHDAYS.with(|service| service.borrow_mut().insert(key, record.clone()));
+    Ok(Some(record))
+}
+
+// Query function to get the caller's birthday and the last year it was rewarded
+#[ic_cdk::query]
+fn get_my_birthday() -> Option<CustomerBirthday> {
+    BIRTHDAYS.with(|service| service.borrow().get(&PrincipalKey(account_holder())))
+}
+
+// Function to set the reward customers get on their birthday, or with none stop issuing them
+#[ic_cdk::update]
+fn set_birthday_reward(reward: Option<BirthdayReward>) -> Result<(), Error> {
+    ensure_admin()?;
+    journal_call("set_birthday_reward");
+    if reward.is_some_and(|reward| reward.points == 0 && reward.store_credit == 0) {
+        return Err(Error::InvalidOperation {
+            msg: "A birthday reward needs points or store credit.".to_string(),
+        });
+    }
+    update_config(|config| config.birthday_reward = reward)?;
+    Ok(())
+}
+
+// Query function to get the reward customers get on their birthday
+#[ic_cdk::query]
+fn get_birthday_reward() -> Option<BirthdayReward> {
+    CONFIG.with(|config| config.borrow().get().birthday_reward)
+}
+
+// Helper function to build the pick list of a day from the orders due on it that are still
+// placed or being prepared, counting the units not yet handed over or refunded
+fn build_pick_list(day: u64) -> PickList {
+    let mut orders: Vec<Order> = ORDERS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, order)| order)
+            .filter(|order| {
+                matches!(order.status, OrderStatus::Placed | OrderStatus::Preparing)
+                    && order_due_at(order) / NANOS_PER_DAY == day
+                    && !is_held(order.id)
+            })
+            .collect()
+    });
+    orders.sort_by_key(|order| (order_due_at(order), order.id));
+    let mut list = PickList {
+        day,
+        orders: 0,
+        units: 0,
+        slots: Vec::new(),
+    };
+    for order in &orders {
+        let delivery = order
+            .schedule
+            .as_ref()
+            .is_some_and(|schedule| schedule.delivery_address.is_some());
+        let slot_start = order_due_at(order);
+        let mut counted = false;
+        for line in &order.lines {
+            let quantity = line
+                .quantity
+                .saturating_sub(line.fulfilled.unwrap_or(0))
+                .saturating_sub(line.unfulfillable.unwrap_or(0));
+            if quantity == 0 {
+                continue;
+            }
+            counted = true;
+            list.units += quantity;
+            let slot = match list
+                .slots
+                .iter()
+                .position(|slot| slot.slot_start == slot_start && slot.delivery == delivery)
+            {
+                Some(index) => &mut list.slots[index],
+                None => {
+                    list.slots.push(PickSlot {
+                        slot_start,
+                        delivery,
+                        items: Vec::new(),
+                    });
+                    list.slots.last_mut().expect("a slot was just added")
+                }
+            };
+            let item = match slot
+                .items
+                .iter()
+                .position(|item| item.product_id == line.product_id)
+            {
+                Some(index) => &mut slot.items[index],
+                None => {
+                    slot.items.push(PickItem {
+                        product_id: line.product_id,
+                        name: _get_product(&line.product_id).map_or_else(
+                            || format!("Product #{}", line.product_id),
+                            |product| product.name,
+                        ),
+                        quantity: 0,
+                        variants: Vec::new(),
+                        order_ids: Vec::new(),
+                        notes: Vec::new(),
+                    });
+                    slot.items.last_mut().expect("an item was just added")
+                }
+            };
+            item.quantity += quantity;
+            let options: Vec<String> = line
+                .options
+                .iter()
+                .flatten()
+                .map(|option| format!("{}: {}", option.group, option.choice))
+                .collect();
+            match item
+                .variants
+                .iter_mut()
+                .find(|variant| variant.options == options)
+            {
+                Some(variant) => variant.quantity += quantity,
+                None => item.variants.push(PickVariant { options, quantity }),
+            }
+            if !item.order_ids.contains(&order.id) {
+                item.order_ids.push(order.id);
+                if let Some(note) = order.notes.clone().filter(|note| !note.trim().is_empty()) {
+                    item.notes.push(PickNote {
+                        order_id: order.id,
+                        order_number: order.order_number.clone(),
+                        note,
+                    });
+                }
+            }
+        }
+        list.orders += counted as u32;
+    }
+    list.slots
+        .sort_by_key(|slot| (slot.slot_start, slot.delivery));
+    for slot in list.slots.iter_mut() {
+        slot.items.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+    list
+}
+
+// Helper function to render a time as HH:MM in UTC
+fn clock_time(at: u64) -> String {
+    let minutes = (at % NANOS_PER_DAY) / NANOS_PER_MINUTE;
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+// Helper function to quote a CSV field when it holds a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// Helper function to render a pick list for the kitchen printer: plain text by slot, or CSV with
+// one row per slot, product and set of options
+fn render_pick_list(list: &PickList, format: PickListFormat) -> String {
+    let (year, month, day) = civil_date(list.day);
+    let mut out = String::new();
+    match format {
+        PickListFormat::Text => {
+            out.push_str(&format!(
+                "PICK LIST {:04}-{:02}-{:02} ({} orders, {} units)\n",
+                year, month, day, list.orders, list.units
+            ));
+            for slot in &list.slots {
+                out.push_str(&format!(
+                    "\n{} {}\n",
+                    clock_time(slot.slot_start),
+                    if slot.delivery { "DELIVERY" } else { "PICKUP" }
+                ));
+                for item in &slot.items {
+                    out.push_str(&format!("  {:>4} x {}\n", item.quantity, item.name));
+                    for variant in item
+                        .variants
+                        .iter()
+                        .filter(|variant| !variant.options.is_empty())
+                    {
+                        out.push_str(&format!(
+                            "       {:>4} x {}\n",
+                            variant.quantity,
+                            variant.options.join(", ")
+                        ));
+                    }
+                    for note in &item.notes {
+                        out.push_str(&format!(
+                            "       Note {}: {}\n",
+                            note.order_number
+                                .clone()
+                                .unwrap_or_else(|| format!("#{}", note.order_id)),
+                            note.note.replace(['\n', '\r'], " ")
+                        ));
+                    }
+                }
+            }
+        }
+        PickListFormat::Csv => {
+            out.push_str("slot,type,product_id,product,options,quantity,orders,notes\n");
+            for slot in &list.slots {
+                for item in &slot.items {
+                    let orders: Vec<String> =
+                        item.order_ids.iter().map(|id| id.to_string()).collect();
+                    let notes: Vec<String> = item
+                        .notes
+                        .iter()
+                        .map(|note| {
+                            format!(
+                                "#{}: {}",
+                                note.order_id,
+                                note.note.replace(['\n', '\r'], " ")
+                            )
+                        })
+                        .collect();
+                    for variant in &item.variants {
+                        let row = [
+                            clock_time(slot.slot_start),
+                            if slot.delivery { "delivery" } else { "pickup" }.to_string(),
+                            item.product_id.to_string(),
+                            item.name.clone(),
+                            variant.options.join("; "),
+                            variant.quantity.to_string(),
+                            orders.join(" "),
+                            notes.join(" | "),
+                        ];
+                        let row: Vec<String> = row.iter().map(|field| csv_field(field)).collect();
+                        out.push_str(&row.join(","));
+                        out.push('\n');
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+// Query function to list everything to pick for the open orders due on a day (in days since
+// the Unix epoch), grouped by slot and product with their options and customer notes
+#[ic_cdk::query]
+fn pick_list(day: u64) -> Result<PickList, Error> {
+    ensure_viewer()?;
+    Ok(build_pick_list(day))
+}
+
+// Query function to get the pick list of a day as text or CSV for the kitchen printer
+#[ic_cdk::query]
+fn export_pick_list(day: u64, format: PickListFormat) -> Result<String, Error> {
+    ensure_viewer()?;
+    Ok(render_pick_list(&build_pick_list(day), format))
+}
+
+// Function to set the shop details printed on packing slips, or with none stop printing them
+#[ic_cdk::update]
+fn set_shop_info(info: Option<ShopInfo>) -> Result<Option<ShopInfo>, Error> {
+    ensure_admin()?;
+    journal_call("set_shop_info");
+    if let Some(info) = &info {
+        validate_address_field("shop name", &info.name)?;
+        for (name, value) in [
+            ("shop address", &info.address),
+            ("shop phone", &info.phone),
+            ("shop website", &info.website),
+        ] {
+            if let Some(value) = value {
+                validate_address_field(name, value)?;
+            }
+        }
+    }
+    update_config(|config| config.shop_info = info.clone())?;
+    Ok(info)
+}
+
+// Query function to get the shop details printed on packing slips
+#[ic_cdk::query]
+fn get_shop_info() -> Option<ShopInfo> {
+    CONFIG.with(|config| config.borrow().get().shop_info.clone())
+}
+
+// Helper function to derive the code that proves a pickup QR was printed for an order
+fn pickup_code(order: &Order) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(b"pickup");
+    hasher.update(order.id.to_be_bytes());
+    hasher.update(order.customer.as_slice());
+    hasher.update(order.created_at.to_be_bytes());
+    hasher
+        .finalize()
+        .iter()
+        .take(6)
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// Helper function to build the QR payload confirming the pickup of an order
+fn pickup_qr(order: &Order) -> String {
+    format!("{}:{}:{}", PICKUP_QR_PREFIX, order.id, pickup_code(order))
+}
+
+// Query function to get the print-ready packing slip and label data of an order: shop details,
+// order number, the lines still to pack with their allergens, the delivery address and, for
+// pickups, the QR payload the counter scans when handing the order over
+#[ic_cdk::query]
+fn packing_data(order_id: u64) -> Result<PackingData, Error> {
+    ensure_viewer()?;
+    let order = _get_order(&order_id).ok_or(Error::NotFound {
+        msg: format!("An order with id={} was not found", order_id),
+    })?;
+    let mut allergen_warnings: Vec<String> = Vec::new();
+    let lines = order
+        .lines
+        .iter()
+        .map(|line| {
+            let product = _get_product(&line.product_id);
+            let allergens = product
+                .as_ref()
+                .map_or_else(Vec::new, |product| product.allergens().to_vec());
+            for allergen in &allergens {
+                if !allergen_warnings.contains(allergen) {
+                    allergen_warnings.push(allergen.clone());
+                }
+            }
+            PackingLine {
+                product_id: line.product_id,
+                name: product.map_or_else(
+                    || format!("Product #{}", line.product_id),
+                    |product| product.name,
+                ),
+                quantity: line
+                    .quantity
+                    .saturating_sub(line.fulfilled.unwrap_or(0))
+                    .saturating_sub(line.unfulfillable.unwrap_or(0)),
+                options: line
+                    .options
+                    .iter()
+                    .flatten()
+                    .map(|option| format!("{}: {}", option.group, option.choice))
+                    .collect(),
+                allergens,
+            }
+        })
+        .collect();
+    allergen_warnings.sort();
+    let delivery_address = order
+        .schedule
+        .as_ref()
+        .and_then(|schedule| schedule.delivery_address.clone());
+    Ok(PackingData {
+        shop: CONFIG.with(|config| config.borrow().get().shop_info.clone()),
+        order_id: order.id,
+        order_number: order.order_number.clone(),
+        status: order.status,
+        placed_at: order.created_at,
+        slot_start: order.schedule.as_ref().map(|schedule| schedule.slot_start),
+        pickup_qr: delivery_address.is_none().then(|| pickup_qr(&order)),
+        delivery_address,
+        notes: order.notes.clone(),
+        lines,
+        allergen_warnings,
+        total: order.total,
+    })
+}
+
+// Function to hand a ready order over at the counter by scanning the QR on its label
+#[ic_cdk::update]
+fn confirm_pickup(qr: String) -> Result<Order, Error> {
+    ensure_admin()?;
+    journal_call("confirm_pickup");
+    let invalid = || Error::InvalidOperation {
+        msg: "This is not a valid pickup code.".to_string(),
+    };
+    let mut parts = qr.trim().splitn(3, ':');
+    if parts.next() != Some(PICKUP_QR_PREFIX) {
+        return Err(invalid());
+    }
+    let order_id: u64 = parts
+        .next()
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(invalid)?;
+    let order = _get_order(&order_id).ok_or_else(invalid)?;
+    if parts.next().map(str::to_lowercase) != Some(pickup_code(&order)) {
+        return Err(invalid());
+    }
+    if order
+        .schedule
+        .as_ref()
+        .is_some_and(|schedule| schedule.delivery_address.is_some())
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!("Order with id={} is for delivery, not pickup", order_id),
+        });
+    }
+    do_update_order_status(order_id, OrderStatus::PickedUp, caller())
+}
+
+// Function to check the invariants that hold across records: stock kept at back-of-house
+// locations and held by reservations within the product's quantity, order totals, line units,
+// refunds and tenders adding up, the order number index, returns and disputes within their
+// order, the ledger's account totals matching its journal, counters ahead of the keys they
+// issued and the secondary indexes matching their records
+fn run_invariant_checks(now: u64) -> InvariantReport {
+    let mut records_checked: u64 = 0;
+    let mut violations: Vec<InvariantViolation> = Vec::new();
+    let mut violate = |rule: &str, record: String, detail: String| {
+        violations.push(InvariantViolation {
+            rule: rule.to_string(),
+            record,
+            detail,
+        });
+    };
+
+    let reserved = reserved_quantities();
+    let quantities: HashMap<u64, u32> = STORAGE.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(id, product)| (id, product.quantity))
+            .collect()
+    });
+    records_checked += quantities.len() as u64;
+    for (id, quantity) in &quantities {
+        let back: u32 = Location::BACK_OF_HOUSE
+            .iter()
+            .map(|location| back_location_quantity(*id, *location))
+            .sum();
+        if back > *quantity {
+            violate(
+                "locations_within_stock",
+                format!("product {}", id),
+                format!(
+                    "{} units at back-of-house locations, {} in stock",
+                    back, quantity
+                ),
+            );
+        }
+        let held = reserved.get(id).copied().unwrap_or(0);
+        if held > *quantity {
+            violate(
+                "reserved_within_stock",
+                format!("product {}", id),
+                format!("{} units reserved, {} in stock", held, quantity),
+            );
+        }
+    }
+    LOCATION_STOCK.with(|service| {
+        for ((product_id, _), units) in service.borrow().iter() {
+            if units > 0 && !quantities.contains_key(&product_id) {
+                violate(
+                    "locations_within_stock",
+                    format!("product {}", product_id),
+                    format!("{} units at a location of a missing product", units),
+                );
+            }
+        }
+    });
+    RESERVATIONS.with(|service| {
+        for (id, reservation) in service.borrow().iter() {
+            records_checked += 1;
+            if !quantities.contains_key(&reservation.product_id) {
+                violate(
+                    "reservation_product",
+                    format!("reservation {}", id),
+                    format!("Holds missing product {}", reservation.product_id),
+                );
+            }
+        }
+    });
+
+    let mut status_counts: HashMap<u8, u64> = HashMap::new();
+    let order_totals: HashMap<u64, (u64, Option<String>)> = ORDERS.with(|service| {
+        let mut totals = HashMap::new();
+        for (id, order) in service.borrow().iter() {
+            records_checked += 1;
+            *status_counts.entry(order.status.code()).or_default() += 1;
+            let record = format!("order {}", id);
+            if order.subtotal.checked_sub(order.discount) != Some(order.total) {
+                violate(
+                    "order_total",
+                    record.clone(),
+                    format!(
+                        "Total {} is not the subtotal {} less the discount {}",
+                        order.total, order.subtotal, order.discount
+                    ),
+                );
+            }
+            if order.refund_due.is_some_and(|refund| refund > order.total) {
+                violate(
+                    "order_refund",
+                    record.clone(),
+                    format!(
+                        "Refund due {} is more than the total {}",
+                        order.refund_due.unwrap_or(0),
+                        order.total
+                    ),
+                );
+            }
+            for line in &order.lines {
+                let accounted =
+                    line.fulfilled.unwrap_or(0) as u64 + line.unfulfillable.unwrap_or(0) as u64;
+                if accounted > line.quantity as u64 {
+                    violate(
+                        "order_line_units",
+                        record.clone(),
+                        format!(
+                            "Product {} has {} units fulfilled or unfulfillable out of {}",
+                            line.product_id, accounted, line.quantity
+                        ),
+                    );
+                }
+            }
+            if let Some(split) = &order.tenders {
+                let paid: u64 = split.tenders.iter().map(|tender| tender.value).sum();
+                if paid != order.total
+                    || split.refunded > order.total
+                    || split
+                        .tenders
+                        .iter()
+                        .any(|tender| tender.refunded_units > tender.units)
+                {
+                    violate(
+                        "order_tenders",
+                        record.clone(),
+                        format!(
+                            "Tenders pay {} and refunded {} of a total of {}",
+                            paid, split.refunded, order.total
+                        ),
+                    );
+                }
+            }
+            totals.insert(id, (order.total, order.order_number));
+        }
+        totals
+    });
+    ORDER_NUMBERS.with(|service| {
+        for (number, order_id) in service.borrow().iter() {
+            records_checked += 1;
+            let indexed = order_totals
+                .get(&order_id)
+                .and_then(|(_, number)| number.as_deref())
+                .map(OrderNumber::normalize);
+            if indexed.as_ref().map(|indexed| &indexed.0) != Some(&number.0) {
+                violate(
+                    "order_number_index",
+                    format!("order number {}", number.0),
+                    format!(
+                        "Points at order {}, which has another number or none",
+                        order_id
+                    ),
+                );
+            }
+        }
+    });
+    RETURNS.with(|service| {
+        for (id, request) in service.borrow().iter() {
+            records_checked += 1;
+            match order_totals.get(&request.order_id) {
+                None => violate(
+                    "return_within_order",
+                    format!("return {}", id),
+                    format!("Returns missing order {}", request.order_id),
+                ),
+                Some((total, _)) if request.refund_amount.unwrap_or(0) > *total => violate(
+                    "return_within_order",
+                    format!("return {}", id),
+                    format!(
+                        "Refunded {} of an order total of {}",
+                        request.refund_amount.unwrap_or(0),
+                        total
+                    ),
+                ),
+                _ => {}
+            }
+        }
+    });
+    DISPUTES.with(|service| {
+        for (id, dispute) in service.borrow().iter() {
+            records_checked += 1;
+            let total = order_totals.get(&dispute.order_id).map(|(total, _)| *total);
+            if total.is_none_or(|total| {
+                dispute.amount > total || dispute.refund_amount.unwrap_or(0) > dispute.amount
+            }) {
+                violate(
+                    "dispute_within_order",
+                    format!("dispute {}", id),
+                    format!(
+                        "Disputes {} (refunded {}) of order {} with a total of {:?}",
+                        dispute.amount,
+                        dispute.refund_amount.unwrap_or(0),
+                        dispute.order_id,
+                        total
+                    ),
+                );
+            }
+        }
+    });
+
+    for status in OrderStatus::ALL {
+        let kept = ORDER_STATUS_COUNTS
+            .with(|service| service.borrow().get(&status.code()))
+            .unwrap_or(0);
+        let counted = status_counts.get(&status.code()).copied().unwrap_or(0);
+        if kept != counted {
+            violate(
+                "order_status_counts",
+                format!("status {:?}", status),
+                format!("Count is {} but {} orders are {:?}", kept, counted, status),
+            );
+        }
+    }
+
+    let mut journal_totals: HashMap<u8, (u64, u64)> = HashMap::new();
+    JOURNAL.with(|service| {
+        for (_, entry) in service.borrow().iter() {
+            records_checked += 1;
+            for posting in &entry.postings {
+                let totals = journal_totals.entry(posting.account.code()).or_default();
+                totals.0 += posting.debit;
+                totals.1 += posting.credit;
+            }
+        }
+    });
+    for account in LedgerAccount::ALL {
+        let kept = ACCOUNT_TOTALS
+            .with(|service| service.borrow().get(&account.code()))
+            .unwrap_or_default();
+        let posted = journal_totals
+            .get(&account.code())
+            .copied()
+            .unwrap_or_default();
+        if (kept.debits, kept.credits) != posted {
+            violate(
+                "account_totals",
+                format!("account {:?}", account),
+                format!(
+                    "Totals are {}/{} but the journal posts {}/{}",
+                    kept.debits, kept.credits, posted.0, posted.1
+                ),
+            );
+        }
+    }
+
+    let sequences = [
+        (
+            "notification",
+            NOTIFICATION_SEQ.with(|counter| *counter.borrow().get()),
+            NOTIFICATIONS.with(|service| service.borrow().iter().map(|((_, id), _)| id).max()),
+        ),
+        (
+            "product version",
+            PRODUCT_VERSION_SEQ.with(|counter| *counter.borrow().get()),
+            PRODUCT_VERSIONS.with(|service| service.borrow().iter().map(|((_, seq), _)| seq).max()),
+        ),
+        (
+            "waitlist",
+            WAITLIST_SEQ.with(|counter| *counter.borrow().get()),
+            WAITLIST.with(|service| service.borrow().iter().map(|((_, seq), _)| seq).max()),
+        ),
+        (
+            "stock movement",
+            STOCK_MOVEMENT_SEQ.with(|counter| *counter.borrow().get()),
+            STOCK_MOVEMENTS.with(|service| service.borrow().iter().map(|((_, seq), _)| seq).max()),
+        ),
+    ];
+    for (name, counter, issued) in sequences {
+        if let Some(issued) = issued.filter(|issued| *issued >= counter) {
+            violate(
+                "counter_ahead",
+                format!("{} counter", name),
+                format!(
+                    "Counter is at {} but id {} was already issued",
+                    counter, issued
+                ),
+            );
+        }
+    }
+    for kind in IndexKind::ALL {
+        let report = verify_index(kind);
+        records_checked += report.entries;
+        if report.missing + report.orphaned > 0 {
+            violate(
+                "index_entries",
+                format!("index {:?}", kind),
+                format!(
+                    "{} missing and {} orphaned entries: {}",
+                    report.missing,
+                    report.orphaned,
+                    report.samples.join("; ")
+                ),
+            );
+        }
+    }
+
+    let mut by_rule: Vec<HealthCount> = Vec::new();
+    for violation in &violations {
+        match by_rule
+            .iter_mut()
+            .find(|count| count.name == violation.rule)
+        {
+            Some(count) => count.count += 1,
+            None => by_rule.push(health_count(&violation.rule, 1)),
+        }
+    }
+    let count = violations.len() as u64;
+    violations.truncate(MAX_HEALTH_ISSUES);
+    InvariantReport {
+        checked_at: now,
+        records_checked,
+        violations: count,
+        by_rule,
+        samples: violations,
+    }
+}
+
+// Function to run the invariant check once a day from the expiry sweep, keep its report and
+// message the alert channels when it finds violations
+fn check_invariants_nightly() {
+    let now = time();
+    let state = INVARIANT_STATE.with(|cell| cell.borrow().get().clone());
+    if state
+        .last
+        .as_ref()
+        .is_some_and(|last| last.checked_at / NANOS_PER_DAY == now / NANOS_PER_DAY)
+    {
+        return;
+    }
+    let report = run_invariant_checks(now);
+    let mut alerted_channels = 0;
+    if report.violations > 0 {
+        ic_cdk::println!("Invariant check found {} violations", report.violations);
+        let rules: Vec<String> = report
+            .by_rule
+            .iter()
+            .map(|count| format!("{} ({})", count.name, count.count))
+            .collect();
+        let text = format!(
+            "Invariant check found {} violations: {}",
+            report.violations,
+            rules.join(", ")
+        );
+        let webhook = serde_json::json!({
+            "event": "InvariantViolations",
+            "violations": report.violations,
+            "rules": rules,
+            "text": text,
+        });
+        alerted_channels = post_alert(text, webhook, format!("invariants-{}", now));
+    }
+    let state = InvariantState {
+        last: Some(report),
+        alerted_channels,
+    };
+    INVARIANT_STATE.with(|cell| {
+        cell.borrow_mut()
+            .set(state)
+            .expect("Cannot store the invariant check")
+    });
+}
+
+// Helper function to post an alert to every enabled channel that takes alerts, returning how
+// many it was posted to
+fn post_alert(text: String, webhook: serde_json::Value, idempotency_key: String) -> u32 {
+    let channels: Vec<NotifierChannel> = NOTIFIER_CHANNELS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, channel)| channel)
+            .filter(|channel| channel.enabled && channel.alerts == Some(true))
+            .collect()
+    });
+    let mut alerted = 0;
+    for channel in channels {
+        let request = channel_post(
+            &channel,
+            text.clone(),
+            webhook.clone(),
+            idempotency_key.clone(),
+        );
+        ic_cdk::spawn(async move {
+            let _ = post_to_channel(channel.id, request).await;
+        });
+        alerted += 1;
+    }
+    alerted
+}
+
+// Query function to check the cross-record invariants now
+#[ic_cdk::query]
+fn check_invariants() -> Result<InvariantReport, Error> {
+    ensure_admin()?;
+    Ok(run_invariant_checks(time()))
+}
+
+// Query function to get the report of the last nightly invariant check
+#[ic_cdk::query]
+fn get_invariant_state() -> Result<InvariantState, Error> {
+    ensure_admin()?;
+    Ok(INVARIANT_STATE.with(|cell| cell.borrow().get().clone()))
+}
+
+// Helper function to list the shards of a collection with their sizes
+fn collection_shards<K, V>(map: &ShardedMap<K, V>) -> Vec<StorageShard>
+where
+    K: BoundedStorable + Ord + Clone,
+    V: BoundedStorable,
+{
+    let code = map.collection.code();
+    let opened: Vec<ShardInfo> = SHARD_DIRECTORY.with(|service| {
+        service
+            .borrow()
+            .range((code, 0)..=(code, u8::MAX))
+            .map(|(_, shard)| shard)
+            .collect()
+    });
+    map.shards
+        .iter()
+        .enumerate()
+        .map(|(index, shard)| {
+            let info = index.checked_sub(1).and_then(|index| opened.get(index));
+            let memory_id = info.map_or(map.collection.first_memory(), |info| info.memory_id);
+            let memory = MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(memory_id)));
+            StorageShard {
+                collection: map.collection,
+                index: index as u8,
+                memory_id,
+                entries: shard.len(),
+                size_pages: ic_stable_structures::Memory::size(&memory),
+                opened_at: info.map(|info| info.opened_at),
+                active: index + 1 == map.shards.len(),
+            }
+        })
+        .collect()
+}
+
+// Query function to list the storage shards of the large collections with their entries and
+// the stable memory they use
+#[ic_cdk::query]
+fn list_storage_shards() -> Result<Vec<StorageShard>, Error> {
+    ensure_admin()?;
+    let mut shards = ORDERS.with(|service| collection_shards(&service.borrow()));
+    shards.extend(SALE_EVENTS.with(|service| collection_shards(&service.borrow())));
+    shards.extend(STOCK_MOVEMENTS.with(|service| collection_shards(&service.borrow())));
+    Ok(shards)
+}
+
+// Function to open the next storage shard of a collection before its current one is full, e.g.
+// to start a fresh memory region for a new season; existing entries stay where they are
+#[ic_cdk::update]
+fn open_storage_shard(collection: ShardedCollection) -> Result<Vec<StorageShard>, Error> {
+    ensure_admin()?;
+    journal_call("open_storage_shard");
+    let shards = match collection {
+        ShardedCollection::Orders => ORDERS.with(|service| {
+            service.borrow_mut().open_shard()?;
+            Ok(collection_shards(&service.borrow()))
+        }),
+        ShardedCollection::SaleEvents => SALE_EVENTS.with(|service| {
+            service.borrow_mut().open_shard()?;
+            Ok(collection_shards(&service.borrow()))
+        }),
+        ShardedCollection::StockMovements => STOCK_MOVEMENTS.with(|service| {
+            service.borrow_mut().open_shard()?;
+            Ok(collection_shards(&service.borrow()))
+        }),
+    };
+    shards
+}
+
+// Helper function to check whether an order is held for staff review
+fn is_held(order_id: u64) -> bool {
+    ORDER_REVIEWS.with(|service| {
+        service
+            .borrow()
+            .get(&order_id)
+            .is_some_and(|review| review.status == ReviewStatus::Held)
+    })
+}
+
+// Helper function to check whether an order redeems a promotion, points or store credit
+fn redeems_rewards(order: &Order) -> bool {
+    order.promotion_id.is_some()
+        || order.tenders.as_ref().is_some_and(|split| {
+            split
+                .tenders
+                .iter()
+                .any(|tender| matches!(tender.tender, Tender::Points | Tender::StoreCredit))
+        })
+}
+
+// Function to screen a new order against the fraud rules. The customer's recent orders are
+// counted first, so an order over a limit is never refused: it is flagged for staff review, or
+// held in Placed until staff clear it, and the alert channels are told
+fn screen_order(order: &Order) {
+    let Some(rules) = CONFIG.with(|config| config.borrow().get().fraud_rules) else {
+        return;
+    };
+    let now = order.created_at;
+    let key = PrincipalKey(order.customer);
+    let mut velocity = ORDER_VELOCITY
+        .with(|service| service.borrow().get(&key))
+        .unwrap_or_default();
+    velocity
+        .recent_orders
+        .retain(|at| now.saturating_sub(*at) < NANOS_PER_HOUR);
+    velocity.recent_orders.push(now);
+    if velocity.recent_orders.len() > MAX_VELOCITY_ORDERS {
+        velocity.recent_orders.remove(0);
+    }
+    let today = now / NANOS_PER_DAY;
+    if velocity.day != today {
+        velocity.day = today;
+        velocity.value_today = 0;
+        velocity.redemptions_today = 0;
+    }
+    velocity.value_today = velocity.value_today.saturating_add(order.total);
+    let redeemed = redeems_rewards(order);
+    if redeemed {
+        velocity.redemptions_today += 1;
+    }
+
+    let mut signals = Vec::new();
+    if let Some(limit) = rules.max_orders_per_hour {
+        let orders = velocity.recent_orders.len() as u32;
+        if orders > limit {
+            signals.push(FraudSignal::OrderRate { orders, limit });
+        }
+    }
+    if let Some(limit) = rules.new_account_daily_value {
+        let first_order_at =
+            _get_customer(&order.customer).map_or(now, |customer| customer.first_order_at);
+        let new_account =
+            now.saturating_sub(first_order_at) < rules.new_account_days as u64 * NANOS_PER_DAY;
+        if new_account && velocity.value_today > limit {
+            signals.push(FraudSignal::NewAccountValue {
+                value: velocity.value_today,
+                limit,
+            });
+        }
+    }
+    if let Some(limit) = rules.max_redemptions_per_day {
+        if redeemed && velocity.redemptions_today > limit {
+            signals.push(FraudSignal::RedemptionRate {
+                redemptions: velocity.redemptions_today,
+                limit,
+            });
+        }
+    }
+    ORDER_VELOCITY.with(|service| service.borrow_mut().insert(key, velocity));
+    if signals.is_empty() {
+        return;
+    }
+
+    let status = match rules.action {
+        FraudAction::Flag => ReviewStatus::Flagged,
+        FraudAction::Hold => ReviewStatus::Held,
+    };
+    let review = OrderReview {
+        order_id: order.id,
+        customer: order.customer,
+        total: order.total,
+        signals,
+        status,
+        flagged_at: now,
+        reviewed_by: None,
+        reviewed_at: None,
+        note: None,
+    };
+    ORDER_REVIEWS.with(|service| service.borrow_mut().insert(order.id, review));
+    let text = format!(
+        "Order #{} was {} for review by the fraud rules.",
+        order.id,
+        if status == ReviewStatus::Held {
+            "held"
+        } else {
+            "flagged"
+        }
+    );
+    let webhook = serde_json::json!({
+        "event": "OrderReview",
+        "order_id": order.id,
+        "status": format!("{:?}", status),
+        "text": text,
+    });
+    post_alert(text, webhook, format!("order-review-{}", order.id));
+}
+
+// Function to set the heuristics orders are screened against, or with none stop screening
+#[ic_cdk::update]
+fn set_fraud_rules(rules: Option<FraudRules>) -> Result<Option<FraudRules>, Error> {
+    ensure_admin()?;
+    journal_call("set_fraud_rules");
+    if rules
+        .and_then(|rules| rules.max_orders_per_hour)
+        .is_some_and(|limit| limit as usize >= MAX_VELOCITY_ORDERS)
+    {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "The hourly order limit must be below {}.",
+                MAX_VELOCITY_ORDERS
+            ),
+        });
+    }
+    update_config(|config| config.fraud_rules = rules)?;
+    Ok(rules)
+}
+
+// Query function to get the heuristics orders are screened against
+#[ic_cdk::query]
+fn get_fraud_rules() -> Result<Option<FraudRules>, Error> {
+    ensure_admin()?;
+    Ok(CONFIG.with(|config| config.borrow().get().fraud_rules))
+}
+
+// Query function to list the orders the fraud rules flagged or held, newest first
+#[ic_cdk::query]
+fn list_order_reviews(status: Option<ReviewStatus>) -> Result<Vec<OrderReview>, Error> {
+    ensure_viewer()?;
+    let mut reviews: Vec<OrderReview> = ORDER_REVIEWS.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, review)| review)
+            .filter(|review| status.is_none_or(|status| review.status == status))
+            .collect()
+    });
+    reviews.sort_by_key(|review| std::cmp::Reverse((review.flagged_at, review.order_id)));
+    Ok(reviews)
+}
+
+// Query function to get the review of an order the fraud rules flagged or held
+#[ic_cdk::query]
+fn get_order_review(order_id: u64) -> Result<OrderReview, Error> {
+    ensure_viewer()?;
+    ORDER_REVIEWS
+        .with(|service| service.borrow().get(&order_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Order id={} was not flagged for review", order_id),
+        })
+}
+
+// Function to decide on a flagged or held order: cleared orders move on as usual, and rejected
+// ones are cancelled and refunded like any other cancellation
+#[ic_cdk::update]
+fn review_order(
+    order_id: u64,
+    decision: ReviewDecision,
+    note: Option<String>,
+) -> Result<OrderReview, Error> {
+    ensure_admin()?;
+    journal_call("review_order");
+    validate_notes(&note)?;
+    let mut review = ORDER_REVIEWS
+        .with(|service| service.borrow().get(&order_id))
+        .ok_or(Error::NotFound {
+            msg: format!("Order id={} was not flagged for review", order_id),
+        })?;
+    if !matches!(review.status, ReviewStatus::Flagged | ReviewStatus::Held) {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Order id={} was already reviewed ({:?})",
+                order_id, review.status
+            ),
+        });
+    }
+    if let ReviewDecision::Reject = decision {
+        let cancelled =
+            _get_order(&order_id).is_some_and(|order| order.status == OrderStatus::Cancelled);
+        if !cancelled {
+            do_update_order_status(order_id, OrderStatus::Cancelled, caller())?;
+        }
+    }
+    review.status = match decision {
+        ReviewDecision::Clear => ReviewStatus::Cleared,
+        ReviewDecision::Reject => ReviewStatus::Rejected,
+    };
+    review.reviewed_by = Some(caller());
+    review.reviewed_at = Some(time());
+    review.note = note;
+    ORDER_REVIEWS.with(|service| service.borrow_mut().insert(order_id, review.clone()));
+    Ok(review)
+}
+
+// Helper function to get the deprecation notice of a public API version, if any
+fn api_deprecation(version: u32) -> Option<ApiDeprecation> {
+    CONFIG.with(|config| {
+        config
+            .borrow()
+            .get()
+            .public_api_deprecations
+            .iter()
+            .flatten()
+            .find(|deprecation| deprecation.version == version)
+            .cloned()
+    })
+}
+
+// Function to deprecate a public API version, announcing when it stops being served and what
+// replaces it, or with none withdraw the notice
+#[ic_cdk::update]
+fn set_public_api_deprecation(
+    version: u32,
+    payload: Option<ApiDeprecationPayload>,
+) -> Result<Option<ApiDeprecation>, Error> {
+    ensure_admin()?;
+    journal_call("set_public_api_deprecation");
+    if !PUBLIC_API_VERSIONS.contains(&version) {
+        return Err(Error::NotFound {
+            msg: format!("Public API version {} is not served", version),
+        });
+    }
+    let now = time();
+    let deprecation = match payload {
+        Some(payload) => {
+            validate_notes(&payload.note)?;
+            if payload
+                .successor
+                .as_ref()
+                .is_some_and(|successor| successor.len() > MAX_LABEL_LENGTH)
+            {
+                return Err(Error::InvalidOperation {
+                    msg: format!(
+                        "A successor can be at most {} characters long.",
+                        MAX_LABEL_LENGTH
+                    ),
+                });
+            }
+            if payload.sunset_at.is_some_and(|sunset_at| sunset_at <= now) {
+                return Err(Error::InvalidOperation {
+                    msg: "A sunset must be in the future.".to_string(),
+                });
+            }
+            Some(ApiDeprecation {
+                version,
+                // A notice that is only updated keeps the time it was first announced
+                deprecated_at: api_deprecation(version)
+                    .map_or(now, |deprecation| deprecation.deprecated_at),
+                sunset_at: payload.sunset_at,
+                successor: payload.successor,
+                note: payload.note,
+            })
+        }
+        None => None,
+    };
+    update_config(|config| {
+        let deprecations = config.public_api_deprecations.get_or_insert_with(Vec::new);
+        deprecations.retain(|notice| notice.version != version);
+        deprecations.extend(deprecation.clone());
+    })?;
+    Ok(deprecation)
+}
+
+// Helper function to build the metadata sent with every v1 response
+fn api_v1_meta() -> ApiV1Meta {
+    ApiV1Meta {
+        version: 1,
+        revision: API_V1_REVISION,
+        deprecation: api_deprecation(1),
+    }
+}
+
+// Helper function to convert a published product into its v1 schema
+fn api_v1_product(product: &Product, reserved: u32) -> ApiV1Product {
+    ApiV1Product {
+        id: product.id,
+        name: product.name.clone(),
+        category: format!("{:?}", product.category),
+        price: product.price(),
+        tags: product.tags().to_vec(),
+        allergens: product.allergens().to_vec(),
+        age_restricted: is_age_restricted(product),
+        available: availability_of(product, reserved).available.max(0) as u32,
+        min_order_qty: product.min_order_qty,
+        max_order_qty: product.max_order_qty,
+        order_qty_step: product.order_qty_step,
+        prep_minutes: product.prep_minutes,
+        updated_at: product.updated_at.unwrap_or(product.created_at),
+    }
+}
+
+// Query function to describe the v1 public API: its revision, methods and deprecation status
+#[ic_cdk::query]
+fn api_v1_info() -> ApiV1Info {
+    ApiV1Info {
+        meta: api_v1_meta(),
+        methods: API_V1_METHODS
+            .iter()
+            .map(|method| method.to_string())
+            .collect(),
+        http_prefix: "/api/v1".to_string(),
+        versions: PUBLIC_API_VERSIONS.to_vec(),
+    }
+}
+
+// Query function to tell aggregators whether the shop takes orders and payments
+#[ic_cdk::query]
+fn api_v1_shop_status() -> ApiV1ShopStatus {
+    let maintenance = get_maintenance_mode();
+    let pause = payments_pause();
+    ApiV1ShopStatus {
+        meta: api_v1_meta(),
+        open: maintenance.is_none(),
+        accepting_payments: maintenance.is_none() && pause.is_none(),
+        reopens_at: maintenance.as_ref().and_then(|maintenance| maintenance.eta),
+        message: maintenance
+            .map(|maintenance| maintenance.message)
+            .or(pause.map(|pause| pause.message)),
+    }
+}
+
+// Query function to list one page of the published, listed products in the v1 schema
+#[ic_cdk::query]
+fn api_v1_list_products(cursor: Option<u64>, limit: Option<u32>) -> ApiV1ProductPage {
+    let page = PageRequest { cursor, limit };
+    let now = time();
+    let reserved = reserved_quantities();
+    STORAGE.with(|service| {
+        let storage = service.borrow();
+        let products = storage
+            .range(page_start(&page))
+            .map(|(id, product)| (id, with_effective_status(product, now)))
+            .filter(|(_, product)| is_listed(product, false))
+            .map(|(id, product)| {
+                let reserved = reserved.get(&id).copied().unwrap_or(0);
+                (id, api_v1_product(&product, reserved))
+            });
+        let (products, next_cursor) = take_page(products, &page);
+        ApiV1ProductPage {
+            meta: api_v1_meta(),
+            products,
+            next_cursor,
+        }
+    })
+}
+
+// Query function to get a published product in the v1 schema; unlisted products are found by id
+#[ic_cdk::query]
+fn api_v1_get_product(id: u64) -> ApiV1ProductResponse {
+    let product = _get_product(&id)
+        .map(|product| with_effective_status(product, time()))
+        .filter(|product| is_visible(product, false))
+        .map(|product| {
+            let reserved = reserved_quantities().get(&id).copied().unwrap_or(0);
+            api_v1_product(&product, reserved)
+        });
+    ApiV1ProductResponse {
+        meta: api_v1_meta(),
+        product,
+    }
+}
+
+// Helper function to read a parameter from the query string of a URL
+fn query_param<'a>(url: &'a str, name: &str) -> Option<&'a str> {
+    let query = url.split('#').next()?.split_once('?')?.1;
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+// Helper function to format a time as an HTTP date, e.g. Sun, 06 Nov 1994 08:49:37 GMT
+fn http_date(at: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let day = at / NANOS_PER_DAY;
+    let (year, month, day_of_month) = civil_date(day);
+    let seconds = (at % NANOS_PER_DAY) / 1_000_000_000;
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(day % 7) as usize],
+        day_of_month,
+        MONTHS[(month - 1) as usize],
+        year,
+        seconds / 3_600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+// Function to answer the v1 public API over HTTP as JSON, with the same schema as the query
+// methods; a deprecated version also says so in Deprecation and Sunset headers
+fn route_api_v1(request: &HttpRequest, path: &str) -> HttpResponse {
+    let body = match path.trim_end_matches('/') {
+        "" => serde_json::to_vec(&api_v1_info()),
+        "/shop" => serde_json::to_vec(&api_v1_shop_status()),
+        "/products" => {
+            let cursor = query_param(&request.url, "cursor").and_then(|value| value.parse().ok());
+            let limit = query_param(&request.url, "limit").and_then(|value| value.parse().ok());
+            serde_json::to_vec(&api_v1_list_products(cursor, limit))
+        }
+        other => match other
+            .strip_prefix("/products/")
+            .and_then(|id| id.parse::<u64>().ok())
+            .map(api_v1_get_product)
+        {
+            Some(response) if response.product.is_some() => serde_json::to_vec(&response),
+            _ => {
+                return HttpResponse {
+                    status_code: 404,
+                    headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+                    body: br#"{"error":"not_found"}"#.to_vec(),
+                }
+            }
+        },
+    };
+    let mut headers = vec![("Content-Type".to_string(), "application/json".to_string())];
+    if let Some(deprecation) = api_deprecation(1) {
+        headers.push((
+            "Deprecation".to_string(),
+            format!("@{}", deprecation.deprecated_at / 1_000_000_000),
+        ));
+        if let Some(sunset_at) = deprecation.sunset_at {
+            headers.push(("Sunset".to_string(), http_date(sunset_at)));
+        }
+    }
+    HttpResponse {
+        status_code: 200,
+        headers,
+        body: body.unwrap_or_default(),
+    }
+}
+
+// Helper function to get the earliest time a scheduled price change is due
+fn next_price_change_at() -> Option<u64> {
+    SCHEDULED_PRICE_CHANGES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, change)| change)
+            .filter(|change| {
+                matches!(
+                    change.status,
+                    ScheduledPriceStatus::Scheduled | ScheduledPriceStatus::AwaitingApproval
+                )
+            })
+            .map(|change| change.effective_at)
+            .min()
+    })
+}
+
+// Function to apply the scheduled price changes that are due, in the order they were due.
+// A large change not approved by its time lapses, as does one whose product was removed
+fn apply_due_price_changes(now: u64) {
+    let mut due: Vec<ScheduledPriceChange> = SCHEDULED_PRICE_CHANGES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, change)| change)
+            .filter(|change| {
+                change.effective_at <= now
+                    && matches!(
+                        change.status,
+                        ScheduledPriceStatus::Scheduled | ScheduledPriceStatus::AwaitingApproval
+                    )
+            })
+            .collect()
+    });
+    due.sort_by_key(|change| (change.effective_at, change.id));
+    for mut change in due {
+        let product = _get_product(&change.product_id);
+        let lapse_reason = match &product {
+            None => Some("The product was removed.".to_string()),
+            Some(product)
+                if change.approved_by.is_none()
+                    && is_large_price_change(product.price(), change.new_price) =>
+            {
+                Some("A second admin did not approve the change in time.".to_string())
+            }
+            Some(_) => None,
+        };
+        match (product, lapse_reason) {
+            (Some(mut product), None) => {
+                if product.price() != change.new_price {
+                    record_price_change_by(
+                        product.id,
+                        product.price(),
+                        change.new_price,
+                        change.scheduled_by,
+                    );
+                    product.price = Some(change.new_price);
+                    product.updated_at = Some(now);
+                    do_insert(&product);
+                }
+                change.status = ScheduledPriceStatus::Applied;
+            }
+            (_, reason) => {
+                change.status = ScheduledPriceStatus::Lapsed;
+                change.lapse_reason = reason;
+            }
+        }
+        change.closed_at = Some(now);
+        SCHEDULED_PRICE_CHANGES.with(|service| service.borrow_mut().insert(change.id, change));
+    }
+}
+
+// Helper function to get a scheduled price change that has not been applied yet
+fn open_price_change(id: u64) -> Result<ScheduledPriceChange, Error> {
+    let change = SCHEDULED_PRICE_CHANGES
+        .with(|service| service.borrow().get(&id))
+        .ok_or(Error::NotFound {
+            msg: format!("A scheduled price change with id={} was not found", id),
+        })?;
+    if !matches!(
+        change.status,
+        ScheduledPriceStatus::Scheduled | ScheduledPriceStatus::AwaitingApproval
+    ) {
+        return Err(Error::InvalidOperation {
+            msg: format!("Price change id={} is {:?}", id, change.status),
+        });
+    }
+    Ok(change)
+}
+
+// Function to schedule a product's price to change at a future time, applied by the timer.
+// Changes of more than 25% wait for a second admin's approval, like immediate ones
+#[ic_cdk::update]
+fn set_price(
+    product_id: u64,
+    new_price: u64,
+    effective_at: u64,
+) -> Result<ScheduledPriceChange, Error> {
+    ensure_admin()?;
+    journal_call("set_price");
+    let product = _get_product(&product_id).ok_or(Error::NotFound {
+        msg: format!("A product with id={} was not found", product_id),
+    })?;
+    let now = time();
+    if effective_at <= now || effective_at > now.saturating_add(MAX_PRICE_SCHEDULE_NS) {
+        return Err(Error::InvalidOperation {
+            msg: "A price change must take effect in the future, within a year. Use \
+                  update_product to change a price now."
+                .to_string(),
+        });
+    }
+    let taken = SCHEDULED_PRICE_CHANGES.with(|service| {
+        service.borrow().iter().any(|(_, change)| {
+            change.product_id == product_id
+                && change.effective_at == effective_at
+                && matches!(
+                    change.status,
+                    ScheduledPriceStatus::Scheduled | ScheduledPriceStatus::AwaitingApproval
+                )
+        })
+    });
+    if taken {
+        return Err(Error::Other {
+            kind: "Conflict".to_string(),
+            msg: format!(
+                "A price change of product id={} is already scheduled for that time",
+                product_id
+            ),
+        });
+    }
+    let id = generate_unique_id(|id| {
+        SCHEDULED_PRICE_CHANGES.with(|service| service.borrow().contains_key(&id))
+    })?;
+    let change = ScheduledPriceChange {
+        id,
+        product_id,
+        old_price: product.price(),
+        new_price,
+        effective_at,
+        scheduled_by: caller(),
+        scheduled_at: now,
+        status: if is_large_price_change(product.price(), new_price) {
+            ScheduledPriceStatus::AwaitingApproval
+        } else {
+            ScheduledPriceStatus::Scheduled
+        },
+        approved_by: None,
+        closed_at: None,
+        lapse_reason: None,
+    };
+    SCHEDULED_PRICE_CHANGES.with(|service| service.borrow_mut().insert(id, change.clone()));
+    schedule_next_job();
+    Ok(change)
+}
+
+// Function to approve a large scheduled price change requested by another admin
+#[ic_cdk::update]
+fn approve_price_change(id: u64) -> Result<ScheduledPriceChange, Error> {
+    ensure_admin()?;
+    journal_call("approve_price_change");
+    let mut change = open_price_change(id)?;
+    if change.status != ScheduledPriceStatus::AwaitingApproval {
+        return Err(Error::InvalidOperation {
+            msg: format!("Price change id={} does not need an approval", id),
+        });
+    }
+    if change.scheduled_by == caller() {
+        return Err(Error::Unauthorized {
+            msg: "A price change must be approved by a different admin.".to_string(),
+        });
+    }
+    change.status = ScheduledPriceStatus::Scheduled;
+    change.approved_by = Some(caller());
+    SCHEDULED_PRICE_CHANGES.with(|service| service.borrow_mut().insert(id, change.clone()));
+    Ok(change)
+}
+
+// Function to cancel a scheduled price change before it applies
+#[ic_cdk::update]
+fn cancel_price_change(id: u64) -> Result<ScheduledPriceChange, Error> {
+    ensure_admin()?;
+    journal_call("cancel_price_change");
+    let mut change = open_price_change(id)?;
+    change.status = ScheduledPriceStatus::Cancelled;
+    change.closed_at = Some(time());
+    SCHEDULED_PRICE_CHANGES.with(|service| service.borrow_mut().insert(id, change.clone()));
+    schedule_next_job();
+    Ok(change)
+}
+
+// Query function to list the price changes still to apply, optionally of one product, soonest
+// first
+#[ic_cdk::query]
+fn list_upcoming_price_changes(
+    product_id: Option<u64>,
+) -> Result<Vec<ScheduledPriceChange>, Error> {
+    ensure_viewer()?;
+    let mut changes: Vec<ScheduledPriceChange> = SCHEDULED_PRICE_CHANGES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, change)| change)
+            .filter(|change| {
+                matches!(
+                    change.status,
+                    ScheduledPriceStatus::Scheduled | ScheduledPriceStatus::AwaitingApproval
+                ) && product_id.is_none_or(|product_id| change.product_id == product_id)
+            })
+            .collect()
+    });
+    changes.sort_by_key(|change| (change.effective_at, change.id));
+    Ok(changes)
+}
+
+// Query function to count the products matching a filter, without listing them; callers who
+// are not admins count the published, listed products only
+#[ic_cdk::query]
+fn count_products(filter: ProductCountFilter) -> u64 {
+    let now = time();
+    let is_admin = ensure_admin().is_ok();
+    let tag = filter.tag.as_deref().map(|tag| tag.trim().to_lowercase());
+    let reserved = filter.in_stock.map(|_| reserved_quantities());
+    let matches = |product: Product| {
+        let product = with_effective_status(product, now);
+        is_listed(&product, is_admin)
+            && filter
+                .category
+                .as_ref()
+                .is_none_or(|category| &product.category == category)
+            && filter
+                .status
+                .is_none_or(|status| product.status() == status)
+            && tag.as_ref().is_none_or(|tag| product.tags().contains(tag))
+            && filter.in_stock.is_none_or(|in_stock| {
+                let held = reserved
+                    .as_ref()
+                    .and_then(|reserved| reserved.get(&product.id).copied())
+                    .unwrap_or(0);
+                (availability_of(&product, held).available > 0) == in_stock
+            })
+    };
+    match &filter.category {
+        // The category index bounds the scan to the category's products
+        Some(category) => category_product_ids(category, None, usize::MAX)
+            .into_iter()
+            .filter_map(|id| _get_product(&id))
+            .map(matches)
+            .filter(|matched| *matched)
+            .count() as u64,
+        None => STORAGE.with(|service| {
+            service
+                .borrow()
+                .iter()
+                .map(|(_, product)| matches(product))
+                .filter(|matched| *matched)
+                .count() as u64
+        }),
+    }
+}
+
+// Query function to count the orders, or those in one status, from the maintained counts
+#[ic_cdk::query]
+fn count_orders(status: Option<OrderStatus>) -> Result<u64, Error> {
+    ensure_viewer()?;
+    Ok(match status {
+        Some(status) => ORDER_STATUS_COUNTS
+            .with(|service| service.borrow().get(&status.code()))
+            .unwrap_or(0),
+        None => ORDERS.with(|service| service.borrow().len()),
+    })
+}
+
+// Query function to count the customers who have ordered
+#[ic_cdk::query]
+fn count_customers() -> Result<u64, Error> {
+    ensure_viewer()?;
+    Ok(CUSTOMERS.with(|service| service.borrow().len()))
+}
 
-    static ID_COUNTER: RefCell<IdCell> = RefCell::new(
-        IdCell::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))), 0)
-            .expect("Cannot create a counter")
-    );
+// Helper function to compute the check character of a code's random characters (given as
+// alphabet indices) with the Luhn mod 32 algorithm, catching any single mistyped character and
+// most swapped neighbours
+fn code_check_index(indices: &[usize]) -> usize {
+    let base = CODE_ALPHABET.len();
+    let sum: usize = indices
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(position, index)| {
+            let addend = if position % 2 == 0 { index * 2 } else { *index };
+            addend / base + addend % base
+        })
+        .sum();
+    (base - sum % base) % base
+}
 
-    static STORAGE: RefCell<StableBTreeMap<u64, Product, Memory>> =
-        RefCell::new(StableBTreeMap::init(
-            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1)))
-    ));
+// Helper function to give the prefix of the codes of a kind
+fn code_prefix(kind: &CodeKind) -> &'static str {
+    match kind {
+        CodeKind::Coupon { .. } => "CP",
+        CodeKind::GiftCard { .. } => "GC",
+    }
 }
 
-// Product payload struct used to create or update a product
-#[derive(candid::CandidType, Serialize, Deserialize, Default)]
-struct ProductPayload {
-    name: String,
-    quantity: u32,
-    category: Category,
+// Helper function to format random characters (as alphabet indices) into a code with its check
+// character, in groups of four
+fn format_code(prefix: &str, indices: &[usize]) -> String {
+    let mut characters: Vec<char> = indices
+        .iter()
+        .map(|index| CODE_ALPHABET[*index] as char)
+        .collect();
+    characters.push(CODE_ALPHABET[code_check_index(indices)] as char);
+    let groups: Vec<String> = characters
+        .chunks(4)
+        .map(|group| group.iter().collect())
+        .collect();
+    format!("{}-{}", prefix, groups.join("-"))
 }
 
-// Payload for adding or removing stock
-#[derive(candid::CandidType, Serialize, Deserialize, Default)]
-struct StockPayload {
-    amount: u32,
+// Helper function to check the format and check character of a code as a customer typed it,
+// before it is looked up; gives the code in its canonical form
+fn parse_code(input: &str) -> Result<CodeKey, Error> {
+    let invalid = || Error::InvalidOperation {
+        msg: "This is not a valid code. Please check it for typos.".to_string(),
+    };
+    let compact: String = input
+        .chars()
+        .filter(|character| !character.is_whitespace() && *character != '-')
+        .map(|character| character.to_ascii_uppercase())
+        .collect();
+    let (prefix, rest) = compact.split_at_checked(2).ok_or_else(invalid)?;
+    if !["CP", "GC"].contains(&prefix) || rest.len() != CODE_RANDOM_LENGTH + 1 {
+        return Err(invalid());
+    }
+    let indices = rest
+        .bytes()
+        .map(|byte| CODE_ALPHABET.iter().position(|letter| *letter == byte))
+        .collect::<Option<Vec<usize>>>()
+        .ok_or_else(invalid)?;
+    let (random, check) = indices.split_at(CODE_RANDOM_LENGTH);
+    if check[0] != code_check_index(random) {
+        return Err(invalid());
+    }
+    Ok(CodeKey(format_code(prefix, random)))
 }
 
-// Custom error handling enum
-#[derive(candid::CandidType, Deserialize, Serialize)]
-enum Error {
-    NotFound { msg: String },
-    InvalidOperation { msg: String },
+// Function to generate a batch of coupon or gift-card codes from on-canister randomness, each
+// unique among the codes ever issued
+#[ic_cdk::update]
+async fn generate_codes(
+    kind: CodeKind,
+    count: u32,
+    expires_at: Option<u64>,
+) -> Result<Vec<IssuedCode>, Error> {
+    ensure_admin()?;
+    journal_call("generate_codes");
+    if count == 0 || count > MAX_CODES_PER_BATCH {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Between 1 and {} codes can be generated at a time.",
+                MAX_CODES_PER_BATCH
+            ),
+        });
+    }
+    match &kind {
+        CodeKind::Coupon { promotion_id } => {
+            if PROMOTIONS.with(|service| !service.borrow().contains_key(promotion_id)) {
+                return Err(Error::NotFound {
+                    msg: format!("A promotion with id={} was not found", promotion_id),
+                });
+            }
+        }
+        CodeKind::GiftCard { value: 0 } => {
+            return Err(Error::InvalidOperation {
+                msg: "A gift card must be worth more than zero.".to_string(),
+            });
+        }
+        CodeKind::GiftCard { .. } => {}
+    }
+    if expires_at.is_some_and(|expires_at| expires_at <= time()) {
+        return Err(Error::InvalidOperation {
+            msg: "Codes must expire in the future.".to_string(),
+        });
+    }
+    let (seed,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, msg)| Error::ExternalCallFailed {
+            msg: format!("Could not get randomness: {:?} {}", code, msg),
+        })?;
+    let created_by = caller();
+    let now = time();
+    let mut codes: Vec<IssuedCode> = Vec::with_capacity(count as usize);
+    // One byte per character, as the alphabet divides 256 evenly; collisions draw again
+    let mut counter: u64 = 0;
+    while codes.len() < count as usize {
+        if counter >= 4 * count as u64 {
+            return Err(Error::Other {
+                kind: "Conflict".to_string(),
+                msg: "Could not generate enough unique codes. Please try again.".to_string(),
+            });
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(&seed);
+        hasher.update(counter.to_be_bytes());
+        counter += 1;
+        let indices: Vec<usize> = hasher
+            .finalize()
+            .iter()
+            .take(CODE_RANDOM_LENGTH)
+            .map(|byte| *byte as usize % CODE_ALPHABET.len())
+            .collect();
+        let key = CodeKey(format_code(code_prefix(&kind), &indices));
+        // Codes of either kind share one space, so a code is never reused across kinds
+        let random = &key.0[3..];
+        let taken = ["CP-", "GC-"].iter().any(|prefix| {
+            let other = CodeKey(format!("{}{}", prefix, random));
+            ISSUED_CODES.with(|service| service.borrow().contains_key(&other))
+        });
+        if taken || codes.iter().any(|code| code.code == key.0) {
+            continue;
+        }
+        codes.push(IssuedCode {
+            code: key.0,
+            kind: kind.clone(),
+            created_by,
+            created_at: now,
+            expires_at,
+            redeemed_by: None,
+            redeemed_at: None,
+        });
+    }
+    ISSUED_CODES.with(|service| {
+        let mut service = service.borrow_mut();
+        for code in &codes {
+            service.insert(CodeKey(code.code.clone()), code.clone());
+        }
+    });
+    Ok(codes)
 }
 
-// Utility function to generate unique IDs
-fn generate_unique_id() -> Result<u64, Error> {
-    ID_COUNTER
-        .with(|counter| {
-            let current_value = *counter.borrow().get();
-            counter.borrow_mut().set(current_value + 1)
-        })
-        .map_err(|_| Error::InvalidOperation {
-            msg: "Failed to generate a unique ID.".to_string(),
+// Query function to look up a generated code, in any spelling that passes the format check
+#[ic_cdk::query]
+fn get_code(code: String) -> Result<IssuedCode, Error> {
+    ensure_admin()?;
+    let key = parse_code(&code)?;
+    ISSUED_CODES
+        .with(|service| service.borrow().get(&key))
+        .ok_or(Error::NotFound {
+            msg: format!("The code {} was not found", key.0),
         })
 }
 
-// Function to validate ProductPayload inputs
-fn validate_product_payload(payload: &ProductPayload) -> Result<(), Error> {
-    if payload.name.trim().is_empty() {
+// Function to redeem a coupon or gift-card code: a gift card adds its value to the store credit
+// of the caller's account, booked as sold for cash like credit granted by admins, and a coupon
+// applies its promotion to the account's next order
+#[ic_cdk::update]
+fn redeem_code(code: String) -> Result<IssuedCode, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    journal_call("redeem_code");
+    let key = parse_code(&code)?;
+    let mut issued = ISSUED_CODES
+        .with(|service| service.borrow().get(&key))
+        .ok_or(Error::NotFound {
+            msg: format!("The code {} was not found", key.0),
+        })?;
+    let now = time();
+    if issued.redeemed_at.is_some() {
         return Err(Error::InvalidOperation {
-            msg: "Product name cannot be empty.".to_string(),
+            msg: format!("The code {} has already been redeemed", key.0),
         });
     }
-    if payload.quantity == 0 {
+    if issued
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= now)
+    {
         return Err(Error::InvalidOperation {
-            msg: "Product quantity must be greater than zero.".to_string(),
+            msg: format!("The code {} has expired", key.0),
         });
     }
-    Ok(())
+    let holder = account_holder();
+    match &issued.kind {
+        CodeKind::Coupon { promotion_id } => {
+            let holder_key = PrincipalKey(holder);
+            if PENDING_COUPONS.with(|service| service.borrow().contains_key(&holder_key)) {
+                return Err(Error::InvalidOperation {
+                    msg: "A redeemed coupon is waiting for your next order already.".to_string(),
+                });
+            }
+            if PROMOTIONS.with(|service| !service.borrow().contains_key(promotion_id)) {
+                return Err(Error::NotFound {
+                    msg: format!("The promotion of the code {} has ended", key.0),
+                });
+            }
+            PENDING_COUPONS.with(|service| service.borrow_mut().insert(holder_key, *promotion_id));
+        }
+        CodeKind::GiftCard { value } => {
+            let value = i64::try_from(*value).map_err(|_| Error::InvalidOperation {
+                msg: "The gift card's value is too large.".to_string(),
+            })?;
+            change_balance(&holder, &Tender::StoreCredit, value)?;
+            post_transfer(
+                format!("Gift card redeemed by {}", holder.to_text()),
+                None,
+                LedgerAccount::Cash,
+                LedgerAccount::StoreCredit,
+                value.unsigned_abs(),
+            );
+        }
+    }
+    issued.redeemed_by = Some(caller());
+    issued.redeemed_at = Some(now);
+    ISSUED_CODES.with(|service| service.borrow_mut().insert(key, issued.clone()));
+    Ok(issued)
 }
 
-// Function to validate StockPayload inputs
-fn validate_stock_payload(payload: &StockPayload) -> Result<(), Error> {
-    if payload.amount == 0 {
+// Helper function to get the promotion of the coupon a customer's account redeemed and has not
+// used yet
+fn pending_coupon(customer: &Principal) -> Option<Promotion> {
+    PENDING_COUPONS
+        .with(|service| {
+            service
+                .borrow()
+                .get(&PrincipalKey(balance_holder(customer)))
+        })
+        .and_then(|promotion_id| PROMOTIONS.with(|service| service.borrow().get(&promotion_id)))
+}
+
+// Helper function to use up a customer's redeemed coupon once an order applied its promotion
+fn use_pending_coupon(customer: &Principal, promotion_id: Option<u64>) {
+    let key = PrincipalKey(balance_holder(customer));
+    let pending = PENDING_COUPONS.with(|service| service.borrow().get(&key));
+    if pending.is_some() && pending == promotion_id {
+        PENDING_COUPONS.with(|service| service.borrow_mut().remove(&key));
+    }
+}
+
+// Query function to get the promotion of the coupon the caller redeemed for their next order
+#[ic_cdk::query]
+fn my_pending_coupon() -> Option<Promotion> {
+    pending_coupon(&caller())
+}
+
+// Helper function to check that an email address looks deliverable
+fn validate_email(address: &str) -> Result<(), Error> {
+    let valid = address.len() <= MAX_ADDRESS_FIELD_LENGTH
+        && !address.contains(char::is_whitespace)
+        && address.split_once('@').is_some_and(|(local, domain)| {
+            !local.is_empty()
+                && !domain.contains('@')
+                && domain.contains('.')
+                && !domain.starts_with('.')
+                && !domain.ends_with('.')
+        });
+    if !valid {
         return Err(Error::InvalidOperation {
-            msg: "Stock amount must be greater than zero.".to_string(),
+            msg: format!(
+                "'{}' is not a valid email address of at most {} bytes.",
+                address, MAX_ADDRESS_FIELD_LENGTH
+            ),
         });
     }
     Ok(())
 }
 
-// Helper function to retrieve a product by its ID
-fn _get_product(id: &u64) -> Option<Product> {
-    STORAGE.with(|service| service.borrow().get(id))
+// Function to set the HTTP email API order receipts are sent through, or with none stop emailing
+// receipts; a bridge set without an API key keeps the current one
+#[ic_cdk::update]
+fn set_email_bridge(bridge: Option<EmailBridge>) -> Result<Option<EmailBridge>, Error> {
+    ensure_admin()?;
+    journal_call("set_email_bridge");
+    let mut bridge = bridge;
+    if let Some(bridge) = bridge.as_mut() {
+        if !bridge.url.starts_with("https://") || bridge.url.len() > MAX_NOTIFIER_URL_LENGTH {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "Email bridge URLs must use HTTPS and be at most {} bytes long.",
+                    MAX_NOTIFIER_URL_LENGTH
+                ),
+            });
+        }
+        validate_email(&bridge.from)?;
+        if bridge
+            .api_key
+            .as_ref()
+            .is_some_and(|key| key.is_empty() || key.len() > MAX_NOTIFIER_URL_LENGTH)
+        {
+            return Err(Error::InvalidOperation {
+                msg: format!(
+                    "API keys must be non-empty and at most {} bytes long.",
+                    MAX_NOTIFIER_URL_LENGTH
+                ),
+            });
+        }
+        if bridge.api_key.is_none() {
+            bridge.api_key = CONFIG.with(|config| {
+                config
+                    .borrow()
+                    .get()
+                    .email_bridge
+                    .as_ref()
+                    .and_then(|current| current.api_key.clone())
+            });
+        }
+    }
+    update_config(|config| config.email_bridge = bridge.clone())?;
+    Ok(bridge.map(redacted_bridge))
 }
 
-// Query function to retrieve a product by ID
-#[ic_cdk::query]
-fn get_product(id: u64) -> Result<Product, Error> {
-    match _get_product(&id) {
-        Some(product) => Ok(product),
-        None => Err(Error::NotFound {
-            msg: format!("A product with id={} was not found", id),
-        }),
-    }
+// Helper function to hide the API key of the email bridge before returning it
+fn redacted_bridge(mut bridge: EmailBridge) -> EmailBridge {
+    bridge.api_key = bridge.api_key.map(|_| "***".to_string());
+    bridge
 }
 
-// Query function to get the current stock of a product by ID
+// Query function to get the HTTP email API receipts are sent through, without its API key
 #[ic_cdk::query]
-fn get_stock(id: u64) -> Result<u32, Error> {
-    match _get_product(&id) {
-        Some(product) => Ok(product.quantity),
-        None => Err(Error::NotFound {
-            msg: format!("A product with id={} was not found", id),
-        }),
+fn get_email_bridge() -> Result<Option<EmailBridge>, Error> {
+    ensure_admin()?;
+    Ok(CONFIG.with(|config| {
+        config
+            .borrow()
+            .get()
+            .email_bridge
+            .clone()
+            .map(redacted_bridge)
+    }))
+}
+
+// Function to set the template of receipt emails, or with none go back to the default one
+#[ic_cdk::update]
+fn set_receipt_template(template: Option<ReceiptTemplate>) -> Result<ReceiptTemplate, Error> {
+    ensure_admin()?;
+    journal_call("set_receipt_template");
+    if template.as_ref().is_some_and(|template| {
+        [&template.subject, &template.body]
+            .iter()
+            .any(|text| text.trim().is_empty() || text.len() > MAX_RECEIPT_TEMPLATE_LENGTH)
+    }) {
+        return Err(Error::InvalidOperation {
+            msg: format!(
+                "Receipt subjects and bodies must be non-empty and at most {} bytes long.",
+                MAX_RECEIPT_TEMPLATE_LENGTH
+            ),
+        });
     }
+    update_config(|config| config.receipt_template = template)?;
+    Ok(receipt_template())
 }
 
-// Function to insert a product into the stable storage
-fn do_insert(product: &Product) {
-    STORAGE.with(|service| service.borrow_mut().insert(product.id, product.clone()));
+// Helper function to get the template receipt emails are rendered with
+fn receipt_template() -> ReceiptTemplate {
+    CONFIG
+        .with(|config| config.borrow().get().receipt_template.clone())
+        .unwrap_or_else(|| ReceiptTemplate {
+            subject: DEFAULT_RECEIPT_SUBJECT.to_string(),
+            body: DEFAULT_RECEIPT_BODY.to_string(),
+        })
 }
 
-// Function to add a new product to the storage
-#[ic_cdk::update]
-fn add_product(payload: ProductPayload) -> Result<Product, Error> {
-    validate_product_payload(&payload)?;
+// Query function to get the template receipt emails are rendered with
+#[ic_cdk::query]
+fn get_receipt_template() -> Result<ReceiptTemplate, Error> {
+    ensure_admin()?;
+    Ok(receipt_template())
+}
 
-    let id = generate_unique_id()?;
-    let product = Product {
-        id,
-        name: payload.name,
-        category: payload.category,
-        quantity: payload.quantity,
-        created_at: time(),
-        updated_at: None,
+// Helper function to fill the receipt template with the details of an order
+fn render_receipt(order: &Order) -> RenderedReceipt {
+    let template = receipt_template();
+    let shop = CONFIG
+        .with(|config| config.borrow().get().shop_info.clone())
+        .map(|shop| shop.name)
+        .unwrap_or_default();
+    let (year, month, day) = civil_date(order.created_at / NANOS_PER_DAY);
+    let lines: Vec<String> = order
+        .lines
+        .iter()
+        .map(|line| {
+            let name = _get_product(&line.product_id).map_or_else(
+                || format!("Product #{}", line.product_id),
+                |product| product.name,
+            );
+            let options: Vec<String> = line
+                .options
+                .iter()
+                .flatten()
+                .map(|option| format!("{}: {}", option.group, option.choice))
+                .collect();
+            let options = if options.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", options.join(", "))
+            };
+            format!(
+                "{} x {}{} @ {} = {}",
+                line.quantity,
+                name,
+                options,
+                line.unit_price,
+                line.unit_price * line.quantity as u64
+            )
+        })
+        .collect();
+    let fill = |text: &str| {
+        text.replace("{shop}", &shop)
+            .replace("{order_id}", &order.id.to_string())
+            .replace(
+                "{order_number}",
+                order.order_number.as_deref().unwrap_or_default(),
+            )
+            .replace("{date}", &format!("{:04}-{:02}-{:02}", year, month, day))
+            .replace("{lines}", &lines.join("\n"))
+            .replace("{subtotal}", &order.subtotal.to_string())
+            .replace("{discount}", &order.discount.to_string())
+            .replace("{total}", &order.total.to_string())
     };
+    RenderedReceipt {
+        subject: fill(&template.subject),
+        body: fill(&template.body),
+    }
+}
 
-    STORAGE.with(|service| service.borrow_mut().insert(product.id, product.clone()));
-    Ok(product)
+// Query function to render the receipt email of an order with the current template
+#[ic_cdk::query]
+fn preview_receipt(order_id: u64) -> Result<RenderedReceipt, Error> {
+    ensure_admin()?;
+    let order = _get_order(&order_id).ok_or(Error::NotFound {
+        msg: format!("An order with id={} was not found", order_id),
+    })?;
+    Ok(render_receipt(&order))
 }
 
-// Function to update an existing product's details
+// Function to set the address the receipts of the caller's orders are emailed to, or with none
+// stop emailing them; it is kept for the account holder of linked devices
 #[ic_cdk::update]
-fn update_product(id: u64, payload: ProductPayload) -> Result<Product, Error> {
-    validate_product_payload(&payload)?;
+fn set_my_receipt_email(address: Option<String>) -> Result<Option<CustomerEmail>, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    journal_call("set_my_receipt_email");
+    let key = PrincipalKey(account_holder());
+    let Some(address) = address.map(|address| address.trim().to_string()) else {
+        CUSTOMER_EMAILS.with(|service| service.borrow_mut().remove(&key));
+        return Ok(None);
+    };
+    validate_email(&address)?;
+    let email = CustomerEmail {
+        address,
+        set_at: time(),
+    };
+    CUSTOMER_EMAILS.with(|service| service.borrow_mut().insert(key, email.clone()));
+    Ok(Some(email))
+}
 
-    STORAGE.with(|service| {
-        let mut storage = service.borrow_mut();
-        if let Some(mut product) = storage.get(&id) {
-            product.name = payload.name;
-            product.category = payload.category;
-            product.quantity = payload.quantity;
-            product.updated_at = Some(time());
-            storage.insert(id, product.clone());
-            Ok(product)
-        } else {
-            Err(Error::NotFound {
-                msg: format!("Product with id={} not found", id),
-            })
-        }
+// Query function to get the address the receipts of the caller's orders are emailed to
+#[ic_cdk::query]
+fn get_my_receipt_email() -> Option<CustomerEmail> {
+    CUSTOMER_EMAILS.with(|service| {
+        service
+            .borrow()
+            .get(&PrincipalKey(balance_holder(&caller())))
     })
 }
 
-// Function to add stock to a product's quantity
-#[ic_cdk::update]
-fn add_quantity(id: u64, payload: StockPayload) -> Result<Product, Error> {
-    // Validate the stock payload
-    validate_stock_payload(&payload)?;
-
-    match STORAGE.with(|service| service.borrow().get(&id)) {
-        Some(mut product) => {
-            product.quantity += payload.amount;
-            product.updated_at = Some(time());
-            do_insert(&product);
-            Ok(product)
+// Helper function to forget a customer's receipt address, along with the deliveries sent to it;
+// reports whether one was set
+fn forget_receipt_email(key: &PrincipalKey) -> bool {
+    let Some(email) = CUSTOMER_EMAILS.with(|service| service.borrow_mut().remove(key)) else {
+        return false;
+    };
+    RECEIPT_DELIVERIES.with(|service| {
+        let sent: Vec<u64> = service
+            .borrow()
+            .iter()
+            .filter(|(_, delivery)| delivery.to == email.address)
+            .map(|(order_id, _)| order_id)
+            .collect();
+        let mut deliveries = service.borrow_mut();
+        for order_id in sent {
+            deliveries.remove(&order_id);
         }
-        None => Err(Error::NotFound {
-            msg: format!("Couldn't add quantity to product with id={}. Product not found", id),
-        }),
-    }
+    });
+    true
 }
 
-#[ic_cdk::query]
-fn search_by_category(category: Category) -> Vec<Product> {
-    STORAGE.with(|service| {
+// Function to queue the receipt email of an order when the email bridge is set and its
+// customer gave an address; the first attempt starts right away
+fn queue_receipt_email(order: &Order, resends: u32) -> Option<ReceiptDelivery> {
+    if CONFIG.with(|config| config.borrow().get().email_bridge.is_none()) {
+        return None;
+    }
+    let email = CUSTOMER_EMAILS.with(|service| {
         service
             .borrow()
-            .iter()
-            .filter(|(_, product)| product.category == category) // Compare with dereferencing
-            .map(|(_, product)| product.clone()) // Clone to move into Vec
-            .collect()
-    })
+            .get(&PrincipalKey(balance_holder(&order.customer)))
+    })?;
+    let now = time();
+    let delivery = ReceiptDelivery {
+        order_id: order.id,
+        to: email.address,
+        status: OutboxStatus::Pending,
+        attempts: 0,
+        created_at: now,
+        next_attempt_at: now,
+        last_attempt_at: None,
+        delivered_at: None,
+        last_error: None,
+        resends,
+    };
+    RECEIPT_DELIVERIES.with(|service| service.borrow_mut().insert(order.id, delivery.clone()));
+    ic_cdk::spawn(send_receipt_email(order.id));
+    Some(delivery)
 }
 
-// Function to remove stock from a product's quantity
-#[ic_cdk::update]
-fn offload_quantity(id: u64, payload: StockPayload) -> Result<Product, Error> {
-    // Validate the stock payload
-    validate_stock_payload(&payload)?;
+// Helper function to build the HTTPS request handing a receipt email to the email bridge
+fn receipt_request(
+    bridge: &EmailBridge,
+    delivery: &ReceiptDelivery,
+    receipt: RenderedReceipt,
+) -> outcall::CanisterHttpRequestArgument {
+    let body = serde_json::json!({
+        "from": bridge.from,
+        "to": delivery.to,
+        "subject": receipt.subject,
+        "text": receipt.body,
+        "order_id": delivery.order_id,
+    });
+    let mut headers = vec![
+        outcall::HttpHeader {
+            name: "Content-Type".to_string(),
+            value: "application/json".to_string(),
+        },
+        // Every replica sends the request; the bridge can drop the copies with this key
+        outcall::HttpHeader {
+            name: "Idempotency-Key".to_string(),
+            value: format!("receipt-{}-{}", delivery.order_id, delivery.created_at),
+        },
+    ];
+    if let Some(key) = &bridge.api_key {
+        headers.push(outcall::HttpHeader {
+            name: "Authorization".to_string(),
+            value: format!("Bearer {}", key),
+        });
+    }
+    outcall::CanisterHttpRequestArgument {
+        url: bridge.url.clone(),
+        max_response_bytes: Some(NOTIFIER_MAX_RESPONSE_BYTES),
+        method: outcall::HttpMethod::POST,
+        headers,
+        body: Some(body.to_string().into_bytes()),
+        transform: Some(outcall::TransformContext::from_name(
+            "transform_outcall_response".to_string(),
+            vec![],
+        )),
+    }
+}
 
-    match STORAGE.with(|service| service.borrow().get(&id)) {
-        Some(mut product) => {
-            if product.quantity == 0 {
-                return Err(Error::InvalidOperation {
-                    msg: format!("Product with id={} cannot be offloaded because the quantity is 0", id),
-                });
-            } else if payload.amount > product.quantity {
-                return Err(Error::InvalidOperation {
-                    msg: format!(
-                        "Cannot offload more than available quantity. Available: {}, Trying to offload: {}",
-                        product.quantity, payload.amount
-                    ),
-                });
+// Function to make one delivery attempt of a receipt email and record its outcome; the bridge
+// refusing the message fails it for good, anything else is retried with the outbox backoff
+async fn send_receipt_email(order_id: u64) {
+    if !RECEIPTS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().insert(order_id)) {
+        return;
+    }
+    let delivery = RECEIPT_DELIVERIES
+        .with(|service| service.borrow().get(&order_id))
+        .filter(|delivery| delivery.status == OutboxStatus::Pending);
+    let bridge = CONFIG.with(|config| config.borrow().get().email_bridge.clone());
+    let (Some(delivery), Some(order)) = (delivery, _get_order(&order_id)) else {
+        RECEIPTS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&order_id));
+        return;
+    };
+    let outcome = match bridge {
+        Some(bridge) => {
+            let request = receipt_request(&bridge, &delivery, render_receipt(&order));
+            match outcall::http_request(request, NOTIFIER_OUTCALL_CYCLES).await {
+                Ok((response,)) if response.status >= 200u32 && response.status < 300u32 => Ok(()),
+                Ok((response,)) => {
+                    let retry = !(response.status >= 400u32 && response.status < 500u32)
+                        || response.status == 408u32
+                        || response.status == 429u32;
+                    Err((
+                        retry,
+                        format!("The email bridge answered with status {}", response.status),
+                    ))
+                }
+                Err((code, msg)) => Err((
+                    true,
+                    format!("Failed to reach the email bridge ({:?}): {}", code, msg),
+                )),
             }
-            product.quantity -= payload.amount;
-            product.updated_at = Some(time());
-            do_insert(&product);
-            Ok(product)
         }
-        None => Err(Error::NotFound {
-            msg: format!("Couldn't offload a product with id={}. Product not found", id),
-        }),
+        // Waits for a bridge to be set again
+        None => Err((true, "No email bridge is set".to_string())),
+    };
+    RECEIPTS_IN_FLIGHT.with(|in_flight| in_flight.borrow_mut().remove(&order_id));
+
+    let Some(mut delivery) = RECEIPT_DELIVERIES.with(|service| service.borrow().get(&order_id))
+    else {
+        return;
+    };
+    let now = time();
+    delivery.attempts += 1;
+    delivery.last_attempt_at = Some(now);
+    match outcome {
+        Ok(()) => {
+            delivery.status = OutboxStatus::Delivered;
+            delivery.delivered_at = Some(now);
+            delivery.last_error = None;
+        }
+        Err((retry, msg)) => {
+            if retry && delivery.attempts < MAX_OUTBOX_ATTEMPTS {
+                let delay = OUTBOX_RETRY_BASE_NS
+                    .saturating_mul(1 << (delivery.attempts - 1).min(16))
+                    .min(OUTBOX_RETRY_MAX_NS);
+                delivery.next_attempt_at = now.saturating_add(delay);
+            } else {
+                delivery.status = OutboxStatus::Failed;
+            }
+            let mut end = msg.len().min(MAX_RECEIPT_ERROR_LENGTH);
+            while !msg.is_char_boundary(end) {
+                end -= 1;
+            }
+            delivery.last_error = Some(msg[..end].to_string());
+        }
     }
+    RECEIPT_DELIVERIES.with(|service| service.borrow_mut().insert(order_id, delivery));
 }
 
-// Function to get all products
-#[ic_cdk::query]
-fn list_all_products() -> Vec<Product> {
-    STORAGE.with(|service| service.borrow().iter().map(|(_, product)| product).collect())
+// Helper function to get an order the caller may see receipts of: their own, or any for admins
+fn receipt_order(order_id: u64) -> Result<Order, Error> {
+    match _get_order(&order_id) {
+        Some(order) if is_own_record(&order.customer) || ensure_admin().is_ok() => Ok(order),
+        _ => Err(Error::NotFound {
+            msg: format!("An order with id={} was not found", order_id),
+        }),
+    }
 }
 
-// Function to clear all products
+// Function to email the receipt of an order again, to the address its customer has set now, at
+// most MAX_RECEIPT_RESENDS times
 #[ic_cdk::update]
-fn clear_all_products() {
-    STORAGE.with(|service| {
-        let keys: Vec<u64> = service.borrow().iter().map(|(key, _)| key).collect();
-        let mut storage = service.borrow_mut();
-        for key in keys {
-            storage.remove(&key);
-        }
-    });
+fn resend_receipt(order_id: u64) -> Result<ReceiptDelivery, Error> {
+    ensure_caller_allowed(Endpoint::PlaceOrder)?;
+    journal_call("resend_receipt");
+    let order = receipt_order(order_id)?;
+    if CONFIG.with(|config| config.borrow().get().email_bridge.is_none()) {
+        return Err(Error::InvalidOperation {
+            msg: "Receipts are not emailed by this shop.".to_string(),
+        });
+    }
+    if RECEIPTS_IN_FLIGHT.with(|in_flight| in_flight.borrow().contains(&order_id)) {
+        return Err(Error::Other {
+            kind: "Conflict".to_string(),
+            msg: format!("The receipt of order id={} is being sent", order_id),
+        });
+    }
+    let resends = RECEIPT_DELIVERIES
+        .with(|service| service.borrow().get(&order_id))
+        .map_or(0, |delivery| delivery.resends);
+    if resends >= MAX_RECEIPT_RESENDS {
+        return Err(Error::CapacityExceeded {
+            msg: format!(
+                "The receipt of order id={} was already sent again {} times",
+                order_id, MAX_RECEIPT_RESENDS
+            ),
+        });
+    }
+    queue_receipt_email(&order, resends + 1).ok_or(Error::InvalidOperation {
+        msg: "No email address is set for the customer of this order.".to_string(),
+    })
 }
 
-// Function to remove a product from storage
-#[ic_cdk::update]
-fn remove_product(id: u64) -> Result<Product, Error> {
-    STORAGE.with(|service| {
-        service.borrow_mut().remove(&id).ok_or(Error::NotFound {
-            msg: format!("Couldn't delete a product with id={}. Product not found", id),
+// Query function to get the delivery of an order's receipt email
+#[ic_cdk::query]
+fn get_receipt_delivery(order_id: u64) -> Result<ReceiptDelivery, Error> {
+    receipt_order(order_id)?;
+    RECEIPT_DELIVERIES
+        .with(|service| service.borrow().get(&order_id))
+        .ok_or(Error::NotFound {
+            msg: format!("No receipt email was sent for order id={}", order_id),
         })
-    })
+}
+
+// Query function for operators to list receipt emails, optionally only those in one status
+#[ic_cdk::query]
+fn list_receipt_deliveries(status: Option<OutboxStatus>) -> Result<Vec<ReceiptDelivery>, Error> {
+    ensure_admin()?;
+    Ok(RECEIPT_DELIVERIES.with(|service| {
+        service
+            .borrow()
+            .iter()
+            .map(|(_, delivery)| delivery)
+            .filter(|delivery| status.is_none_or(|status| delivery.status == status))
+            .collect()
+    }))
 }
 
 // Export candid interface
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Helper function to fix the system time seen by the clock
+    fn set_system_time(at: u64) {
+        TEST_SYSTEM_TIME.with(|time| time.set(at));
+    }
+
+    #[test]
+    fn clock_moves_the_system_time_by_its_offset() {
+        set_system_time(10 * NANOS_PER_DAY);
+        let clock = Clock {
+            offset_ns: 2 * NANOS_PER_DAY as i64,
+        };
+        assert_eq!(clock.now(), 12 * NANOS_PER_DAY);
+        assert_eq!(clock.system_time_at(clock.now()), 10 * NANOS_PER_DAY);
+
+        let behind = Clock {
+            offset_ns: -(11 * NANOS_PER_DAY as i64),
+        };
+        assert_eq!(behind.now(), 0);
+        assert_eq!(Clock::default().now(), 10 * NANOS_PER_DAY);
+    }
+
+    #[test]
+    fn categories_compare_by_normalized_name() {
+        let bread = Category::Other("Bread".to_string());
+        assert_eq!(bread, Category::Other(" bread ".to_string()));
+        assert_ne!(bread, Category::Other("Breads".to_string()));
+        assert_eq!(Category::Other("cake".to_string()), Category::Cake);
+        assert_eq!(Category::Other(" COOKIES".to_string()).code(), Some(2));
+        assert!(matches!(
+            Category::Other(" Cake".to_string()).canonical(),
+            Category::Cake
+        ));
+        assert_eq!(
+            category_key(&Category::Other("Bread ".to_string())),
+            category_key(&Category::Other("bread".to_string()))
+        );
+    }
+
+    #[test]
+    fn rounding_does_not_overflow_near_the_largest_price() {
+        let rounding = PriceRounding {
+            increment: Some(10),
+            ending: Some(99),
+            token_decimals: Vec::new(),
+        };
+        assert_eq!(round_price_with(&rounding, 1_234), 1_199);
+        assert_eq!(
+            round_price_with(&rounding, u64::MAX),
+            18_446_744_073_709_551_599
+        );
+        assert_eq!(
+            round_price_with(&rounding, u64::MAX - 20),
+            18_446_744_073_709_551_599
+        );
+    }
+}